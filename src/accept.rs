@@ -0,0 +1,73 @@
+//! Accepting client connections without giving up on transient failures
+//!
+//! Running out of file descriptors makes every `accept()` fail at once, so
+//! retrying straight away only spins. Failures other than a client aborting
+//! mid-handshake back off instead, until descriptors are freed again.
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// First wait after a failed accept
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Longest wait between accept attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Accept the next connection on `listener`, waiting out any errors
+pub fn accept(listener: &TcpListener) -> TcpStream {
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match listener.accept() {
+            Ok((stream, _remote)) => return stream,
+            Err(error) if is_transient(&error) => {
+                debug!("Accept failed, retrying: {}", error);
+            },
+            Err(error) => {
+                warn!("Failed to accept connection, retrying in {:?}: {}", backoff, error);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Errors about a single connection, which don't affect the next accept
+fn is_transient(error: &io::Error) -> bool {
+    matches!(error.kind(),
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock)
+}
+
+/// Raise the soft limit on open files to the hard limit, returning the new limit
+///
+/// Each session holds two sockets, so the default soft limit of 1024 on
+/// many systems caps the proxy at a few hundred sessions.
+#[cfg(target_os = "linux")]
+pub fn raise_nofile_limit() -> io::Result<u64> {
+    use nix::sys::resource::{getrlimit, setrlimit, Resource};
+    let (soft, hard) = getrlimit(Resource::RLIMIT_NOFILE)?;
+    if soft < hard {
+        setrlimit(Resource::RLIMIT_NOFILE, hard, hard)?;
+    }
+    Ok(hard)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn raise_nofile_limit() -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Not supported on this platform"))
+}
+
+/// Current soft and hard limits on open files
+#[cfg(target_os = "linux")]
+pub fn nofile_limit() -> io::Result<(u64, u64)> {
+    use nix::sys::resource::{getrlimit, Resource};
+    Ok(getrlimit(Resource::RLIMIT_NOFILE)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn nofile_limit() -> io::Result<(u64, u64)> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Not supported on this platform"))
+}
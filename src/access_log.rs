@@ -0,0 +1,148 @@
+//! Access log in the Apache combined log format
+//!
+//! One line is written per connection once it ends, e.g.
+//!
+//! ```text
+//! 10.0.0.5 - alice [06/May/2024:10:00:00 +0200] "CONNECT example.com:443" 200 5120 "-" "socks5 m=00 cmd=1 atyp=3"
+//! ```
+//!
+//! so analyzers built for web server logs, such as GoAccess or awstats, can
+//! read it. Statuses follow HTTP: 200 relayed, 400 malformed handshake, 407
+//! failed login, 403 denied, 502 destination unreachable, 500 anything else.
+//! CONNECTs have no referer, so that field carries the TLS server name
+//! instead when it is inspected, see [`crate::sni`].
+//!
+//! The user agent field carries a fingerprint of the client's handshake
+//! instead: the SOCKS version, auth methods offered in the client's order,
+//! the username/password subnegotiation version, and the command and address
+//! type of the request, as far as the client got, e.g.
+//!
+//! ```text
+//! "socks5 m=00,02 up=1 cmd=1 atyp=3"
+//! ```
+//!
+//! Clients built on the same library tend to share one, so an odd one out
+//! points at a misconfigured or hand-rolled client.
+//!
+//! Busy proxies can log only 1 in N relayed sessions with
+//! [`AccessLog::with_sampling`]; failures and denials are always logged.
+use chrono::{DateTime, Local};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Destination of access log lines, shared by every connection
+#[derive(Clone)]
+pub struct AccessLog {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    /// Log 1 in this many relayed sessions
+    sampling: u64,
+    /// Relayed sessions seen so far, logged or not
+    relayed: Arc<AtomicU64>,
+}
+
+impl AccessLog {
+    /// Append to the file at `path`, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog::new(file))
+    }
+
+    /// Write lines to `writer`
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        AccessLog { writer: Arc::new(Mutex::new(Box::new(writer))), sampling: 1, relayed: Arc::new(AtomicU64::new(0)) }
+    }
+
+    /// Only log 1 in `n` relayed sessions, starting with the first
+    pub fn with_sampling(mut self, n: u64) -> Self {
+        self.sampling = n.max(1);
+        self
+    }
+
+    /// Write one line for `entry`
+    pub fn log(&self, entry: &Entry) {
+        if entry.status == 200 && !self.relayed.fetch_add(1, Ordering::Relaxed).is_multiple_of(self.sampling) {
+            return;
+        }
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(error) = writeln!(writer, "{}", entry).and_then(|_| writer.flush()) {
+            warn!("Failed to write access log: {}", error);
+        }
+    }
+}
+
+/// A finished connection
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry<'a> {
+    pub client: IpAddr,
+    pub user: Option<&'a str>,
+    /// When the client connected
+    pub time: DateTime<Local>,
+    /// Requested `host:port`, if the client got as far as a request
+    pub request: Option<&'a str>,
+    pub status: u16,
+    /// Bytes relayed in both directions
+    pub bytes: u64,
+    /// Server name from the client's TLS ClientHello
+    pub sni: Option<&'a str>,
+    /// How the client went about its handshake
+    pub fingerprint: Option<&'a Fingerprint>,
+}
+
+/// Characteristics of a client's handshake, see the [module docs](self)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Fingerprint {
+    /// SOCKS version of the greeting
+    pub version: u8,
+    /// Auth methods offered, in the order the client sent them
+    pub methods: Vec<u8>,
+    /// Version of the username/password subnegotiation, when the client sent credentials
+    pub userpass: Option<u8>,
+    pub command: Option<u8>,
+    /// Address type of the request, as sent
+    pub address: Option<u8>,
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "socks{}", self.version)?;
+        if !self.methods.is_empty() {
+            let methods: Vec<String> = self.methods.iter().map(|method| format!("{:02x}", method)).collect();
+            write!(f, " m={}", methods.join(","))?;
+        }
+        if let Some(version) = self.userpass {
+            write!(f, " up={}", version)?;
+        }
+        if let Some(command) = self.command {
+            write!(f, " cmd={}", command)?;
+        }
+        if let Some(address) = self.address {
+            write!(f, " atyp={}", address)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for Entry<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} - {} [{}] ", self.client, self.user.unwrap_or("-"), self.time.format("%d/%b/%Y:%H:%M:%S %z"))?;
+        match self.request {
+            Some(request) => write!(f, "\"CONNECT {}\" ", request)?,
+            None => write!(f, "\"-\" ")?,
+        }
+        write!(f, "{} ", self.status)?;
+        match self.bytes {
+            0 => write!(f, "-")?,
+            bytes => write!(f, "{}", bytes)?,
+        }
+        write!(f, " \"{}\" ", self.sni.unwrap_or("-"))?;
+        match self.fingerprint {
+            Some(fingerprint) => write!(f, "\"{}\"", fingerprint),
+            None => write!(f, "\"-\""),
+        }
+    }
+}
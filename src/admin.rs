@@ -0,0 +1,588 @@
+//! Control over live sessions: listing, killing and temporary bans
+//!
+//! Served over HTTP on the address given with [`crate::Merino::with_admin`]:
+//!
+//! ```text
+//! GET  /sessions                     one line per relaying session
+//! POST /sessions/<id>/kill           reset both sides of a session
+//! POST /sessions/<id>/capture        mirror a session's traffic into a pcap file
+//! DELETE /sessions/<id>/capture      stop capturing it
+//! GET  /bans                         one line per active ban
+//! POST /bans/source/<ip>?minutes=N   refuse a client address
+//! POST /bans/user/<name>?minutes=N   refuse a user
+//! DELETE /bans/source/<ip>           lift a ban
+//! DELETE /bans/user/<name>
+//! GET  /destinations?limit=N         connects and failures by destination, failing most first
+//! GET  /resources                    cgroup limits and usage, descriptors, memory and connections
+//! GET  /listeners                    one line per listener opened at runtime
+//! POST /listeners/<addr>?auth=none    open a listener, see [`crate::listeners`]
+//! DELETE /listeners/<addr>           close it
+//! GET  /maintenance                  whether new clients are being turned away
+//! POST /maintenance                  refuse new clients, letting sessions finish
+//! POST /maintenance?standby=<addr>   pass new clients on to the proxy at addr instead
+//! DELETE /maintenance                take new clients again
+//! ```
+//!
+//! Bans last an hour unless `minutes` is given, and end every matching live
+//! session. Each action is logged to the `merino::audit` target. The API
+//! has no authentication of its own, so bind it to loopback. Requests with
+//! an `Origin` header, or a `Host` other than loopback or the admin
+//! address, are refused, so web pages can't reach it through a browser.
+//!
+//! Captures are written to `session-<id>.pcap` in the directory given with
+//! [`Admin::capture_to`], and end with the session at the latest, see
+//! [`crate::capture`].
+//!
+//! Bans are kept in the proxy's [`SharedState`]. With one shared between
+//! instances, e.g. Redis, every instance refuses the banned clients, though
+//! live sessions are only ended on the instance the ban was made on.
+//!
+//! With [`Admin::persist_to`], bans are kept in a CSV file so they survive
+//! restarts:
+//!
+//! ```csv
+//! kind,target,until
+//! source,203.0.113.7,2024-05-06T11:00:00+02:00
+//! user,mallory,2024-05-07T10:00:00+02:00
+//! ```
+use chrono::{DateTime, Local};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, prelude::*, BufReader};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::capture::Tap;
+use crate::cgroup::Cgroup;
+use crate::process::ProcessUsage;
+use crate::lifecycle::{Lifecycle, Maintenance};
+use crate::listeners::{Handle, ListenerSpec};
+use crate::state::{LocalState, SharedState};
+use crate::metrics::{Metrics, TOP_DESTINATIONS};
+
+/// How long bans last when no duration is given
+const DEFAULT_BAN: Duration = Duration::from_secs(60 * 60);
+
+/// Longest ban, keeping its expiry within what dates can represent
+const MAX_BAN: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// A session that is relaying
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub source: IpAddr,
+    pub user: Option<String>,
+    /// Requested `host:port`
+    pub request: String,
+    pub started: DateTime<Local>,
+}
+
+impl fmt::Display for SessionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\t{}\t{}\t{}\t{}", self.id, self.source, self.user.as_deref().unwrap_or("-"), self.request, self.started.to_rfc3339())
+    }
+}
+
+/// Who a ban refuses
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BanTarget {
+    Source(IpAddr),
+    User(String),
+}
+
+impl BanTarget {
+    fn matches(&self, source: IpAddr, user: Option<&str>) -> bool {
+        match self {
+            BanTarget::Source(ip) => *ip == source,
+            BanTarget::User(name) => user == Some(name.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BanTarget::Source(ip) => write!(f, "source {}", ip),
+            BanTarget::User(name) => write!(f, "user {}", name),
+        }
+    }
+}
+
+/// A temporary ban
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub until: DateTime<Local>,
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\tuntil {}", self.target, self.until.to_rfc3339())
+    }
+}
+
+/// Row of the ban list as it appears on disk
+#[derive(Debug, Serialize, Deserialize)]
+struct BanRecord {
+    kind: String,
+    target: String,
+    until: String,
+}
+
+impl BanRecord {
+    fn parse(self) -> Result<Ban, Box<dyn Error>> {
+        parse_ban(&self.kind, &self.target, &self.until)
+    }
+}
+
+/// The ban of the `kind` `target` until the RFC 3339 time `until`, as the ban list and [`SharedState`]s store it
+pub(crate) fn parse_ban(kind: &str, target: &str, until: &str) -> Result<Ban, Box<dyn Error>> {
+    let until = DateTime::parse_from_rfc3339(until)
+        .map_err(|e| format!("Invalid ban expiry `{}`: {}", until, e))?
+        .with_timezone(&Local);
+    let target = match kind {
+        "source" => BanTarget::Source(target.parse().map_err(|e| format!("Invalid banned address `{}`: {}", target, e))?),
+        "user" => BanTarget::User(target.to_string()),
+        kind => return Err(format!("Unknown ban kind `{}`", kind).into()),
+    };
+    Ok(Ban { target, until })
+}
+
+impl From<&Ban> for BanRecord {
+    fn from(ban: &Ban) -> Self {
+        let (kind, target) = match &ban.target {
+            BanTarget::Source(ip) => ("source", ip.to_string()),
+            BanTarget::User(name) => ("user", name.clone()),
+        };
+        BanRecord { kind: kind.to_string(), target, until: ban.until.to_rfc3339() }
+    }
+}
+
+/// A relaying session and how to end it
+struct Live {
+    info: SessionInfo,
+    kill: Box<dyn Fn() + Send>,
+    /// Capture of its traffic, for sessions that can be captured
+    tap: Option<Tap>,
+}
+
+/// Live sessions and bans, shared by every connection
+#[derive(Default)]
+pub struct Admin {
+    sessions: Mutex<HashMap<u64, Live>>,
+    /// Keeps the bans, see [`Admin::share_bans`]
+    state: Mutex<Option<Arc<dyn SharedState>>>,
+    /// File the bans are saved to whenever they change
+    ban_list: OnceLock<PathBuf>,
+    /// Directory captures are written to
+    capture_dir: OnceLock<PathBuf>,
+    /// Opens listeners sharing the proxy's settings, once it is serving
+    opener: OnceLock<Box<Opener>>,
+    listeners: Mutex<HashMap<SocketAddr, (ListenerSpec, Handle)>>,
+}
+
+type Opener = dyn Fn(ListenerSpec) -> io::Result<(ListenerSpec, Handle)> + Send + Sync;
+
+impl Admin {
+    /// Keep bans in `state`, e.g. to share them with other instances, along with those made so far
+    pub(crate) fn share_bans(&self, state: Arc<dyn SharedState>) {
+        let previous = self.state.lock().unwrap().replace(state.clone());
+        let carried = previous.map_or(Ok(()), |previous| previous.bans()?.iter().try_for_each(|ban| state.ban(ban)));
+        if let Err(error) = carried {
+            warn!("Failed to carry the bans over: {}", error);
+        }
+    }
+
+    /// Where the bans are kept, only in this process unless shared
+    fn state(&self) -> Arc<dyn SharedState> {
+        self.state.lock().unwrap().get_or_insert_with(|| Arc::new(LocalState::default())).clone()
+    }
+
+    /// Load the bans saved at `path`, if it exists, and save them there from now on
+    pub fn persist_to<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        if path.exists() {
+            let now = Local::now();
+            let mut loaded = Vec::new();
+            for result in csv::Reader::from_path(path)?.deserialize() {
+                let record: BanRecord = result?;
+                let ban = record.parse()?;
+                if ban.until > now {
+                    loaded.push(ban);
+                }
+            }
+            info!("Loaded {} bans from {}", loaded.len(), path.display());
+            let state = self.state();
+            for ban in loaded {
+                state.ban(&ban)?;
+            }
+        }
+        self.ban_list.set(path.to_path_buf()).map_err(|_| "Already saving bans")?;
+        Ok(())
+    }
+
+    /// Allow capturing sessions, writing the captures to `dir`
+    pub fn capture_to<P: AsRef<Path>>(&self, dir: P) -> Result<(), Box<dyn Error>> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            return Err(format!("{} isn't a directory", dir.display()).into());
+        }
+        self.capture_dir.set(dir.to_path_buf()).map_err(|_| "Already capturing to a directory")?;
+        Ok(())
+    }
+
+    /// Mirror session `id`'s traffic into a pcap file, returning its path or `None` if the session isn't relaying
+    pub fn start_capture(&self, id: u64) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        let dir = self.capture_dir.get().ok_or("Captures aren't enabled, set a capture directory")?;
+        let sessions = self.sessions.lock().unwrap();
+        let live = match sessions.get(&id) {
+            Some(live) => live,
+            None => return Ok(None),
+        };
+        let tap = live.tap.as_ref().ok_or("This session can't be captured")?;
+        let path = dir.join(format!("session-{}.pcap", id));
+        tap.start(&path)?;
+        info!(target: "merino::audit", "Capturing session {} from {} to {} into {}", id, live.info.source, live.info.request, path.display());
+        Ok(Some(path))
+    }
+
+    /// Stop capturing session `id`, returning whether it was being captured
+    pub fn stop_capture(&self, id: u64) -> bool {
+        let stopped = self.sessions.lock().unwrap().get(&id)
+            .and_then(|live| live.tap.as_ref())
+            .is_some_and(Tap::stop);
+        if stopped {
+            info!(target: "merino::audit", "Stopped capturing session {}", id);
+        }
+        stopped
+    }
+
+    /// Open a listener while serving, returning it with the address it bound
+    pub fn open_listener(&self, spec: ListenerSpec) -> Result<ListenerSpec, Box<dyn Error>> {
+        let opener = self.opener.get().ok_or("Not serving yet")?;
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.contains_key(&spec.addr) {
+            return Err(format!("Already listening on {}", spec.addr).into());
+        }
+        let (spec, handle) = opener(spec)?;
+        info!(target: "merino::audit", "Opened listener {}", spec);
+        listeners.insert(spec.addr, (spec.clone(), handle));
+        Ok(spec)
+    }
+
+    /// Close a listener opened with [`Admin::open_listener`], returning whether there was one
+    pub fn close_listener(&self, addr: SocketAddr) -> bool {
+        match self.listeners.lock().unwrap().remove(&addr) {
+            Some((spec, handle)) => {
+                handle.close();
+                info!(target: "merino::audit", "Closed listener {}", spec);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Listeners opened at runtime, by address
+    pub fn listeners(&self) -> Vec<ListenerSpec> {
+        let mut listeners: Vec<ListenerSpec> = self.listeners.lock().unwrap().values().map(|(spec, _)| spec.clone()).collect();
+        listeners.sort_by_key(|spec| spec.addr);
+        listeners
+    }
+
+    pub(crate) fn open_with<F>(&self, opener: F)
+    where F: Fn(ListenerSpec) -> io::Result<(ListenerSpec, Handle)> + Send + Sync + 'static {
+        self.opener.set(Box::new(opener)).unwrap_or(());
+    }
+
+    /// Sessions currently relaying, oldest first
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self.sessions.lock().unwrap().values().map(|live| live.info.clone()).collect();
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+
+    /// Reset both sides of session `id`, returning whether it was relaying
+    pub fn kill(&self, id: u64) -> bool {
+        match self.sessions.lock().unwrap().remove(&id) {
+            Some(live) => {
+                info!(target: "merino::audit", "Killed session {} from {} to {}", id, live.info.source, live.info.request);
+                (live.kill)();
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Refuse `target` for `duration`, at most a hundred years, ending its live sessions
+    pub fn ban(&self, target: BanTarget, duration: Duration) -> Result<(), Box<dyn Error>> {
+        let until = Local::now() + chrono::Duration::from_std(duration.min(MAX_BAN)).expect("MAX_BAN fits a chrono::Duration");
+        let state = self.state();
+        state.ban(&Ban { target: target.clone(), until })?;
+        info!(target: "merino::audit", "Banned {} until {}", target, until.to_rfc3339());
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let ids: Vec<u64> = sessions.values()
+            .filter(|live| target.matches(live.info.source, live.info.user.as_deref()))
+            .map(|live| live.info.id)
+            .collect();
+        for id in ids {
+            if let Some(live) = sessions.remove(&id) {
+                info!(target: "merino::audit", "Killed session {} of banned {}", id, target);
+                (live.kill)();
+            }
+        }
+        drop(sessions);
+        self.save(&state.bans()?);
+        Ok(())
+    }
+
+    /// End the ban on `target`, returning whether there was one
+    pub fn lift(&self, target: &BanTarget) -> Result<bool, Box<dyn Error>> {
+        let state = self.state();
+        if !state.lift(target)? {
+            return Ok(false);
+        }
+        info!(target: "merino::audit", "Lifted the ban on {}", target);
+        self.save(&state.bans()?);
+        Ok(true)
+    }
+
+    /// Bans still in effect
+    pub fn bans(&self) -> Result<Vec<Ban>, Box<dyn Error>> {
+        self.state().bans()
+    }
+
+    /// The ban refusing a client at `source`, authenticated as `user`, if any
+    pub fn banned(&self, source: IpAddr, user: Option<&str>) -> Result<Option<Ban>, Box<dyn Error>> {
+        let mut targets = vec![BanTarget::Source(source)];
+        targets.extend(user.map(|user| BanTarget::User(user.to_string())));
+        self.state().banned(&targets)
+    }
+
+    /// Write `bans` to the ban list, if there is one
+    fn save(&self, bans: &[Ban]) {
+        let path = match self.ban_list.get() {
+            Some(path) => path,
+            None => return,
+        };
+        // Replace the file at once so a crash can't leave half a list behind
+        let temp = path.with_extension("tmp");
+        let written = csv::Writer::from_path(&temp).map_err(Box::<dyn Error>::from).and_then(|mut writer| {
+            for ban in bans {
+                writer.serialize(BanRecord::from(ban))?;
+            }
+            writer.flush()?;
+            Ok(())
+        });
+        if let Err(error) = written.and_then(|_| fs::rename(&temp, path).map_err(Box::from)) {
+            warn!("Failed to save bans to {}: {}", path.display(), error);
+        }
+    }
+
+    /// Track a session that started relaying until [`Admin::unregister`]
+    pub(crate) fn register<F: Fn() + Send + 'static>(&self, info: SessionInfo, kill: F, tap: Option<Tap>) {
+        self.sessions.lock().unwrap().insert(info.id, Live { info, kill: Box::new(kill), tap });
+    }
+
+    pub(crate) fn unregister(&self, id: u64) {
+        self.sessions.lock().unwrap().remove(&id);
+    }
+}
+
+/// Answer admin requests on `listener`
+pub(crate) fn serve(listener: TcpListener, admin: Arc<Admin>, metrics: Arc<Metrics>, lifecycle: Arc<Lifecycle>, max_connections: Option<u64>) {
+    loop {
+        let stream = crate::accept::accept(&listener);
+        let (admin, metrics, lifecycle) = (admin.clone(), metrics.clone(), lifecycle.clone());
+        thread::spawn(move || {
+            if let Err(error) = respond(stream, &admin, &metrics, &lifecycle, max_connections) {
+                debug!("Failed to serve admin request: {}", error);
+            }
+        });
+    }
+}
+
+fn respond(mut stream: TcpStream, admin: &Admin, metrics: &Metrics, lifecycle: &Lifecycle, max_connections: Option<u64>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new((&stream).take(8192));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    // Only Origin and Host matter, to tell browsers apart from operators
+    let mut cross_site = false;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("origin") || (name.eq_ignore_ascii_case("host") && !local_host(value.trim(), &stream)) {
+                cross_site = true;
+            }
+        }
+        header.clear();
+    }
+    let mut words = line.split_whitespace();
+    let (method, target) = (words.next().unwrap_or_default(), words.next().unwrap_or_default());
+
+    let (status, body) = if cross_site {
+        warn!(target: "merino::audit", "Refused admin request {} {} from {} made by a web page", method, target, stream.peer_addr()?);
+        ("403 Forbidden", "Requests from web pages are refused\n".to_string())
+    } else {
+        handle(admin, metrics, lifecycle, max_connections, method, target)
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+    stream.shutdown(Shutdown::Write)
+}
+
+/// Whether `host`, a Host header, names loopback or the address `stream` was accepted on
+///
+/// Any other name may be one a web page rebound to this address.
+fn local_host(host: &str, stream: &TcpStream) -> bool {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) && !name.ends_with(':') => name,
+        _ => host,
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    if name.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    match name.parse::<IpAddr>() {
+        Ok(ip) => ip.is_loopback() || stream.local_addr().is_ok_and(|local| local.ip() == ip),
+        Err(_) => false,
+    }
+}
+
+/// Status line and body answering `method` on `target`
+fn handle(admin: &Admin, metrics: &Metrics, lifecycle: &Lifecycle, max_connections: Option<u64>, method: &str, target: &str) -> (&'static str, String) {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target, ""),
+    };
+    let minutes = query.split('&').find_map(|pair| pair.strip_prefix("minutes="));
+    let duration = match minutes.map(str::parse::<u64>) {
+        Some(Ok(minutes)) => match minutes.checked_mul(60).map(Duration::from_secs) {
+            Some(duration) if duration <= MAX_BAN => duration,
+            _ => return ("400 Bad Request", format!("minutes must be at most {}\n", MAX_BAN.as_secs() / 60)),
+        },
+        Some(Err(_)) => return ("400 Bad Request", "minutes must be a number\n".to_string()),
+        None => DEFAULT_BAN,
+    };
+    let limit = match query.split('&').find_map(|pair| pair.strip_prefix("limit=")).map(str::parse::<usize>) {
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return ("400 Bad Request", "limit must be a number\n".to_string()),
+        None => TOP_DESTINATIONS,
+    };
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (method, segments.as_slice()) {
+        ("GET", ["sessions"]) => ("200 OK", admin.sessions().iter().map(|session| format!("{}\n", session)).collect()),
+        ("POST", ["sessions", id, "kill"]) => match id.parse() {
+            Ok(id) if admin.kill(id) => ("200 OK", format!("Killed session {}\n", id)),
+            Ok(id) => ("404 Not Found", format!("No session {}\n", id)),
+            Err(_) => ("400 Bad Request", format!("Invalid session `{}`\n", id)),
+        },
+        ("POST", ["sessions", id, "capture"]) => match id.parse() {
+            Ok(id) => match admin.start_capture(id) {
+                Ok(Some(path)) => ("200 OK", format!("Capturing session {} into {}\n", id, path.display())),
+                Ok(None) => ("404 Not Found", format!("No session {}\n", id)),
+                Err(error) => ("409 Conflict", format!("Can't capture session {}: {}\n", id, error)),
+            },
+            Err(_) => ("400 Bad Request", format!("Invalid session `{}`\n", id)),
+        },
+        ("DELETE", ["sessions", id, "capture"]) => match id.parse() {
+            Ok(id) if admin.stop_capture(id) => ("200 OK", format!("Stopped capturing session {}\n", id)),
+            Ok(id) => ("404 Not Found", format!("Session {} isn't being captured\n", id)),
+            Err(_) => ("400 Bad Request", format!("Invalid session `{}`\n", id)),
+        },
+        ("GET", ["destinations"]) => ("200 OK", metrics.destinations(limit).iter().map(|stats| format!("{}\n", stats)).collect()),
+        ("GET", ["resources"]) => ("200 OK", resources(metrics, max_connections)),
+        ("GET", ["listeners"]) => ("200 OK", admin.listeners().iter().map(|spec| format!("{}\n", spec)).collect()),
+        ("POST", ["listeners", addr]) => match ListenerSpec::parse(addr, query) {
+            Ok(spec) => match admin.open_listener(spec) {
+                Ok(spec) => ("200 OK", format!("Listening on {}\n", spec.addr)),
+                Err(error) => ("409 Conflict", format!("Can't listen on {}: {}\n", addr, error)),
+            },
+            Err(error) => ("400 Bad Request", format!("{}\n", error)),
+        },
+        ("DELETE", ["listeners", addr]) => match addr.parse() {
+            Ok(addr) if admin.close_listener(addr) => ("200 OK", format!("Closed listener {}\n", addr)),
+            Ok(addr) => ("404 Not Found", format!("No listener on {}\n", addr)),
+            Err(_) => ("400 Bad Request", format!("Invalid address `{}`\n", addr)),
+        },
+        ("GET", ["maintenance"]) => match lifecycle.maintenance() {
+            Some(maintenance) => ("200 OK", format!("{}\n", maintenance)),
+            None => ("200 OK", "off\n".to_string()),
+        },
+        ("POST", ["maintenance"]) => {
+            let maintenance = match query.split('&').find_map(|pair| pair.strip_prefix("standby=")).map(str::parse) {
+                Some(Ok(addr)) => Maintenance::Standby(addr),
+                Some(Err(_)) => return ("400 Bad Request", "standby must be an address, e.g. 10.0.0.2:1080\n".to_string()),
+                None => Maintenance::Refuse,
+            };
+            lifecycle.start_maintenance(maintenance);
+            info!(target: "merino::audit", "Maintenance started, {}", maintenance);
+            ("200 OK", format!("{}\n", maintenance))
+        },
+        ("DELETE", ["maintenance"]) => match lifecycle.end_maintenance() {
+            true => {
+                info!(target: "merino::audit", "Maintenance ended");
+                ("200 OK", "Taking new clients again\n".to_string())
+            },
+            false => ("404 Not Found", "Not in maintenance\n".to_string()),
+        },
+        ("GET", ["bans"]) => match admin.bans() {
+            Ok(bans) => ("200 OK", bans.iter().map(|ban| format!("{}\n", ban)).collect()),
+            Err(error) => ("503 Service Unavailable", format!("Can't list the bans: {}\n", error)),
+        },
+        ("POST", ["bans", "source", ip]) => match ip.parse() {
+            Ok(ip) => match admin.ban(BanTarget::Source(ip), duration) {
+                Ok(()) => ("200 OK", format!("Banned source {}\n", ip)),
+                Err(error) => ("503 Service Unavailable", format!("Can't ban source {}: {}\n", ip, error)),
+            },
+            Err(_) => ("400 Bad Request", format!("Invalid address `{}`\n", ip)),
+        },
+        ("POST", ["bans", "user", name]) if !name.is_empty() => match admin.ban(BanTarget::User(name.to_string()), duration) {
+            Ok(()) => ("200 OK", format!("Banned user {}\n", name)),
+            Err(error) => ("503 Service Unavailable", format!("Can't ban user {}: {}\n", name, error)),
+        },
+        ("DELETE", ["bans", kind, target]) => {
+            let target = match *kind {
+                "source" => match target.parse() {
+                    Ok(ip) => BanTarget::Source(ip),
+                    Err(_) => return ("400 Bad Request", format!("Invalid address `{}`\n", target)),
+                },
+                "user" => BanTarget::User(target.to_string()),
+                _ => return ("404 Not Found", "Unknown request\n".to_string()),
+            };
+            match admin.lift(&target) {
+                Ok(true) => ("200 OK", format!("Lifted the ban on {}\n", target)),
+                Ok(false) => ("404 Not Found", format!("{} isn't banned\n", target)),
+                Err(error) => ("503 Service Unavailable", format!("Can't lift the ban on {}: {}\n", target, error)),
+            }
+        },
+        _ => ("404 Not Found", "Unknown request\n".to_string()),
+    }
+}
+
+/// One `name value` line per resource limit and usage, `-` where unknown or unlimited
+fn resources(metrics: &Metrics, max_connections: Option<u64>) -> String {
+    let cgroup = Cgroup::detect();
+    let limits = cgroup.as_ref().map(Cgroup::limits).unwrap_or_default();
+    let usage = cgroup.as_ref().map(Cgroup::usage).unwrap_or_default();
+    let process = ProcessUsage::sample();
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    [
+        ("memory_limit_bytes", optional(limits.memory.map(|bytes| bytes.to_string()))),
+        ("memory_used_bytes", optional(usage.memory.map(|bytes| bytes.to_string()))),
+        ("cpu_limit", optional(limits.cpus.map(|cpus| cpus.to_string()))),
+        ("cpu_used_seconds", optional(usage.cpu.map(|cpu| format!("{:.3}", cpu.as_secs_f64())))),
+        ("open_fds", optional(process.open_fds.map(|fds| fds.to_string()))),
+        ("resident_memory_bytes", optional(process.resident_memory.map(|bytes| bytes.to_string()))),
+        ("relay_buffer_bytes", metrics.relay_buffers.load(std::sync::atomic::Ordering::Relaxed).to_string()),
+        ("connections", metrics.open_connections().to_string()),
+        ("max_connections", optional(max_connections.map(|max| max.to_string()))),
+        ("refused_connections", metrics.refused.load(std::sync::atomic::Ordering::Relaxed).to_string()),
+    ].iter().map(|(name, value)| format!("{} {}\n", name, value)).collect()
+}
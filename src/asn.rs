@@ -0,0 +1,68 @@
+//! Autonomous system lookups in a MaxMind DB (GeoLite2-ASN, GeoIP2-ISP or compatible)
+//!
+//! Rules can name destinations by the network announcing them, e.g.
+//! `AS15169`, which stays accurate as that network renumbers, unlike a
+//! hand-kept list of CIDRs. Databases are read with the `maxminddb` crate,
+//! and only `autonomous_system_number` and `autonomous_system_organization`
+//! are used.
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Autonomous system a network belongs to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Asn {
+    pub number: u32,
+    pub organization: Option<String>,
+}
+
+impl fmt::Display for Asn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AS{}", self.number)?;
+        if let Some(organization) = &self.organization {
+            write!(f, " ({})", organization)?;
+        }
+        Ok(())
+    }
+}
+
+/// An ASN database loaded into memory
+#[derive(Debug)]
+pub struct AsnDb {
+    reader: maxminddb::Reader<Vec<u8>>,
+}
+
+impl AsnDb {
+    /// Load a database file, e.g. GeoLite2-ASN.mmdb
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::from_bytes(data).map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    /// Parse a database already in memory
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        Ok(AsnDb { reader: maxminddb::Reader::from_source(data)? })
+    }
+
+    /// Autonomous system announcing `ip`, if the database knows it
+    pub fn lookup(&self, ip: IpAddr) -> Option<Asn> {
+        // Mapped addresses are looked up as the IPv4 addresses they are
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        let record: maxminddb::geoip2::Asn = self.reader.lookup(ip).ok()?;
+        Some(Asn {
+            number: record.autonomous_system_number?,
+            organization: record.autonomous_system_organization.map(str::to_string),
+        })
+    }
+}
+
+/// Parse `AS15169` or `as15169` as an AS number
+pub fn parse_asn(s: &str) -> Option<u32> {
+    s.strip_prefix("AS").or_else(|| s.strip_prefix("as"))?.parse().ok()
+}
@@ -0,0 +1,164 @@
+//! Load generator driving concurrent sessions through a SOCKS5 proxy
+use std::error::Error;
+use std::fmt;
+use std::io::prelude::*;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client;
+
+/// Settings for a benchmark run
+#[derive(Clone, Debug)]
+pub struct BenchConfig {
+    /// Proxy under test
+    pub proxy: SocketAddr,
+    /// Echo server the proxy connects to, one is started locally when `None`
+    pub target: Option<(String, u16)>,
+    /// Total number of sessions to run
+    pub sessions: usize,
+    /// Number of sessions in flight at once
+    pub concurrency: usize,
+    /// Bytes sent (and echoed back) per session
+    pub payload: usize,
+    pub credentials: Option<(String, String)>,
+}
+
+/// Results of a benchmark run
+#[derive(Debug)]
+pub struct BenchReport {
+    pub succeeded: usize,
+    pub failed: usize,
+    /// Handshake latencies of the successful sessions, sorted ascending
+    pub handshakes: Vec<Duration>,
+    /// Payload bytes relayed in both directions
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl BenchReport {
+    /// Nearest-rank percentile of the handshake latencies
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.handshakes.is_empty() {
+            return None;
+        }
+        let rank = ((p / 100.0) * self.handshakes.len() as f64).ceil() as usize;
+        Some(self.handshakes[rank.max(1).min(self.handshakes.len()) - 1])
+    }
+
+    /// Relayed bytes per second over the whole run
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Sessions:   {} ok, {} failed in {:.2?}", self.succeeded, self.failed, self.elapsed)?;
+        write!(f, "Handshake: ")?;
+        for p in &[50.0, 90.0, 99.0, 100.0] {
+            match self.percentile(*p) {
+                Some(latency) => write!(f, " p{}={:.2?}", p, latency)?,
+                None => write!(f, " p{}=-", p)?,
+            }
+        }
+        writeln!(f)?;
+        write!(f, "Throughput: {:.2} MiB/s ({} bytes)", self.throughput() / (1024.0 * 1024.0), self.bytes)
+    }
+}
+
+/// Start a thread echoing back everything sent to it, returning its address
+pub fn spawn_echo_server() -> Result<SocketAddr, Box<dyn Error>> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for mut reader in listener.incoming().flatten() {
+            thread::spawn(move || {
+                if let Ok(mut writer) = reader.try_clone() {
+                    std::io::copy(&mut reader, &mut writer).unwrap_or(0);
+                    writer.shutdown(Shutdown::Write).unwrap_or(());
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+/// Run a single session, returning its handshake latency
+fn session(config: &BenchConfig, host: &str, port: u16, payload: &[u8]) -> Result<Duration, Box<dyn Error>> {
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(config.proxy)?;
+    let credentials = config.credentials.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+    client::connect(&mut stream, host, port, credentials)?;
+    let handshake = start.elapsed();
+
+    let mut writer = stream.try_clone()?;
+    let payload_owned = payload.to_vec();
+    let upload = thread::spawn(move || -> std::io::Result<()> {
+        writer.write_all(&payload_owned)?;
+        writer.shutdown(Shutdown::Write)
+    });
+
+    let mut echoed = Vec::with_capacity(payload.len());
+    stream.read_to_end(&mut echoed)?;
+    upload.join().map_err(|_| "Upload thread panicked")??;
+
+    if echoed.len() != payload.len() {
+        return Err(format!("Echoed {} of {} bytes", echoed.len(), payload.len()).into());
+    }
+
+    Ok(handshake)
+}
+
+/// Drive `config.sessions` sessions through the proxy and collect the results
+pub fn run(config: &BenchConfig) -> Result<BenchReport, Box<dyn Error>> {
+    let (host, port) = match &config.target {
+        Some((host, port)) => (host.clone(), *port),
+        None => {
+            let addr = spawn_echo_server()?;
+            (addr.ip().to_string(), addr.port())
+        }
+    };
+
+    let payload: Arc<Vec<u8>> = Arc::new((0..config.payload).map(|i| i as u8).collect());
+    let remaining = Arc::new(AtomicUsize::new(config.sessions));
+    let results = Arc::new(Mutex::new(Vec::with_capacity(config.sessions)));
+
+    let start = Instant::now();
+    let workers: Vec<_> = (0..config.concurrency.max(1)).map(|_| {
+        let config = config.clone();
+        let host = host.clone();
+        let payload = payload.clone();
+        let remaining = remaining.clone();
+        let results = results.clone();
+        thread::spawn(move || {
+            // Claim sessions until none are left
+            while remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok() {
+                let result = session(&config, &host, port, &payload);
+                if let Err(error) = &result {
+                    debug!("Bench session failed: {}", error);
+                }
+                results.lock().unwrap().push(result.ok());
+            }
+        })
+    }).collect();
+
+    for worker in workers {
+        worker.join().map_err(|_| "Bench worker panicked")?;
+    }
+    let elapsed = start.elapsed();
+
+    let results = results.lock().unwrap();
+    let mut handshakes: Vec<Duration> = results.iter().flatten().cloned().collect();
+    handshakes.sort();
+
+    Ok(BenchReport {
+        succeeded: handshakes.len(),
+        failed: results.len() - handshakes.len(),
+        bytes: handshakes.len() as u64 * config.payload as u64 * 2,
+        handshakes,
+        elapsed,
+    })
+}
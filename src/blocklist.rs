@@ -0,0 +1,222 @@
+//! Domain and IP blocklists, read from files or pulled from threat-intel feeds
+//!
+//! A list holds one entry per line: a domain, which also blocks its
+//! subdomains, an IP or a CIDR. Hosts-file style lines such as
+//! `0.0.0.0 ads.example` are accepted too, as many feeds are published that
+//! way. Anything after a `#` is a comment.
+//!
+//! Lists given as an `https://` or `http://` URL are fetched again every refresh interval,
+//! sending the last `ETag` so an unchanged feed costs a `304 Not Modified`.
+//! Lists given as a path are read again when the file changes. A refresh
+//! that fails keeps the previous entries.
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use ipnet::IpNet;
+
+use crate::rules::Destination;
+
+/// How long fetching a feed may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Entries of a list at one point in time
+#[derive(Debug, Default)]
+struct Entries {
+    domains: HashSet<String>,
+    nets: Vec<IpNet>,
+}
+
+impl Entries {
+    fn parse<R: Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut entries = Entries::default();
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut fields = line.split_whitespace();
+            let entry = match (fields.next(), fields.next()) {
+                (None, _) => continue,
+                // Hosts-file style, the first field is the sinkhole address
+                (Some(_), Some(domain)) => domain,
+                (Some(entry), None) => entry,
+            };
+            if let Ok(net) = entry.parse::<IpNet>() {
+                entries.nets.push(net);
+            } else if let Ok(ip) = entry.parse::<IpAddr>() {
+                entries.nets.push(IpNet::from(ip));
+            } else {
+                entries.domains.insert(entry.trim_end_matches('.').to_lowercase());
+            }
+        }
+        Ok(entries)
+    }
+
+    fn contains(&self, destination: &Destination) -> bool {
+        match destination {
+            Destination::Ip(ip) => self.nets.iter().any(|net| net.contains(ip)),
+            Destination::Domain(domain) => {
+                // The domain itself or any domain it is under
+                let domain = domain.trim_end_matches('.').to_lowercase();
+                let mut name = domain.as_str();
+                loop {
+                    if self.domains.contains(name) {
+                        return true;
+                    }
+                    match name.split_once('.') {
+                        Some((_, parent)) => name = parent,
+                        None => return false,
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Where a list comes from, and what identifies the version last read
+#[derive(Debug)]
+enum Source {
+    /// Parsed once, never refreshed
+    Static,
+    File { path: PathBuf, modified: Option<SystemTime> },
+    Url { url: String, etag: Option<String> },
+}
+
+/// A blocklist, refreshed from its source
+#[derive(Debug)]
+pub struct Blocklist {
+    name: String,
+    source: Mutex<Source>,
+    entries: RwLock<Arc<Entries>>,
+}
+
+impl Blocklist {
+    /// Read the list at `source`, an `https://` or `http://` URL or a path, failing if it can't be read yet
+    pub fn load(source: &str) -> Result<Arc<Self>, Box<dyn Error>> {
+        let source = if source.starts_with("http://") || source.starts_with("https://") {
+            Source::Url { url: source.to_string(), etag: None }
+        } else {
+            Source::File { path: PathBuf::from(source), modified: None }
+        };
+        let list = Blocklist { name: source_name(&source), source: Mutex::new(source), entries: RwLock::new(Arc::default()) };
+        list.refresh().map_err(|e| format!("{}: {}", list.name, e))?;
+        Ok(Arc::new(list))
+    }
+
+    /// Parse a list from `reader`, which is never refreshed
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        Ok(Blocklist {
+            name: "-".to_string(),
+            source: Mutex::new(Source::Static),
+            entries: RwLock::new(Arc::new(Entries::parse(reader)?)),
+        })
+    }
+
+    /// The URL or path the list is read from
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Number of domains and networks listed
+    pub fn len(&self) -> usize {
+        let entries = self.entries.read().unwrap();
+        entries.domains.len() + entries.nets.len()
+    }
+
+    /// Is the list empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Is `destination` listed, directly or as a subdomain of a listed domain
+    pub fn contains(&self, destination: &Destination) -> bool {
+        self.entries.read().unwrap().contains(destination)
+    }
+
+    /// Read the list again if it changed, returning whether the entries were replaced
+    ///
+    /// On failure the previous entries stay in use.
+    pub fn refresh(&self) -> Result<bool, Box<dyn Error>> {
+        let mut source = self.source.lock().unwrap();
+        let entries = match &mut *source {
+            Source::Static => return Ok(false),
+            Source::File { path, modified } => {
+                let current = fs::metadata(&*path).and_then(|metadata| metadata.modified()).ok();
+                if current.is_some() && current == *modified {
+                    return Ok(false);
+                }
+                let entries = Entries::parse(fs::File::open(&*path)?)?;
+                *modified = current;
+                entries
+            },
+            Source::Url { url, etag } => match fetch(url, etag.as_deref())? {
+                None => return Ok(false),
+                Some(feed) => {
+                    let entries = Entries::parse(&feed.body[..])?;
+                    *etag = feed.etag;
+                    entries
+                },
+            },
+        };
+        *self.entries.write().unwrap() = Arc::new(entries);
+        Ok(true)
+    }
+
+    /// Refresh every `interval` on a background thread, which stops once `self` is dropped
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let weak = Arc::downgrade(self);
+        thread::spawn(move || watch(weak, interval));
+    }
+}
+
+fn source_name(source: &Source) -> String {
+    match source {
+        Source::Static => "-".to_string(),
+        Source::File { path, .. } => path.display().to_string(),
+        Source::Url { url, .. } => url.clone(),
+    }
+}
+
+fn watch(list: Weak<Blocklist>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let list = match list.upgrade() {
+            Some(list) => list,
+            None => return,
+        };
+        match list.refresh() {
+            Ok(true) => info!("Refreshed the blocklist {}, {} entries", list.name, list.len()),
+            Ok(false) => debug!("The blocklist {} is unchanged", list.name),
+            Err(error) => warn!("Keeping the previous blocklist {}, failed to refresh: {}", list.name, error),
+        }
+    }
+}
+
+/// A feed as downloaded
+struct Feed {
+    body: Vec<u8>,
+    etag: Option<String>,
+}
+
+/// GET `url`, or `None` if it still matches `etag`
+fn fetch(url: &str, etag: Option<&str>) -> Result<Option<Feed>, Box<dyn Error>> {
+    let mut request = crate::http::agent(FETCH_TIMEOUT).get(url);
+    if let Some(etag) = etag {
+        request = request.set("If-None-Match", etag);
+    }
+    let response = request.call().map_err(crate::http::error)?;
+    match response.status() {
+        304 => return Ok(None),
+        200 => {},
+        status => return Err(format!("Server answered {}", status).into()),
+    }
+    let etag = response.header("ETag").map(str::to_string);
+    let mut body = Vec::new();
+    response.into_reader().read_to_end(&mut body)?;
+    Ok(Some(Feed { body, etag }))
+}
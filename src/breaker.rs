@@ -0,0 +1,94 @@
+//! Fast failure of requests to destinations that are down
+//!
+//! Once connecting to a `host:port` has failed a number of times in a row,
+//! the breaker opens: requests for it are answered "host unreachable" straight
+//! away for the cooldown, instead of each client waiting out a connect
+//! timeout of its own. After the cooldown one request is let through to probe
+//! the destination. Success closes the breaker, failure opens it again.
+//!
+//! Only the first [`MAX_TRACKED`] destinations failing at once are tracked.
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Most destinations tracked at once
+pub const MAX_TRACKED: usize = 4096;
+
+/// Recent connect failures of a destination
+#[derive(Debug, Default)]
+struct Failures {
+    /// Failed connects since the last success
+    count: u32,
+    /// Requests fail fast until then
+    open_until: Option<Instant>,
+}
+
+/// Connect failures per destination, and which destinations are failed fast
+#[derive(Debug)]
+pub struct Breaker {
+    threshold: u32,
+    cooldown: Duration,
+    destinations: Mutex<HashMap<(String, u16), Failures>>,
+}
+
+impl Breaker {
+    /// Open after `threshold` failures in a row, for `cooldown` at a time
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Breaker {
+            threshold: threshold.max(1),
+            cooldown,
+            destinations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether to try connecting to `host:port`, letting a single probe through once the cooldown is over
+    pub fn allow(&self, host: &str, port: u16) -> bool {
+        let mut destinations = self.destinations.lock().unwrap();
+        let failures = match destinations.get_mut(&(host.to_string(), port)) {
+            Some(failures) => failures,
+            None => return true,
+        };
+        match failures.open_until {
+            Some(until) if until > Instant::now() => false,
+            Some(_) => {
+                // Others keep failing fast while the probe connects
+                failures.open_until = Some(Instant::now() + self.cooldown);
+                debug!("Probing {}:{} after its cooldown", host, port);
+                true
+            },
+            None => true,
+        }
+    }
+
+    /// Count a connect to `host:port` that succeeded or failed
+    pub fn record(&self, host: &str, port: u16, succeeded: bool) {
+        let key = (host.to_string(), port);
+        let mut destinations = self.destinations.lock().unwrap();
+        if succeeded {
+            if destinations.remove(&key).is_some_and(|failures| failures.open_until.is_some()) {
+                info!("{}:{} is reachable again", host, port);
+            }
+            return;
+        }
+        if !destinations.contains_key(&key) && destinations.len() >= MAX_TRACKED {
+            destinations.retain(|_, failures| failures.open_until.is_some());
+            if destinations.len() >= MAX_TRACKED {
+                return;
+            }
+        }
+        let failures = destinations.entry(key).or_default();
+        failures.count = failures.count.saturating_add(1);
+        if failures.count >= self.threshold {
+            if failures.open_until.is_none() {
+                warn!("{}:{} failed {} connects in a row, failing requests to it fast for {:?}", host, port, failures.count, self.cooldown);
+            }
+            failures.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    /// What requests failed fast get
+    pub fn open_error(host: &str, port: u16) -> io::Error {
+        io::Error::new(io::ErrorKind::HostUnreachable, format!("{}:{} is failing, not trying it again yet", host, port))
+    }
+}
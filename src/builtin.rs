@@ -0,0 +1,61 @@
+//! Destinations served by merino itself, for checking a proxy end to end
+//!
+//! Requests for these names never leave the proxy, on any port:
+//!
+//! ```text
+//! merino.echo      sends back everything it receives, like the echo service on port 7
+//! merino.discard   reads and drops everything, like the discard service on port 9
+//! ```
+//!
+//! so a client can check its whole path through the proxy, and measure
+//! throughput, without a server of its own. They are still subject to the
+//! access rules, and relayed like any other session: counted, logged and
+//! paced.
+use std::fmt;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+use crate::rules::Destination;
+
+/// A destination merino serves itself
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Service {
+    Echo,
+    Discard,
+}
+
+impl Service {
+    /// The service `destination` names, if it is one
+    pub fn of(destination: &Destination) -> Option<Service> {
+        match destination {
+            Destination::Domain(name) if name.eq_ignore_ascii_case("merino.echo") => Some(Service::Echo),
+            Destination::Domain(name) if name.eq_ignore_ascii_case("merino.discard") => Some(Service::Discard),
+            _ => None,
+        }
+    }
+
+    /// Start serving a connection, returning the end to relay the client to
+    pub fn spawn(self, session: u64) -> io::Result<UnixStream> {
+        let (target, mut server) = UnixStream::pair()?;
+        thread::spawn(move || {
+            let served = match self {
+                Service::Echo => io::copy(&mut server.try_clone()?, &mut server),
+                Service::Discard => io::copy(&mut server, &mut io::sink()),
+            };
+            trace!("{} served {:?} bytes (session {})", self, served, session);
+            server.shutdown(Shutdown::Both)
+        });
+        Ok(target)
+    }
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Service::Echo => write!(f, "merino.echo"),
+            Service::Discard => write!(f, "merino.discard"),
+        }
+    }
+}
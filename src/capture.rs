@@ -0,0 +1,228 @@
+//! Relayed bytes mirrored into pcap files, for debugging single sessions
+//!
+//! Started and stopped per session over the admin API, see [`crate::admin`].
+//! merino only sees the byte streams, not the packets carrying them, so each
+//! chunk relayed is written as a synthetic TCP segment between the client
+//! and the destination, after a made-up handshake. Sequence and
+//! acknowledgement numbers follow the bytes relayed, so Wireshark's
+//! "Follow TCP Stream" and protocol dissectors work as on a real capture.
+//! Client ports aren't known to merino and are made up too.
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// pcap link type for packets starting with an IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+
+/// Largest TCP payload written per packet, keeping packets under the IP length limit
+const MAX_SEGMENT: usize = 32 * 1024;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// Which way relayed bytes travel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+/// A pcap file being written for one session
+pub struct Capture {
+    writer: Box<dyn Write + Send>,
+    client: SocketAddr,
+    server: SocketAddr,
+    /// Next sequence number of the client and of the server
+    seq: [u32; 2],
+}
+
+impl Capture {
+    /// Create the file at `path` for traffic between `client` and `server`
+    pub fn create<P: AsRef<Path>>(path: P, client: SocketAddr, server: SocketAddr) -> io::Result<Self> {
+        Capture::new(BufWriter::new(File::create(path)?), client, server)
+    }
+
+    /// Write the capture to `writer`, starting with the file header and a handshake
+    pub fn new<W: Write + Send + 'static>(writer: W, client: SocketAddr, server: SocketAddr) -> io::Result<Self> {
+        // IPv4 only when both ends are, otherwise both as IPv6
+        let (client, server) = match (unmapped(client), unmapped(server)) {
+            (client @ SocketAddr::V4(_), server @ SocketAddr::V4(_)) => (client, server),
+            (client, server) => (mapped(client), mapped(server)),
+        };
+        let mut capture = Capture { writer: Box::new(writer), client, server, seq: [0, 0] };
+
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&[0; 8]); // time zone and timestamp accuracy
+        header.extend_from_slice(&65535u32.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        capture.writer.write_all(&header)?;
+
+        capture.packet(Direction::ToServer, SYN, &[])?;
+        capture.packet(Direction::ToClient, SYN | ACK, &[])?;
+        capture.packet(Direction::ToServer, ACK, &[])?;
+        Ok(capture)
+    }
+
+    /// Record `data` relayed in `direction`
+    pub fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        for segment in data.chunks(MAX_SEGMENT) {
+            self.packet(direction, PSH | ACK, segment)?;
+        }
+        self.writer.flush()
+    }
+
+    /// Write one packet, advancing the sender's sequence number past it
+    fn packet(&mut self, direction: Direction, flags: u8, payload: &[u8]) -> io::Result<()> {
+        let (src, dst, sender) = match direction {
+            Direction::ToServer => (self.client, self.server, 0),
+            Direction::ToClient => (self.server, self.client, 1),
+        };
+        let (seq, ack) = (self.seq[sender], self.seq[1 - sender]);
+        // SYN and FIN take up a sequence number, like a byte of data
+        let consumed = payload.len() as u32 + (flags & (SYN | FIN) != 0) as u32;
+        self.seq[sender] = seq.wrapping_add(consumed);
+        // A bare SYN acknowledges nothing
+        let ack = if flags & ACK != 0 { ack } else { 0 };
+
+        let mut tcp = Vec::with_capacity(20 + payload.len());
+        tcp.extend_from_slice(&src.port().to_be_bytes());
+        tcp.extend_from_slice(&dst.port().to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&ack.to_be_bytes());
+        tcp.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        tcp.extend_from_slice(payload);
+
+        let mut pseudo = Vec::with_capacity(40);
+        let mut ip = Vec::with_capacity(40);
+        match (src.ip(), dst.ip()) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                pseudo.extend_from_slice(&src.octets());
+                pseudo.extend_from_slice(&dst.octets());
+                pseudo.extend_from_slice(&[0, 6]);
+                pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+
+                ip.extend_from_slice(&[0x45, 0]);
+                ip.extend_from_slice(&(20 + tcp.len() as u16).to_be_bytes());
+                ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+                ip.extend_from_slice(&src.octets());
+                ip.extend_from_slice(&dst.octets());
+                let checksum = checksum(&[&ip]);
+                ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+            },
+            (src, dst) => {
+                let (src, dst) = (v6(src), v6(dst));
+                pseudo.extend_from_slice(&src);
+                pseudo.extend_from_slice(&dst);
+                pseudo.extend_from_slice(&(tcp.len() as u32).to_be_bytes());
+                pseudo.extend_from_slice(&[0, 0, 0, 6]);
+
+                ip.extend_from_slice(&[0x60, 0, 0, 0]);
+                ip.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+                ip.extend_from_slice(&[6, 64]);
+                ip.extend_from_slice(&src);
+                ip.extend_from_slice(&dst);
+            },
+        }
+        let checksum = checksum(&[&pseudo, &tcp]);
+        tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let len = (ip.len() + tcp.len()) as u32;
+        let mut record = Vec::with_capacity(16);
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&len.to_le_bytes());
+        record.extend_from_slice(&len.to_le_bytes());
+        self.writer.write_all(&record)?;
+        self.writer.write_all(&ip)?;
+        self.writer.write_all(&tcp)
+    }
+}
+
+impl Drop for Capture {
+    /// Close the synthetic connection so the capture ends cleanly
+    fn drop(&mut self) {
+        let closed = self.packet(Direction::ToServer, FIN | ACK, &[])
+            .and_then(|_| self.packet(Direction::ToClient, FIN | ACK, &[]))
+            .and_then(|_| self.packet(Direction::ToServer, ACK, &[]))
+            .and_then(|_| self.writer.flush());
+        if let Err(error) = closed {
+            warn!("Failed to finish a capture: {}", error);
+        }
+    }
+}
+
+fn unmapped(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr,
+        },
+        addr => addr,
+    }
+}
+
+fn mapped(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(match addr.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }), addr.port())
+}
+
+fn v6(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+/// Internet checksum over the concatenation of `parts`
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut odd = None;
+    for byte in parts.iter().flat_map(|part| part.iter().copied()) {
+        match odd.take() {
+            None => odd = Some(byte),
+            Some(high) => sum += u16::from_be_bytes([high, byte]) as u32,
+        }
+    }
+    if let Some(high) = odd {
+        sum += u16::from_be_bytes([high, 0]) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Where a session's relay threads look for a capture to write to
+pub(crate) type Slot = Arc<Mutex<Option<Capture>>>;
+
+/// A relaying session's capture, as controlled from the admin API
+pub(crate) struct Tap {
+    pub(crate) slot: Slot,
+    pub(crate) client: SocketAddr,
+    pub(crate) server: SocketAddr,
+}
+
+impl Tap {
+    /// Start writing to `path`, replacing any capture in progress
+    pub(crate) fn start(&self, path: &Path) -> io::Result<()> {
+        let capture = Capture::create(path, self.client, self.server)?;
+        *self.slot.lock().unwrap() = Some(capture);
+        Ok(())
+    }
+
+    /// Stop capturing, returning whether a capture was in progress
+    pub(crate) fn stop(&self) -> bool {
+        self.slot.lock().unwrap().take().is_some()
+    }
+}
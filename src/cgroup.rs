@@ -0,0 +1,177 @@
+//! Memory and CPU limits of the cgroup merino runs in, such as a container's
+//!
+//! Sessions each hold two relay buffers, a thread per direction and the
+//! kernel's socket buffers, so a flood of connections can push a container
+//! past its memory limit and get it OOM-killed. When the cgroup limits
+//! memory, [`ResourceLimits::defaults`] derives a connection limit and relay
+//! buffer size that keep the sessions within half of it, leaving the rest to
+//! the allocator, handshakes and the kernel.
+//!
+//! Both cgroup v2 (`memory.max`, `cpu.max`) and v1 (`memory.limit_in_bytes`,
+//! `cpu.cfs_quota_us`) are read.
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{RELAY_BUFFER_MIN, RELAY_BUFFER_MAX};
+
+/// Where the cgroup filesystem is mounted
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Memory a session uses beyond its relay buffers: touched thread stacks and socket buffers
+const SESSION_OVERHEAD: u64 = 256 * 1024;
+
+/// Largest relay buffer derived from a memory limit, bigger ones only pay off on fast, distant links
+const DERIVED_RELAY_BUFFER_MAX: usize = 128 * 1024;
+
+/// Most connections per CPU the derived limit allows, past which handshakes queue on the CPU anyway
+const CONNECTIONS_PER_CPU: f64 = 4096.0;
+
+/// v1 reports an unlimited cgroup with a huge page-aligned number rather than `max`
+const V1_UNLIMITED: u64 = 1 << 60;
+
+/// The cgroup of this process
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cgroup {
+    memory: PathBuf,
+    cpu: PathBuf,
+    /// Directory of the v1 CPU accounting controller, the same as `cpu` on v2
+    cpuacct: PathBuf,
+    v2: bool,
+}
+
+/// Limits set on the cgroup, `None` where unlimited
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceLimits {
+    /// Bytes
+    pub memory: Option<u64>,
+    /// CPUs' worth of time per period, e.g. `0.5`
+    pub cpus: Option<f64>,
+}
+
+/// What the cgroup has used so far, `None` where it can't be read
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceUsage {
+    /// Bytes currently charged, page cache included
+    pub memory: Option<u64>,
+    /// CPU time used since the cgroup was created
+    pub cpu: Option<Duration>,
+}
+
+/// Settings derived from the limits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Defaults {
+    pub max_connections: u64,
+    /// Bytes relayed at once
+    pub relay_buffer: usize,
+}
+
+impl Cgroup {
+    /// The cgroup this process belongs to, if it is in one the filesystem exposes
+    pub fn detect() -> Option<Cgroup> {
+        let membership = fs::read_to_string("/proc/self/cgroup").ok()?;
+        Cgroup::at(CGROUP_ROOT, &membership)
+    }
+
+    /// The cgroup given by `membership`, as read from `/proc/<pid>/cgroup`, under the filesystem mounted at `root`
+    pub fn at<P: AsRef<Path>>(root: P, membership: &str) -> Option<Cgroup> {
+        let root = root.as_ref();
+        // Inside a cgroup namespace the path can name a directory only the host sees, the container's own cgroup is the root then
+        let beneath = |dir: PathBuf, path: &str| {
+            let nested = dir.join(path.trim_start_matches('/'));
+            if nested.is_dir() { nested } else { dir }
+        };
+        let mut lines = membership.lines().filter_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            Some((fields.next()?, fields.next()?, fields.next()?))
+        });
+
+        if root.join("cgroup.controllers").exists() {
+            let (_, _, path) = lines.find(|(id, _, _)| *id == "0")?;
+            let dir = beneath(root.to_path_buf(), path);
+            return Some(Cgroup { memory: dir.clone(), cpu: dir.clone(), cpuacct: dir, v2: true });
+        }
+        let lines: Vec<_> = lines.collect();
+        let controller = |name: &str| {
+            let path = lines.iter()
+                .find(|(_, controllers, _)| controllers.split(',').any(|controller| controller == name))
+                .map_or("/", |(_, _, path)| *path);
+            beneath(root.join(name), path)
+        };
+        let memory = controller("memory");
+        if !memory.is_dir() {
+            return None;
+        }
+        Some(Cgroup { memory, cpu: controller("cpu"), cpuacct: controller("cpuacct"), v2: false })
+    }
+
+    pub fn limits(&self) -> ResourceLimits {
+        if self.v2 {
+            let cpus = read(&self.cpu, "cpu.max").and_then(|max| {
+                let (quota, period) = max.split_once(' ')?;
+                Some(quota.parse::<f64>().ok()? / period.parse::<f64>().ok()?)
+            });
+            ResourceLimits { memory: number(&self.memory, "memory.max"), cpus }
+        } else {
+            let memory = number(&self.memory, "memory.limit_in_bytes").filter(|limit| *limit < V1_UNLIMITED);
+            let quota = read(&self.cpu, "cpu.cfs_quota_us").and_then(|quota| quota.parse::<f64>().ok()).filter(|quota| *quota > 0.0);
+            let cpus = quota.zip(number(&self.cpu, "cpu.cfs_period_us")).map(|(quota, period)| quota / period as f64);
+            ResourceLimits { memory, cpus }
+        }
+    }
+
+    pub fn usage(&self) -> ResourceUsage {
+        if self.v2 {
+            let cpu = read(&self.cpuacct, "cpu.stat").and_then(|stat| {
+                let usec = stat.lines().find_map(|line| line.strip_prefix("usage_usec "))?;
+                Some(Duration::from_micros(usec.trim().parse().ok()?))
+            });
+            ResourceUsage { memory: number(&self.memory, "memory.current"), cpu }
+        } else {
+            ResourceUsage {
+                memory: number(&self.memory, "memory.usage_in_bytes"),
+                cpu: number(&self.cpuacct, "cpuacct.usage").map(Duration::from_nanos),
+            }
+        }
+    }
+}
+
+impl ResourceLimits {
+    /// Connection limit and relay buffer size fitting the memory limit, if there is one
+    ///
+    /// The relay buffer shrinks with the memory limit, down to the 16 KiB
+    /// minimum for a 128 MiB limit. A CPU limit further caps the connections.
+    pub fn defaults(&self) -> Option<Defaults> {
+        let memory = self.memory?;
+        let relay_buffer = ((memory / 8192) as usize).clamp(RELAY_BUFFER_MIN, DERIVED_RELAY_BUFFER_MAX.min(RELAY_BUFFER_MAX));
+        let session = 2 * relay_buffer as u64 + SESSION_OVERHEAD;
+        let mut max_connections = (memory / 2 / session).max(1);
+        if let Some(cpus) = self.cpus {
+            max_connections = max_connections.min((cpus * CONNECTIONS_PER_CPU).ceil() as u64);
+        }
+        Some(Defaults { max_connections, relay_buffer })
+    }
+}
+
+impl fmt::Display for ResourceLimits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.memory {
+            Some(memory) => write!(f, "memory {} MiB", memory / (1024 * 1024))?,
+            None => write!(f, "memory unlimited")?,
+        }
+        match self.cpus {
+            Some(cpus) => write!(f, ", {} CPUs", cpus),
+            None => write!(f, ", CPU unlimited"),
+        }
+    }
+}
+
+fn read(dir: &Path, file: &str) -> Option<String> {
+    fs::read_to_string(dir.join(file)).ok().map(|text| text.trim().to_string())
+}
+
+/// A file holding a single number, `None` for `max` or if it can't be read
+fn number(dir: &Path, file: &str) -> Option<u64> {
+    read(dir, file)?.parse().ok()
+}
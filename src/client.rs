@@ -0,0 +1,88 @@
+//! Minimal SOCKS5 client, used to drive requests through a proxy
+use std::error::Error;
+use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::{AuthMethods, SockCommand, SOCKS_VERSION, RESERVED};
+
+/// Perform a SOCKS5 CONNECT handshake over `stream`
+///
+/// Offers username/password authentication when `credentials` are given,
+/// otherwise NOAUTH. Returns the BND.ADDR/BND.PORT reported by the proxy.
+pub fn connect<S: Read + Write>(stream: &mut S, host: &str, port: u16, credentials: Option<(&str, &str)>) -> Result<SocketAddr, Box<dyn Error>> {
+    request(stream, SockCommand::Connect, host, port, credentials)
+}
+
+fn request<S: Read + Write>(stream: &mut S, command: SockCommand, host: &str, port: u16, credentials: Option<(&str, &str)>) -> Result<SocketAddr, Box<dyn Error>> {
+    let method = match credentials {
+        Some(_) => AuthMethods::UserPass as u8,
+        None => AuthMethods::NoAuth as u8,
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method])?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(format!("Proxy replied with SOCKS{}", choice[0]).into());
+    }
+    if choice[1] != method {
+        return Err("Proxy accepted none of the offered auth methods".into());
+    }
+
+    if let Some((username, password)) = credentials {
+        let mut packet = vec![1, username.len() as u8];
+        packet.extend_from_slice(username.as_bytes());
+        packet.push(password.len() as u8);
+        packet.extend_from_slice(password.as_bytes());
+        stream.write_all(&packet)?;
+
+        let mut status = [0u8; 2];
+        stream.read_exact(&mut status)?;
+        if status[1] != 0 {
+            return Err("Proxy rejected the credentials".into());
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, command as u8, RESERVED];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        },
+        Err(_) => {
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Err(format!("Proxy refused the request with reply code {}", reply[1]).into());
+    }
+
+    let ip = match reply[3] {
+        1 => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr)?;
+            IpAddr::from(Ipv4Addr::from(addr))
+        },
+        4 => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr)?;
+            IpAddr::from(Ipv6Addr::from(addr))
+        },
+        atyp => return Err(format!("Unexpected BND.ADDR type {}", atyp).into()),
+    };
+    let mut bound_port = [0u8; 2];
+    stream.read_exact(&mut bound_port)?;
+
+    Ok(SocketAddr::new(ip, u16::from_be_bytes(bound_port)))
+}
@@ -0,0 +1,71 @@
+//! How strictly clients are held to RFC 1928 and RFC 1929
+//!
+//! Plenty of SOCKS clients get details wrong, such as a non-zero reserved
+//! byte or an empty username. Lenient mode lets them through and logs each
+//! deviation so misbehaving clients can still be tracked down; strict mode
+//! refuses the handshake instead.
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Whether protocol deviations are tolerated
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Compliance {
+    /// Refuse handshakes deviating from the RFCs
+    Strict,
+    /// Tolerate common client quirks, logging each one
+    #[default]
+    Lenient,
+}
+
+impl Compliance {
+    /// Handle the client at `peer` in `session` deviating from the protocol as described by `deviation`
+    ///
+    /// Errors in strict mode, otherwise logs the deviation and carries on.
+    pub fn deviation(self, session: u64, peer: IpAddr, deviation: fmt::Arguments) -> Result<(), Deviation> {
+        match self {
+            Compliance::Strict => {
+                info!("Refusing {}: {} (session {})", peer, deviation, session);
+                Err(Deviation(deviation.to_string()))
+            },
+            Compliance::Lenient => {
+                info!("Tolerating protocol deviation from {}: {} (session {})", peer, deviation, session);
+                Ok(())
+            },
+        }
+    }
+}
+
+impl FromStr for Compliance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Compliance::Strict),
+            "lenient" => Ok(Compliance::Lenient),
+            _ => Err(format!("`{}` must be strict or lenient", s)),
+        }
+    }
+}
+
+impl fmt::Display for Compliance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compliance::Strict => write!(f, "strict"),
+            Compliance::Lenient => write!(f, "lenient"),
+        }
+    }
+}
+
+/// A deviation refused in strict mode
+#[derive(Debug, PartialEq)]
+pub struct Deviation(pub String);
+
+impl fmt::Display for Deviation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Protocol violation: {}", self.0)
+    }
+}
+
+impl Error for Deviation {}
@@ -0,0 +1,87 @@
+//! Command line options read from a file or the environment
+//!
+//! Deployments that can't easily change a command line, such as a
+//! Kubernetes sidecar configured from a ConfigMap, can give the long options
+//! in a file instead, one per line:
+//!
+//! ```text
+//! # merino.conf
+//! port = 1080
+//! no-auth
+//! rules = /etc/merino/rules.csv
+//! blocklist = /etc/merino/ads.txt
+//! blocklist = /etc/merino/malware.txt
+//! ```
+//!
+//! A bare name sets a flag, and `name = false` leaves it unset. Options
+//! given on the command line take precedence: any option named there is
+//! ignored in the file, so a repeated option is replaced as a whole.
+use std::error::Error;
+use std::ffi::OsString;
+
+/// Parse a config file into the command line arguments it stands for
+pub fn parse(text: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut args = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(unquote(value.trim()))),
+            None => (line, None),
+        };
+        let name = name.trim_start_matches("--");
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("Line {}: expected `name = value` or a flag name, got `{}`", number + 1, line).into());
+        }
+        match value {
+            Some("false") => {},
+            Some("true") | None => args.push(format!("--{}", name)),
+            Some(value) => {
+                args.push(format!("--{}", name));
+                args.push(value.to_string());
+            },
+        }
+    }
+    Ok(args)
+}
+
+/// Strip one pair of matching quotes around `value`
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''].iter() {
+        if let Some(inner) = value.strip_prefix(*quote).and_then(|rest| rest.strip_suffix(*quote)) {
+            return inner;
+        }
+    }
+    value
+}
+
+/// Add `defaults` to the command line `args`, except for options `args` already names
+///
+/// `args` starts with the program name, the defaults go right after it so
+/// they apply to the program rather than a subcommand.
+pub fn merge(args: Vec<OsString>, defaults: &[String]) -> Vec<OsString> {
+    let named = |name: &str| args.iter().skip(1).any(|arg| {
+        let arg = arg.to_string_lossy();
+        arg == name || arg.starts_with(&format!("{}=", name))
+    });
+
+    let mut kept = Vec::new();
+    let mut skipping = false;
+    for arg in defaults {
+        if arg.starts_with("--") {
+            skipping = named(arg);
+        }
+        if !skipping {
+            kept.push(OsString::from(arg));
+        }
+    }
+
+    let mut merged = Vec::with_capacity(args.len() + kept.len());
+    let mut args = args.into_iter();
+    merged.extend(args.next());
+    merged.extend(kept);
+    merged.extend(args);
+    merged
+}
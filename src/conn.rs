@@ -0,0 +1,158 @@
+//! Byte streams the proxy can speak SOCKS over and relay between
+//!
+//! Handshakes and relays only need a [`Connection`], so clients can arrive
+//! over any transport and destinations can be reached over one too.
+use socket2::SockRef;
+use std::env;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use crate::faults;
+
+/// A bidirectional byte stream to a client or destination
+pub trait Connection: Read + Write + Send + Sized + 'static {
+    /// Address the client connects from, used to match access rules
+    fn peer_ip(&self) -> io::Result<IpAddr>;
+
+    /// Port the client connects from, where the connection has ports
+    fn peer_port(&self) -> Option<u16> {
+        None
+    }
+
+    /// Another handle to the same connection, so each direction can be relayed on its own thread
+    fn try_clone(&self) -> io::Result<Self>;
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// Abort the connection instead of closing it cleanly
+    fn reset(&self);
+
+    /// Mark packets sent on the connection with `dscp`, where it has packets to mark
+    fn set_dscp(&self, _dscp: u8) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Not a network connection"))
+    }
+
+    /// Larger of the send and receive buffers the OS gave the connection, if it has any
+    fn buffer_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Fail reads waiting longer than `timeout`, where the connection can time out
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for TcpStream {
+    /// IPv4 clients of dual-stack listeners come as IPv4, not mapped into IPv6
+    fn peer_ip(&self) -> io::Result<IpAddr> {
+        Ok(self.peer_addr()?.ip().to_canonical())
+    }
+
+    fn peer_port(&self) -> Option<u16> {
+        self.peer_addr().ok().map(|addr| addr.port())
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn reset(&self) {
+        faults::reset(self);
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        crate::connect::set_dscp(self, dscp)
+    }
+
+    fn buffer_size(&self) -> Option<usize> {
+        let socket = SockRef::from(self);
+        let recv = socket.recv_buffer_size().ok()?;
+        let send = socket.send_buffer_size().ok()?;
+        Some(recv.max(send))
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// A local client, e.g. accepted on a Unix socket listener
+#[cfg(unix)]
+impl Connection for UnixStream {
+    /// Local clients are matched against the rules as loopback
+    fn peer_ip(&self) -> io::Result<IpAddr> {
+        Ok(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn reset(&self) {
+        UnixStream::shutdown(self, Shutdown::Both).unwrap_or(());
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// A client on stdin/stdout, as launched by inetd or an SSH `ProxyCommand`
+///
+/// Standard streams can't be shut down, so the process should exit once the
+/// destination closes its side.
+#[derive(Debug, Default)]
+pub struct Stdio;
+
+impl Read for Stdio {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::stdin().read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stdout = io::stdout().lock();
+        let n = stdout.write(buf)?;
+        stdout.flush()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl Connection for Stdio {
+    /// The client's address as reported by SSH or xinetd, loopback otherwise
+    fn peer_ip(&self) -> io::Result<IpAddr> {
+        let reported = env::var("SSH_CLIENT").ok()
+            .and_then(|client| client.split_whitespace().next().map(str::to_string))
+            .or_else(|| env::var("REMOTE_HOST").ok());
+        Ok(reported
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+            .map_or(IpAddr::V4(Ipv4Addr::LOCALHOST), |ip| ip.to_canonical()))
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Stdio)
+    }
+
+    fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reset(&self) {}
+}
@@ -0,0 +1,216 @@
+//! Outbound connections to destinations
+use socket2::{Domain, Protocol, Socket, Type};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+
+/// How connections to destinations are made
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectOptions {
+    /// Send the first data in the SYN with TCP Fast Open, where the OS supports it
+    ///
+    /// The handshake is only sent once there is data to send, so this only
+    /// suits protocols where the client speaks first, such as HTTP and TLS.
+    /// Server-first protocols like SSH or SMTP stall.
+    pub fast_open: bool,
+    /// Set SO_MARK on every connection (Linux), so `ip rule` policy routing can steer them
+    ///
+    /// Needs `CAP_NET_ADMIN`.
+    pub mark: Option<u32>,
+    /// Connect with Multipath TCP (Linux 5.6+), to use every uplink of a multihomed host
+    ///
+    /// Destinations that don't speak MPTCP get plain TCP, as do kernels
+    /// without it.
+    pub mptcp: bool,
+}
+
+/// The BND.ADDR reported to clients once connected
+///
+/// The address the proxy connected from tells clients about the internal
+/// network it sits in. Some clients, e.g. FTP and Java stacks, use it, others
+/// ignore it, so operators who'd rather not leak it can report a placeholder.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReplyAddress {
+    /// The address the proxy connected to the destination from
+    #[default]
+    Local,
+    /// `0.0.0.0:0`
+    Unspecified,
+    /// A fixed address, e.g. the proxy's public one, with the port connected from
+    Fixed(IpAddr),
+}
+
+impl ReplyAddress {
+    /// What to report for a connection made from `local`
+    pub fn report(self, local: Option<SocketAddr>) -> SocketAddr {
+        let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+        match self {
+            ReplyAddress::Local => local.unwrap_or(unspecified),
+            ReplyAddress::Unspecified => unspecified,
+            ReplyAddress::Fixed(ip) => SocketAddr::new(ip, local.map_or(0, |local| local.port())),
+        }
+    }
+}
+
+impl FromStr for ReplyAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(ReplyAddress::Local),
+            "unspecified" => Ok(ReplyAddress::Unspecified),
+            _ => s.parse().map(ReplyAddress::Fixed).map_err(|_| format!("`{}` must be local, unspecified or an IP address", s)),
+        }
+    }
+}
+
+impl fmt::Display for ReplyAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplyAddress::Local => write!(f, "local"),
+            ReplyAddress::Unspecified => write!(f, "unspecified"),
+            ReplyAddress::Fixed(ip) => write!(f, "{}", ip),
+        }
+    }
+}
+
+/// Connect to the first of `addr`'s addresses that accepts
+pub fn connect<A: ToSocketAddrs>(addr: A, options: &ConnectOptions) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in addr.to_socket_addrs()? {
+        match connect_one(addr, options) {
+            Ok(stream) => return Ok(stream),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No addresses to connect to")))
+}
+
+fn connect_one(addr: SocketAddr, options: &ConnectOptions) -> io::Result<TcpStream> {
+    let socket = stream_socket(Domain::for_address(addr), options.mptcp)?;
+    if let Some(mark) = options.mark {
+        // Set before connecting, so the SYN is routed by the mark too
+        set_mark(&socket, mark)?;
+    }
+    if options.fast_open {
+        if let Err(error) = set_fast_open(&socket) {
+            debug!("TCP Fast Open unavailable for {}: {}", addr, error);
+        }
+    }
+    socket.connect(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Listen on `addr` with Multipath TCP, or plain TCP where the kernel doesn't support it
+pub fn listen_mptcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = stream_socket(Domain::for_address(addr), true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+/// Connections a listener queues with TCP Fast Open data before their handshake completes
+pub const FAST_OPEN_QUEUE: i32 = 256;
+
+/// Accept TCP Fast Open on `listener`, queueing up to [`FAST_OPEN_QUEUE`] such connections
+///
+/// The kernel only hands out cookies with the server bit of
+/// `net.ipv4.tcp_fastopen` set, e.g. `3`. Until then clients fall back to
+/// the full handshake.
+#[cfg(target_os = "linux")]
+pub fn listen_fast_open(listener: &TcpListener) -> io::Result<()> {
+    use std::os::fd::AsFd;
+    merino_sys::setsockopt_int(listener.as_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN, FAST_OPEN_QUEUE)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn listen_fast_open(_listener: &TcpListener) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Not supported on this platform"))
+}
+
+/// Connections `listener` queues with TCP Fast Open data, 0 when it doesn't accept TFO
+#[cfg(target_os = "linux")]
+pub fn fast_open_queue(listener: &TcpListener) -> io::Result<i32> {
+    use std::os::fd::AsFd;
+    merino_sys::getsockopt_int(listener.as_fd(), libc::IPPROTO_TCP, libc::TCP_FASTOPEN)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fast_open_queue(_listener: &TcpListener) -> io::Result<i32> {
+    Ok(0)
+}
+
+/// Whether `listener` accepts Multipath TCP connections
+#[cfg(target_os = "linux")]
+pub fn is_mptcp(listener: &TcpListener) -> io::Result<bool> {
+    Ok(socket2::SockRef::from(listener).protocol()? == Some(Protocol::MPTCP))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_mptcp(_listener: &TcpListener) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// A TCP socket, speaking MPTCP if `mptcp` and the kernel supports it
+#[cfg(target_os = "linux")]
+fn stream_socket(domain: Domain, mptcp: bool) -> io::Result<Socket> {
+    if mptcp {
+        match Socket::new(domain, Type::STREAM, Some(Protocol::MPTCP)) {
+            // Kernels built without MPTCP, or with net.mptcp.enabled = 0
+            Err(error) if matches!(error.raw_os_error(), Some(libc::EPROTONOSUPPORT | libc::EINVAL | libc::ENOPROTOOPT)) => {
+                debug!("MPTCP unavailable, using TCP: {}", error);
+            },
+            socket => return socket,
+        }
+    }
+    Socket::new(domain, Type::STREAM, Some(Protocol::TCP))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn stream_socket(domain: Domain, _mptcp: bool) -> io::Result<Socket> {
+    Socket::new(domain, Type::STREAM, Some(Protocol::TCP))
+}
+
+/// Mark the packets sent on `stream` with `dscp`, for QoS further along the path
+#[cfg(target_os = "linux")]
+pub fn set_dscp(stream: &TcpStream, dscp: u8) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+    // DSCP is the upper six bits of the ToS byte and IPv6 traffic class
+    let tos = i32::from(dscp) << 2;
+    match stream.local_addr()? {
+        SocketAddr::V4(_) => setsockopt(stream, sockopt::IpTos, &tos)?,
+        SocketAddr::V6(_) => setsockopt(stream, sockopt::Ipv6TClass, &tos)?,
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_dscp(_stream: &TcpStream, _dscp: u8) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Not supported on this platform"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+    setsockopt(socket, sockopt::Mark, &mark)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_socket: &Socket, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SO_MARK is only supported on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_fast_open(socket: &Socket) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+    setsockopt(socket, sockopt::TcpFastOpenConnect, &true)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fast_open(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Not supported on this platform"))
+}
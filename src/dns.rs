@@ -0,0 +1,314 @@
+//! Name resolution for destinations, with per-rule resolvers and brief caching of failures
+//!
+//! Rules may pick a [`Resolver`] for the names they match: a DNS server
+//! asked directly over UDP, e.g. the corporate one for internal domains, a
+//! DNS over HTTPS server ([RFC 8484](https://www.rfc-editor.org/rfc/rfc8484)),
+//! or a static answer. Other names go to the system resolver.
+//!
+//! Failures are cached briefly by a [`NegativeCache`]. A client retrying a
+//! dead hostname, or many clients asking for one, would otherwise send the
+//! resolver the same doomed query every time and wait for its answer in the
+//! handshake. Names that didn't resolve, whether because they don't exist or
+//! because the resolver failed, fail straight away with the same error until
+//! the TTL is up. Successful lookups aren't cached, that's left to the
+//! system resolver and the DNS servers themselves.
+//!
+//! Only the first [`MAX_CACHED`] failing names at once are cached.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Most failed names cached at once
+pub const MAX_CACHED: usize = 4096;
+
+/// How long a DNS server has to answer each query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Times a query is sent before giving up
+const QUERY_ATTEMPTS: usize = 2;
+
+/// How long a DNS over HTTPS server has to answer each query, connecting included
+const HTTPS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest DNS over HTTPS answer read
+const MAX_HTTPS_ANSWER: u64 = 65535;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// Where a rule's names are resolved instead of by the system resolver
+#[derive(Clone, Debug, PartialEq)]
+pub enum Resolver {
+    /// A DNS server asked over UDP, written `dns:10.0.0.53` or `dns:10.0.0.53:5353`
+    Server(SocketAddr),
+    /// A DNS over HTTPS server, written `doh:https://dns.example/dns-query`
+    Https(String),
+    /// The address every name resolves to, written `static:10.1.2.3`
+    Static(IpAddr),
+}
+
+impl FromStr for Resolver {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, addr) = s.split_once(':').ok_or_else(|| format!("Invalid resolver `{}`, expected dns:ADDRESS, doh:URL or static:ADDRESS", s))?;
+        match kind.to_lowercase().as_str() {
+            "dns" => addr.parse::<SocketAddr>()
+                .or_else(|_| addr.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+                .map(Resolver::Server)
+                .map_err(|_| format!("Invalid DNS server `{}`", addr)),
+            "static" => addr.parse().map(Resolver::Static).map_err(|_| format!("Invalid static answer `{}`", addr)),
+            "doh" => match addr.split_once("://") {
+                Some((scheme, rest)) if ["https", "http"].contains(&scheme) && !rest.is_empty() && !rest.starts_with('/') => Ok(Resolver::Https(addr.to_string())),
+                _ => Err(format!("Invalid DNS over HTTPS server `{}`, expected an https:// URL", addr)),
+            },
+            _ => Err(format!("Invalid resolver `{}`, expected dns:ADDRESS, doh:URL or static:ADDRESS", s)),
+        }
+    }
+}
+
+impl fmt::Display for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resolver::Server(addr) => write!(f, "dns:{}", addr),
+            Resolver::Https(url) => write!(f, "doh:{}", url),
+            Resolver::Static(ip) => write!(f, "static:{}", ip),
+        }
+    }
+}
+
+impl Resolver {
+    /// Addresses of `host:port`, as this resolver answers
+    pub fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let server: &dyn fmt::Display = match self {
+            Resolver::Static(ip) => return Ok(vec![SocketAddr::new(*ip, port)]),
+            Resolver::Server(server) => server,
+            Resolver::Https(url) => url,
+        };
+        let name = host.trim_end_matches('.');
+        let mut addrs = Vec::new();
+        let mut missing = 0;
+        for qtype in [TYPE_A, TYPE_AAAA] {
+            let answer = match self {
+                Resolver::Server(server) => query(*server, name, qtype),
+                Resolver::Https(url) => query_https(url, name, qtype),
+                Resolver::Static(ip) => Ok(vec![*ip]),
+            };
+            match answer {
+                Ok(ips) => addrs.extend(ips.into_iter().map(|ip| SocketAddr::new(ip, port))),
+                Err(error) if error.kind() == io::ErrorKind::NotFound => missing += 1,
+                Err(error) => return Err(error),
+            }
+        }
+        match (addrs.is_empty(), missing) {
+            (true, 2) => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't exist according to {}", name, server))),
+            (true, _) => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses according to {}", name, server))),
+            (false, _) => Ok(addrs),
+        }
+    }
+}
+
+/// Addresses of `host:port`, from `resolver` or the system resolver
+pub fn lookup(host: &str, port: u16, resolver: Option<&Resolver>) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let addrs: Vec<SocketAddr> = match resolver {
+        Some(resolver) => resolver.lookup(host, port)?,
+        None => (host, port).to_socket_addrs()?.collect(),
+    };
+    match addrs.is_empty() {
+        true => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host))),
+        false => Ok(addrs),
+    }
+}
+
+/// A query with `id` for the `qtype` records of `name`
+fn question(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(name.len() + 18);
+    packet.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 || name.len() > 253 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("`{}` isn't a valid DNS name", name)));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+/// Ask `server` for the `qtype` records of `name`
+fn query(server: SocketAddr, name: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+    let id: u16 = rand::random();
+    let packet = question(id, name, qtype)?;
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(server)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    let mut buf = [0u8; 1232];
+    for _ in 0..QUERY_ATTEMPTS {
+        socket.send(&packet)?;
+        loop {
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(error) if matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+                Err(error) => return Err(error),
+            };
+            // Stray answers, e.g. late ones to an earlier attempt, are skipped
+            if len >= 12 && buf[..2] == id.to_be_bytes() && buf[2] & 0x80 != 0 {
+                return parse_answer(&buf[..len], name, qtype);
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} didn't answer for {}", server, name)))
+}
+
+/// Ask the DNS over HTTPS server at `url` for the `qtype` records of `name`
+///
+/// Queries are POSTed with an ID of 0, as RFC 8484 suggests, over connections
+/// shared by all queries.
+fn query_https(url: &str, name: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+    static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+    let packet = question(0, name, qtype)?;
+    let response = AGENT.get_or_init(|| crate::http::agent(HTTPS_TIMEOUT))
+        .post(url)
+        .set("Accept", "application/dns-message")
+        .set("Content-Type", "application/dns-message")
+        .send_bytes(&packet)
+        .map_err(|error| io::Error::other(format!("{} failed to resolve {}: {}", url, name, crate::http::error(error))))?;
+    if response.content_type() != "application/dns-message" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} answered {} for {}", url, response.content_type(), name)));
+    }
+    let mut answer = Vec::new();
+    response.into_reader().take(MAX_HTTPS_ANSWER).read_to_end(&mut answer)?;
+    if answer.len() < 12 || answer[..2] != [0, 0] || answer[2] & 0x80 == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Malformed DNS answer for {} from {}", name, url)));
+    }
+    parse_answer(&answer, name, qtype)
+}
+
+/// Records of `qtype` in an answer to a query for `name`
+fn parse_answer(answer: &[u8], name: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, format!("Malformed DNS answer for {}", name));
+    match answer[3] & 0x0f {
+        0 => {},
+        3 => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} doesn't exist", name))),
+        rcode => return Err(io::Error::other(format!("DNS server failed to resolve {} (rcode {})", name, rcode))),
+    }
+    let count = |at: usize| u16::from_be_bytes([answer[at], answer[at + 1]]) as usize;
+    let (questions, answers) = (count(4), count(6));
+
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(answer, at).ok_or_else(malformed)? + 4;
+    }
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        at = skip_name(answer, at).ok_or_else(malformed)?;
+        let record = answer.get(at..at + 10).ok_or_else(malformed)?;
+        let (rtype, class) = (u16::from_be_bytes([record[0], record[1]]), u16::from_be_bytes([record[2], record[3]]));
+        let len = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let data = answer.get(at + 10..at + 10 + len).ok_or_else(malformed)?;
+        match (rtype, class, len) {
+            (TYPE_A, CLASS_IN, 4) if qtype == TYPE_A => ips.push(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+            (TYPE_AAAA, CLASS_IN, 16) if qtype == TYPE_AAAA => ips.push(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+            _ => {},
+        }
+        at += 10 + len;
+    }
+    Ok(ips)
+}
+
+/// Offset just past the possibly compressed name at `at`
+fn skip_name(packet: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(at)? as usize;
+        match len {
+            0 => return Some(at + 1),
+            _ if len & 0xc0 == 0xc0 => return Some(at + 2),
+            _ => at += 1 + len,
+        }
+    }
+}
+
+/// A failed resolution, as it's repeated to later requests
+#[derive(Debug)]
+struct Failure {
+    kind: io::ErrorKind,
+    message: String,
+    until: Instant,
+}
+
+/// Hostnames that recently failed to resolve
+#[derive(Debug)]
+pub struct NegativeCache {
+    ttl: Duration,
+    failures: Mutex<HashMap<String, Failure>>,
+}
+
+impl NegativeCache {
+    /// Remember failures for `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        NegativeCache { ttl, failures: Mutex::new(HashMap::new()) }
+    }
+
+    /// Whether lookups of `host` currently fail straight away
+    pub fn failing(&self, host: &str) -> bool {
+        let name = host.trim_end_matches('.').to_ascii_lowercase();
+        self.failures.lock().unwrap().get(&name).is_some_and(|failure| failure.until > Instant::now())
+    }
+
+    /// Addresses of `host:port`, failing straight away if `host` recently failed to resolve
+    pub fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        self.resolve_via(host, port, None)
+    }
+
+    /// Addresses of `host:port` from `resolver`, failing straight away if `host` recently failed to resolve there
+    pub fn resolve_via(&self, host: &str, port: u16, resolver: Option<&Resolver>) -> io::Result<Vec<SocketAddr>> {
+        if host.parse::<IpAddr>().is_ok() || matches!(resolver, Some(Resolver::Static(_))) {
+            return lookup(host, port, resolver);
+        }
+        let mut name = host.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(resolver) = resolver {
+            name = format!("{}@{}", name, resolver);
+        }
+        if let Some(failure) = self.failures.lock().unwrap().get(&name) {
+            if failure.until > Instant::now() {
+                trace!("{} failed to resolve recently, not asking again yet", host);
+                return Err(io::Error::new(failure.kind, failure.message.clone()));
+            }
+        }
+
+        let resolved = lookup(host, port, resolver);
+        let mut failures = self.failures.lock().unwrap();
+        match &resolved {
+            Ok(_) => {
+                failures.remove(&name);
+            },
+            Err(error) => {
+                let now = Instant::now();
+                if failures.len() >= MAX_CACHED {
+                    failures.retain(|_, failure| failure.until > now);
+                }
+                if failures.len() < MAX_CACHED {
+                    failures.insert(name, Failure { kind: error.kind(), message: error.to_string(), until: now + self.ttl });
+                }
+            },
+        }
+        resolved
+    }
+}
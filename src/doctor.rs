@@ -0,0 +1,180 @@
+//! Self-test of a proxy and the host it runs on
+//!
+//! Each check reports ok, a warning or a failure with details, e.g.
+//!
+//! ```text
+//! [ ok ] proxy: relayed 4096 bytes through 127.0.0.1:40123 to 127.0.0.1:40125 in 1.21ms
+//! [ ok ] dns: localhost resolved to 127.0.0.1, ::1 in 0.31ms
+//! [FAIL] outbound: example.com:443 is unreachable: Connection timed out
+//! [warn] fd limit: soft 1024, hard 1024, about 256 concurrent sessions
+//! ```
+//!
+//! The proxy check sends data through the proxy to a local echo server, so
+//! a proxy given with [`DoctorConfig::proxy`] must run on the same host.
+use std::error::Error;
+use std::fmt;
+use std::io::prelude::*;
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::{accept, bench, client};
+
+/// Bytes sent through the proxy and echoed back
+const PAYLOAD: usize = 4096;
+
+/// Open files per session: both sockets, and the clones relaying the other direction
+const FILES_PER_SESSION: u64 = 4;
+
+/// Sessions below which the open file limit is worth a warning
+const MIN_SESSIONS: u64 = 1024;
+
+/// What `merino doctor` checks
+#[derive(Clone, Debug)]
+pub struct DoctorConfig {
+    /// Proxy to check, a temporary one is started on loopback when `None`
+    pub proxy: Option<SocketAddr>,
+    pub credentials: Option<(String, String)>,
+    /// Hostname resolved to check DNS
+    pub resolve: String,
+    /// Destination connected to directly to check outbound connectivity
+    pub outbound: (String, u16),
+    /// How long each network check may take
+    pub timeout: Duration,
+}
+
+/// Outcome of a single check
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Ok,
+    /// Works, but likely to cause trouble
+    Warn,
+    Fail,
+}
+
+#[derive(Clone, Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn from_result(name: &'static str, result: Result<(Status, String), Box<dyn Error>>) -> Self {
+        match result {
+            Ok((status, detail)) => Check { name, status, detail },
+            Err(error) => Check { name, status: Status::Fail, detail: error.to_string() },
+        }
+    }
+}
+
+/// Results of every check
+#[derive(Debug)]
+pub struct DoctorReport {
+    pub checks: Vec<Check>,
+}
+
+impl DoctorReport {
+    /// Did no check fail
+    pub fn healthy(&self) -> bool {
+        self.checks.iter().all(|check| check.status != Status::Fail)
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            let status = match check.status {
+                Status::Ok => "[ ok ]",
+                Status::Warn => "[warn]",
+                Status::Fail => "[FAIL]",
+            };
+            writeln!(f, "{} {}: {}", status, check.name, check.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Run every check in `config`
+pub fn run(config: &DoctorConfig) -> DoctorReport {
+    DoctorReport {
+        checks: vec![
+            Check::from_result("proxy", proxy(config)),
+            Check::from_result("dns", dns(config)),
+            Check::from_result("outbound", outbound(config)),
+            Check::from_result("fd limit", fd_limit()),
+        ],
+    }
+}
+
+/// A temporary proxy accepting unauthenticated clients on loopback
+#[cfg(feature = "socks5")]
+fn spawn_proxy() -> Result<SocketAddr, Box<dyn Error>> {
+    let mut merino = crate::Merino::new(0, "127.0.0.1", vec![crate::AuthMethods::NoAuth as u8], Vec::new())?;
+    let addr = merino.local_addr()?;
+    std::thread::spawn(move || merino.serve());
+    Ok(addr)
+}
+
+#[cfg(not(feature = "socks5"))]
+fn spawn_proxy() -> Result<SocketAddr, Box<dyn Error>> {
+    Err("merino was built without SOCKS5, pass --proxy to check a running instance".into())
+}
+
+/// Relay a payload through the proxy to an echo server and back
+fn proxy(config: &DoctorConfig) -> Result<(Status, String), Box<dyn Error>> {
+    let proxy = match config.proxy {
+        Some(proxy) => proxy,
+        None => spawn_proxy()?,
+    };
+    let echo = bench::spawn_echo_server()?;
+
+    let start = Instant::now();
+    let mut stream = TcpStream::connect_timeout(&proxy, config.timeout)
+        .map_err(|e| format!("can't reach the proxy at {}: {}", proxy, e))?;
+    stream.set_read_timeout(Some(config.timeout))?;
+    stream.set_write_timeout(Some(config.timeout))?;
+    let credentials = config.credentials.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+    client::connect(&mut stream, &Ipv4Addr::LOCALHOST.to_string(), echo.port(), credentials)
+        .map_err(|e| format!("the proxy at {} refused the request: {}", proxy, e))?;
+
+    let payload: Vec<u8> = (0..PAYLOAD).map(|i| i as u8).collect();
+    stream.write_all(&payload)?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut echoed = Vec::with_capacity(PAYLOAD);
+    stream.read_to_end(&mut echoed)?;
+    if echoed != payload {
+        return Err(format!("echoed {} of {} bytes through {}", echoed.len(), PAYLOAD, proxy).into());
+    }
+    Ok((Status::Ok, format!("relayed {} bytes through {} to {} in {:.2?}", PAYLOAD, proxy, echo, start.elapsed())))
+}
+
+fn dns(config: &DoctorConfig) -> Result<(Status, String), Box<dyn Error>> {
+    let start = Instant::now();
+    let addrs: Vec<String> = (config.resolve.as_str(), 0).to_socket_addrs()
+        .map_err(|e| format!("can't resolve {}: {}", config.resolve, e))?
+        .map(|addr| addr.ip().to_string())
+        .collect();
+    let elapsed = start.elapsed();
+    let status = if elapsed > config.timeout { Status::Warn } else { Status::Ok };
+    Ok((status, format!("{} resolved to {} in {:.2?}", config.resolve, addrs.join(", "), elapsed)))
+}
+
+fn outbound(config: &DoctorConfig) -> Result<(Status, String), Box<dyn Error>> {
+    let (host, port) = (&config.outbound.0, config.outbound.1);
+    let addr = (host.as_str(), port).to_socket_addrs()
+        .map_err(|e| format!("can't resolve {}: {}", host, e))?
+        .next()
+        .ok_or_else(|| format!("{} has no addresses", host))?;
+    let start = Instant::now();
+    TcpStream::connect_timeout(&addr, config.timeout)
+        .map_err(|e| format!("{}:{} is unreachable: {}", host, port, e))?;
+    Ok((Status::Ok, format!("connected to {}:{} ({}) in {:.2?}", host, port, addr, start.elapsed())))
+}
+
+fn fd_limit() -> Result<(Status, String), Box<dyn Error>> {
+    let (soft, hard) = accept::nofile_limit()?;
+    // merino raises the soft limit to the hard one when it starts
+    let sessions = hard / FILES_PER_SESSION;
+    let status = if sessions < MIN_SESSIONS { Status::Warn } else { Status::Ok };
+    Ok((status, format!("soft {}, hard {}, about {} concurrent sessions", soft, hard, sessions)))
+}
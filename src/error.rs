@@ -0,0 +1,53 @@
+//! Errors returned by the serving API, by cause
+use snafu::Snafu;
+use std::error::Error;
+use std::io;
+
+use crate::compliance::Deviation;
+
+/// Why serving a client or the proxy failed
+#[derive(Debug, Snafu)]
+pub enum MerinoError {
+    /// The client sent something that isn't valid SOCKS
+    #[snafu(display("Protocol error: {}", message))]
+    Protocol { message: String },
+    /// The client's credentials were rejected
+    #[snafu(display("Authentication failed for {}", user))]
+    Auth { user: String },
+    /// Access rules, a plugin or a session limit refused the client
+    #[snafu(display("Denied: {}", reason))]
+    Denied { reason: String },
+    /// The destination couldn't be resolved or connected to
+    #[snafu(display("Failed to connect to {}:{}: {}", host, port, source))]
+    Connect { host: String, port: u16, source: io::Error },
+    /// A user store, shared state or plugin failed
+    #[snafu(display("{}", source))]
+    Backend { source: Box<dyn Error + Send + Sync> },
+    #[snafu(display("I/O error: {}", source))]
+    Io { source: io::Error },
+}
+
+impl MerinoError {
+    pub(crate) fn protocol<M: Into<String>>(message: M) -> Self {
+        MerinoError::Protocol { message: message.into() }
+    }
+}
+
+impl From<io::Error> for MerinoError {
+    fn from(source: io::Error) -> Self {
+        MerinoError::Io { source }
+    }
+}
+
+impl From<Deviation> for MerinoError {
+    fn from(deviation: Deviation) -> Self {
+        MerinoError::protocol(deviation.0)
+    }
+}
+
+impl From<Box<dyn Error>> for MerinoError {
+    /// Keeps only the message, so the error can be sent across threads
+    fn from(source: Box<dyn Error>) -> Self {
+        MerinoError::Backend { source: source.to_string().into() }
+    }
+}
@@ -0,0 +1,56 @@
+//! Expiring credentials, so long-lived passwords don't linger
+//!
+//! Users whose store gives them an expiry are refused from then on, as if
+//! their password was wrong. Logins in the [`WARN_BEFORE`] leading up to it
+//! are logged as warnings, at most once every [`WARN_EVERY`] per user, so
+//! whoever rotates credentials hears about it in time.
+use chrono::{DateTime, NaiveDate, Utc};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long before their expiry logins are warned about
+pub const WARN_BEFORE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Least time between warnings about the same user
+pub const WARN_EVERY: Duration = Duration::from_secs(60 * 60);
+
+/// Parse an RFC 3339 time, or a date meaning midnight UTC at its start
+pub fn parse(value: &str) -> Result<DateTime<Utc>, Box<dyn Error>> {
+    let value = value.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("`{}` isn't a date (2026-12-31) or RFC 3339 time", value))?;
+    Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+}
+
+/// Users recently warned about
+#[derive(Default)]
+pub struct Warnings {
+    warned: Mutex<HashMap<String, Instant>>,
+}
+
+impl Warnings {
+    /// Whether `username`'s credentials, expiring at `expires`, have expired by `now`, warning if they soon will
+    pub fn expired(&self, username: &str, expires: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        if expires <= now {
+            info!("Credentials of {} expired at {}", username, expires.to_rfc3339());
+            return true;
+        }
+        let left = (expires - now).to_std().unwrap_or_default();
+        if left > WARN_BEFORE {
+            return false;
+        }
+        let mut warned = self.warned.lock().unwrap();
+        if warned.get(username).is_some_and(|at| at.elapsed() < WARN_EVERY) {
+            return false;
+        }
+        warn!("Credentials of {} expire at {}, in {} hours", username, expires.to_rfc3339(), left.as_secs() / 3600);
+        warned.retain(|_, at| at.elapsed() < WARN_EVERY);
+        warned.insert(username.to_string(), Instant::now());
+        false
+    }
+}
@@ -0,0 +1,195 @@
+//! External authorization over gRPC, compatible with Envoy's `ext_authz` API
+//!
+//! Every request is sent to `envoy.service.auth.v3.Authorization/Check`.
+//! A response with an OK status allows it, any other status denies it. The
+//! peer addresses, username (as the source principal) and a synthetic
+//! `CONNECT host:port` HTTP request describe the SOCKS request.
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::{Channel, Endpoint};
+
+use crate::plugin::Plugin;
+use crate::rules::{Action, Request};
+
+const CHECK: &str = "/envoy.service.auth.v3.Authorization/Check";
+
+/// The subset of the ext_authz protobuf messages merino sends and reads
+mod proto {
+    use std::collections::HashMap;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CheckRequest {
+        #[prost(message, optional, tag = "1")]
+        pub attributes: Option<AttributeContext>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AttributeContext {
+        #[prost(message, optional, tag = "1")]
+        pub source: Option<Peer>,
+        #[prost(message, optional, tag = "2")]
+        pub destination: Option<Peer>,
+        #[prost(message, optional, tag = "4")]
+        pub request: Option<AttributeRequest>,
+        #[prost(map = "string, string", tag = "10")]
+        pub context_extensions: HashMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Peer {
+        #[prost(message, optional, tag = "1")]
+        pub address: Option<Address>,
+        #[prost(string, tag = "4")]
+        pub principal: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Address {
+        #[prost(message, optional, tag = "1")]
+        pub socket_address: Option<SocketAddress>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct SocketAddress {
+        #[prost(string, tag = "2")]
+        pub address: String,
+        #[prost(uint32, tag = "3")]
+        pub port_value: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct AttributeRequest {
+        #[prost(message, optional, tag = "2")]
+        pub http: Option<HttpRequest>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct HttpRequest {
+        #[prost(string, tag = "1")]
+        pub id: String,
+        #[prost(string, tag = "2")]
+        pub method: String,
+        #[prost(string, tag = "5")]
+        pub host: String,
+        #[prost(string, tag = "10")]
+        pub protocol: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct CheckResponse {
+        #[prost(message, optional, tag = "1")]
+        pub status: Option<Status>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Status {
+        #[prost(int32, tag = "1")]
+        pub code: i32,
+        #[prost(string, tag = "2")]
+        pub message: String,
+    }
+}
+
+/// Delegates authorization of each request to a central policy server
+pub struct ExtAuthz {
+    runtime: tokio::runtime::Runtime,
+    channel: Channel,
+    timeout: Duration,
+    /// Allow requests when the server can't be reached in time
+    fail_open: bool,
+}
+
+impl ExtAuthz {
+    /// Create a client for the server at `url` (e.g. `http://authz:9000`)
+    ///
+    /// The connection is established lazily, so merino starts even while the
+    /// policy server is down.
+    pub fn new(url: &str, timeout: Duration, fail_open: bool) -> Result<Self, Box<dyn Error>> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        let channel = {
+            let _guard = runtime.enter();
+            Endpoint::from_shared(url.to_string())?
+                .connect_timeout(timeout)
+                .timeout(timeout)
+                .connect_lazy()
+        };
+
+        Ok(ExtAuthz { runtime, channel, timeout, fail_open })
+    }
+
+    fn check(&self, message: proto::CheckRequest) -> Result<proto::CheckResponse, Box<dyn Error>> {
+        let mut grpc = tonic::client::Grpc::new(self.channel.clone());
+        let call = async move {
+            grpc.ready().await?;
+            let codec = tonic::codec::ProstCodec::default();
+            let response = grpc.unary(tonic::Request::new(message), PathAndQuery::from_static(CHECK), codec).await?;
+            Ok::<_, Box<dyn Error + Send + Sync>>(response.into_inner())
+        };
+
+        let timeout = self.timeout;
+        match self.runtime.block_on(async move { tokio::time::timeout(timeout, call).await }) {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(error)) => Err(error.to_string().into()),
+            Err(_) => Err(format!("No answer within {:?}", self.timeout).into()),
+        }
+    }
+}
+
+impl Plugin for ExtAuthz {
+    fn on_request(&self, session: u64, request: &Request) -> Result<Action, Box<dyn Error>> {
+        let host = format!("{}:{}", request.destination, request.port);
+        let message = proto::CheckRequest {
+            attributes: Some(proto::AttributeContext {
+                source: Some(proto::Peer {
+                    address: Some(proto::Address {
+                        socket_address: Some(proto::SocketAddress {
+                            address: request.source.to_string(),
+                            port_value: 0,
+                        }),
+                    }),
+                    principal: request.user.unwrap_or("").to_string(),
+                }),
+                destination: Some(proto::Peer {
+                    address: Some(proto::Address {
+                        socket_address: Some(proto::SocketAddress {
+                            address: request.destination.to_string(),
+                            port_value: u32::from(request.port),
+                        }),
+                    }),
+                    principal: String::new(),
+                }),
+                request: Some(proto::AttributeRequest {
+                    http: Some(proto::HttpRequest {
+                        id: session.to_string(),
+                        method: "CONNECT".to_string(),
+                        host,
+                        protocol: "SOCKS5".to_string(),
+                    }),
+                }),
+                context_extensions: HashMap::new(),
+            }),
+        };
+
+        match self.check(message) {
+            Ok(proto::CheckResponse { status: Some(status) }) if status.code == 0 => Ok(Action::Allow),
+            Ok(response) => {
+                let reason = response.status.map(|status| status.message).unwrap_or_default();
+                debug!("External authorization denied session {}: {}", session, reason);
+                Ok(Action::Deny)
+            },
+            Err(error) if self.fail_open => {
+                warn!("External authorization failed, allowing session {}: {}", session, error);
+                Ok(Action::Allow)
+            },
+            Err(error) => {
+                warn!("External authorization failed, denying session {}: {}", session, error);
+                Ok(Action::Deny)
+            }
+        }
+    }
+}
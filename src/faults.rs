@@ -0,0 +1,104 @@
+//! Artificial network faults for testing SOCKS clients against bad conditions
+//!
+//! When enabled every relayed chunk can be delayed, throttled, or cause the
+//! whole session to be reset, turning merino into a harness for exercising
+//! client error handling.
+use rand::Rng;
+use socket2::SockRef;
+use std::io::{self, prelude::*};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Faults injected into relayed traffic
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Faults {
+    /// Delay added before forwarding each chunk
+    pub latency: Duration,
+    /// Random extra delay, up to this much, added on top of `latency`
+    pub jitter: Duration,
+    /// Probability (0.0 - 1.0) that a chunk resets the session instead of being forwarded
+    pub reset_chance: f64,
+    /// Maximum bytes per second relayed in each direction
+    pub throttle: Option<u64>,
+}
+
+impl Faults {
+    /// Is any fault configured
+    pub fn is_enabled(&self) -> bool {
+        self.latency > Duration::from_secs(0)
+            || self.jitter > Duration::from_secs(0)
+            || self.reset_chance > 0.0
+            || self.throttle.is_some()
+    }
+
+    /// Delay to apply before forwarding the next chunk
+    fn delay(&self) -> Duration {
+        if self.jitter > Duration::from_secs(0) {
+            let jitter = rand::thread_rng().gen_range(0..=self.jitter.as_micros() as u64);
+            self.latency + Duration::from_micros(jitter)
+        } else {
+            self.latency
+        }
+    }
+}
+
+/// How a relay finished
+#[derive(Debug, PartialEq)]
+pub enum Relayed {
+    /// The reader reached EOF after copying this many bytes
+    Eof(u64),
+    /// An injected fault reset the session
+    Reset,
+}
+
+/// Copy `reader` into `writer` until EOF in chunks of up to `buffer_size` bytes,
+/// applying `faults` to every chunk
+pub fn relay<R: Read, W: Write>(reader: &mut R, writer: &mut W, faults: &Faults, buffer_size: usize) -> io::Result<Relayed> {
+    let mut buf = vec![0u8; buffer_size.max(1)];
+    // Keep chunks small enough that throttled transfers don't burst
+    let chunk = match faults.throttle {
+        Some(rate) => (rate as usize / 10).max(1).min(buf.len()),
+        None => buf.len(),
+    };
+
+    let start = Instant::now();
+    let mut total = 0u64;
+    loop {
+        let n = match reader.read(&mut buf[..chunk]) {
+            Ok(0) => return Ok(Relayed::Eof(total)),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        if faults.reset_chance > 0.0 && rand::thread_rng().gen_bool(faults.reset_chance.min(1.0)) {
+            debug!("Injecting reset after {} bytes", total);
+            return Ok(Relayed::Reset);
+        }
+
+        let delay = faults.delay();
+        if delay > Duration::from_secs(0) {
+            thread::sleep(delay);
+        }
+
+        if let Some(rate) = faults.throttle {
+            let due = Duration::from_secs_f64((total + n as u64) as f64 / rate.max(1) as f64);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+}
+
+/// Abort one side of a session with a TCP reset
+///
+/// Blocked reads on the socket are woken so the opposite relay finishes
+/// too; the reset is sent once the last handle to the socket is dropped.
+pub fn reset(stream: &TcpStream) {
+    SockRef::from(stream).set_linger(Some(Duration::from_secs(0))).unwrap_or(());
+    stream.shutdown(Shutdown::Read).unwrap_or(());
+}
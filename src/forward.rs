@@ -0,0 +1,196 @@
+//! Static port forwarding, relaying a local port to a fixed destination
+//!
+//! Forwarded connections skip the SOCKS handshake but otherwise go through
+//! the same access rules, plugins, faults and accounting as proxied ones.
+use chrono::{DateTime, Local};
+use std::fmt;
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+
+use crate::{access_log, rules, Connection, MerinoError};
+use crate::transport::Stack;
+use crate::{Session, Settings, NEXT_SESSION};
+
+/// A local address forwarded to a remote destination
+#[derive(Clone, Debug, PartialEq)]
+pub struct Forward {
+    /// Address to accept connections on
+    pub listen: SocketAddr,
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for Forward {
+    type Err = String;
+
+    /// Parse `LISTEN=HOST:PORT`, e.g. `127.0.0.1:5433=db.internal:5432`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (listen, remote) = s.split_once("->")
+            .or_else(|| s.split_once('='))
+            .ok_or_else(|| format!("`{}` must be LISTEN=HOST:PORT", s))?;
+
+        let listen = listen.trim().parse()
+            .map_err(|e| format!("Invalid listen address `{}`: {}", listen.trim(), e))?;
+
+        let remote = remote.trim();
+        let (host, port) = remote.rsplit_once(':')
+            .ok_or_else(|| format!("`{}` must be HOST:PORT", remote))?;
+        let port = port.parse().map_err(|e| format!("Invalid port in `{}`: {}", remote, e))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("`{}` must be HOST:PORT", remote));
+        }
+
+        Ok(Forward { listen, host: host.to_string(), port })
+    }
+}
+
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "{} -> [{}]:{}", self.listen, self.host, self.port)
+        } else {
+            write!(f, "{} -> {}:{}", self.listen, self.host, self.port)
+        }
+    }
+}
+
+/// Accept connections on `listener` and forward each of them
+pub(crate) fn serve(listener: TcpListener, forward: Forward, settings: Arc<Settings>) {
+    let forward = Arc::new(forward);
+    loop {
+        let stream = crate::accept::accept(&listener);
+        if settings.lifecycle.is_draining() {
+            continue;
+        }
+        let handed_over = settings.lifecycle.is_handed_over();
+        spawn(stream, forward.clone(), settings.clone());
+        // The new process accepts from here on
+        if handed_over {
+            return;
+        }
+    }
+}
+
+/// Forward `stream` on a new thread
+pub(crate) fn spawn(stream: TcpStream, forward: Arc<Forward>, settings: Arc<Settings>) {
+    let handshaking = match settings.admit() {
+        Some(handshaking) => handshaking,
+        None => return,
+    };
+    let id = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+    thread::spawn(move || {
+        let _handshaking = handshaking;
+        let connected = Local::now();
+        if let Err(error) = handle(id, &stream, &forward, settings.clone(), connected) {
+            if !matches!(error, MerinoError::Denied { .. }) {
+                error!("Forward {} failed: {} (session {})", forward, error, id);
+            }
+            stream.shutdown(Shutdown::Both).unwrap_or(());
+            settings.metrics.connection_failed();
+            if let Some(log) = &settings.access_log {
+                log.log(&access_log::Entry {
+                    client: stream.peer_ip().unwrap_or(IpAddr::from([0, 0, 0, 0])),
+                    user: None,
+                    time: connected,
+                    request: Some(&format!("{}:{}", forward.host, forward.port)),
+                    status: crate::access_status(&error),
+                    bytes: 0,
+                    sni: None,
+                    fingerprint: None,
+                });
+            }
+        }
+    });
+}
+
+fn handle<S: Connection>(id: u64, stream: &S, forward: &Forward, settings: Arc<Settings>, connected: DateTime<Local>) -> Result<(), MerinoError> {
+    info!("New Forward: Source: {}, Addr: {}, Port: {} (session {})", stream.peer_ip()?, forward.host, forward.port, id);
+    let upstream = settings.forward_transport.clone();
+    tunnel(id, stream, &forward.host, forward.port, upstream.as_ref(), settings, connected)
+}
+
+/// Relay `stream` to `host:port` if the rules allow it, without a user, scrambling the connection to it with `upstream`
+pub(crate) fn tunnel<S: Connection>(id: u64, stream: &S, host: &str, port: u16, upstream: Option<&Stack>, settings: Arc<Settings>, connected: DateTime<Local>) -> Result<(), MerinoError> {
+    let source = stream.peer_ip()?;
+    if let Some(ban) = settings.admin.banned(source, None)? {
+        info!("Refusing banned {} (session {})", ban.target, id);
+        return Err(MerinoError::Denied { reason: format!("{} is banned", ban.target) });
+    }
+
+    let session = Arc::new(Session {
+        id,
+        settings: settings.clone(),
+        source,
+        user: None,
+        tag: None,
+        request: format!("{}:{}", host, port),
+        started: connected,
+        relayed: AtomicBool::new(false),
+        _slot: None,
+        cap_slot: OnceLock::new(),
+        lifetime: OnceLock::new(),
+        aborted: AtomicBool::new(false),
+        sni: Mutex::new(None),
+        denied: AtomicBool::new(false),
+        fingerprint: None,
+        bytes: AtomicU64::new(0)
+    });
+
+    let destination = rules::Destination::parse(host);
+    let asn = settings.asn_of(&destination, port);
+    let request = rules::Request {
+        source,
+        user: None,
+        groups: &[],
+        destination: &destination,
+        port,
+        asn: asn.as_ref(),
+        tag: None
+    };
+    let route = match settings.authorize(session.id, None, &request)?.filter(|route| route.claim(&session)) {
+        Some(route) => route,
+        None => return Err(MerinoError::Denied { reason: format!("{}:{}", host, port) }),
+    };
+
+    let sni = settings.sni.then_some(crate::sni::Policy { user_rules: None, port });
+    let (host, port) = (route.host.clone(), route.port);
+    let pooled = match route.via {
+        Some(_) => None,
+        None => settings.connect_pooled(&host, port),
+    };
+    let target = match pooled {
+        Some(target) => Ok(target),
+        None if settings.tripped(&host, port) => Err(crate::breaker::Breaker::open_error(&host, port)),
+        None => {
+            let connecting = std::time::Instant::now();
+            let target = match route.via {
+                Some(rules::Via::Tor) => settings.connect_tor(&format!("client:{}", source), &host, port),
+                None => settings.resolve(&host, port, route.resolver.as_ref()).and_then(|addrs| crate::connect::connect(&addrs[..], &settings.connect)),
+            };
+            settings.connect_finished(&host, port, connecting.elapsed(), target.is_ok());
+            target
+        },
+    };
+    for plugin in &settings.plugins {
+        plugin.on_connect_result(session.id, target.is_ok());
+    }
+
+    let mut target = target.map_err(|source| MerinoError::Connect { host, port, source })?;
+    route.mark(session.id, stream, &target);
+    match upstream {
+        Some(upstream) => {
+            let mut target = upstream.wrap(target);
+            route.introduce(session.id, stream, &mut target)?;
+            crate::relay(session, route.priority, stream, &target, sni, Vec::new())?
+        },
+        None => {
+            route.introduce(session.id, stream, &mut target)?;
+            crate::relay(session, route.priority, stream, &target, sni, Vec::new())?
+        },
+    };
+    Ok(())
+}
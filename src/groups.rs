@@ -0,0 +1,72 @@
+//! User groups, so access rules can name a team instead of each member
+//!
+//! Memberships are loaded from a CSV file with one `group,user` pair per
+//! row, and a user may belong to several groups:
+//!
+//! ```csv
+//! group,user
+//! developers,alice
+//! developers,bob
+//! ci-bots,runner-1
+//! ```
+//!
+//! Rules then match every member with `@developers` in the `user` column.
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Read;
+use std::path::Path;
+
+/// Row of the groups file as it appears on disk
+#[derive(Debug, Deserialize)]
+struct Membership {
+    group: String,
+    user: String,
+}
+
+/// Groups each user belongs to
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Groups {
+    memberships: HashMap<String, Vec<String>>,
+}
+
+impl Groups {
+    /// Parse memberships from CSV
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut memberships: HashMap<String, Vec<String>> = HashMap::new();
+
+        let mut rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+        for result in rdr.deserialize() {
+            let record: Membership = result?;
+            if record.group.is_empty() || record.user.is_empty() {
+                return Err("Group memberships need both a group and a user".into());
+            }
+            trace!("Loaded membership: {} in {}", record.user, record.group);
+            let groups = memberships.entry(record.user).or_default();
+            if !groups.contains(&record.group) {
+                groups.push(record.group);
+            }
+        }
+
+        Ok(Groups { memberships })
+    }
+
+    /// Load memberships from a CSV file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::from_reader(std::fs::File::open(path)?)
+    }
+
+    /// Groups `user` belongs to, if any
+    pub fn of(&self, user: Option<&str>) -> &[String] {
+        user.and_then(|user| self.memberships.get(user)).map_or(&[], Vec::as_slice)
+    }
+
+    /// Number of users in at least one group
+    pub fn len(&self) -> usize {
+        self.memberships.len()
+    }
+
+    /// Does no user belong to a group
+    pub fn is_empty(&self) -> bool {
+        self.memberships.is_empty()
+    }
+}
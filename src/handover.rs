@@ -0,0 +1,228 @@
+//! Hitless upgrades: a new merino takes over the listening sockets of the running one
+//!
+//! With [`crate::Merino::with_upgrades`], merino listens for commands on a
+//! Unix socket. `merino upgrade` sends it `upgrade`, and it starts its own
+//! binary again with the same arguments, e.g. after a package replaced it.
+//! The new process connects back and receives every listening socket over
+//! the Unix socket (`SCM_RIGHTS`), the control socket included. Once it is
+//! serving, the old process stops accepting, gives its sessions the grace
+//! period to finish, and exits. No connection is refused along the way, as
+//! the listening sockets are never closed.
+//!
+//! If the new process fails to start or to take over, the old one keeps
+//! serving and the upgrade command reports the failure.
+use std::error::Error;
+use std::ffi::OsString;
+use std::io::{self, prelude::*, BufReader, IoSlice};
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+
+use crate::lifecycle::Lifecycle;
+
+/// Environment variable telling a new process which control socket to take over from
+const UPGRADE_FROM: &str = "MERINO_UPGRADE_FROM";
+
+/// How long the new process has to take over and start serving
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most listening sockets handed over at once
+const MAX_LISTENERS: usize = 64;
+
+/// Listening sockets received from the previous process, until bound again
+struct Inherited {
+    control: Option<UnixListener>,
+    tcp: Vec<TcpListener>,
+    /// Connection to the previous process, told once this one is serving
+    previous: Option<UnixStream>,
+}
+
+static INHERITED: Mutex<Inherited> = Mutex::new(Inherited { control: None, tcp: Vec::new(), previous: None });
+
+/// Take over the listeners of the process that started this one for an upgrade, if any
+///
+/// Must run before anything is bound, returning whether there was a
+/// previous process.
+pub fn receive() -> Result<bool, Box<dyn Error>> {
+    let path = match std::env::var_os(UPGRADE_FROM) {
+        Some(path) => PathBuf::from(path),
+        None => return Ok(false),
+    };
+    std::env::remove_var(UPGRADE_FROM);
+    let mut previous = UnixStream::connect(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    previous.write_all(b"handover\n")?;
+
+    let fds = merino_sys::recv_fds::<MAX_LISTENERS>(&previous)?;
+    let mut fds = fds.into_iter();
+    let control = fds.next().ok_or("The previous process handed over no sockets")?;
+
+    let mut inherited = INHERITED.lock().unwrap();
+    inherited.control = Some(UnixListener::from(control));
+    inherited.tcp = fds.map(TcpListener::from).collect();
+    inherited.previous = Some(previous);
+    info!("Took over {} listeners from the previous process", inherited.tcp.len());
+    Ok(true)
+}
+
+/// The listener handed over for `addr`, if any
+pub(crate) fn take(addr: SocketAddr) -> Option<TcpListener> {
+    let mut inherited = INHERITED.lock().unwrap();
+    let index = inherited.tcp.iter().position(|listener| listener.local_addr().ok() == Some(addr))?;
+    Some(inherited.tcp.swap_remove(index))
+}
+
+/// The control socket handed over, or a new one bound at `path`
+pub(crate) fn bind_control(path: &Path) -> io::Result<UnixListener> {
+    if let Some(control) = INHERITED.lock().unwrap().control.take() {
+        return Ok(control);
+    }
+    // Left behind by a process that didn't exit cleanly
+    if UnixStream::connect(path).is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    UnixListener::bind(path)
+}
+
+/// Tell the previous process this one is serving, closing the listeners nothing took over
+pub(crate) fn serving() {
+    let mut inherited = INHERITED.lock().unwrap();
+    for listener in inherited.tcp.drain(..) {
+        info!("Closing the listener on {:?}, which is no longer configured", listener.local_addr());
+    }
+    inherited.control = None;
+    if let Some(mut previous) = inherited.previous.take() {
+        if let Err(error) = previous.write_all(b"ready\n") {
+            warn!("Failed to tell the previous process this one is serving: {}", error);
+        }
+    }
+}
+
+/// Ask the merino listening on the control socket at `path` to upgrade, returning its answer
+pub fn request_upgrade<P: AsRef<Path>>(path: P) -> Result<String, Box<dyn Error>> {
+    let path = path.as_ref();
+    let mut control = UnixStream::connect(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    control.write_all(b"upgrade\n")?;
+    let mut answer = String::new();
+    control.read_to_string(&mut answer)?;
+    match answer.strip_prefix("error: ") {
+        Some(error) => Err(error.trim_end().into()),
+        None => Ok(answer),
+    }
+}
+
+/// How to start the new process, and how long the old one drains for
+#[derive(Clone, Debug)]
+pub(crate) struct Upgrade {
+    pub(crate) path: PathBuf,
+    pub(crate) program: PathBuf,
+    pub(crate) args: Vec<OsString>,
+    pub(crate) grace: Duration,
+}
+
+/// Answer commands on `control` until an upgrade succeeds, then drain and exit
+pub(crate) fn serve(control: UnixListener, upgrade: Upgrade, listeners: Vec<TcpListener>, lifecycle: Arc<Lifecycle>) {
+    loop {
+        let mut operator = match control.accept() {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                warn!("Failed to accept on the upgrade socket: {}", error);
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            },
+        };
+        let mut command = String::new();
+        if BufReader::new(&operator).read_line(&mut command).is_err() {
+            continue;
+        }
+        let (answer, upgraded) = match command.trim() {
+            "upgrade" => {
+                info!(target: "merino::audit", "Upgrade requested, starting {}", upgrade.program.display());
+                match hand_over(&control, &upgrade, &listeners) {
+                    Ok(pid) => (format!("Upgraded, process {} is serving\n", pid), true),
+                    Err(error) => {
+                        error!("Upgrade failed, still serving: {}", error);
+                        (format!("error: {}\n", error), false)
+                    },
+                }
+            },
+            other => (format!("error: unknown command `{}`\n", other), false),
+        };
+        operator.write_all(answer.as_bytes()).unwrap_or(());
+        if upgraded {
+            break;
+        }
+    }
+
+    lifecycle.hand_over();
+    info!("Handed over, draining sessions for up to {}s", upgrade.grace.as_secs());
+    if !lifecycle.wait_idle(upgrade.grace) {
+        warn!("Sessions still relaying at the end of the grace period, exiting anyway");
+    }
+    std::process::exit(0);
+}
+
+/// Start the new process and pass it every listener, returning its pid once it is serving
+fn hand_over(control: &UnixListener, upgrade: &Upgrade, listeners: &[TcpListener]) -> Result<u32, Box<dyn Error>> {
+    let mut child = Command::new(&upgrade.program)
+        .args(&upgrade.args)
+        .env(UPGRADE_FROM, &upgrade.path)
+        .spawn()
+        .map_err(|e| format!("{}: {}", upgrade.program.display(), e))?;
+    let result = take_over(control, &mut child, listeners);
+    if result.is_err() {
+        child.kill().unwrap_or(());
+        child.wait().unwrap_or_default();
+    }
+    result.map(|_| child.id())
+}
+
+fn take_over(control: &UnixListener, child: &mut Child, listeners: &[TcpListener]) -> Result<(), Box<dyn Error>> {
+    // Poll, so a new process that dies before connecting doesn't leave this waiting
+    let start = Instant::now();
+    control.set_nonblocking(true)?;
+    let accepted = loop {
+        match control.accept() {
+            Ok((stream, _)) => break Ok(stream),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => {},
+            Err(error) => break Err(error),
+        }
+        if let Some(status) = child.try_wait()? {
+            break Err(io::Error::other(format!("the new process exited with {}", status)));
+        }
+        if start.elapsed() > TAKEOVER_TIMEOUT {
+            break Err(io::Error::other("the new process didn't connect in time"));
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    // The new process gets the same open file, which mustn't be left non-blocking
+    control.set_nonblocking(false)?;
+    let stream = accepted?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(TAKEOVER_TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim() != "handover" {
+        return Err(format!("expected the new process to ask for the listeners, got `{}`", line.trim()).into());
+    }
+    let fds: Vec<RawFd> = Some(control.as_raw_fd()).into_iter()
+        .chain(listeners.iter().map(AsRawFd::as_raw_fd))
+        .collect();
+    sendmsg::<()>(stream.as_raw_fd(), &[IoSlice::new(b"x")], &[ControlMessage::ScmRights(&fds)], MsgFlags::empty(), None)?;
+
+    line.clear();
+    reader.read_line(&mut line)?;
+    match line.trim() {
+        "ready" => Ok(()),
+        "" => Err("the new process failed before serving".into()),
+        other => Err(format!("unexpected answer from the new process: `{}`", other).into()),
+    }
+}
@@ -0,0 +1,101 @@
+//! Static destination overrides read from a hosts-file style mapping
+//!
+//! Each line maps one or more requested hostnames to the address merino
+//! connects to instead, optionally with a different port:
+//!
+//! ```text
+//! # target            hostnames
+//! 10.1.2.3            api.example.com www.example.com
+//! staging.internal    shop.example.com
+//! 10.1.2.4:8443       payments.example.com
+//! [fd00::7]:443       cdn.example.com
+//! ```
+//!
+//! Access rules still see the hostname the client asked for.
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::rules::Destination;
+
+/// Where connections to an overridden host go instead
+#[derive(Clone, Debug, PartialEq)]
+struct Target {
+    host: String,
+    /// Keep the requested port when `None`
+    port: Option<u16>,
+}
+
+impl Target {
+    fn parse(s: &str) -> Result<Self, Box<dyn Error>> {
+        // A bare IPv6 address has colons but no port
+        if s.parse::<std::net::Ipv6Addr>().is_ok() {
+            return Ok(Target { host: s.to_string(), port: None });
+        }
+        match s.rfind(':') {
+            Some(i) => {
+                let port = s[i + 1..].parse().map_err(|e| format!("Invalid port in `{}`: {}", s, e))?;
+                let host = s[..i].trim_start_matches('[').trim_end_matches(']');
+                Ok(Target { host: host.to_string(), port: Some(port) })
+            },
+            None => Ok(Target { host: s.to_string(), port: None }),
+        }
+    }
+}
+
+/// Hostnames rewritten before connecting
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Hosts {
+    overrides: HashMap<String, Target>,
+}
+
+impl Hosts {
+    /// Parse a mapping, ignoring blank lines and `#` comments
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut overrides = HashMap::new();
+
+        for (number, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let target = match fields.next() {
+                Some(target) => Target::parse(target).map_err(|e| format!("Line {}: {}", number + 1, e))?,
+                None => continue,
+            };
+
+            let mut names = 0;
+            for name in fields {
+                trace!("Override: {} -> {:?}", name, target);
+                overrides.insert(Destination::parse(name).to_string(), target.clone());
+                names += 1;
+            }
+            if names == 0 {
+                return Err(format!("Line {}: no hostnames for `{}`", number + 1, target.host).into());
+            }
+        }
+
+        Ok(Hosts { overrides })
+    }
+
+    /// Load a mapping from a file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::from_reader(std::fs::File::open(path)?)
+    }
+
+    /// Number of overridden hostnames
+    pub fn len(&self) -> usize {
+        self.overrides.len()
+    }
+
+    /// Are there no overrides
+    pub fn is_empty(&self) -> bool {
+        self.overrides.is_empty()
+    }
+
+    /// Where to connect for a request to `destination:port`, `None` if it isn't overridden
+    pub fn resolve(&self, destination: &Destination, port: u16) -> Option<(&str, u16)> {
+        self.overrides.get(&destination.to_string())
+            .map(|target| (target.host.as_str(), target.port.unwrap_or(port)))
+    }
+}
@@ -0,0 +1,23 @@
+//! HTTP and HTTPS requests to blocklist feeds, Vault, token endpoints and DNS over HTTPS servers
+//!
+//! `https://` servers are verified against the Mozilla root certificates
+//! built into merino, so no system certificate store is read. Plain
+//! `http://` is still accepted, e.g. for a Vault Agent on loopback.
+use std::error::Error;
+use std::time::Duration;
+
+/// An agent giving up on requests after `timeout`
+pub(crate) fn agent(timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .user_agent(concat!("merino/", env!("CARGO_PKG_VERSION")))
+        .build()
+}
+
+/// `error` with the status code for error responses, rather than the whole response
+pub(crate) fn error(error: ureq::Error) -> Box<dyn Error> {
+    match error {
+        ureq::Error::Status(status, _) => format!("Server answered {}", status).into(),
+        ureq::Error::Transport(transport) => transport.into(),
+    }
+}
@@ -3,10 +3,100 @@
 #[macro_use] extern crate log;
 use snafu::{Snafu};
 
+pub mod accept;
+pub mod admin;
+pub mod asn;
+pub mod access_log;
+pub mod bench;
+pub mod blocklist;
+pub mod breaker;
+pub mod builtin;
+pub mod capture;
+pub mod cgroup;
+pub mod client;
+pub mod compliance;
+pub mod config;
+pub mod conn;
+pub mod connect;
+pub mod dns;
+pub mod doctor;
+pub mod error;
+pub mod expiry;
+#[cfg(feature = "ext-authz")]
+pub mod ext_authz;
+pub mod faults;
+pub mod forward;
+pub mod groups;
+#[cfg(target_os = "linux")]
+pub mod handover;
+pub mod hosts;
+mod http;
+pub mod lifecycle;
+pub mod limits;
+pub mod listeners;
+pub mod metrics;
+pub mod plugin;
+pub mod pool;
+pub mod priority;
+pub mod process;
+pub mod proxy_protocol;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod rules;
+pub mod sandbox;
+pub mod secrets;
+#[cfg(feature = "shadowsocks")]
+pub mod shadowsocks;
+pub mod sinkhole;
+pub mod sni;
+pub mod speedtest;
+#[cfg(feature = "socks6")]
+pub mod socks6;
+#[cfg(feature = "rhai")]
+pub mod script;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod state;
+pub mod statsd;
+pub mod store;
+pub mod tarpit;
+#[cfg(feature = "token")]
+pub mod token;
+pub mod totp;
+pub mod transport;
+#[cfg(feature = "testing")]
+pub mod testing;
+#[cfg(feature = "socks5")]
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub use access_log::AccessLog;
+pub use admin::Admin;
+pub use blocklist::Blocklist;
+pub use compliance::Compliance;
+pub use conn::Connection;
+pub use connect::{ConnectOptions, ReplyAddress};
+pub use error::MerinoError;
+pub use faults::Faults;
+pub use forward::Forward;
+pub use groups::Groups;
+pub use hosts::Hosts;
+pub use lifecycle::Lifecycle;
+pub use limits::Limits;
+pub use metrics::Metrics;
+pub use plugin::Plugin;
+pub use pool::Pool;
+pub use priority::Priority;
+pub use rules::Rules;
+pub use state::SharedState;
+pub use store::UserStore;
+
+use chrono::{DateTime, Local};
 use std::io::prelude::*;
-use std::io::copy;
 use std::error::Error;
-use std::net::{Shutdown, TcpStream, TcpListener, SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::net::{IpAddr, Shutdown, TcpStream, TcpListener, SocketAddr};
+use std::net::ToSocketAddrs;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::{thread};
 
 
@@ -15,16 +105,40 @@ const SOCKS_VERSION: u8 = 0x05;
 
 const RESERVED: u8 = 0x00;
 
+/// How long a client turned away during maintenance has to send its greeting
+const MAINTENANCE_GREETING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Smallest chunk relayed at once
+pub const RELAY_BUFFER_MIN: usize = 16 * 1024;
+
+/// Largest chunk relayed at once
+pub const RELAY_BUFFER_MAX: usize = 512 * 1024;
+
+/// BND.ADDR sent with replies that don't bind anything
+#[cfg(feature = "socks5")]
+const UNSPECIFIED: SocketAddr = SocketAddr::V4(std::net::SocketAddrV4::new(std::net::Ipv4Addr::UNSPECIFIED, 0));
+
+/// Source of unique session IDs
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+
 #[derive(Clone,Debug, PartialEq, Deserialize)]
 pub struct User {
     pub username: String,
-    password: String
+    password: String,
+    /// Base32 TOTP secret, if the user's password must be followed by a code
+    #[serde(default)]
+    totp: Option<String>,
+    /// Date or time the credentials stop being accepted
+    #[serde(default)]
+    expires: Option<String>
 }
 
 
-#[derive(Debug, Snafu)]
+#[derive(Clone, Copy, Debug, PartialEq, Snafu)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[allow(dead_code)]
 /// Possible SOCKS5 Response Codes
-enum ResponseCode {
+pub enum ResponseCode {
     Success = 0x00,
     #[snafu(display("SOCKS5 Server Failure"))]
     Failure = 0x01,
@@ -46,6 +160,7 @@ enum ResponseCode {
 
 /// DST.addr variant types
 #[derive(PartialEq)]
+#[cfg_attr(not(feature = "socks5"), allow(dead_code))]
 enum AddrType {
     V4 = 0x01,
     Domain = 0x03,
@@ -54,6 +169,7 @@ enum AddrType {
 
 impl AddrType {
     /// Parse Byte to Command
+    #[cfg(feature = "socks5")]
     fn from(n: usize) -> Option<AddrType> {
         match n {
             1 => Some(AddrType::V4),
@@ -74,8 +190,9 @@ impl AddrType {
 }
 
 /// SOCK5 CMD Type
-#[derive(Debug)]
-enum SockCommand {
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SockCommand {
     Connect = 0x01,
     Bind = 0x02,
     UdpAssosiate = 0x3
@@ -83,6 +200,7 @@ enum SockCommand {
 
 impl SockCommand {
     /// Parse Byte to Command
+    #[cfg(feature = "socks5")]
     fn from(n: usize) -> Option<SockCommand> {
         match n {
             1 => Some(SockCommand::Connect),
@@ -107,134 +225,1184 @@ pub enum AuthMethods {
 
 pub struct Merino {
     listener: TcpListener,
-    users: Vec<User>,
-    auth_methods: Vec<u8>
+    settings: Settings,
+    /// Static port forwards served alongside the proxy
+    forwards: Vec<(TcpListener, Forward)>,
+    /// Shadowsocks listeners served alongside the proxy
+    #[cfg(feature = "shadowsocks")]
+    shadowsocks: Vec<(TcpListener, shadowsocks::Inbound)>,
+    /// Serves Prometheus metrics when set
+    metrics_listener: Option<TcpListener>,
+    /// Serves the admin API when set
+    admin_listener: Option<TcpListener>,
+    /// Serves readiness when set
+    readiness_listener: Option<TcpListener>,
+    /// Takes upgrade commands when set
+    #[cfg(target_os = "linux")]
+    upgrades: Option<(std::os::unix::net::UnixListener, handover::Upgrade)>
+}
+
+/// Bind `addr`, taking over the listener a previous process handed over for it if there is one
+fn bind<A: ToSocketAddrs + Copy>(addr: A) -> std::io::Result<TcpListener> {
+    #[cfg(target_os = "linux")]
+    {
+        for addr in addr.to_socket_addrs()? {
+            if let Some(listener) = handover::take(addr) {
+                return Ok(listener);
+            }
+        }
+    }
+    TcpListener::bind(addr)
+}
+
+/// Where an authorized request is connected to, and how its traffic is marked
+struct Route {
+    host: String,
+    port: u16,
+    /// DSCP for the connection to the destination
+    dscp: Option<u8>,
+    /// DSCP for the connection to the client
+    client_dscp: Option<u8>,
+    priority: Priority,
+    /// Upstream to connect through instead of directly
+    via: Option<rules::Via>,
+    /// Cap of the deciding rule, and its number
+    cap: Option<(usize, rules::Cap)>,
+    /// Send the destination a PROXY protocol header naming the client
+    proxy_protocol: bool,
+    /// Resolves the destination instead of the system resolver
+    resolver: Option<dns::Resolver>,
+}
+
+impl Route {
+    /// Take a session under the deciding rule's cap for `session`, false if it is full
+    fn claim(&self, session: &Session) -> bool {
+        let (index, cap) = match &self.cap {
+            Some(cap) => cap,
+            None => return true,
+        };
+        match cap.claim() {
+            Some(slot) => {
+                session.cap_slot.set(slot).unwrap_or(());
+                true
+            },
+            None => {
+                info!("Denied, rule #{} allows at most {} connections at once: {} (session {})", index + 1, cap.max(), session.request, session.id);
+                false
+            },
+        }
+    }
+
+    /// Mark the connections to `client` and `target` as the deciding rule asked
+    fn mark<S: Connection, T: Connection>(&self, session: u64, client: &S, target: &T) {
+        if let Some(dscp) = self.dscp {
+            if let Err(error) = target.set_dscp(dscp) {
+                warn!("Failed to set DSCP {} towards the destination: {} (session {})", dscp, error, session);
+            }
+        }
+        if let Some(dscp) = self.client_dscp {
+            if let Err(error) = client.set_dscp(dscp) {
+                warn!("Failed to set DSCP {} towards the client: {} (session {})", dscp, error, session);
+            }
+        }
+    }
+
+    /// Tell `target` who `client` is with a PROXY protocol header, if the deciding rule asked
+    fn introduce<S: Connection, T: Connection>(&self, session: u64, client: &S, target: &mut T) -> Result<(), MerinoError> {
+        if !self.proxy_protocol {
+            return Ok(());
+        }
+        let client = SocketAddr::new(client.peer_ip()?, client.peer_port().unwrap_or(0));
+        let destination = SocketAddr::new(target.peer_ip()?, target.peer_port().unwrap_or(self.port));
+        trace!("Sending a PROXY protocol header for {} to {} (session {})", client, destination, session);
+        target.write_all(&proxy_protocol::v2(client, destination))?;
+        Ok(())
+    }
+}
+
+/// Configuration shared by every client connection
+#[derive(Clone)]
+#[cfg_attr(not(feature = "socks5"), allow(dead_code))]
+struct Settings {
+    users: Arc<dyn UserStore>,
+    auth_methods: Vec<u8>,
+    /// Commands clients may send
+    commands: Vec<u8>,
+    /// SOCKS versions served
+    versions: Vec<u8>,
+    rules: Rules,
+    /// Groups rules can match users by
+    groups: Groups,
+    /// Destinations connected to in place of the requested ones
+    hosts: Hosts,
+    /// Autonomous systems rules can match destinations by
+    asn: Option<Arc<asn::AsnDb>>,
+    /// Destinations denied before the rules are consulted
+    blocklists: Vec<Arc<Blocklist>>,
+    connect: ConnectOptions,
+    /// BND.ADDR reported to clients once connected
+    reply_address: ReplyAddress,
+    /// Pre-warmed connections to hot destinations
+    pool: Option<Arc<Pool>>,
+    /// Fails requests to destinations that keep failing to connect fast
+    breaker: Option<Arc<breaker::Breaker>>,
+    /// Fails lookups of names that recently failed to resolve fast
+    negative_dns: Option<Arc<dns::NegativeCache>>,
+    /// Shares a bandwidth limit between priority classes
+    scheduler: Option<Arc<priority::Scheduler>>,
+    faults: Faults,
+    compliance: Compliance,
+    limits: Limits,
+    access_log: Option<AccessLog>,
+    metrics: Arc<Metrics>,
+    admin: Arc<Admin>,
+    lifecycle: Arc<Lifecycle>,
+    /// Size of the chunks relayed at once, sized from the socket buffers when `None`
+    relay_buffer: Option<usize>,
+    state: Arc<dyn SharedState>,
+    /// Most sessions a single user may have open
+    max_sessions: Option<u64>,
+    /// Longest a session may relay before it is shut down
+    max_lifetime: Option<std::time::Duration>,
+    /// Holds back answers to sources failing to log in
+    tarpit: Option<Arc<tarpit::Tarpit>>,
+    /// Second factor codes recently verified
+    totp: Arc<totp::Logins>,
+    /// Users warned about their credentials expiring
+    expiry: Arc<expiry::Warnings>,
+    /// Most connections open at once, handshaking or relaying
+    max_connections: Option<u64>,
+    /// Sheds new connections while the process uses too many descriptors or too much memory
+    shedder: Option<Arc<process::Shedder>>,
+    /// Split tags off usernames, e.g. `alice+fast`, for rules to match, see [`rules::split_tag`]
+    username_tags: bool,
+    /// Refuse to open listeners at runtime that let clients in without a password
+    require_auth: bool,
+    /// Refuse literal IPs while a domain-only rule applies, so resolving on the client can't bypass it
+    require_hostnames: bool,
+    /// Read the server name from TLS ClientHellos and check it against the rules
+    sni: bool,
+    /// Answer denied requests with success and hold them, see [`sinkhole`]
+    sinkhole: bool,
+    /// Tor SOCKS port rules can route through
+    tor: Option<SocketAddr>,
+    /// Scrambles connections accepted on the proxy listener
+    transport: Option<transport::Stack>,
+    /// Scrambles connections forwards make to their destination
+    forward_transport: Option<transport::Stack>,
+    plugins: Vec<Arc<dyn Plugin>>
+}
+
+/// Counts a connection as handshaking until dropped
+pub(crate) struct Handshaking(Arc<Metrics>);
+
+impl Drop for Handshaking {
+    fn drop(&mut self) {
+        self.0.handshaking.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Settings {
+    /// Count a newly accepted connection as handshaking, or `None` if the connection limit is reached
+    pub(crate) fn admit(&self) -> Option<Handshaking> {
+        if let Some(max) = self.max_connections {
+            if self.metrics.open_connections() >= max {
+                debug!("Connection limit of {} reached, closing a new connection", max);
+                self.metrics.connection_refused();
+                return None;
+            }
+        }
+        if let Some(reason) = self.shedder.as_ref().and_then(|shedder| shedder.overloaded()) {
+            debug!("Shedding a new connection: {}", reason);
+            self.metrics.connection_refused();
+            return None;
+        }
+        self.metrics.handshaking.fetch_add(1, Ordering::Relaxed);
+        Some(Handshaking(self.metrics.clone()))
+    }
+
+    /// Check a request against the access rules and every plugin
+    ///
+    /// `user_rules` take precedence over the global rules when one of them
+    /// matches. Returns where to connect, after any rewrite and host
+    /// override, or `None` if the request is denied.
+    fn authorize(&self, session: u64, user_rules: Option<&Rules>, request: &rules::Request) -> Result<Option<Route>, Box<dyn Error>> {
+        if let Some(list) = self.blocklists.iter().find(|list| list.contains(request.destination)) {
+            info!("Denied by blocklist {}: {}:{} (session {})", list.name(), request.destination, request.port, session);
+            return Ok(None);
+        }
+
+        if self.require_hostnames && matches!(request.destination, rules::Destination::Ip(_)) {
+            let domain_rule = user_rules.and_then(|rules| rules.domain_rule(request)).or_else(|| self.rules.domain_rule(request));
+            if let Some((index, rule)) = domain_rule {
+                info!("Denied a literal IP, rule #{} ({}) needs a hostname: {}:{} (session {})", index + 1, rule, request.destination, request.port, session);
+                return Ok(None);
+            }
+        }
+
+        let verdict = match user_rules.map(|rules| rules.evaluate(request)) {
+            Some(verdict) if verdict.rule.is_some() => verdict,
+            _ => self.rules.evaluate(request)
+        };
+
+        // Every plugin gets to veto the request too
+        let mut denied_by_plugin = false;
+        for plugin in &self.plugins {
+            if plugin.on_request(session, request)? == rules::Action::Deny {
+                denied_by_plugin = true;
+            }
+        }
+
+        if verdict.action == rules::Action::Deny || denied_by_plugin {
+            let (destination, port) = (request.destination, request.port);
+            match verdict.rule {
+                Some((index, rule)) if verdict.action == rules::Action::Deny => info!("Denied by rule #{} ({}): {}:{} (session {})", index + 1, rule, destination, port, session),
+                _ if denied_by_plugin => info!("Denied by plugin: {}:{} (session {})", destination, port, session),
+                _ => info!("Denied: {}:{} (session {})", destination, port, session),
+            }
+            return Ok(None);
+        }
+
+        let (mut destination, mut port) = (request.destination.clone(), request.port);
+        if let Some((index, rule)) = verdict.rule {
+            if let Some(rewrite) = rule.rewrite() {
+                let (new_destination, new_port) = rewrite.apply(&destination, port);
+                info!("Rewrote {}:{} to {}:{} by rule #{} (session {})", destination, port, new_destination, new_port, index + 1, session);
+                destination = new_destination;
+                port = new_port;
+            }
+        }
+
+        let (host, port) = match self.hosts.resolve(&destination, port) {
+            Some((host, new_port)) => {
+                debug!("Overriding {}:{} with {}:{} (session {})", destination, port, host, new_port, session);
+                (host.to_string(), new_port)
+            },
+            None => (destination.to_string(), port),
+        };
+        let rule = verdict.rule.map(|(_, rule)| rule);
+        Ok(Some(Route {
+            host,
+            port,
+            dscp: rule.and_then(rules::Rule::dscp),
+            client_dscp: rule.and_then(rules::Rule::client_dscp),
+            priority: rule.and_then(rules::Rule::priority).unwrap_or_default(),
+            via: rule.and_then(rules::Rule::via),
+            cap: verdict.rule.and_then(|(index, rule)| Some((index, rule.cap()?.clone()))),
+            proxy_protocol: rule.is_some_and(rules::Rule::proxy_protocol),
+            resolver: rule.and_then(rules::Rule::resolver).cloned(),
+        }))
+    }
+
+    /// Finds the autonomous system of `destination` for the rules that ask, resolving domains to find it
+    fn asn_of<'a>(&'a self, destination: &'a rules::Destination, port: u16) -> Option<rules::AsnLookup<'a>> {
+        let db = self.asn.as_ref()?;
+        Some(rules::AsnLookup::new(move |resolver| {
+            let ip = match destination {
+                rules::Destination::Ip(ip) => *ip,
+                rules::Destination::Domain(domain) => self.resolve(domain, port, resolver).ok()?.first()?.ip(),
+            };
+            db.lookup(ip).map(|asn| asn.number)
+        }))
+    }
+
+    /// Addresses of `host:port` from `resolver` or the system's, through the negative cache if there is one
+    fn resolve(&self, host: &str, port: u16, resolver: Option<&dns::Resolver>) -> std::io::Result<Vec<SocketAddr>> {
+        match &self.negative_dns {
+            Some(cache) => cache.resolve_via(host, port, resolver),
+            None => dns::lookup(host, port, resolver),
+        }
+    }
+
+    /// Whether the circuit breaker has given up on `host:port` for now
+    fn tripped(&self, host: &str, port: u16) -> bool {
+        self.breaker.as_ref().is_some_and(|breaker| !breaker.allow(host, port))
+    }
+
+    /// Account for a connect to `host:port` having finished after `latency`
+    fn connect_finished(&self, host: &str, port: u16, latency: std::time::Duration, succeeded: bool) {
+        self.metrics.connect_finished(host, port, latency, succeeded);
+        if let Some(breaker) = &self.breaker {
+            breaker.record(host, port, succeeded);
+        }
+    }
+
+    /// A pre-warmed connection to `host:port`, if it is pooled and one is ready
+    fn connect_pooled(&self, host: &str, port: u16) -> Option<TcpStream> {
+        self.pool.as_ref().and_then(|pool| pool.take(host, port))
+    }
+
+    /// Connect to `host:port` through the Tor SOCKS port, on circuits of `isolation`'s own
+    ///
+    /// Tor keeps streams with different SOCKS credentials on different
+    /// circuits (`IsolateSOCKSAuth`, on by default), so the credentials name
+    /// the user or client. The host is resolved by Tor, not here.
+    fn connect_tor(&self, isolation: &str, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        let tor = self.tor.ok_or_else(|| std::io::Error::other("The rule routes via Tor, but no Tor SOCKS port is set"))?;
+        let mut stream = connect::connect(tor, &self.connect)?;
+        client::connect(&mut stream, host, port, Some((isolation, "merino")))
+            .map_err(|error| std::io::Error::other(format!("Tor refused the stream: {}", error)))?;
+        Ok(stream)
+    }
+
+    /// Size of the chunks relayed to and from `target`
+    ///
+    /// Without a configured size this follows the socket buffers the kernel
+    /// negotiated, so links with a high bandwidth-delay product aren't capped
+    /// by a small copy buffer.
+    fn relay_buffer<T: Connection>(&self, target: &T) -> usize {
+        let size = self.relay_buffer.or_else(|| target.buffer_size()).unwrap_or(0);
+        size.clamp(RELAY_BUFFER_MIN, RELAY_BUFFER_MAX)
+    }
 }
 
 impl Merino {
     /// Create a new Merino instance
-    pub fn new(port: u16,  ip: &str, auth_methods: Vec<u8>, users: Vec<User>) -> Result<Self, Box<dyn Error>> {
+    pub fn new(port: u16,  ip: &str, auth_methods: Vec<u8>, users: Vec<User>) -> Result<Self, MerinoError> {
         info!("Listening on {}:{}", ip, port);
+        let metrics = Arc::new(Metrics::default());
         Ok(Merino {
-            listener: TcpListener::bind((ip, port))?,
-            auth_methods,
-            users
+            listener: bind((ip, port))?,
+            settings: Settings {
+                auth_methods,
+                commands: listeners::DEFAULT_COMMANDS.to_vec(),
+                versions: listeners::DEFAULT_VERSIONS.to_vec(),
+                users: Arc::new(users),
+                rules: Rules::default(),
+                groups: Groups::default(),
+                hosts: Hosts::default(),
+                asn: None,
+                blocklists: Vec::new(),
+                connect: ConnectOptions::default(),
+                reply_address: ReplyAddress::default(),
+                pool: None,
+                breaker: None,
+                negative_dns: None,
+                scheduler: None,
+                faults: Faults::default(),
+                compliance: Compliance::default(),
+                limits: Limits::default(),
+                access_log: None,
+                lifecycle: Arc::new(Lifecycle::new(metrics.clone())),
+                metrics,
+                admin: Arc::new(Admin::default()),
+                relay_buffer: None,
+                state: Arc::new(state::LocalState::default()),
+                max_sessions: None,
+                max_lifetime: None,
+                tarpit: None,
+                totp: Arc::new(totp::Logins::default()),
+                expiry: Arc::new(expiry::Warnings::default()),
+                max_connections: None,
+                shedder: None,
+                username_tags: false,
+                require_auth: false,
+                require_hostnames: false,
+                sni: false,
+                sinkhole: false,
+                tor: None,
+                transport: None,
+                forward_transport: None,
+                plugins: Vec::new()
+            },
+            forwards: Vec::new(),
+            #[cfg(feature = "shadowsocks")]
+            shadowsocks: Vec::new(),
+            metrics_listener: None,
+            admin_listener: None,
+            readiness_listener: None,
+            #[cfg(target_os = "linux")]
+            upgrades: None
         })
     }
 
-    pub fn serve(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Address the proxy is listening on
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Address readiness probes are answered on, when set
+    pub fn readiness_addr(&self) -> Option<SocketAddr> {
+        self.readiness_listener.as_ref().and_then(|listener| listener.local_addr().ok())
+    }
+
+    /// Authenticate users against `store` instead of the user list
+    pub fn with_store<S: UserStore + 'static>(mut self, store: S) -> Self {
+        self.settings.users = Arc::new(store);
+        self
+    }
+
+    /// Keep session counters in `state`, and record bans and failed handshakes there, e.g. to share them with other instances
+    pub fn with_state<S: SharedState + 'static>(mut self, state: S) -> Self {
+        let state: Arc<dyn SharedState> = Arc::new(state);
+        self.settings.admin.share_bans(state.clone());
+        if let Some(tarpit) = &self.settings.tarpit {
+            tarpit.share_failures(state.clone());
+        }
+        self.settings.state = state;
+        self
+    }
+
+    /// Serve only `commands`, as [`listeners::parse_commands`] gives them
+    ///
+    /// Only CONNECT is served by default.
+    pub fn with_commands(mut self, commands: Vec<u8>) -> Self {
+        self.settings.commands = commands;
+        self
+    }
+
+    /// Serve only the SOCKS `versions`, as [`listeners::parse_versions`] gives them
+    ///
+    /// Only SOCKS5 is served by default.
+    pub fn with_versions(mut self, versions: Vec<u8>) -> Self {
+        self.settings.versions = versions;
+        self
+    }
+
+    /// Limit how many sessions each user may have open at once
+    pub fn with_max_sessions(mut self, max: u64) -> Self {
+        self.settings.max_sessions = Some(max);
+        self
+    }
+
+    /// Shut sessions down once they have relayed for `lifetime`, so forgotten tunnels don't stay open for good
+    pub fn with_max_lifetime(mut self, lifetime: std::time::Duration) -> Self {
+        self.settings.max_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Answer failed handshakes after `base`, doubling with each further failure from the same source or subnet, see [`tarpit`]
+    ///
+    /// Failed logins count, and so do malformed handshakes, unsupported
+    /// versions and refused method negotiations.
+    pub fn with_auth_delay(mut self, base: std::time::Duration, subnets: tarpit::Subnets) -> Self {
+        let tarpit = tarpit::Tarpit::new(base, subnets);
+        tarpit.share_failures(self.settings.state.clone());
+        self.settings.tarpit = Some(Arc::new(tarpit));
+        self
+    }
+
+    /// Fail requests to a destination fast for `cooldown` once `threshold` connects to it failed in a row, see [`breaker`]
+    pub fn with_circuit_breaker(mut self, threshold: u32, cooldown: std::time::Duration) -> Self {
+        self.settings.breaker = Some(Arc::new(breaker::Breaker::new(threshold, cooldown)));
+        self
+    }
+
+    /// Fail lookups of hostnames that failed to resolve within the last `ttl` straight away, see [`dns`]
+    pub fn with_negative_dns_ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.settings.negative_dns = Some(Arc::new(dns::NegativeCache::new(ttl)));
+        self
+    }
+
+    /// Close new connections straight away while `max` are open, handshaking or relaying
+    ///
+    /// Bounds the memory a connection flood can take, see [`cgroup`] for
+    /// deriving a limit from a container's.
+    pub fn with_max_connections(mut self, max: u64) -> Self {
+        self.settings.max_connections = Some(max);
+        self
+    }
+
+    /// Close new connections straight away while the process is past `limits`, see [`process`]
+    pub fn with_soft_limits(mut self, limits: process::SoftLimits) -> Self {
+        self.settings.shedder = Some(Arc::new(process::Shedder::new(limits)));
+        self
+    }
+
+    /// Accept Multipath TCP clients on the SOCKS listener, where the kernel supports it
+    ///
+    /// Rebinds the listener on the same address, unless it already came
+    /// from an upgrade speaking MPTCP.
+    pub fn with_mptcp_listener(mut self) -> Result<Self, MerinoError> {
+        if !connect::is_mptcp(&self.listener)? {
+            let addr = self.listener.local_addr()?;
+            // Closed first, so the new socket can take its port
+            drop(self.listener);
+            self.listener = connect::listen_mptcp(addr)?;
+        }
+        Ok(self)
+    }
+
+    /// Accept TCP Fast Open on the SOCKS listener, see [`connect::listen_fast_open`]
+    ///
+    /// Apply after [`Merino::with_mptcp_listener`], which rebinds the listener.
+    pub fn with_fast_open_listener(self) -> Result<Self, MerinoError> {
+        connect::listen_fast_open(&self.listener)?;
+        Ok(self)
+    }
+
+    /// Split tags off usernames, e.g. `alice+fast` into `alice` and `fast`, for rules with a `tag` to match
+    ///
+    /// Users log in without their tag. Off by default, so usernames with a
+    /// `+` in them, e.g. email addresses, keep working as they are.
+    pub fn with_username_tags(mut self, tags: bool) -> Self {
+        self.settings.username_tags = tags;
+        self
+    }
+
+    /// Refuse to open listeners offering NO AUTH through the admin API
+    ///
+    /// For proxies whose own listener only lets users with a password in, so
+    /// `auth=none` can't open an anonymous way around them while serving.
+    pub fn with_require_auth(mut self, require: bool) -> Self {
+        self.settings.require_auth = require;
+        self
+    }
+
+    /// Refuse requests for literal IPs that a domain-only rule would apply to if named by domain
+    ///
+    /// Otherwise a client can resolve `blocked.example.com` itself and
+    /// request its address, slipping past a rule denying the name.
+    pub fn with_require_hostnames(mut self, require: bool) -> Self {
+        self.settings.require_hostnames = require;
+        self
+    }
+
+    /// Peek at the TLS ClientHello of relayed connections to log the server name and check it against the rules
+    ///
+    /// A session whose server name the rules deny is reset, even if the
+    /// client sent CONNECT with a bare IP. TLS isn't terminated.
+    pub fn with_sni(mut self, inspect: bool) -> Self {
+        self.settings.sni = inspect;
+        self
+    }
+
+    /// Tell clients requests the access rules deny succeeded, and hold them on a connection to nowhere
+    ///
+    /// Keeps scanners busy and logs what they send, see [`sinkhole`]. Requests
+    /// refused for other reasons, such as a rule's connection cap, are still refused.
+    pub fn with_sinkhole(mut self, sinkhole: bool) -> Self {
+        self.settings.sinkhole = sinkhole;
+        self
+    }
+
+    /// Consult `plugin` for every request, in addition to the access rules
+    pub fn with_plugin<P: Plugin + 'static>(mut self, plugin: P) -> Self {
+        self.settings.plugins.push(Arc::new(plugin));
+        self
+    }
+
+    /// Apply access control rules to incoming requests
+    pub fn with_rules(mut self, rules: Rules) -> Self {
+        self.settings.rules = rules;
+        self
+    }
+
+    /// Let access rules match users by the groups they belong to
+    pub fn with_groups(mut self, groups: Groups) -> Self {
+        self.settings.groups = groups;
+        self
+    }
+
+    /// Connect to overridden destinations instead of the requested ones
+    pub fn with_hosts(mut self, hosts: Hosts) -> Self {
+        self.settings.hosts = hosts;
+        self
+    }
+
+    /// Deny every destination on `list`, whatever the rules say
+    pub fn with_blocklist(mut self, list: Arc<Blocklist>) -> Self {
+        self.settings.blocklists.push(list);
+        self
+    }
+
+    /// Look up destinations in `db` so rules can match them by autonomous system
+    pub fn with_asn_db(mut self, db: asn::AsnDb) -> Self {
+        self.settings.asn = Some(Arc::new(db));
+        self
+    }
+
+    /// Connect to destinations using `options`
+    pub fn with_connect_options(mut self, options: ConnectOptions) -> Self {
+        self.settings.connect = options;
+        self
+    }
+
+    /// Report `address` as BND.ADDR to clients once connected, instead of the address connected from
+    pub fn with_reply_address(mut self, address: ReplyAddress) -> Self {
+        self.settings.reply_address = address;
+        self
+    }
+
+    /// Relay at most `bytes_per_sec` across all sessions, shared between priority classes by weight
+    pub fn with_bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.settings.scheduler = Some(Arc::new(priority::Scheduler::new(bytes_per_sec)));
+        self
+    }
+
+    /// Take connections to pooled destinations from `pool` instead of connecting on request
+    pub fn with_pool(mut self, pool: Arc<Pool>) -> Self {
+        self.settings.pool = Some(pool);
+        self
+    }
+
+    /// Inject artificial network faults into relayed traffic
+    pub fn with_faults(mut self, faults: Faults) -> Self {
+        self.settings.faults = faults;
+        self
+    }
+
+    /// Refuse or tolerate clients deviating from the SOCKS RFCs
+    pub fn with_compliance(mut self, compliance: Compliance) -> Self {
+        self.settings.compliance = compliance;
+        self
+    }
+
+    /// Refuse handshakes with fields longer than `limits` allow
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.settings.limits = limits;
+        self
+    }
+
+    /// Write a line to `log` for every connection once it ends
+    pub fn with_access_log(mut self, log: AccessLog) -> Self {
+        self.settings.access_log = Some(log);
+        self
+    }
+
+    /// Serve Prometheus metrics over HTTP on `addr`
+    pub fn with_metrics(mut self, addr: SocketAddr) -> Result<Self, MerinoError> {
+        self.metrics_listener = Some(bind(addr)?);
+        info!("Serving metrics on {}", addr);
+        Ok(self)
+    }
+
+    /// Answer readiness probes over HTTP on `addr`, see [`lifecycle`]
+    ///
+    /// Port 0 picks a free port, reported by [`Merino::readiness_addr`].
+    pub fn with_readiness(mut self, addr: SocketAddr) -> Result<Self, MerinoError> {
+        let listener = bind(addr)?;
+        info!("Serving readiness on {}", listener.local_addr()?);
+        self.readiness_listener = Some(listener);
+        Ok(self)
+    }
+
+    /// Take upgrade commands on the Unix socket at `path`, see [`handover`]
+    ///
+    /// Upgrading starts the program merino was started as again, with the
+    /// same arguments, and gives the old process's sessions `grace` to end.
+    #[cfg(target_os = "linux")]
+    pub fn with_upgrades<P: AsRef<std::path::Path>>(mut self, path: P, grace: std::time::Duration) -> Result<Self, MerinoError> {
+        let path = path.as_ref();
+        let control = handover::bind_control(path)?;
+        let upgrade = handover::Upgrade {
+            path: path.to_path_buf(),
+            program: std::env::current_exe()?,
+            args: std::env::args_os().skip(1).collect(),
+            grace,
+        };
+        info!("Taking upgrade commands on {}", path.display());
+        self.upgrades = Some((control, upgrade));
+        Ok(self)
+    }
+
+    /// Also push metrics to a statsd agent as sessions start and end
+    pub fn with_statsd(self, statsd: statsd::Statsd) -> Self {
+        self.settings.metrics.push_to(statsd);
+        self
+    }
+
+    /// Serve the admin API over HTTP on `addr`, see [`admin`]
+    pub fn with_admin(mut self, addr: SocketAddr) -> Result<Self, MerinoError> {
+        self.admin_listener = Some(bind(addr)?);
+        info!("Serving the admin API on {}", addr);
+        Ok(self)
+    }
+
+    /// Keep bans in the CSV file at `path`, loading any saved there before
+    pub fn with_ban_list<P: AsRef<std::path::Path>>(self, path: P) -> Result<Self, MerinoError> {
+        self.settings.admin.persist_to(path)?;
+        Ok(self)
+    }
+
+    /// Allow capturing single sessions from the admin API, writing pcap files to `dir`
+    pub fn with_capture_dir<P: AsRef<std::path::Path>>(self, dir: P) -> Result<Self, MerinoError> {
+        self.settings.admin.capture_to(dir)?;
+        Ok(self)
+    }
+
+    /// Live sessions and bans, to kill or ban from outside the admin API
+    pub fn admin(&self) -> Arc<Admin> {
+        self.settings.admin.clone()
+    }
+
+    /// Metrics about the sessions served so far
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.settings.metrics.clone()
+    }
+
+    /// Readiness, and draining sessions to shut down cleanly
+    pub fn lifecycle(&self) -> Arc<Lifecycle> {
+        self.settings.lifecycle.clone()
+    }
+
+    /// Relay data in chunks of `size` bytes, between 16 KiB and 512 KiB
+    ///
+    /// Sizes outside that range are clamped. By default the size follows the
+    /// destination socket's buffers.
+    pub fn with_relay_buffer(mut self, size: usize) -> Self {
+        self.settings.relay_buffer = Some(size);
+        self
+    }
+
+    /// Forward connections to a local port to a fixed destination
+    pub fn with_forward(mut self, forward: Forward) -> Result<Self, MerinoError> {
+        let listener = bind(forward.listen)?;
+        info!("Forwarding {}", forward);
+        self.forwards.push((listener, forward));
+        Ok(self)
+    }
+
+    /// Scramble connections to the proxy listener with `stack`, for clients wrapping theirs the same way
+    pub fn with_transport(mut self, stack: transport::Stack) -> Self {
+        self.settings.transport = Some(stack);
+        self
+    }
+
+    /// Scramble connections forwards make to their destinations with `stack`, e.g. to reach a remote merino's
+    /// [`Merino::with_transport`] listener
+    pub fn with_forward_transport(mut self, stack: transport::Stack) -> Self {
+        self.settings.forward_transport = Some(stack);
+        self
+    }
+
+    /// Connect requests routed `via` `tor` through the Tor SOCKS port at `addr`, e.g. `127.0.0.1:9050`
+    pub fn with_tor(mut self, addr: SocketAddr) -> Self {
+        self.settings.tor = Some(addr);
+        self
+    }
+
+    /// Serve Shadowsocks clients on a listener of their own
+    #[cfg(feature = "shadowsocks")]
+    pub fn with_shadowsocks(mut self, inbound: shadowsocks::Inbound) -> Result<Self, MerinoError> {
+        let listener = bind(inbound.listen)?;
+        info!("Serving Shadowsocks on {}", inbound);
+        self.shadowsocks.push((listener, inbound));
+        Ok(self)
+    }
+
+    pub fn serve(&mut self) -> Result<(), MerinoError> {
         info!("Serving Connections...");
+        if self.settings.faults.is_enabled() {
+            warn!("Injecting faults into relayed traffic: {:?}", self.settings.faults);
+        }
+        let settings = Arc::new(self.settings.clone());
+        if let Some(listener) = &self.metrics_listener {
+            let (listener, metrics) = (listener.try_clone()?, settings.metrics.clone());
+            thread::spawn(move || metrics::serve(listener, metrics));
+        }
+        if let Some(listener) = &self.admin_listener {
+            // Weak, as the settings hold the admin state the opener lives in
+            let weak = Arc::downgrade(&settings);
+            settings.admin.open_with(move |spec| match weak.upgrade() {
+                Some(settings) => listeners::open(spec, &settings),
+                None => Err(std::io::Error::other("No longer serving")),
+            });
+            let (listener, admin, metrics) = (listener.try_clone()?, settings.admin.clone(), settings.metrics.clone());
+            let (lifecycle, max_connections) = (settings.lifecycle.clone(), settings.max_connections);
+            thread::spawn(move || admin::serve(listener, admin, metrics, lifecycle, max_connections));
+        }
+        if let Some(listener) = &self.readiness_listener {
+            let (listener, lifecycle) = (listener.try_clone()?, settings.lifecycle.clone());
+            thread::spawn(move || lifecycle::serve(listener, lifecycle));
+        }
+        for (listener, forward) in &self.forwards {
+            let (listener, forward, settings) = (listener.try_clone()?, forward.clone(), settings.clone());
+            thread::spawn(move || forward::serve(listener, forward, settings));
+        }
+        #[cfg(feature = "shadowsocks")]
+        for (listener, inbound) in &self.shadowsocks {
+            let (listener, inbound, settings) = (listener.try_clone()?, inbound.clone(), settings.clone());
+            thread::spawn(move || shadowsocks::serve(listener, inbound, settings));
+        }
+        #[cfg(target_os = "linux")]
+        {
+            if let Some((control, upgrade)) = &self.upgrades {
+                let mut listeners = vec![self.listener.try_clone()?];
+                for listener in self.forwards.iter().map(|(listener, _)| listener)
+                    .chain(&self.metrics_listener)
+                    .chain(&self.admin_listener)
+                    .chain(&self.readiness_listener) {
+                    listeners.push(listener.try_clone()?);
+                }
+                let (control, upgrade, lifecycle) = (control.try_clone()?, upgrade.clone(), settings.lifecycle.clone());
+                thread::spawn(move || handover::serve(control, upgrade, listeners, lifecycle));
+            }
+            handover::serving();
+        }
+        settings.lifecycle.started();
         loop {
-            if let Ok((stream, _remote)) = self.listener.accept() {
-                    // TODO Optimize this
-                    let mut client = SOCKClient::new(stream, self.users.clone(), self.auth_methods.clone());
-                    thread::spawn(move || {
-                        match client.init() {
-                            Ok(_) => {},
-                            Err(error) => {
-                                error!("Error! {}", error);
-                                let error_text = format!("{}", error);
-                                
-
-                                let response: ResponseCode;
-
-                                if error_text.contains("Host") {
-                                    response = ResponseCode::HostUnreachable;
-                                }
-                                else if error_text.contains("Network"){
-                                    response = ResponseCode::NetworkUnreachable;
-                                }
-                                else if error_text.contains("ttl") {
-                                    response = ResponseCode::TtlExpired
-                                }
-                                else {
-                                    response = ResponseCode::Failure
-                                }
-
-                                if client.error(response).is_err() {
-                                    warn!("Failed to send error code");
-                                };
-                                if client.shutdown().is_err() {
-                                    warn!("Failed to shutdown TcpStream");
-                                };
-                            } 
-                        };
-                    });
-
+            let stream = accept::accept(&self.listener);
+            if settings.lifecycle.is_draining() {
+                continue;
+            }
+            let handed_over = settings.lifecycle.is_handed_over();
+            if let Some(handshaking) = settings.admit() {
+                match &settings.transport {
+                    Some(transport) => spawn_client(transport.wrap(stream), settings.clone(), handshaking),
+                    None => spawn_client(stream, settings.clone(), handshaking),
+                }
+            }
+            // The new process accepts from here on, this one waits for its sessions to end
+            if handed_over {
+                loop {
+                    thread::park();
+                }
             }
         }
     }
+
+    /// Serve a single client on stdin/stdout, relaying until the destination closes
+    ///
+    /// Used when merino is launched by inetd or as an SSH `ProxyCommand`.
+    /// Forwards configured with [`Merino::with_forward`] aren't served.
+    pub fn serve_stdio(&mut self) -> Result<(), MerinoError> {
+        self.serve_connection(conn::Stdio)
+    }
+
+    /// Serve a single client accepted by the caller, relaying until the destination closes
+    ///
+    /// Lets merino speak SOCKS over transports it doesn't listen on itself,
+    /// such as Unix sockets or TLS streams. Returns why the session failed, if it did.
+    pub fn serve_connection<S: Connection>(&self, stream: S) -> Result<(), MerinoError> {
+        serve_client(stream, Arc::new(self.settings.clone()))
+    }
+}
+
+/// Serve a client on a new thread
+fn spawn_client<S: Connection>(stream: S, settings: Arc<Settings>, handshaking: Handshaking) {
+    let mut client = SOCKClient::new(stream, settings);
+    thread::spawn(move || {
+        let _handshaking = handshaking;
+        client.run().unwrap_or(())
+    });
+}
+
+/// Serve one client on the current thread until its relay finishes
+fn serve_client<S: Connection>(stream: S, settings: Arc<Settings>) -> Result<(), MerinoError> {
+    let mut client = SOCKClient::new(stream, settings);
+    client.run()?;
+    if let Some(download) = client.download.take() {
+        download.join().map_err(|_| std::io::Error::other("Relay thread panicked"))?;
+    }
+    Ok(())
+}
+
+/// HTTP-style status recorded in the access log for a connection that failed with `error`
+fn access_status(error: &MerinoError) -> u16 {
+    match error {
+        MerinoError::Protocol { .. } => 400,
+        MerinoError::Auth { .. } => 407,
+        MerinoError::Denied { .. } => 403,
+        MerinoError::Connect { .. } => 502,
+        _ => 500,
+    }
 }
 
-struct SOCKClient {
-    stream: TcpStream,
+/// Reply owed to a client whose handshake failed with `error`
+///
+/// `None` if the client was already answered, as with failed logins and denials.
+fn reply_code(error: &MerinoError) -> Option<ResponseCode> {
+    match error {
+        MerinoError::Auth { .. } | MerinoError::Denied { .. } => None,
+        MerinoError::Connect { source, .. } => Some(match source.kind() {
+            std::io::ErrorKind::ConnectionRefused => ResponseCode::ConnectionRefused,
+            std::io::ErrorKind::HostUnreachable => ResponseCode::HostUnreachable,
+            std::io::ErrorKind::NetworkUnreachable => ResponseCode::NetworkUnreachable,
+            std::io::ErrorKind::TimedOut => ResponseCode::TtlExpired,
+            _ => ResponseCode::Failure,
+        }),
+        _ => Some(ResponseCode::Failure),
+    }
+}
+
+#[cfg_attr(not(feature = "socks5"), allow(dead_code))]
+struct SOCKClient<S: Connection = TcpStream> {
+    /// Unique ID of the connection, included in everything logged about it
+    id: u64,
+    stream: S,
+    /// When the client connected
+    connected: DateTime<Local>,
+    /// Requested `host:port`, once the request is parsed
+    request: Option<String>,
     auth_nmethods: u8,
-    auth_methods: Vec<u8>,
-    authed_users: Vec<User>,
+    settings: Arc<Settings>,
+    /// Username the client authenticated as
+    user: Option<String>,
+    /// Tag the client passed in its username, for rules to match
+    tag: Option<String>,
+    /// Access rules specific to the authenticated user
+    user_rules: Option<Rules>,
+    /// Counts against the user's session limit while held
+    slot: Option<Arc<state::SessionSlot>>,
+    /// Relays the destination back to the client once connected
+    download: Option<thread::JoinHandle<()>>,
+    /// What the client sent in its handshake so far, for the access log
+    fingerprint: access_log::Fingerprint,
+    /// Whether a failed handshake was already held back, see [`tarpit`]
+    held: bool,
     socks_version: u8
 }
 
-impl SOCKClient {
+impl<S: Connection> SOCKClient<S> {
     /// Create a new SOCKClient
-    pub fn new(stream: TcpStream, authed_users: Vec<User>, auth_methods: Vec<u8>) -> Self {
+    fn new(stream: S, settings: Arc<Settings>) -> Self {
         SOCKClient {
+            id: NEXT_SESSION.fetch_add(1, Ordering::Relaxed),
             stream,
+            connected: Local::now(),
+            request: None,
             auth_nmethods: 0,
             socks_version: 0,
-            authed_users,
-            auth_methods
+            settings,
+            user: None,
+            tag: None,
+            user_rules: None,
+            slot: None,
+            download: None,
+            fingerprint: access_log::Fingerprint::default(),
+            held: false,
         }
     }
 
-    /// Check if username + password pair are valid
-    fn authed(&self, user: &User) -> bool {
-        self.authed_users.contains(user)
+    /// Hold back the answer to a failed handshake from `peer`, once per session, see [`tarpit`]
+    fn hold(&mut self, peer: IpAddr) {
+        if let Some(tarpit) = &self.settings.tarpit {
+            if !self.held {
+                self.held = true;
+                tarpit.hold(peer);
+            }
+        }
+    }
+
+    /// Handle the client, reporting any error back to it
+    fn run(&mut self) -> Result<(), MerinoError> {
+        let error = match self.init() {
+            Ok(()) => return Ok(()),
+            Err(error) => error,
+        };
+
+        // Malformed handshakes are held back like failed logins, before answering
+        if let MerinoError::Protocol { .. } = error {
+            if let Ok(peer) = self.stream.peer_ip() {
+                self.hold(peer);
+            }
+        }
+        match reply_code(&error) {
+            Some(response) => {
+                error!("Error! {} (session {})", error, self.id);
+                if self.error(response).is_err() {
+                    warn!("Failed to send error code");
+                };
+                if self.shutdown().is_err() {
+                    warn!("Failed to shutdown TcpStream");
+                };
+            },
+            None => debug!("{} (session {})", error, self.id),
+        }
+        self.settings.metrics.connection_failed();
+
+        if let Some(log) = &self.settings.access_log {
+            log.log(&access_log::Entry {
+                client: self.stream.peer_ip().unwrap_or(IpAddr::from([0, 0, 0, 0])),
+                user: self.user.as_deref(),
+                time: self.connected,
+                request: self.request.as_deref(),
+                status: access_status(&error),
+                bytes: 0,
+                sni: None,
+                fingerprint: Some(&self.fingerprint).filter(|fingerprint| fingerprint.version != 0),
+            });
+        }
+        Err(error)
+    }
+
+    /// Check if username + password pair are valid, with the code following the password for users with a TOTP secret
+    #[cfg(feature = "socks5")]
+    fn authed(&self, peer: IpAddr, user: &User) -> Result<bool, Box<dyn Error>> {
+        let secret = match self.settings.users.totp_secret(&user.username)? {
+            Some(secret) => secret,
+            None => return self.settings.users.authenticate(&user.username, &user.password),
+        };
+        let (password, code) = match totp::split(&user.password) {
+            Some(split) => split,
+            None => return Ok(false),
+        };
+        Ok(self.settings.users.authenticate(&user.username, password)?
+            && self.settings.totp.check(&user.username, peer, &secret, code))
+    }
+
+    /// Check if `user`'s credentials have expired, warning if they soon will
+    #[cfg(feature = "socks5")]
+    fn expired(&self, user: &User) -> Result<bool, Box<dyn Error>> {
+        Ok(match self.settings.users.expires(&user.username)? {
+            Some(expires) => self.settings.expiry.expired(&user.username, expires, chrono::Utc::now()),
+            None => false,
+        })
+    }
+
+    /// Check `user`'s password, bans and session limit, taking the user on if they pass
+    ///
+    /// The client still has to be told the outcome.
+    #[cfg(feature = "socks5")]
+    fn login(&mut self, peer: IpAddr, mut user: User) -> Result<(), MerinoError> {
+        if self.settings.username_tags {
+            if let (username, Some(tag)) = rules::split_tag(&user.username) {
+                debug!("{} tagged its request {} (session {})", username, tag, self.id);
+                self.tag = Some(tag.to_string());
+                user.username = username.to_string();
+            }
+        }
+        if !self.authed(peer, &user)? || self.expired(&user)? {
+            debug!("Access Denied. User: {} (session {})", user.username, self.id);
+            self.hold(peer);
+            self.settings.metrics.request_decided(None, metrics::Outcome::DeniedAuth);
+            return Err(MerinoError::Auth { user: user.username });
+        }
+        if let Some(tarpit) = &self.settings.tarpit {
+            tarpit.succeeded(peer);
+        }
+        if let Some(ban) = self.settings.admin.banned(peer, Some(&user.username))? {
+            info!("Refusing banned {} (session {})", ban.target, self.id);
+            return Err(MerinoError::Denied { reason: format!("{} is banned", ban.target) });
+        }
+
+        // Enforce the per-user session limit
+        if let Some(max) = self.settings.max_sessions {
+            match state::SessionSlot::claim(&self.settings.state, &user.username, max)? {
+                Some(slot) => self.slot = Some(Arc::new(slot)),
+                None => {
+                    info!("Session limit of {} reached for {} (session {})", max, user.username, self.id);
+                    return Err(MerinoError::Denied { reason: format!("session limit of {} reached for {}", max, user.username) });
+                }
+            }
+        }
+
+        debug!("Access Granted. User: {} (session {})", user.username, self.id);
+        self.user_rules = self.settings.users.user_rules(&user.username)?;
+        self.user = Some(user.username);
+        Ok(())
     }
 
     /// Send an error to the client
-    pub fn error(&mut self, r: ResponseCode) -> Result<(), Box<dyn Error>> {
+    pub fn error(&mut self, r: ResponseCode) -> Result<(), MerinoError> {
+        #[cfg(feature = "socks6")]
+        {
+            if self.socks_version == socks6::SOCKS6_VERSION {
+                self.stream.write_all(&socks6::operation_reply(r, UNSPECIFIED))?;
+                return Ok(());
+            }
+        }
         self.stream.write_all(&[5, r as u8])?;
         Ok(())
     }
 
     /// Shutdown a client
-    pub fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn shutdown(&mut self) -> Result<(), MerinoError> {
         self.stream.shutdown(Shutdown::Both)?;
         Ok(())
     }
 
-    fn init(&mut self) -> Result<(), Box<dyn Error>> {
-        debug!("New connection from: {}", self.stream.peer_addr()?.ip());
+    fn init(&mut self) -> Result<(), MerinoError> {
+        let peer = self.stream.peer_ip()?;
+        debug!("New connection from: {} (session {})", peer, self.id);
+        if let Some(ban) = self.settings.admin.banned(peer, None)? {
+            info!("Refusing banned {} (session {})", ban.target, self.id);
+            self.shutdown()?;
+            return Err(MerinoError::Denied { reason: format!("{} is banned", ban.target) });
+        }
+        if let Some(maintenance) = self.settings.lifecycle.maintenance() {
+            return self.maintenance(maintenance);
+        }
         let mut header = [0u8; 2];
         // Read a byte from the stream and determine the version being requested
         self.stream.read_exact(&mut header)?;
 
         self.socks_version = header[0];
         self.auth_nmethods = header[1];
+        self.fingerprint.version = header[0];
 
-        trace!("Version: {} Auth nmethods: {}", self.socks_version, self.auth_nmethods);
+        trace!("Version: {} Auth nmethods: {} (session {})", self.socks_version, self.auth_nmethods, self.id);
 
-        // Handle SOCKS4 requests
-        if header[0] != SOCKS_VERSION {
-            warn!("Init: Unsupported version: SOCKS{}", self.socks_version);
-            self.shutdown()?;
-        }
         // Valid SOCKS5
-        else {
-            // Authenticate w/ client
-            self.auth()?;
-            // Handle requests
-            self.handle_client()?;
+        #[cfg(feature = "socks5")]
+        {
+            if header[0] == SOCKS_VERSION && self.settings.versions.contains(&SOCKS_VERSION) {
+                // Authenticate w/ client
+                self.auth()?;
+                // Handle requests
+                self.handle_client()?;
+                return Ok(());
+            }
         }
 
+        #[cfg(feature = "socks6")]
+        {
+            if header[0] == socks6::SOCKS6_VERSION && self.settings.versions.contains(&socks6::SOCKS6_VERSION) {
+                return self.socks6(header[1]);
+            }
+        }
+
+        // Handle SOCKS4 requests, and SOCKS5 when compiled out
+        warn!("Init: Unsupported version: SOCKS{} (session {})", self.socks_version, self.id);
+        self.hold(peer);
+        self.shutdown()?;
         Ok(())
     }
 
-    fn auth(&mut self) -> Result<(), Box<dyn Error>> {
-        debug!("Authenticating w/ {}", self.stream.peer_addr()?.ip());
+    /// Turn the client away, or pass it on to the standby proxy, while in maintenance
+    fn maintenance(&mut self, maintenance: lifecycle::Maintenance) -> Result<(), MerinoError> {
+        match maintenance {
+            lifecycle::Maintenance::Refuse => {
+                info!("Refusing a new client during maintenance (session {})", self.id);
+                // Idle clients mustn't hold a thread while draining
+                self.stream.set_read_timeout(Some(MAINTENANCE_GREETING_TIMEOUT))?;
+                let mut header = [0u8; 2];
+                self.stream.read_exact(&mut header)?;
+                if header[0] == SOCKS_VERSION {
+                    let mut methods = vec![0u8; header[1] as usize];
+                    self.stream.read_exact(&mut methods)?;
+                    self.stream.write_all(&[SOCKS_VERSION, AuthMethods::NoMethods as u8])?;
+                }
+                self.shutdown()?;
+                Err(MerinoError::Denied { reason: "in maintenance".to_string() })
+            },
+            lifecycle::Maintenance::Standby(addr) => {
+                info!("Passing a new client to standby {} during maintenance (session {})", addr, self.id);
+                let standby = TcpStream::connect(addr).map_err(|source| MerinoError::Connect { host: addr.ip().to_string(), port: addr.port(), source })?;
+                let (mut upload_from, mut upload_to) = (self.stream.try_clone()?, standby.try_clone()?);
+                let upload = thread::spawn(move || {
+                    std::io::copy(&mut upload_from, &mut upload_to).unwrap_or(0);
+                    upload_to.shutdown(Shutdown::Write).unwrap_or(());
+                });
+                let (mut download_from, mut download_to) = (standby, self.stream.try_clone()?);
+                std::io::copy(&mut download_from, &mut download_to).unwrap_or(0);
+                download_to.shutdown(Shutdown::Write).unwrap_or(());
+                upload.join().map_err(|_| std::io::Error::other("Relay thread panicked"))?;
+                Ok(())
+            },
+        }
+    }
+
+    #[cfg(feature = "socks5")]
+    fn auth(&mut self) -> Result<(), MerinoError> {
+        let peer = self.stream.peer_ip()?;
+        debug!("Authenticating w/ {} (session {})", peer, self.id);
+        if self.auth_nmethods == 0 {
+            self.settings.compliance.deviation(self.id, peer, format_args!("no auth methods offered"))?;
+        }
+        if self.auth_nmethods as usize > self.settings.limits.max_methods {
+            warn!("Client offered {} auth methods, more than the limit of {} (session {})", self.auth_nmethods, self.settings.limits.max_methods, self.id);
+            self.hold(peer);
+            self.stream.write_all(&[SOCKS_VERSION, AuthMethods::NoMethods as u8])?;
+            self.shutdown()?;
+            return Err(MerinoError::protocol("Too many auth methods"));
+        }
         // Get valid auth methods
-        let methods = self.get_avalible_methods()?;
-        trace!("methods: {:?}", methods);
+        let offered = self.get_avalible_methods()?;
+        self.fingerprint.methods = offered.clone();
+        trace!("methods: {:?} (session {})", offered, self.id);
+        let methods: Vec<u8> = offered.iter().copied().filter(|method| self.settings.auth_methods.contains(method)).collect();
 
         let mut response = [0u8; 2];
 
@@ -245,7 +1413,7 @@ impl SOCKClient {
             // Set the default auth method (NO AUTH)
             response[1] = AuthMethods::UserPass as u8;
 
-            debug!("Sending USER/PASS packet");
+            debug!("Sending USER/PASS packet (session {})", self.id);
             self.stream.write_all(&response)?;
 
             let mut header = [0u8;2];
@@ -254,135 +1422,177 @@ impl SOCKClient {
             self.stream.read_exact(&mut header)?;
 
             // debug!("Auth Header: [{}, {}]", header[0], header[1]);
+            self.fingerprint.userpass = Some(header[0]);
+            if header[0] != 1 {
+                self.settings.compliance.deviation(self.id, peer, format_args!("username/password auth version {}", header[0]))?;
+            }
 
             // Username parsing
             let ulen = header[1];
-
-            let mut username = Vec::with_capacity(ulen as usize);
-
-            // For some reason the vector needs to actually be full
-            for _ in 0..ulen {
-                username.push(0);
+            if ulen == 0 {
+                self.settings.compliance.deviation(self.id, peer, format_args!("empty username"))?;
             }
+            if ulen as usize > self.settings.limits.max_username {
+                warn!("Username of {} bytes exceeds the limit of {} (session {})", ulen, self.settings.limits.max_username, self.id);
+                self.hold(peer);
+                self.stream.write_all(&[1, ResponseCode::Failure as u8])?;
+                self.shutdown()?;
+                return Err(MerinoError::protocol("Username too long"));
+            }
+
+            let mut username = vec![0; ulen as usize];
 
             self.stream.read_exact(&mut username)?;
 
             // Password Parsing
             let mut plen = [0u8; 1];
             self.stream.read_exact(&mut plen)?;
-            
-
-            let mut password = Vec::with_capacity(plen[0] as usize);
-
-            // For some reason the vector needs to actually be full
-            for _ in 0..plen[0] {
-                password.push(0);
+            if plen[0] == 0 {
+                self.settings.compliance.deviation(self.id, peer, format_args!("empty password"))?;
             }
 
+            let mut password = vec![0; plen[0] as usize];
+
             self.stream.read_exact(&mut password)?;
 
-            let username_str = String::from_utf8(username)?;
-            let password_str = String::from_utf8(password)?;
+            let username_str = String::from_utf8(username).map_err(|_| MerinoError::protocol("Username isn't UTF-8"))?;
+            let password_str = String::from_utf8(password).map_err(|_| MerinoError::protocol("Password isn't UTF-8"))?;
 
            let user = User { 
                 username: username_str,
-                password: password_str 
+                password: password_str,
+                totp: None,
+                expires: None
             };
 
             // Authenticate passwords
-            if self.authed(&user) {
-                debug!("Access Granted. User: {}", user.username);
-                let response = [1, ResponseCode::Success as u8];
-                self.stream.write_all(&response)?;
-            } 
-            else {
-                debug!("Access Denied. User: {}", user.username);
-                let response = [1, ResponseCode::Failure as u8];
-                self.stream.write_all(&response)?;
-
-                // Shutdown 
+            if let Err(error) = self.login(peer, user) {
+                self.stream.write_all(&[1, ResponseCode::Failure as u8])?;
                 self.shutdown()?;
-
+                return Err(error);
             }
+            self.stream.write_all(&[1, ResponseCode::Success as u8])?;
 
             Ok(())
         }
         else if methods.contains(&(AuthMethods::NoAuth as u8)) {
             // set the default auth method (no auth)
             response[1] = AuthMethods::NoAuth as u8;
-            debug!("Sending NOAUTH packet");
+            debug!("Sending NOAUTH packet (session {})", self.id);
             self.stream.write_all(&response)?;
             Ok(())
         }
+        else if offered.contains(&(AuthMethods::NoAuth as u8)) {
+            info!("Refusing unauthenticated client {} (session {})", peer, self.id);
+            self.settings.metrics.request_decided(None, metrics::Outcome::DeniedAuth);
+            self.hold(peer);
+            response[1] = AuthMethods::NoMethods as u8;
+            self.stream.write_all(&response)?;
+            self.shutdown()?;
+            Err(MerinoError::Denied { reason: "authentication required".to_string() })
+        }
         else {
-            warn!("Client has no suitable Auth methods!");
+            warn!("Client has no suitable Auth methods! (session {})", self.id);
+            self.hold(peer);
             response[1] = AuthMethods::NoMethods as u8;
             self.stream.write_all(&response)?;
             self.shutdown()?;
-            Err(Box::new(ResponseCode::Failure))
+            Err(MerinoError::protocol("No suitable auth methods"))
         }
 
     }
 
     /// Handles a client
-    pub fn handle_client(&mut self) -> Result<(), Box<dyn Error>> {
-        debug!("Handling requests for {}", self.stream.peer_addr()?.ip());
+    #[cfg(feature = "socks5")]
+    pub fn handle_client(&mut self) -> Result<(), MerinoError> {
+        debug!("Handling requests for {} (session {})", self.stream.peer_ip()?, self.id);
         // Read request
         // loop {
             // Parse Request
-            let req = SOCKSReq::from_stream(&mut self.stream)?;
-            
-            if req.addr_type == AddrType::V6 {
-            }
+            let req = SOCKSReq::from_stream(&mut self.stream, self.id, &self.settings, &mut self.fingerprint)?;
 
             // Log Request
-            let displayed_addr = pretty_print_addr(&req.addr_type, &req.addr);
-            info!("New Request: Source: {}, Command: {:?} Addr: {}, Port: {}", 
-                  self.stream.peer_addr()?.ip(),
+            let displayed_addr = req.destination.to_string();
+            self.request = Some(format!("{}:{}", displayed_addr, req.port));
+            info!("New Request: Source: {}, Command: {:?} Addr: {}, Port: {} (session {})", 
+                  self.stream.peer_ip()?,
                   req.command, 
                   displayed_addr,
-                  req.port
+                  req.port,
+                  self.id
             );
 
+            if !self.settings.commands.contains(&(req.command as u8)) {
+                info!("Refusing {:?}, which this listener isn't granted (session {})", req.command, self.id);
+                self.settings.metrics.request_decided(Some(req.command), metrics::Outcome::DeniedAcl);
+                self.stream.write_all(&SOCKSReply::new(ResponseCode::CommandNotSupported, UNSPECIFIED).to_bytes())?;
+                self.shutdown()?;
+                return Err(MerinoError::Denied { reason: format!("{:?} isn't granted", req.command) });
+            }
+
+            let session = self.session(format!("{}:{}", displayed_addr, req.port))?;
+
+            // Check the request against the access rules
+            let source = self.stream.peer_ip()?;
+            let authorized = {
+                let asn = self.settings.asn_of(&req.destination, req.port);
+                let request = rules::Request {
+                    source,
+                    user: self.user.as_deref(),
+                    groups: self.settings.groups.of(self.user.as_deref()),
+                    destination: &req.destination,
+                    port: req.port,
+                    asn: asn.as_ref(),
+                    tag: self.tag.as_deref()
+                };
+                self.settings.authorize(session.id, self.user_rules.as_ref(), &request)?
+            };
+            let sinkhole = self.settings.sinkhole && authorized.is_none();
+            let route = match authorized.filter(|route| route.claim(&session)) {
+                Some(route) => route,
+                None if sinkhole => {
+                    self.settings.metrics.request_decided(Some(req.command), metrics::Outcome::DeniedAcl);
+                    info!("Sinkholing denied request for {}:{} (session {})", displayed_addr, req.port, self.id);
+                    self.stream.write_all(&SOCKSReply::new(ResponseCode::Success, UNSPECIFIED).to_bytes())?;
+                    sinkhole::hold(self.id, &self.stream)?;
+                    return Err(MerinoError::Denied { reason: format!("{}:{}", displayed_addr, req.port) });
+                },
+                None => {
+                    self.settings.metrics.request_decided(Some(req.command), metrics::Outcome::DeniedAcl);
+                    self.stream.write_all(&SOCKSReply::new(ResponseCode::RuleFailure, UNSPECIFIED).to_bytes())?;
+                    self.shutdown()?;
+                    return Err(MerinoError::Denied { reason: format!("{}:{}", displayed_addr, req.port) });
+                }
+            };
 
             // Respond
             match req.command {
                 // Use the Proxy to connect to the specified addr/port
                 SockCommand::Connect => {
-                    debug!("Handling CONNECT Command");
-
-                    let sock_addr = addr_to_socket(&req.addr_type, &req.addr, req.port)?;
+                    debug!("Handling CONNECT Command (session {})", self.id);
 
-                    trace!("Connecting to: {:?}", sock_addr);
+                    if let Some(service) = builtin::Service::of(&req.destination) {
+                        debug!("Serving {} (session {})", service, self.id);
+                        let target = service.spawn(self.id)?;
+                        self.settings.metrics.request_decided(Some(req.command), metrics::Outcome::Granted);
+                        self.stream.write_all(&SOCKSReply::new(ResponseCode::Success, UNSPECIFIED).to_bytes())?;
+                        self.download = Some(relay(session, route.priority, &self.stream, &target, None, Vec::new())?);
+                        return Ok(());
+                    }
 
-                    let target = TcpStream::connect(&sock_addr[..])?;
+                    let mut target = self.connect_counted(SockCommand::Connect, &route)?;
 
-                    trace!("Connected!");
+                    trace!("Connected! (session {})", self.id);
+                    route.mark(self.id, &self.stream, &target);
+                    route.introduce(self.id, &self.stream, &mut target)?;
 
-                    self.stream.write_all(&[SOCKS_VERSION, ResponseCode::Success as u8, RESERVED, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+                    // Some clients, e.g. FTP and Java stacks, use the address the proxy connected from
+                    let bind = self.settings.reply_address.report(target.local_addr().ok());
+                    self.stream.write_all(&SOCKSReply::new(ResponseCode::Success, bind).to_bytes())?;
 
                     // Copy it all
-                    let mut outbound_in = target.try_clone()?;
-                    let mut outbound_out = target.try_clone()?;
-                    let mut inbound_in = self.stream.try_clone()?;
-                    let mut inbound_out = self.stream.try_clone()?;
-
-
-                    // Download Thread
-                    thread::spawn(move || {
-                        copy(&mut outbound_in, &mut inbound_out).is_ok();
-                        outbound_in.shutdown(Shutdown::Read).unwrap_or(());
-                        inbound_out.shutdown(Shutdown::Write).unwrap_or(());
-                    });
-
-                    // Upload Thread
-                    thread::spawn(move || {
-                        copy(&mut inbound_in, &mut outbound_out).is_ok();
-                        inbound_in.shutdown(Shutdown::Read).unwrap_or(());
-                        outbound_out.shutdown(Shutdown::Write).unwrap_or(());
-                    });
-
-
+                    let sni = self.settings.sni.then(|| sni::Policy { user_rules: self.user_rules.clone(), port: req.port });
+                    self.download = Some(relay(session, route.priority, &self.stream, &target, sni, Vec::new())?);
                 },
                 SockCommand::Bind => { },
                 SockCommand::UdpAssosiate => { },
@@ -397,91 +1607,377 @@ impl SOCKClient {
         Ok(())
     }
 
-    /// Return the avalible methods based on `self.auth_nmethods`
-    fn get_avalible_methods(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut methods: Vec<u8> = Vec::with_capacity(self.auth_nmethods as usize);
-        for _ in 0..self.auth_nmethods {
-            let mut method = [0u8; 1];
-            self.stream.read_exact(&mut method)?;
-            if self.auth_methods.contains(&method[0]) {
-                methods.append(&mut method.to_vec());
-            }
+    /// Connect to the route's destination for `command`, counting the request as granted or failed
+    #[cfg(feature = "socks5")]
+    fn connect_counted(&self, command: SockCommand, route: &Route) -> Result<TcpStream, MerinoError> {
+        let connected = self.connect(route);
+        let outcome = if connected.is_ok() { metrics::Outcome::Granted } else { metrics::Outcome::ConnectFailed };
+        self.settings.metrics.request_decided(Some(command), outcome);
+        connected
+    }
+
+    /// Connect to the route's destination, from the pool if it has a connection ready
+    #[cfg(feature = "socks5")]
+    fn connect(&self, route: &Route) -> Result<TcpStream, MerinoError> {
+        let (host, port) = (route.host.as_str(), route.port);
+        let pooled = match route.via {
+            Some(_) => None,
+            None => self.settings.connect_pooled(host, port),
+        };
+        let target = match pooled {
+            Some(target) => {
+                trace!("Using a pre-warmed connection to {}:{} (session {})", host, port, self.id);
+                Ok(target)
+            },
+            None if self.settings.tripped(host, port) => {
+                debug!("Failing {}:{} fast (session {})", host, port, self.id);
+                Err(breaker::Breaker::open_error(host, port))
+            },
+            None => {
+                let connecting = std::time::Instant::now();
+                let target = match route.via {
+                    Some(rules::Via::Tor) => {
+                        trace!("Connecting to {}:{} via Tor (session {})", host, port, self.id);
+                        let isolation = match &self.user {
+                            Some(user) => format!("user:{}", user),
+                            None => format!("client:{}", self.stream.peer_ip()?),
+                        };
+                        self.settings.connect_tor(&isolation, host, port)
+                    },
+                    None => self.settings.resolve(host, port, route.resolver.as_ref()).and_then(|sock_addr| {
+                        trace!("Connecting to: {:?} (session {})", sock_addr, self.id);
+                        connect::connect(&sock_addr[..], &self.settings.connect)
+                    }),
+                };
+                self.settings.connect_finished(host, port, connecting.elapsed(), target.is_ok());
+                target
+            },
+        };
+        for plugin in &self.settings.plugins {
+            plugin.on_connect_result(self.id, target.is_ok());
         }
+        target.map_err(|source| MerinoError::Connect { host: host.to_string(), port, source })
+    }
+
+    /// State shared by the relay directions of the client's request for `request`, as `host:port`
+    #[cfg(feature = "socks5")]
+    fn session(&self, request: String) -> Result<Arc<Session>, MerinoError> {
+        Ok(Arc::new(Session {
+            id: self.id,
+            settings: self.settings.clone(),
+            source: self.stream.peer_ip()?,
+            user: self.user.clone(),
+            tag: self.tag.clone(),
+            request,
+            started: self.connected,
+            relayed: AtomicBool::new(false),
+            _slot: self.slot.clone(),
+            cap_slot: OnceLock::new(),
+            lifetime: OnceLock::new(),
+            aborted: AtomicBool::new(false),
+            sni: Mutex::new(None),
+            denied: AtomicBool::new(false),
+            fingerprint: Some(self.fingerprint.clone()),
+            bytes: AtomicU64::new(0)
+        }))
+    }
+
+    /// Read the `self.auth_nmethods` methods offered by the client
+    #[cfg(feature = "socks5")]
+    fn get_avalible_methods(&mut self) -> Result<Vec<u8>, MerinoError> {
+        let mut methods = vec![0u8; self.auth_nmethods as usize];
+        self.stream.read_exact(&mut methods)?;
         Ok(methods)
     }
 }
 
-/// Convert an address and AddrType to a SocketAddr
-fn addr_to_socket(addr_type: &AddrType, addr: &[u8], port: u16) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
-    match addr_type {
-        AddrType::V6 => {
-            let new_addr = (0..8).map(|x| {
-                trace!("{} and {}", x * 2, (x * 2) + 1);
-                (u16::from(addr[(x * 2)]) << 8) | u16::from(addr[(x * 2) + 1])
-            }).collect::<Vec<u16>>();
+/// State of a single request, shared by the two relay directions once connected
+struct Session {
+    id: u64,
+    settings: Arc<Settings>,
+    source: IpAddr,
+    user: Option<String>,
+    /// Tag the client passed in its username
+    tag: Option<String>,
+    /// Requested `host:port`
+    request: String,
+    /// When the client connected
+    started: DateTime<Local>,
+    /// Set once relaying starts; sessions failing before then are logged with their error
+    relayed: AtomicBool,
+    /// Keeps the session counted against the user's limit until both directions finish
+    _slot: Option<Arc<state::SessionSlot>>,
+    /// Keeps the session counted against the deciding rule's cap until both directions finish
+    cap_slot: OnceLock<rules::CapSlot>,
+    /// Dropped with the session, telling the watchdog enforcing its maximum lifetime to stand down
+    lifetime: OnceLock<mpsc::Sender<()>>,
+    /// Set once an injected fault resets the session, so neither side gets a clean close
+    aborted: AtomicBool,
+    /// Server name from the client's TLS ClientHello, when inspected
+    sni: Mutex<Option<String>>,
+    /// Set when the rules deny that server name
+    denied: AtomicBool,
+    /// The client's handshake, when it made one
+    fingerprint: Option<access_log::Fingerprint>,
+    /// Bytes relayed in both directions
+    bytes: AtomicU64
+}
 
+impl Drop for Session {
+    /// Account the session's traffic once both directions are done
+    fn drop(&mut self) {
+        let bytes = self.bytes.load(Ordering::SeqCst);
+        if let Some(user) = &self.user {
+            if let Err(error) = self.settings.users.record_usage(user, bytes) {
+                warn!("Failed to record usage for {}: {} (session {})", user, error, self.id);
+            }
+        }
+        for plugin in &self.settings.plugins {
+            plugin.on_close(self.id, bytes);
+        }
+        if self.relayed.load(Ordering::SeqCst) {
+            self.settings.admin.unregister(self.id);
+            let duration = (Local::now() - self.started).to_std().unwrap_or_default();
+            self.settings.metrics.session_ended(duration, bytes);
+        }
+        if let Some(log) = &self.settings.access_log {
+            if self.relayed.load(Ordering::SeqCst) {
+                let denied = self.denied.load(Ordering::SeqCst);
+                log.log(&access_log::Entry {
+                    client: self.source,
+                    user: self.user.as_deref(),
+                    time: self.started,
+                    request: Some(&self.request),
+                    status: if denied { 403 } else { 200 },
+                    bytes,
+                    sni: self.sni.lock().unwrap().as_deref(),
+                    fingerprint: self.fingerprint.as_ref(),
+                });
+            }
+        }
+    }
+}
 
-            Ok(vec![SocketAddr::from(
-                SocketAddrV6::new(
-                    Ipv6Addr::new(
-                        new_addr[0], new_addr[1], new_addr[2], new_addr[3], new_addr[4], new_addr[5], new_addr[6], new_addr[7]), 
-                    port, 0, 0)
-            )])
-        },
-        AddrType::V4 => {
-            Ok(vec![SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]), port))])
-        },
-        AddrType::Domain => {
-            let mut domain = String::from_utf8_lossy(&addr[..]).to_string();
-            domain.push_str(&":");
-            domain.push_str(&port.to_string());
+/// Relay traffic between `client` and `target` in both directions, each on its own thread
+///
+/// Writes are paced as `priority` traffic when a bandwidth limit is set.
+/// With an `sni` policy, the client's TLS server name is checked first.
+/// `early` bytes the client sent before the relay, such as SOCKS6 initial
+/// data, go to `target` ahead of the rest. Returns the thread relaying `target` back to `client`.
+fn relay<S: Connection, T: Connection>(session: Arc<Session>, priority: Priority, client: &S, target: &T, sni: Option<sni::Policy>, early: Vec<u8>) -> Result<thread::JoinHandle<()>, Box<dyn Error>> {
+    let mut outbound_in = target.try_clone()?;
+    let mut outbound_out = target.try_clone()?;
+    let mut inbound_in = client.try_clone()?;
+    let mut inbound_out = client.try_clone()?;
+
+    // Client ports aren't known, captures use a made-up one
+    let capture = capture::Slot::default();
+    let port = session.request.rsplit(':').next().and_then(|port| port.parse().ok()).unwrap_or(0);
+    let tap = capture::Tap {
+        slot: capture.clone(),
+        client: SocketAddr::new(session.source, 49152 + (session.id % 16384) as u16),
+        server: SocketAddr::new(target.peer_ip()?, port),
+    };
+
+    let (kill_client, kill_target) = (client.try_clone()?, target.try_clone()?);
+    session.settings.admin.register(admin::SessionInfo {
+        id: session.id,
+        source: session.source,
+        user: session.user.clone(),
+        request: session.request.clone(),
+        started: session.started,
+    }, move || {
+        kill_client.reset();
+        kill_target.reset();
+    }, Some(tap));
+
+    if let Some(lifetime) = session.settings.max_lifetime {
+        let (ended, expired) = mpsc::channel::<()>();
+        session.lifetime.set(ended).unwrap_or(());
+        let (end_client, end_target, id) = (client.try_clone()?, target.try_clone()?, session.id);
+        thread::spawn(move || {
+            if let Err(mpsc::RecvTimeoutError::Timeout) = expired.recv_timeout(lifetime) {
+                info!("Shutting down session {} after its maximum lifetime of {:?}", id, lifetime);
+                end_client.shutdown(Shutdown::Both).unwrap_or(());
+                end_target.shutdown(Shutdown::Both).unwrap_or(());
+            }
+        });
+    }
 
-            Ok(domain.to_socket_addrs()?.collect())
+    session.relayed.store(true, Ordering::SeqCst);
+    session.settings.metrics.session_started((Local::now() - session.started).to_std().unwrap_or_default());
+    let buffer = session.settings.relay_buffer(target);
+    trace!("Relaying in chunks of {} bytes (session {})", buffer, session.id);
+
+    let download = session.clone();
+    let upload = session;
+    let (download_capture, upload_capture) = (capture.clone(), capture);
+
+    // Download Thread
+    let download_thread = thread::spawn(move || {
+        let relayed = {
+            let _buffer = download.settings.metrics.relay_buffer(buffer);
+            let active = download.settings.scheduler.as_deref().map(|scheduler| scheduler.enter(priority));
+            let mut writer = Counted {
+                inner: &mut inbound_out,
+                bytes: &download.bytes,
+                pacing: active.as_ref(),
+                capture: Some((&download_capture, capture::Direction::ToClient)),
+            };
+            faults::relay(&mut outbound_in, &mut writer, &download.settings.faults, buffer)
+        };
+        match relayed {
+            Ok(faults::Relayed::Reset) => {
+                download.aborted.store(true, Ordering::SeqCst);
+                outbound_in.reset();
+                inbound_out.reset();
+            },
+            _ if download.aborted.load(Ordering::SeqCst) => {},
+            _ => {
+                outbound_in.shutdown(Shutdown::Read).unwrap_or(());
+                inbound_out.shutdown(Shutdown::Write).unwrap_or(());
+            }
+        }
+    });
+
+    // Upload Thread
+    thread::spawn(move || {
+        let relayed = {
+            let _buffer = upload.settings.metrics.relay_buffer(buffer);
+            let active = upload.settings.scheduler.as_deref().map(|scheduler| scheduler.enter(priority));
+            let mut writer = Counted {
+                inner: &mut outbound_out,
+                bytes: &upload.bytes,
+                pacing: active.as_ref(),
+                capture: Some((&upload_capture, capture::Direction::ToServer)),
+            };
+            let inspected = match &sni {
+                Some(policy) => sni::inspect(&upload, policy, &mut inbound_in, early),
+                None => Ok((early, true)),
+            };
+            match inspected {
+                Ok((_, false)) => {
+                    upload.denied.store(true, Ordering::SeqCst);
+                    Ok(faults::Relayed::Reset)
+                },
+                Ok((first, true)) => writer.write_all(&first)
+                    .and_then(|_| faults::relay(&mut inbound_in, &mut writer, &upload.settings.faults, buffer)),
+                Err(error) => Err(error),
+            }
+        };
+        match relayed {
+            Ok(faults::Relayed::Reset) => {
+                upload.aborted.store(true, Ordering::SeqCst);
+                inbound_in.reset();
+                outbound_out.reset();
+            },
+            _ if upload.aborted.load(Ordering::SeqCst) => {},
+            _ => {
+                inbound_in.shutdown(Shutdown::Read).unwrap_or(());
+                outbound_out.shutdown(Shutdown::Write).unwrap_or(());
+            }
         }
+    });
 
-    }
+    Ok(download_thread)
 }
 
+/// Writer adding everything written through it to a shared byte count
+struct Counted<'a, W> {
+    inner: W,
+    bytes: &'a AtomicU64,
+    /// The session's place in the scheduler, to pace writes with
+    pacing: Option<&'a priority::Active<'a>>,
+    /// Where to mirror what is written when the session is captured, and which way it goes
+    capture: Option<(&'a capture::Slot, capture::Direction)>
+}
 
-/// Convert an AddrType and address to String
-fn pretty_print_addr(addr_type: &AddrType, addr: &[u8]) -> String {
-    match addr_type {
-        AddrType::Domain => {
-            String::from_utf8_lossy(addr).to_string()
-        },
-        AddrType::V4 => {
-            addr.iter().map(std::string::ToString::to_string).collect::<Vec<String>>().join(".")
-        },
-        AddrType::V6 => {
-            let addr_16 = (0..8).map(|x| {
-                (u16::from(addr[(x * 2)]) << 8) | u16::from(addr[(x * 2) + 1])
-            }).collect::<Vec<u16>>();
-
-            addr_16.iter().map(|x| format!("{:x}", x)).collect::<Vec<String>>().join(":")
+impl<'a, W: Write> Write for Counted<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Checked before writing, so a capture started once the peer has the bytes doesn't record them
+        let capture = self.capture.filter(|(slot, _)| slot.lock().unwrap().is_some());
+        let n = self.inner.write(buf)?;
+        self.bytes.fetch_add(n as u64, Ordering::Relaxed);
+        if let Some(active) = self.pacing {
+            active.pace(n);
         }
+        if let Some((slot, direction)) = capture {
+            let mut capture = slot.lock().unwrap();
+            if let Some(Err(error)) = capture.as_mut().map(|capture| capture.record(direction, &buf[..n])) {
+                warn!("Stopping a capture, failed to write it: {}", error);
+                *capture = None;
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
     }
 }
 
 /// Proxy User Request
-struct SOCKSReq {
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SOCKSReq {
     pub version: u8,
     pub command: SockCommand,
-    pub addr_type: AddrType,
-    pub addr: Vec<u8>,
+    pub destination: rules::Destination,
     pub port: u16
 }
 
+/// Proxy reply to a request
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SOCKSReply {
+    pub version: u8,
+    pub reply: ResponseCode,
+    /// BND.ADDR and BND.PORT
+    pub bind: SocketAddr
+}
+
+impl SOCKSReply {
+    /// A reply with `reply` and `bind` as BND.ADDR and BND.PORT
+    #[cfg(feature = "socks5")]
+    fn new(reply: ResponseCode, bind: SocketAddr) -> Self {
+        SOCKSReply { version: SOCKS_VERSION, reply, bind }
+    }
+
+    /// Encode the reply as sent on the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut packet = vec![self.version, self.reply as u8, RESERVED];
+        match self.bind.ip() {
+            IpAddr::V4(ip) => {
+                packet.push(AddrType::V4 as u8);
+                packet.extend_from_slice(&ip.octets());
+            },
+            IpAddr::V6(ip) => {
+                packet.push(AddrType::V6 as u8);
+                packet.extend_from_slice(&ip.octets());
+            }
+        }
+        packet.extend_from_slice(&self.bind.port().to_be_bytes());
+        packet
+    }
+}
+
+#[cfg(feature = "socks5")]
 impl SOCKSReq {
-    /// Parse a SOCKS Req from a TcpStream
-    fn from_stream(stream: &mut TcpStream) -> Result<Self, Box<dyn Error>> {
+    /// Parse a SOCKS Req from a client connection
+    fn from_stream<S: Connection>(stream: &mut S, session: u64, settings: &Settings, fingerprint: &mut access_log::Fingerprint) -> Result<Self, MerinoError> {
+        let compliance = settings.compliance;
+        let peer = stream.peer_ip()?;
         let mut packet = [0u8; 4];
         // Read a byte from the stream and determine the version being requested
         stream.read_exact(&mut packet)?;
+        fingerprint.command = Some(packet[1]);
+        fingerprint.address = Some(packet[3]);
 
         if packet[0] != SOCKS_VERSION {
-            warn!("from_stream Unsupported version: SOCKS{}", packet[0]);
-            stream.shutdown(Shutdown::Both)?;
-
+            compliance.deviation(session, peer, format_args!("request version SOCKS{}", packet[0]))?;
+        }
+        if packet[2] != RESERVED {
+            compliance.deviation(session, peer, format_args!("reserved byte {:#04x}", packet[2]))?;
         }
 
         // Get command
@@ -492,9 +1988,9 @@ impl SOCKSReq {
                 Ok(())
             },
             None => {
-                warn!("Invalid Command");
+                warn!("Invalid Command (session {})", session);
                 stream.shutdown(Shutdown::Both)?;
-                Err(ResponseCode::CommandNotSupported)
+                Err(MerinoError::protocol("Command not supported"))
             }
         }?;
 
@@ -507,37 +2003,50 @@ impl SOCKSReq {
                 Ok(())
             },
             None => {
-                error!("No Addr");
+                error!("No Addr (session {})", session);
                 stream.shutdown(Shutdown::Both)?;
-                Err(ResponseCode::AddrTypeNotSupported)
+                Err(MerinoError::protocol("Addr Type not supported"))
             }
         }?;
 
-        trace!("Getting Addr");
+        trace!("Getting Addr (session {})", session);
         // Get Addr from addr_type and stream
-        let addr: Result<Vec<u8>, Box<dyn Error>> = match addr_type {
+        let destination: Result<rules::Destination, MerinoError> = match addr_type {
             AddrType::Domain => {
                 let mut dlen = [0u8; 1];
                 stream.read_exact(&mut dlen)?;
+                if dlen[0] as usize > settings.limits.max_domain {
+                    warn!("Domain name of {} bytes exceeds the limit of {} (session {})", dlen[0], settings.limits.max_domain, session);
+                    stream.write_all(&SOCKSReply::new(ResponseCode::Failure, UNSPECIFIED).to_bytes())?;
+                    stream.shutdown(Shutdown::Both)?;
+                    return Err(MerinoError::protocol("Domain name too long"));
+                }
 
                 let mut domain = vec![0u8; dlen[0] as usize];
                 stream.read_exact(&mut domain)?;
-
-                Ok(domain)
+                if domain.is_empty() {
+                    compliance.deviation(session, peer, format_args!("empty domain name"))?;
+                }
+                // A DNS name is at most 253 characters
+                if domain.len() > 253 {
+                    compliance.deviation(session, peer, format_args!("domain name of {} bytes", domain.len()))?;
+                }
+
+                Ok(rules::Destination::parse(&String::from_utf8_lossy(&domain)))
             },
             AddrType::V4 => {
                 let mut addr = [0u8; 4];
                 stream.read_exact(&mut addr)?;
-                Ok(addr.to_vec())
+                Ok(rules::Destination::Ip(IpAddr::from(addr)))
             },
             AddrType::V6 => {
                 let mut addr = [0u8; 16];
                 stream.read_exact(&mut addr)?;
-                Ok(addr.to_vec())
+                Ok(rules::Destination::ip(IpAddr::from(addr)))
             }
         };
 
-        let addr = addr?;
+        let destination = destination?;
 
         // read DST.port
         let mut port = [0u8; 2];
@@ -550,8 +2059,7 @@ impl SOCKSReq {
         Ok(SOCKSReq {
             version: packet[0],
             command,
-            addr_type,
-            addr,
+            destination,
             port
         })
     }
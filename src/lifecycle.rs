@@ -0,0 +1,158 @@
+//! Readiness and graceful shutdown, for running under an orchestrator such as Kubernetes
+//!
+//! The readiness endpoint given with [`crate::Merino::with_readiness`]
+//! answers `200 OK` once merino is serving and `503 Service Unavailable` once
+//! it is draining, so load balancers stop sending clients before it exits.
+//! While draining, new clients are turned away and relaying sessions get
+//! until the grace period ends to finish.
+//!
+//! Maintenance, entered and left over the admin API (see [`crate::admin`]),
+//! drains by hand: relaying sessions carry on for as long as they like, while
+//! new clients are refused at their greeting or passed on untouched to a
+//! standby proxy, see [`Maintenance`]. Readiness reports `503` meanwhile.
+//!
+//! After an upgrade hands the listeners over to a new process (see
+//! `crate::handover`), the old one stops accepting the same way, except the
+//! readiness endpoint keeps answering for both processes.
+use std::fmt;
+use std::io::{self, prelude::*};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::metrics::Metrics;
+
+/// How often draining checks whether sessions are still relaying
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+/// What happens to new clients during maintenance
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Maintenance {
+    /// Answer their greeting with no acceptable auth method
+    Refuse,
+    /// Relay their connection as is to another proxy, which does the handshake
+    Standby(SocketAddr),
+}
+
+impl fmt::Display for Maintenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Maintenance::Refuse => write!(f, "refusing new clients"),
+            Maintenance::Standby(addr) => write!(f, "passing new clients to {}", addr),
+        }
+    }
+}
+
+/// Whether the proxy is serving, and whether it is on its way out
+#[derive(Debug)]
+pub struct Lifecycle {
+    serving: AtomicBool,
+    draining: AtomicBool,
+    /// Another process accepts on the listeners now
+    handed_over: AtomicBool,
+    maintenance: Mutex<Option<Maintenance>>,
+    /// Counts the sessions still relaying
+    metrics: Arc<Metrics>,
+}
+
+impl Lifecycle {
+    pub(crate) fn new(metrics: Arc<Metrics>) -> Self {
+        Lifecycle {
+            serving: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+            handed_over: AtomicBool::new(false),
+            maintenance: Mutex::new(None),
+            metrics,
+        }
+    }
+
+    pub(crate) fn started(&self) {
+        self.serving.store(true, Ordering::Relaxed);
+    }
+
+    /// Serving, and neither draining nor in maintenance
+    pub fn is_ready(&self) -> bool {
+        self.serving.load(Ordering::Relaxed) && !self.is_draining() && self.maintenance().is_none()
+    }
+
+    /// Treat new clients as `maintenance` says until [`Lifecycle::end_maintenance`]
+    pub fn start_maintenance(&self, maintenance: Maintenance) {
+        *self.maintenance.lock().unwrap() = Some(maintenance);
+    }
+
+    /// Take new clients again, returning whether maintenance was on
+    pub fn end_maintenance(&self) -> bool {
+        self.maintenance.lock().unwrap().take().is_some()
+    }
+
+    pub fn maintenance(&self) -> Option<Maintenance> {
+        *self.maintenance.lock().unwrap()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Stop taking clients and wait up to `grace` for relaying sessions to end, returning whether they all did
+    pub fn drain(&self, grace: Duration) -> bool {
+        self.draining.store(true, Ordering::Relaxed);
+        self.wait_idle(grace)
+    }
+
+    /// Leave the listeners to the process they were handed over to
+    pub(crate) fn hand_over(&self) {
+        self.handed_over.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_handed_over(&self) -> bool {
+        self.handed_over.load(Ordering::Relaxed)
+    }
+
+    /// Wait up to `grace` for relaying sessions to end, returning whether they all did
+    pub(crate) fn wait_idle(&self, grace: Duration) -> bool {
+        let start = Instant::now();
+        loop {
+            if self.metrics.active.load(Ordering::Relaxed) == 0 {
+                return true;
+            }
+            if start.elapsed() >= grace {
+                return false;
+            }
+            thread::sleep(DRAIN_POLL.min(grace - start.elapsed()));
+        }
+    }
+}
+
+/// Answer every HTTP request on `listener` with whether the proxy is ready
+pub(crate) fn serve(listener: TcpListener, lifecycle: Arc<Lifecycle>) {
+    loop {
+        let stream = crate::accept::accept(&listener);
+        let lifecycle = lifecycle.clone();
+        thread::spawn(move || {
+            if let Err(error) = respond(stream, &lifecycle) {
+                debug!("Failed to serve readiness: {}", error);
+            }
+        });
+    }
+}
+
+fn respond(mut stream: TcpStream, lifecycle: &Lifecycle) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // The request itself doesn't matter, only that it arrived
+    let mut request = [0u8; 1024];
+    let _ = stream.read(&mut request)?;
+
+    let (status, body) = if lifecycle.is_ready() {
+        ("200 OK", "ready\n")
+    } else if lifecycle.is_draining() {
+        ("503 Service Unavailable", "draining\n")
+    } else if lifecycle.maintenance().is_some() {
+        ("503 Service Unavailable", "maintenance\n")
+    } else {
+        ("503 Service Unavailable", "starting\n")
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+    stream.shutdown(Shutdown::Write)
+}
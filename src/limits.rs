@@ -0,0 +1,26 @@
+//! Bounds on the variable-length fields of a handshake
+//!
+//! Every field is length-prefixed by a single byte, so the defaults allow
+//! the full 255 bytes. Lowering them caps what a pending connection can
+//! make the proxy read and hold before it is authenticated.
+
+/// Largest handshake fields accepted
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    /// Longest username accepted for username/password auth
+    pub max_username: usize,
+    /// Longest domain name accepted as a destination
+    pub max_domain: usize,
+    /// Most auth methods a client may offer
+    pub max_methods: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_username: 255,
+            max_domain: 255,
+            max_methods: 255,
+        }
+    }
+}
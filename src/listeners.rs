@@ -0,0 +1,228 @@
+//! Listeners opened and closed at runtime through the admin API
+//!
+//! Lets a multi-tenant host provision a proxy endpoint without a restart:
+//!
+//! ```text
+//! POST /listeners/<addr>?auth=password             SOCKS5, only users with a password
+//! POST /listeners/<addr>?versions=socks5,socks6
+//! POST /listeners/<addr>?protocol=forward&to=HOST:PORT
+//! POST /listeners/<addr>?fast_open=true            accept TCP Fast Open
+//! DELETE /listeners/<addr>
+//! ```
+//!
+//! SOCKS5 listeners take `auth=none`, `auth=password` or both comma
+//! separated, and offer the proxy's own methods otherwise. Like the main
+//! listener, they serve only what they're granted: CONNECT over SOCKS5
+//! unless `versions` adds `socks6`, so opening a listener never exposes
+//! more than asked for. Everything else, the users, rules, bans and
+//! limits, is shared with the proxy. Closing a
+//! listener stops it accepting, the sessions it accepted carry on.
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::{AuthMethods, Forward, Settings, SockCommand, SOCKClient};
+
+/// Commands listeners serve unless granted more
+pub const DEFAULT_COMMANDS: [u8; 1] = [SockCommand::Connect as u8];
+
+/// SOCKS versions listeners serve unless granted more
+pub const DEFAULT_VERSIONS: [u8; 1] = [crate::SOCKS_VERSION];
+
+/// Parse comma separated command names, only `connect` for now, into command bytes
+pub fn parse_commands(names: &str) -> Result<Vec<u8>, String> {
+    names.split(',').map(|name| match name.trim() {
+        "connect" => Ok(SockCommand::Connect as u8),
+        "bind" | "udp-associate" => Err(format!("`{}` isn't served by merino", name.trim())),
+        other => Err(format!("Unknown command `{}`, expected connect", other)),
+    }).collect()
+}
+
+/// Parse comma separated protocol versions, `socks5` and `socks6`, into version bytes
+pub fn parse_versions(names: &str) -> Result<Vec<u8>, String> {
+    names.split(',').map(|name| match name.trim() {
+        "socks5" => Ok(5),
+        "socks6" => Ok(6),
+        other => Err(format!("Unknown version `{}`, expected socks5 or socks6", other)),
+    }).collect()
+}
+
+/// Names of `commands`, as [`parse_commands`] takes them
+pub fn command_names(commands: &[u8]) -> String {
+    let names: Vec<&str> = commands.iter().map(|command| match *command {
+        c if c == SockCommand::Connect as u8 => "connect",
+        _ => "?",
+    }).collect();
+    names.join(",")
+}
+
+/// Names of `versions`, as [`parse_versions`] takes them
+pub fn version_names(versions: &[u8]) -> String {
+    let names: Vec<String> = versions.iter().map(|version| format!("socks{}", version)).collect();
+    names.join(",")
+}
+
+/// What a listener serves
+#[derive(Clone, Debug, PartialEq)]
+pub enum Protocol {
+    Socks5,
+    /// Forward every connection to a fixed destination
+    Forward(Forward),
+}
+
+/// A listener to open
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListenerSpec {
+    pub addr: SocketAddr,
+    pub protocol: Protocol,
+    /// Auth methods offered to SOCKS clients, the proxy's own when `None`
+    pub auth: Option<Vec<u8>>,
+    /// Commands SOCKS clients may send
+    pub commands: Vec<u8>,
+    /// SOCKS versions served
+    pub versions: Vec<u8>,
+    /// Accept TCP Fast Open, see [`crate::connect::listen_fast_open`]
+    pub fast_open: bool,
+}
+
+impl ListenerSpec {
+    /// Parse the admin API's `<addr>` and query, e.g. `127.0.0.1:1081` and `auth=none`
+    pub fn parse(addr: &str, query: &str) -> Result<Self, String> {
+        let addr = addr.parse().map_err(|e| format!("Invalid address `{}`: {}", addr, e))?;
+        let param = |name: &str| query.split('&').find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='));
+
+        let protocol = match param("protocol").unwrap_or("socks5") {
+            "socks5" => Protocol::Socks5,
+            "forward" => {
+                let to = param("to").ok_or("Forward listeners need `to=HOST:PORT`")?;
+                Protocol::Forward(format!("{}={}", addr, to).parse()?)
+            },
+            other => return Err(format!("Unknown protocol `{}`, expected socks5 or forward", other)),
+        };
+        let auth = match param("auth") {
+            None => None,
+            Some(_) if protocol != Protocol::Socks5 => return Err("Only SOCKS5 listeners authenticate".to_string()),
+            Some(methods) => Some(methods.split(',').map(|method| match method {
+                "none" => Ok(AuthMethods::NoAuth as u8),
+                "password" => Ok(AuthMethods::UserPass as u8),
+                other => Err(format!("Unknown auth method `{}`, expected none or password", other)),
+            }).collect::<Result<Vec<u8>, String>>()?),
+        };
+        let socks = |name: &str, value: Option<&str>, default: &[u8], parse: fn(&str) -> Result<Vec<u8>, String>| match value {
+            None => Ok(default.to_vec()),
+            Some(_) if protocol != Protocol::Socks5 => Err(format!("Only SOCKS5 listeners take `{}`", name)),
+            Some(value) => parse(value),
+        };
+        let commands = socks("commands", param("commands"), &DEFAULT_COMMANDS, parse_commands)?;
+        let versions = socks("versions", param("versions"), &DEFAULT_VERSIONS, parse_versions)?;
+        let fast_open = match param("fast_open").unwrap_or("false") {
+            "true" => true,
+            "false" => false,
+            other => return Err(format!("Invalid fast_open `{}`, expected true or false", other)),
+        };
+        Ok(ListenerSpec { addr, protocol, auth, commands, versions, fast_open })
+    }
+}
+
+impl fmt::Display for ListenerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.protocol {
+            Protocol::Socks5 => {
+                write!(f, "{}\tsocks5", self.addr)?;
+                match &self.auth {
+                    None => write!(f, "\tauth=default")?,
+                    Some(methods) => {
+                        let names: Vec<&str> = methods.iter().map(|method| match *method {
+                            m if m == AuthMethods::NoAuth as u8 => "none",
+                            m if m == AuthMethods::UserPass as u8 => "password",
+                            _ => "?",
+                        }).collect();
+                        write!(f, "\tauth={}", names.join(","))?
+                    },
+                }
+                write!(f, "\tcommands={}\tversions={}", command_names(&self.commands), version_names(&self.versions))?
+            },
+            Protocol::Forward(forward) if forward.host.contains(':') => write!(f, "{}\tforward\tto=[{}]:{}", self.addr, forward.host, forward.port)?,
+            Protocol::Forward(forward) => write!(f, "{}\tforward\tto={}:{}", self.addr, forward.host, forward.port)?,
+        }
+        if self.fast_open {
+            write!(f, "\tfast_open")?;
+        }
+        Ok(())
+    }
+}
+
+/// Stops a runtime listener
+pub(crate) struct Handle {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+}
+
+impl Handle {
+    /// Stop accepting, waking the accepting thread so it lets go of the socket
+    pub(crate) fn close(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let ip = match self.addr.ip() {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        TcpStream::connect_timeout(&SocketAddr::new(ip, self.addr.port()), Duration::from_secs(1)).map(drop).unwrap_or(());
+    }
+}
+
+/// Bind `spec` and serve it with `settings`, returning it with the address bound
+pub(crate) fn open(mut spec: ListenerSpec, settings: &Arc<Settings>) -> io::Result<(ListenerSpec, Handle)> {
+    if settings.require_auth && spec.auth.as_ref().is_some_and(|methods| methods.contains(&(AuthMethods::NoAuth as u8))) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Clients must authenticate, refusing auth=none"));
+    }
+    let listener = TcpListener::bind(spec.addr)?;
+    spec.addr = listener.local_addr()?;
+    if spec.fast_open {
+        crate::connect::listen_fast_open(&listener)?;
+    }
+    if let Protocol::Forward(forward) = &mut spec.protocol {
+        forward.listen = spec.addr;
+    }
+    let settings = Arc::new(Settings {
+        auth_methods: spec.auth.clone().unwrap_or_else(|| settings.auth_methods.clone()),
+        commands: spec.commands.clone(),
+        versions: spec.versions.clone(),
+        ..(**settings).clone()
+    });
+    let stop = Arc::new(AtomicBool::new(false));
+    let handle = Handle { addr: spec.addr, stop: stop.clone() };
+    let protocol = spec.protocol.clone();
+    thread::spawn(move || serve(listener, protocol, settings, stop));
+    Ok((spec, handle))
+}
+
+fn serve(listener: TcpListener, protocol: Protocol, settings: Arc<Settings>, stop: Arc<AtomicBool>) {
+    let forward = match protocol {
+        Protocol::Forward(forward) => Some(Arc::new(forward)),
+        Protocol::Socks5 => None,
+    };
+    loop {
+        let stream = crate::accept::accept(&listener);
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        if settings.lifecycle.is_draining() {
+            continue;
+        }
+        match &forward {
+            Some(forward) => crate::forward::spawn(stream, forward.clone(), settings.clone()),
+            None => if let Some(handshaking) = settings.admit() {
+                let mut client = SOCKClient::new(stream, settings.clone());
+                thread::spawn(move || {
+                    let _handshaking = handshaking;
+                    client.run().unwrap_or(())
+                });
+            },
+        }
+    }
+}
@@ -2,11 +2,26 @@
 #[macro_use] extern crate log;
 
 use structopt::StructOpt;
+use chrono::{DateTime, Utc};
 use merino::*;
 use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 use std::env;
 
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOCATOR: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+// jemalloc wins when both are enabled, e.g. by --all-features
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static ALLOCATOR: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
 /// Logo to be printed at when merino is run 
 const LOGO: &str = r"
                       _
@@ -18,6 +33,27 @@ const LOGO: &str = r"
  A SOCKS5 Proxy server written in Rust
 ";
 
+/// How log lines are written
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LogFormat {
+    /// Colored text on stderr
+    Text,
+    /// A JSON object per line on stdout, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format `{}`, expected text or json", s)),
+        }
+    }
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "merino")]
 /// A SOCKS5 Proxy written in Rust
@@ -30,20 +66,1003 @@ struct Opt {
     /// Set ip to listen on
     ip: String,
 
+    #[structopt(long = "inetd")]
+    /// Serve a single client over stdin/stdout, e.g. from inetd or an SSH ProxyCommand
+    inetd: bool,
+
+    #[structopt(long = "config", parse(from_os_str))]
+    /// File of long options, one `name = value` per line (e.g. a mounted ConfigMap), also read from $MERINO_CONFIG
+    config: Option<PathBuf>,
+
+    #[structopt(long = "sidecar")]
+    /// Run as a container sidecar: log JSON to stdout and drain sessions on SIGTERM before exiting
+    sidecar: bool,
+
+    #[structopt(long = "shutdown-grace", default_value = "25")]
+    /// Seconds relaying sessions get to finish after SIGTERM in sidecar mode or an upgrade, keep it under the pod's grace period
+    shutdown_grace: u64,
+
+    #[structopt(long = "upgrade-socket", parse(from_os_str))]
+    /// Take `merino upgrade` commands on this Unix socket, handing the listeners over to a new process (Linux)
+    upgrade_socket: Option<PathBuf>,
+
+    #[structopt(long = "ready")]
+    /// Answer readiness probes over HTTP on this address (e.g. 0.0.0.0:9102), failing them while draining
+    ready: Option<SocketAddr>,
+
+    #[structopt(long = "log-format")]
+    /// Log as text on stderr or json on stdout (json in sidecar mode, text otherwise)
+    log_format: Option<LogFormat>,
+
     #[structopt(long = "no-auth")]
     /// Allow unauthenticated connections
     no_auth: bool,
 
+    #[structopt(long = "require-auth", conflicts_with = "no_auth")]
+    /// Refuse to start unless clients must authenticate with a username and password
+    require_auth: bool,
+
+    #[structopt(long = "username-tags")]
+    /// Split tags off usernames, e.g. `alice+fast`, for rules with a `tag` column to match
+    username_tags: bool,
+
+    #[structopt(long = "commands", default_value = "connect")]
+    /// Commands clients may send, comma separated: connect
+    commands: String,
+
+    #[structopt(long = "versions", default_value = "socks5")]
+    /// SOCKS versions served, comma separated: socks5, socks6
+    versions: String,
+
     #[structopt(short = "u", long = "users", parse(from_os_str))]
     /// CSV File with username/password pairs
     users: Option<PathBuf>,
 
+    #[structopt(short = "r", long = "rules", parse(from_os_str))]
+    /// CSV File with access control rules
+    rules: Option<PathBuf>,
+
+    #[structopt(long = "profile")]
+    /// Apply the rules for this profile (e.g. staging) along with those without a profile
+    profile: Option<String>,
+
+    #[structopt(long = "require-hostnames")]
+    /// Refuse literal IP requests while a rule naming destinations by domain would apply, so clients can't resolve around it
+    require_hostnames: bool,
+
+    #[structopt(long = "sni")]
+    /// Read the server name from relayed TLS ClientHellos, logging it and checking it against the rules
+    sni: bool,
+
+    #[structopt(long = "sinkhole")]
+    /// Answer requests the rules deny with success and hold them on a silent connection, logging what the client sends
+    sinkhole: bool,
+
+    #[structopt(long = "groups", parse(from_os_str))]
+    /// CSV File with group,user memberships, matched by `@group` in the rules
+    groups: Option<PathBuf>,
+
+    #[cfg(feature = "sqlite")]
+    #[structopt(long = "db", parse(from_os_str), conflicts_with = "users")]
+    /// SQLite database with users, quotas and usage
+    db: Option<PathBuf>,
+
+    #[cfg(feature = "postgres")]
+    #[structopt(long = "postgres", conflicts_with = "users")]
+    /// PostgreSQL connection string (e.g. "host=db user=merino") to authenticate users against, or a secret reference (e.g. env:MERINO_DSN)
+    postgres: Option<String>,
+
+    #[cfg(feature = "token")]
+    #[structopt(long = "token-jwks", conflicts_with = "users")]
+    /// JWK set (e.g. https://idp/.well-known/jwks.json) whose keys must have signed the JWTs sent as passwords
+    token_jwks: Option<String>,
+
+    #[cfg(feature = "token")]
+    #[structopt(long = "token-issuer", requires = "token_jwks")]
+    /// Issuer (iss) the JWTs must name
+    token_issuer: Option<String>,
+
+    #[cfg(feature = "token")]
+    #[structopt(long = "token-audience", requires = "token_jwks")]
+    /// Audience (aud) the JWTs must be meant for
+    token_audience: Option<String>,
+
+    #[cfg(feature = "token")]
+    #[structopt(long = "token-introspection", conflicts_with = "users")]
+    /// OAuth 2.0 introspection endpoint (e.g. https://idp/oauth2/introspect) checking bearer tokens sent as passwords
+    token_introspection: Option<String>,
+
+    #[cfg(feature = "token")]
+    #[structopt(long = "token-introspection-secret", requires = "token_introspection")]
+    /// Bearer token merino authenticates to the introspection endpoint with, or a secret reference (e.g. env:MERINO_IDP_TOKEN)
+    token_introspection_secret: Option<String>,
+
+    #[cfg(feature = "wasm")]
+    #[structopt(long = "plugin", parse(from_os_str), number_of_values = 1)]
+    /// WebAssembly plugin consulted for every request (may be repeated)
+    plugins: Vec<PathBuf>,
+
+    #[cfg(feature = "rhai")]
+    #[structopt(long = "script", parse(from_os_str))]
+    /// Rhai script deciding whether requests are allowed
+    script: Option<PathBuf>,
+
+    #[cfg(feature = "ext-authz")]
+    #[structopt(long = "ext-authz")]
+    /// gRPC ext_authz server (e.g. http://authz:9000) authorizing every request
+    ext_authz: Option<String>,
+
+    #[cfg(feature = "ext-authz")]
+    #[structopt(long = "ext-authz-timeout", default_value = "500")]
+    /// Milliseconds to wait for the ext_authz server
+    ext_authz_timeout: u64,
+
+    #[cfg(feature = "ext-authz")]
+    #[structopt(long = "ext-authz-fail-open")]
+    /// Allow requests when the ext_authz server fails or times out (denied by default)
+    ext_authz_fail_open: bool,
+
+    #[structopt(long = "tcp-fast-open")]
+    /// Use TCP Fast Open when connecting to destinations (Linux, client-first protocols only)
+    tcp_fast_open: bool,
+
+    #[structopt(long = "mark", parse(try_from_str = "parse_mark"))]
+    /// Set SO_MARK (decimal or 0x hex) on connections to destinations, for policy routing (Linux, needs CAP_NET_ADMIN)
+    mark: Option<u32>,
+
+    #[structopt(long = "mptcp")]
+    /// Connect to destinations with Multipath TCP, falling back to TCP (Linux 5.6+)
+    mptcp: bool,
+
+    #[structopt(long = "mptcp-listen")]
+    /// Accept Multipath TCP clients on the SOCKS listener (Linux 5.6+)
+    mptcp_listen: bool,
+
+    #[structopt(long = "tcp-fast-open-listen")]
+    /// Accept TCP Fast Open on the SOCKS listener (Linux, needs the server bit of net.ipv4.tcp_fastopen)
+    tcp_fast_open_listen: bool,
+
+    #[structopt(long = "tor")]
+    /// Tor SOCKS port (e.g. 127.0.0.1:9050) for rules with `via` set to `tor`, isolating circuits per user or client
+    tor: Option<SocketAddr>,
+
+    #[structopt(long = "bandwidth")]
+    /// Limit relayed traffic to this many KiB per second, shared between the rules' priority classes by weight
+    bandwidth: Option<u64>,
+
+    #[structopt(long = "pool", number_of_values = 1)]
+    /// Keep connections to this host:port open ahead of requests for it (may be repeated)
+    pool: Vec<String>,
+
+    #[structopt(long = "pool-size", default_value = "4")]
+    /// Connections kept open per pooled destination
+    pool_size: usize,
+
+    #[structopt(long = "pool-idle", default_value = "30")]
+    /// Seconds a pooled connection may wait before it is closed
+    pool_idle: u64,
+
+    #[structopt(long = "reply-address", default_value = "local")]
+    /// BND.ADDR reported once connected: local (the address connected from), unspecified (0.0.0.0) or a fixed IP
+    reply_address: ReplyAddress,
+
+    #[structopt(long = "compliance", default_value = "lenient")]
+    /// Refuse (strict) or tolerate and log (lenient) clients deviating from the SOCKS RFCs
+    compliance: Compliance,
+
+    #[structopt(long = "max-username-len", default_value = "255")]
+    /// Longest username accepted in a handshake
+    max_username_len: usize,
+
+    #[structopt(long = "max-domain-len", default_value = "255")]
+    /// Longest destination domain name accepted in a request
+    max_domain_len: usize,
+
+    #[structopt(long = "max-auth-methods", default_value = "255")]
+    /// Most auth methods a client may offer
+    max_auth_methods: usize,
+
+    #[structopt(long = "relay-buffer")]
+    /// KiB relayed at once (16-512), sized from the socket buffers or the cgroup's memory limit by default
+    relay_buffer: Option<usize>,
+
+    #[structopt(long = "access-log", parse(from_os_str))]
+    /// Append a line per connection to this file, in the Apache combined log format
+    access_log: Option<PathBuf>,
+
+    #[structopt(long = "access-log-sample", default_value = "1")]
+    /// Log only 1 in N relayed sessions; failures and denials are always logged
+    access_log_sample: u64,
+
+    #[structopt(long = "metrics")]
+    /// Serve Prometheus metrics over HTTP on this address (e.g. 127.0.0.1:9100)
+    metrics: Option<SocketAddr>,
+
+    #[structopt(long = "seccomp")]
+    /// Once set up, restrict merino to the system calls relaying needs (Linux x86_64 and aarch64)
+    seccomp: bool,
+
+    #[structopt(long = "landlock")]
+    /// Only allow opening the configured files and log directories, where the kernel supports Landlock
+    landlock: bool,
+
+    #[structopt(long = "admin")]
+    /// Serve the admin API (list and kill sessions, ban clients) on this address, e.g. 127.0.0.1:9101
+    admin: Option<SocketAddr>,
+
+    #[structopt(long = "ban-list", parse(from_os_str))]
+    /// CSV file bans are saved to, so they survive restarts
+    ban_list: Option<PathBuf>,
+
+    #[structopt(long = "capture-dir", parse(from_os_str))]
+    /// Directory sessions are captured to as pcap files, when asked over the admin API
+    capture_dir: Option<PathBuf>,
+
+    #[structopt(long = "statsd")]
+    /// Push metrics to a statsd/DogStatsD agent over UDP (e.g. 127.0.0.1:8125)
+    statsd: Option<String>,
+
+    #[structopt(long = "statsd-prefix", default_value = "merino")]
+    /// Prefix of the metric names sent to statsd
+    statsd_prefix: String,
+
+    #[structopt(long = "statsd-tag", number_of_values = 1)]
+    /// DogStatsD tag (e.g. env:prod) added to every metric (may be repeated)
+    statsd_tags: Vec<String>,
+
+    #[structopt(long = "hosts", parse(from_os_str))]
+    /// Hosts-file style mapping of hostnames to the addresses connected to instead
+    hosts: Option<PathBuf>,
+
+    #[structopt(long = "asn-db", parse(from_os_str))]
+    /// MaxMind ASN database (e.g. GeoLite2-ASN.mmdb), letting rules match destinations like AS15169
+    asn_db: Option<PathBuf>,
+
+    #[structopt(long = "blocklist", number_of_values = 1)]
+    /// Domains and IPs to deny, one per line, from a file or an https:// or http:// URL (may be repeated)
+    blocklists: Vec<String>,
+
+    #[structopt(long = "blocklist-refresh", default_value = "3600")]
+    /// Seconds between checks of the blocklists for updates
+    blocklist_refresh: u64,
+
+    #[structopt(long = "forward", number_of_values = 1)]
+    /// Forward a local port to a fixed destination, as LISTEN=HOST:PORT (e.g. 127.0.0.1:5433=db.internal:5432)
+    forwards: Vec<Forward>,
+
+    #[structopt(long = "transport")]
+    /// Scramble connections to the proxy listener, as comma-separated layers: xor:KEY, pad:MAX, zstd:LEVEL (e.g. pad:255,xor:s3cret)
+    transport: Option<transport::Stack>,
+
+    #[structopt(long = "forward-transport")]
+    /// Scramble connections forwards make to their destinations, e.g. a remote merino started with the same --transport
+    forward_transport: Option<transport::Stack>,
+
+    #[structopt(long = "max-sessions")]
+    /// Maximum concurrent sessions per user
+    max_sessions: Option<u64>,
+
+    #[structopt(long = "max-lifetime")]
+    /// Seconds a session may relay before it is shut down (e.g. 43200 for 12 hours)
+    max_lifetime: Option<u64>,
+
+    #[structopt(long = "auth-delay")]
+    /// Milliseconds to hold back the answer to a failed login or malformed handshake, doubling with each further failure from the same source or subnet
+    auth_delay: Option<u64>,
+
+    #[structopt(long = "subnet-allowance", requires = "auth_delay")]
+    /// Failed handshakes from a subnet before its further ones are held back as a whole (default 8)
+    subnet_allowance: Option<u32>,
+
+    #[structopt(long = "subnet-prefixes", requires = "auth_delay", parse(try_from_str = "parse_prefixes"))]
+    /// Prefix lengths sources are grouped into subnets by, IPv4 then IPv6 (default 24,64)
+    subnet_prefixes: Option<(u8, u8)>,
+
+    #[structopt(long = "breaker-threshold")]
+    /// Connect failures in a row after which requests to a destination fail straight away for a while
+    breaker_threshold: Option<u32>,
+
+    #[structopt(long = "breaker-cooldown", default_value = "30")]
+    /// Seconds requests to a destination fail straight away for, before one is let through to try it again
+    breaker_cooldown: u64,
+
+    #[structopt(long = "dns-negative-ttl", default_value = "5")]
+    /// Seconds a hostname that failed to resolve fails straight away for, 0 to ask the resolver every time
+    dns_negative_ttl: u64,
+
+    #[structopt(long = "max-connections")]
+    /// Maximum connections open at once, derived from the cgroup's memory limit by default, 0 for no limit
+    max_connections: Option<u64>,
+
+    #[structopt(long = "soft-fd-limit")]
+    /// Close new connections while this many file descriptors are open
+    soft_fd_limit: Option<u64>,
+
+    #[structopt(long = "soft-memory-limit")]
+    /// Close new connections while this many MiB are resident
+    soft_memory_limit: Option<u64>,
+
+    #[cfg(feature = "redis")]
+    #[structopt(long = "redis")]
+    /// Redis URL (e.g. redis://127.0.0.1/) to share session counts, bans and failed handshakes with other instances, or a secret reference
+    redis: Option<String>,
+
+    #[cfg(feature = "shadowsocks")]
+    #[structopt(long = "shadowsocks")]
+    /// Also serve Shadowsocks (chacha20-ietf-poly1305) clients on this address (e.g. 0.0.0.0:8388)
+    shadowsocks: Option<SocketAddr>,
+
+    #[cfg(feature = "shadowsocks")]
+    #[structopt(long = "shadowsocks-password")]
+    /// Password Shadowsocks clients connect with, or a secret reference
+    shadowsocks_password: Option<String>,
+
+    #[structopt(long = "dry-run")]
+    /// Load and check everything, print the effective configuration with secrets redacted, and exit
+    dry_run: bool,
+
+    #[structopt(long = "fault-latency", default_value = "0")]
+    /// Debug: delay in milliseconds added before relaying each chunk
+    fault_latency: u64,
+
+    #[structopt(long = "fault-jitter", default_value = "0")]
+    /// Debug: random extra delay in milliseconds, up to this much, per relayed chunk
+    fault_jitter: u64,
+
+    #[structopt(long = "fault-reset", default_value = "0")]
+    /// Debug: probability (0-1) that a relayed chunk resets the session
+    fault_reset: f64,
+
+    #[structopt(long = "fault-throttle")]
+    /// Debug: limit relayed traffic to this many bytes per second in each direction
+    fault_throttle: Option<u64>,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    println!("{}", LOGO);
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "test-policy")]
+    /// Evaluate the access rules against a request and print the decision
+    TestPolicy {
+        #[structopt(long = "from")]
+        /// Source IP of the client
+        from: IpAddr,
+
+        #[structopt(long = "to")]
+        /// Requested destination as host:port
+        to: String,
+
+        #[structopt(long = "user")]
+        /// Username the client authenticated as
+        user: Option<String>,
+
+        #[structopt(long = "at", parse(try_from_str = "parse_time"))]
+        /// Evaluate at this RFC 3339 time (e.g. 2024-05-06T10:00:00+02:00) instead of now
+        at: Option<DateTime<Utc>>,
+    },
+
+    #[structopt(name = "bench")]
+    /// Drive concurrent sessions through a proxy and report latency and throughput
+    Bench {
+        #[structopt(long = "proxy", default_value = "127.0.0.1:1080")]
+        /// Address of the proxy under test
+        proxy: SocketAddr,
+
+        #[structopt(long = "target")]
+        /// Echo server (host:port) the proxy connects to, a local one is started if omitted
+        target: Option<String>,
+
+        #[structopt(short = "n", long = "sessions", default_value = "1000")]
+        /// Total number of sessions
+        sessions: usize,
+
+        #[structopt(short = "c", long = "concurrency", default_value = "50")]
+        /// Number of sessions running at once
+        concurrency: usize,
+
+        #[structopt(long = "payload", default_value = "65536")]
+        /// Bytes sent and echoed back per session
+        payload: usize,
+
+        #[structopt(long = "user")]
+        /// Username to authenticate with
+        user: Option<String>,
+
+        #[structopt(long = "password", default_value = "")]
+        /// Password to authenticate with, or a secret reference (e.g. env:MERINO_PASSWORD)
+        password: String,
+    },
+
+    #[structopt(name = "speedtest")]
+    /// Measure latency and throughput through a merino, against the echo and discard destinations it serves itself
+    Speedtest {
+        #[structopt(long = "via", default_value = "127.0.0.1:1080")]
+        /// Address (host:port) of the merino to measure
+        via: String,
+
+        #[structopt(long = "mib", default_value = "16")]
+        /// MiB sent for each throughput measurement
+        mib: usize,
+
+        #[structopt(long = "pings", default_value = "10")]
+        /// Round trips timed for the latency
+        pings: usize,
+
+        #[structopt(long = "timeout", default_value = "10")]
+        /// Seconds connecting, and any read or write, may take
+        timeout: u64,
+
+        #[structopt(long = "user")]
+        /// Username to authenticate with
+        user: Option<String>,
+
+        #[structopt(long = "password", default_value = "")]
+        /// Password to authenticate with, or a secret reference (e.g. env:MERINO_PASSWORD)
+        password: String,
+    },
+
+    #[structopt(name = "upgrade")]
+    /// Replace a running merino with the current binary, without refusing a connection
+    Upgrade {
+        #[structopt(long = "socket", parse(from_os_str))]
+        /// Upgrade socket the running merino was started with
+        socket: PathBuf,
+    },
+
+    #[structopt(name = "doctor")]
+    /// Check that proxying, DNS, outbound connections and open file limits work, and print a report
+    Doctor {
+        #[structopt(long = "proxy")]
+        /// Running proxy on this host to check, a temporary one is started if omitted
+        proxy: Option<SocketAddr>,
+
+        #[structopt(long = "user")]
+        /// Username to authenticate with
+        user: Option<String>,
+
+        #[structopt(long = "password", default_value = "")]
+        /// Password to authenticate with, or a secret reference (e.g. env:MERINO_PASSWORD)
+        password: String,
+
+        #[structopt(long = "resolve", default_value = "example.com")]
+        /// Hostname to resolve
+        resolve: String,
+
+        #[structopt(long = "outbound", default_value = "example.com:443")]
+        /// Destination (host:port) to connect to directly
+        outbound: String,
+
+        #[structopt(long = "timeout", default_value = "5")]
+        /// Seconds each network check may take
+        timeout: u64,
+    },
+
+    #[structopt(name = "totp-secret")]
+    /// Print a new TOTP secret for the users file, and the URI to enroll it in an authenticator app
+    TotpSecret {
+        username: String,
+    },
+
+    #[cfg(feature = "sqlite")]
+    #[structopt(name = "user")]
+    /// Manage users in the database given with --db
+    User(UserCommand),
+
+    #[cfg(feature = "postgres")]
+    #[structopt(name = "hash-password")]
+    /// Print the password hash to store in the merino_users table
+    HashPassword {
+        password: String,
+    },
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(StructOpt, Debug)]
+enum UserCommand {
+    #[structopt(name = "add")]
+    /// Add a user
+    Add {
+        username: String,
+        password: String,
+        #[structopt(long = "quota")]
+        /// Maximum bytes the user may relay
+        quota: Option<u64>,
+        #[structopt(long = "expires", parse(try_from_str = "parse_expiry"))]
+        /// Date (2026-12-31) or RFC 3339 time the password stops being accepted
+        expires: Option<DateTime<Utc>>,
+    },
+
+    #[structopt(name = "passwd")]
+    /// Change a user's password
+    Passwd {
+        username: String,
+        password: String,
+    },
+
+    #[structopt(name = "remove")]
+    /// Remove a user
+    Remove {
+        username: String,
+    },
+
+    #[structopt(name = "expire")]
+    /// Set when a user's password stops being accepted, or lift the expiry if omitted
+    Expire {
+        username: String,
+        #[structopt(parse(try_from_str = "parse_expiry"))]
+        at: Option<DateTime<Utc>>,
+    },
+
+    #[structopt(name = "rotate")]
+    /// Replace a user's password with a random one and print it
+    Rotate {
+        username: String,
+        #[structopt(long = "valid-for")]
+        /// Days the new password is accepted for, without expiry if omitted
+        valid_for: Option<u32>,
+    },
+
+    #[structopt(name = "quota")]
+    /// Set a user's quota in bytes, or lift it if omitted
+    Quota {
+        username: String,
+        bytes: Option<u64>,
+    },
+
+    #[structopt(name = "reset")]
+    /// Zero a user's accumulated usage
+    Reset {
+        username: String,
+    },
+
+    #[structopt(name = "list")]
+    /// List users with their quota and usage
+    List,
+}
 
-    let opt = Opt::from_args();
+#[cfg(feature = "sqlite")]
+/// Apply a user management command to the database
+fn manage_users(db: Option<&PathBuf>, cmd: UserCommand) -> Result<(), Box<dyn Error>> {
+    let store = sqlite::SqliteStore::open(db.ok_or("--db is required to manage users")?)?;
+
+    let found = match cmd {
+        UserCommand::Add { username, password, quota, expires } => {
+            store.add_user(&username, &password, quota, expires)?;
+            true
+        },
+        UserCommand::Passwd { username, password } => store.set_password(&username, &password)?,
+        UserCommand::Remove { username } => store.remove_user(&username)?,
+        UserCommand::Expire { username, at } => store.set_expiry(&username, at)?,
+        UserCommand::Rotate { username, valid_for } => {
+            let expires = valid_for.map(|days| Utc::now() + chrono::Duration::days(days.into()));
+            match store.rotate(&username, expires)? {
+                Some(password) => {
+                    println!("{}", password);
+                    true
+                },
+                None => false,
+            }
+        },
+        UserCommand::Quota { username, bytes } => store.set_quota(&username, bytes)?,
+        UserCommand::Reset { username } => store.reset_usage(&username)?,
+        UserCommand::List => {
+            for user in store.users()? {
+                let expires = match user.expires {
+                    Some(expires) if expires <= Utc::now() => format!("\texpired {}", expires.to_rfc3339()),
+                    Some(expires) => format!("\texpires {}", expires.to_rfc3339()),
+                    None => String::new(),
+                };
+                match user.quota {
+                    Some(quota) => println!("{}\t{}/{} bytes{}", user.username, user.used, quota, expires),
+                    None => println!("{}\t{} bytes{}", user.username, user.used, expires),
+                }
+            }
+            true
+        },
+    };
+
+    if !found {
+        return Err("No such user".into());
+    }
+    Ok(())
+}
+
+/// Split `host:port`, accepting bracketed IPv6 hosts
+fn split_host_port(addr: &str) -> Result<(&str, u16), Box<dyn Error>> {
+    match addr.rfind(':') {
+        Some(i) => {
+            let port = addr[i + 1..].parse::<u16>().map_err(|e| format!("Invalid port in `{}`: {}", addr, e))?;
+            Ok((addr[..i].trim_start_matches('[').trim_end_matches(']'), port))
+        },
+        None => Err(format!("`{}` must be host:port", addr).into()),
+    }
+}
+
+/// Parse IPv4 and IPv6 prefix lengths, e.g. `24,64`
+fn parse_prefixes(s: &str) -> Result<(u8, u8), String> {
+    let (v4, v6) = s.split_once(',').ok_or("expected IPv4 and IPv6 prefix lengths, e.g. 24,64")?;
+    let v4: u8 = v4.trim().parse().map_err(|e| format!("`{}`: {}", v4, e))?;
+    let v6: u8 = v6.trim().parse().map_err(|e| format!("`{}`: {}", v6, e))?;
+    if v4 > 32 || v6 > 128 {
+        return Err("prefix lengths are at most 32 for IPv4 and 128 for IPv6".to_string());
+    }
+    Ok((v4, v6))
+}
+
+fn parse_mark(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }.map_err(|e| e.to_string())
+}
+
+/// Directory containing `path`, which may be relative to the current one
+fn parent_dir(path: &std::path::Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if parent != std::path::Path::new("") => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// The files `opt` configures merino to read, and the directories it writes to
+fn sandbox_paths(opt: &Opt) -> Vec<(PathBuf, sandbox::Access)> {
+    let mut paths = Vec::new();
+    for path in [&opt.users, &opt.rules, &opt.groups, &opt.hosts, &opt.asn_db].iter().copied().flatten() {
+        paths.push((path.clone(), sandbox::Access::Read));
+    }
+    // Blocklists are typically replaced by renaming a new file over them
+    for list in opt.blocklists.iter().filter(|list| !list.contains("://")) {
+        paths.push((parent_dir(std::path::Path::new(list)), sandbox::Access::Read));
+    }
+    // The ban list is replaced through a temporary file next to it
+    for path in [&opt.access_log, &opt.ban_list].iter().copied().flatten() {
+        paths.push((parent_dir(path), sandbox::Access::Write));
+    }
+    if let Some(dir) = &opt.capture_dir {
+        paths.push((dir.clone(), sandbox::Access::Write));
+    }
+    #[cfg(feature = "rhai")]
+    {
+        if let Some(path) = &opt.script {
+            paths.push((path.clone(), sandbox::Access::Read));
+        }
+    }
+    #[cfg(feature = "wasm")]
+    {
+        for path in &opt.plugins {
+            paths.push((path.clone(), sandbox::Access::Read));
+        }
+    }
+    // SQLite keeps its journal next to the database
+    #[cfg(feature = "sqlite")]
+    {
+        if let Some(path) = &opt.db {
+            paths.push((parent_dir(path), sandbox::Access::Write));
+        }
+    }
+    paths
+}
+
+/// pledge(2) promises covering what `opt` configures merino to do once serving
+#[cfg(target_os = "openbsd")]
+fn promises(opt: &Opt) -> String {
+    let mut promises = String::from("stdio inet dns");
+    #[allow(unused_mut)]
+    let mut writes = opt.ban_list.is_some() || opt.capture_dir.is_some();
+    #[cfg(feature = "sqlite")]
+    {
+        if opt.db.is_some() {
+            promises.push_str(" flock");
+            writes = true;
+        }
+    }
+    if writes {
+        promises.push_str(" rpath wpath cpath");
+    }
+    // Plugins are compiled to native code
+    #[cfg(feature = "wasm")]
+    {
+        if !opt.plugins.is_empty() {
+            promises.push_str(" prot_exec");
+        }
+    }
+    promises
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "sqlite")]
+fn parse_expiry(s: &str) -> Result<DateTime<Utc>, String> {
+    expiry::parse(s).map_err(|e| e.to_string())
+}
+
+/// Print which rule decides a request, and the resulting decision
+fn test_policy(rules: &Rules, groups: &Groups, asn_db: Option<&asn::AsnDb>, from: IpAddr, to: &str, user: Option<&str>, at: Option<DateTime<Utc>>) -> Result<(), Box<dyn Error>> {
+    let (host, port) = split_host_port(to)?;
+    let destination = rules::Destination::parse(host);
+    let at = at.unwrap_or_else(Utc::now);
+    let (user, tag) = match user.map(rules::split_tag) {
+        Some((user, tag)) => (Some(user), tag),
+        None => (None, None),
+    };
+    let asn = match (asn_db, &destination) {
+        (Some(db), rules::Destination::Ip(ip)) => db.lookup(*ip),
+        (Some(db), rules::Destination::Domain(domain)) => {
+            use std::net::ToSocketAddrs;
+            (domain.as_str(), port).to_socket_addrs()?.next().and_then(|addr| db.lookup(addr.ip()))
+        },
+        (None, _) => None,
+    };
+
+    let verdict = rules.evaluate_at(&rules::Request {
+        source: from,
+        user,
+        groups: groups.of(user),
+        destination: &destination,
+        port,
+        asn: Some(&rules::AsnLookup::known(asn.as_ref().map(|asn| asn.number))),
+        tag,
+    }, at);
+
+    println!("Request: {} -> {}:{} (user: {}) at {}", from, destination, port, user.unwrap_or("none"), at.to_rfc3339());
+    if let Some(asn) = &asn {
+        println!("Destination network: {}", asn);
+    }
+    match verdict.rule {
+        Some((index, rule)) => println!("Matched rule #{}: {}", index + 1, rule),
+        None => println!("No rule matched ({} rules loaded), using default", rules.len()),
+    }
+    println!("Decision: {}", verdict.action);
+
+    Ok(())
+}
+
+/// The connection limit and relay buffer size in bytes, as given or derived from the cgroup's limits
+fn resource_limits(opt: &Opt) -> (Option<u64>, Option<usize>) {
+    let derived = cgroup::Cgroup::detect().and_then(|cgroup| cgroup.limits().defaults());
+    let max_connections = match opt.max_connections {
+        Some(0) => None,
+        Some(max) => Some(max),
+        None => derived.map(|defaults| defaults.max_connections),
+    };
+    let relay_buffer = opt.relay_buffer.map(|kib| kib * 1024).or(derived.map(|defaults| defaults.relay_buffer));
+    (max_connections, relay_buffer)
+}
+
+/// Print every setting `opt` results in, after the files it names were loaded, without secrets
+fn print_config(opt: &Opt, rules: &Rules, groups: &Groups, users: &[User]) -> Result<(), Box<dyn Error>> {
+    let path = |path: &Option<PathBuf>| path.as_ref().map_or("-".to_string(), |path| path.display().to_string());
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    // References name where a secret is kept, not the secret
+    #[allow(unused)]
+    let secret = |value: &Option<String>| match value {
+        Some(value) if secrets::is_reference(value) => value.clone(),
+        Some(_) => "<redacted>".to_string(),
+        None => "-".to_string(),
+    };
+
+    let (max_connections, relay_buffer) = resource_limits(opt);
+
+    let mut settings: Vec<(&str, String)> = vec![
+        ("listen", if opt.inetd { "stdio".to_string() } else { format!("{}:{}", opt.ip, opt.port) }),
+        ("no_auth", opt.no_auth.to_string()),
+        ("require_auth", opt.require_auth.to_string()),
+        ("username_tags", opt.username_tags.to_string()),
+        ("commands", merino::listeners::command_names(&merino::listeners::parse_commands(&opt.commands)?)),
+        ("versions", merino::listeners::version_names(&merino::listeners::parse_versions(&opt.versions)?)),
+        ("users", format!("{} ({} users)", path(&opt.users), users.len())),
+        ("rules", format!("{} ({} rules)", path(&opt.rules), rules.len())),
+        ("profile", optional(opt.profile.clone())),
+        ("require_hostnames", opt.require_hostnames.to_string()),
+        ("sni", opt.sni.to_string()),
+        ("sinkhole", opt.sinkhole.to_string()),
+        ("groups", format!("{} ({} memberships)", path(&opt.groups), groups.len())),
+    ];
+    #[cfg(feature = "sqlite")]
+    settings.push(("db", path(&opt.db)));
+    #[cfg(feature = "postgres")]
+    settings.push(("postgres", secret(&opt.postgres)));
+    #[cfg(feature = "token")]
+    settings.push(("token_jwks", optional(opt.token_jwks.clone())));
+    #[cfg(feature = "token")]
+    settings.push(("token_issuer", optional(opt.token_issuer.clone())));
+    #[cfg(feature = "token")]
+    settings.push(("token_audience", optional(opt.token_audience.clone())));
+    #[cfg(feature = "token")]
+    settings.push(("token_introspection", optional(opt.token_introspection.clone())));
+    #[cfg(feature = "token")]
+    settings.push(("token_introspection_secret", secret(&opt.token_introspection_secret)));
+    #[cfg(feature = "wasm")]
+    settings.push(("plugins", opt.plugins.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(", ")));
+    #[cfg(feature = "rhai")]
+    settings.push(("script", path(&opt.script)));
+    #[cfg(feature = "ext-authz")]
+    settings.extend([
+        ("ext_authz", optional(opt.ext_authz.clone())),
+        ("ext_authz_timeout", format!("{}ms", opt.ext_authz_timeout)),
+        ("ext_authz_fail_open", opt.ext_authz_fail_open.to_string()),
+    ]);
+    let hosts = match &opt.hosts {
+        Some(hosts) => format!("{} ({} overrides)", hosts.display(), Hosts::load(hosts)?.len()),
+        None => "-".to_string(),
+    };
+    for pool in &opt.pool {
+        split_host_port(pool)?;
+    }
+    settings.extend([
+        ("hosts", hosts),
+        ("asn_db", path(&opt.asn_db)),
+        ("blocklists", opt.blocklists.join(", ")),
+        ("blocklist_refresh", format!("{}s", opt.blocklist_refresh)),
+        ("tcp_fast_open", opt.tcp_fast_open.to_string()),
+        ("mptcp", opt.mptcp.to_string()),
+        ("mptcp_listen", opt.mptcp_listen.to_string()),
+        ("tcp_fast_open_listen", opt.tcp_fast_open_listen.to_string()),
+        ("tor", optional(opt.tor.map(|addr| addr.to_string()))),
+        ("mark", optional(opt.mark.map(|mark| format!("{:#x}", mark)))),
+        ("bandwidth", optional(opt.bandwidth.map(|kib| format!("{} KiB/s", kib)))),
+        ("pool", opt.pool.join(", ")),
+        ("pool_size", opt.pool_size.to_string()),
+        ("pool_idle", format!("{}s", opt.pool_idle)),
+        ("reply_address", opt.reply_address.to_string()),
+        ("compliance", opt.compliance.to_string()),
+        ("max_username_len", opt.max_username_len.to_string()),
+        ("max_domain_len", opt.max_domain_len.to_string()),
+        ("max_auth_methods", opt.max_auth_methods.to_string()),
+        ("relay_buffer", optional(relay_buffer.map(|bytes| format!("{} KiB", bytes / 1024)))),
+        ("access_log", path(&opt.access_log)),
+        ("access_log_sample", opt.access_log_sample.to_string()),
+        ("metrics", optional(opt.metrics.map(|addr| addr.to_string()))),
+        ("config", path(&opt.config)),
+        ("sidecar", opt.sidecar.to_string()),
+        ("shutdown_grace", format!("{}s", opt.shutdown_grace)),
+        ("ready", optional(opt.ready.map(|addr| addr.to_string()))),
+        ("upgrade_socket", path(&opt.upgrade_socket)),
+        ("admin", optional(opt.admin.map(|addr| addr.to_string()))),
+        ("ban_list", path(&opt.ban_list)),
+        ("capture_dir", path(&opt.capture_dir)),
+        ("statsd", optional(opt.statsd.clone())),
+        ("statsd_prefix", opt.statsd_prefix.clone()),
+        ("statsd_tags", opt.statsd_tags.join(", ")),
+        ("forwards", opt.forwards.iter().map(Forward::to_string).collect::<Vec<_>>().join(", ")),
+        ("transport", optional(opt.transport.as_ref().map(transport::Stack::to_string))),
+        ("forward_transport", optional(opt.forward_transport.as_ref().map(transport::Stack::to_string))),
+        ("max_sessions", optional(opt.max_sessions.map(|max| max.to_string()))),
+        ("max_lifetime", optional(opt.max_lifetime.map(|secs| format!("{} s", secs)))),
+        ("auth_delay", optional(opt.auth_delay.map(|ms| format!("{} ms", ms)))),
+        ("subnet_allowance", optional(opt.subnet_allowance.map(|allowance| allowance.to_string()))),
+        ("subnet_prefixes", optional(opt.subnet_prefixes.map(|(v4, v6)| format!("/{} /{}", v4, v6)))),
+        ("dns_negative_ttl", format!("{} s", opt.dns_negative_ttl)),
+        ("breaker", optional(opt.breaker_threshold.map(|threshold| format!("after {} failures, for {} s", threshold, opt.breaker_cooldown)))),
+        ("max_connections", optional(max_connections.map(|max| max.to_string()))),
+        ("soft_fd_limit", optional(opt.soft_fd_limit.map(|fds| fds.to_string()))),
+        ("soft_memory_limit", optional(opt.soft_memory_limit.map(|mib| format!("{} MiB", mib)))),
+    ]);
+    #[cfg(feature = "redis")]
+    settings.push(("redis", secret(&opt.redis)));
+    #[cfg(feature = "shadowsocks")]
+    settings.extend([
+        ("shadowsocks", optional(opt.shadowsocks.map(|addr| addr.to_string()))),
+        ("shadowsocks_password", secret(&opt.shadowsocks_password)),
+    ]);
+    settings.extend([
+        ("seccomp", opt.seccomp.to_string()),
+        ("landlock", opt.landlock.to_string()),
+        ("fault_latency", format!("{}ms", opt.fault_latency)),
+        ("fault_jitter", format!("{}ms", opt.fault_jitter)),
+        ("fault_reset", opt.fault_reset.to_string()),
+        ("fault_throttle", optional(opt.fault_throttle.map(|bytes| format!("{} B/s", bytes)))),
+        ("log", env::var("RUST_LOG").unwrap_or_default()),
+    ]);
+
+    for (name, value) in settings {
+        println!("{} = {}", name, if value.is_empty() { "-" } else { &value });
+    }
+    for (index, rule) in rules.iter().enumerate() {
+        println!("rule #{}: {}", index + 1, rule);
+    }
+    Ok(())
+}
+
+/// The command line, with the options from $MERINO_ARGS and the config file it doesn't give
+fn args() -> Result<Vec<OsString>, Box<dyn Error>> {
+    let mut args: Vec<OsString> = env::args_os().collect();
+    if let Ok(extra) = env::var("MERINO_ARGS") {
+        let extra: Vec<String> = extra.split_whitespace().map(String::from).collect();
+        args = config::merge(args, &extra);
+    }
+
+    let given = args.iter().position(|arg| arg == "--config").and_then(|i| args.get(i + 1)).map(PathBuf::from)
+        .or_else(|| args.iter().find_map(|arg| arg.to_str()?.strip_prefix("--config=").map(PathBuf::from)));
+    if let Some(path) = given.or_else(|| env::var_os("MERINO_CONFIG").map(PathBuf::from)) {
+        let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let defaults = config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+        args = config::merge(args, &defaults);
+    }
+    Ok(args)
+}
+
+/// Log a JSON object per line, to stdout unless it carries a SOCKS session
+fn init_json_logging(inetd: bool) {
+    use std::io::Write;
+
+    let mut builder = env_logger::Builder::from_default_env();
+    builder.target(if inetd { env_logger::Target::Stderr } else { env_logger::Target::Stdout });
+    builder.format(|buf, record| {
+        writeln!(
+            buf,
+            "{{\"time\":\"{}\",\"level\":\"{}\",\"target\":{},\"message\":{}}}",
+            Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            record.level(),
+            json_string(record.target()),
+            json_string(&record.args().to_string()),
+        )
+    });
+    builder.init();
+}
+
+/// `s` as a quoted JSON string
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Keep SIGTERM and SIGINT from killing merino, so they can be waited for by [`drain_on_signal`]
+///
+/// Threads inherit the blocked signals, so this must run before any is spawned.
+#[cfg(target_os = "linux")]
+fn block_shutdown_signals() -> Result<nix::sys::signal::SigSet, Box<dyn Error>> {
+    use nix::sys::signal::{SigSet, Signal};
+
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGINT);
+    signals.thread_block()?;
+    Ok(signals)
+}
+
+/// Once one of `signals` arrives, drain the sessions for up to `grace` and exit
+#[cfg(target_os = "linux")]
+fn drain_on_signal(signals: nix::sys::signal::SigSet, lifecycle: std::sync::Arc<Lifecycle>, grace: Duration) {
+    std::thread::spawn(move || {
+        let signal = match signals.wait() {
+            Ok(signal) => signal,
+            Err(error) => {
+                error!("Failed to wait for shutdown signals: {}", error);
+                return;
+            },
+        };
+        info!("Received {}, draining sessions for up to {}s", signal, grace.as_secs());
+        if lifecycle.drain(grace) {
+            info!("All sessions ended, exiting");
+        } else {
+            warn!("Sessions still relaying at the end of the grace period, exiting anyway");
+        }
+        std::process::exit(0);
+    });
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_iter(args()?);
 
     // Setup logging
 
@@ -52,7 +1071,164 @@ fn main() -> Result<(), Box<dyn Error>> {
         env::set_var("RUST_LOG", "merino=INFO");
     }
 
-    pretty_env_logger::init_timed();
+    let log_format = opt.log_format.unwrap_or(if opt.sidecar { LogFormat::Json } else { LogFormat::Text });
+    match log_format {
+        LogFormat::Text => pretty_env_logger::init_timed(),
+        LogFormat::Json => init_json_logging(opt.inetd),
+    }
+
+    #[cfg(target_os = "linux")]
+    let shutdown_signals = if opt.sidecar && opt.cmd.is_none() && !opt.dry_run { Some(block_shutdown_signals()?) } else { None };
+    #[cfg(not(target_os = "linux"))]
+    {
+        if opt.sidecar {
+            warn!("Draining sessions on SIGTERM is only supported on Linux, they end when merino is stopped");
+        }
+    }
+
+    let rules = match &opt.rules {
+        Some(rules_file) => Rules::load(rules_file)?,
+        None => Rules::default(),
+    };
+    let rules = match &opt.profile {
+        Some(profile) => rules.profile(profile),
+        None => rules,
+    };
+    if opt.tor.is_none() {
+        if let Some(rule) = rules.iter().find(|rule| rule.via() == Some(rules::Via::Tor)) {
+            return Err(format!("Rule `{}` routes via Tor, which needs --tor", rule).into());
+        }
+    }
+
+    let groups = match &opt.groups {
+        Some(groups_file) => Groups::load(groups_file)?,
+        None => Groups::default(),
+    };
+
+    match opt.cmd {
+        Some(Command::TestPolicy { from, to, user, at }) => {
+            let asn_db = opt.asn_db.as_ref().map(asn::AsnDb::load).transpose()?;
+            return test_policy(&rules, &groups, asn_db.as_ref(), from, &to, user.as_deref(), at);
+        },
+        Some(Command::Bench { proxy, target, sessions, concurrency, payload, user, password }) => {
+            let target = match target {
+                Some(target) => {
+                    let (host, port) = split_host_port(&target)?;
+                    Some((host.to_string(), port))
+                },
+                None => None,
+            };
+            let config = bench::BenchConfig {
+                proxy,
+                target,
+                sessions,
+                concurrency,
+                payload,
+                credentials: match user {
+                    Some(user) => Some((user, secrets::resolve(&password)?)),
+                    None => None,
+                },
+            };
+            println!("{}", bench::run(&config)?);
+            return Ok(());
+        },
+        Some(Command::Speedtest { via, mib, pings, timeout, user, password }) => {
+            let config = speedtest::SpeedtestConfig {
+                via,
+                credentials: match user {
+                    Some(user) => Some((user, secrets::resolve(&password)?)),
+                    None => None,
+                },
+                bytes: mib * 1024 * 1024,
+                pings,
+                timeout: Duration::from_secs(timeout),
+            };
+            println!("{}", speedtest::run(&config)?);
+            return Ok(());
+        },
+        Some(Command::Upgrade { socket }) => {
+            #[cfg(target_os = "linux")]
+            {
+                print!("{}", handover::request_upgrade(&socket)?);
+                return Ok(());
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                return Err(format!("Upgrading through {} is only supported on Linux", socket.display()).into());
+            }
+        },
+        Some(Command::Doctor { proxy, user, password, resolve, outbound, timeout }) => {
+            let (host, port) = split_host_port(&outbound)?;
+            let config = doctor::DoctorConfig {
+                proxy,
+                credentials: match user {
+                    Some(user) => Some((user, secrets::resolve(&password)?)),
+                    None => None,
+                },
+                resolve,
+                outbound: (host.to_string(), port),
+                timeout: Duration::from_secs(timeout),
+            };
+            let report = doctor::run(&config);
+            print!("{}", report);
+            if !report.healthy() {
+                return Err("Some checks failed".into());
+            }
+            return Ok(());
+        },
+        Some(Command::TotpSecret { username }) => {
+            let secret = totp::generate_secret();
+            println!("{}", secret);
+            println!("otpauth://totp/merino:{}?secret={}&issuer=merino&digits={}&period={}", username, secret, totp::DIGITS, totp::STEP);
+            return Ok(());
+        },
+        #[cfg(feature = "sqlite")]
+        Some(Command::User(cmd)) => {
+            return manage_users(opt.db.as_ref(), cmd);
+        },
+        #[cfg(feature = "postgres")]
+        Some(Command::HashPassword { password }) => {
+            println!("{}", store::hash_password(&password)?);
+            return Ok(());
+        },
+        None => {},
+    }
+
+    // Each of these replaces the user store the others set, so only one may be given
+    #[allow(unused_mut)]
+    let mut stores: Vec<&str> = Vec::new();
+    #[cfg(feature = "sqlite")]
+    stores.extend(opt.db.as_ref().map(|_| "--db"));
+    #[cfg(feature = "postgres")]
+    stores.extend(opt.postgres.as_ref().map(|_| "--postgres"));
+    #[cfg(feature = "token")]
+    {
+        stores.extend(opt.token_introspection.as_ref().map(|_| "--token-introspection"));
+        stores.extend(opt.token_jwks.as_ref().map(|_| "--token-jwks"));
+    }
+    if stores.len() > 1 {
+        return Err(format!("{} each authenticate users on their own and can't be combined", stores.join(", ")).into());
+    }
+
+    if opt.upgrade_socket.is_some() && (opt.seccomp || opt.landlock) {
+        return Err("--upgrade-socket starts a new merino, which --seccomp and --landlock forbid".into());
+    }
+
+    // Before anything else is opened or any thread spawned, so every thread is confined
+    if opt.landlock {
+        let landlock = sandbox_paths(&opt).into_iter()
+            .fold(sandbox::Landlock::new(), |landlock, (path, access)| landlock.allow(path, access));
+        if landlock.restrict()? {
+            info!("Confined filesystem access with Landlock");
+        } else {
+            warn!("Landlock isn't supported by this kernel, filesystem access is unrestricted");
+        }
+    }
+
+    // stdout carries the SOCKS session in inetd mode
+    if !opt.inetd && !opt.dry_run && log_format == LogFormat::Text {
+        println!("{}", LOGO);
+    }
 
     // Setup Proxy settings
 
@@ -62,7 +1238,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     if opt.no_auth { auth_methods.push(merino::AuthMethods::NoAuth as u8); }
 
     // Enable username/password auth
-    let authed_users: Result<Vec<User>, Box<dyn Error>> = match opt.users {
+    let authed_users: Result<Vec<User>, Box<dyn Error>> = match &opt.users {
         Some(users_file) => {
             auth_methods.push(AuthMethods::UserPass as u8);
             let file = std::fs::File::open(users_file)?;
@@ -77,6 +1253,12 @@ fn main() -> Result<(), Box<dyn Error>> {
                 trace!("Loaded user: {}", record.username);
                 users.push(record);
             }
+            for user in &users {
+                store::UserStore::totp_secret(&users, &user.username)
+                    .map_err(|e| format!("Invalid TOTP secret for {}: {}", user.username, e))?;
+                store::UserStore::expires(&users, &user.username)
+                    .map_err(|e| format!("Invalid expiry for {}: {}", user.username, e))?;
+            }
 
             Ok(users)
         },
@@ -85,16 +1267,327 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let authed_users = authed_users?;
 
+    // Enable username/password auth against the user database
+    #[cfg(feature = "sqlite")]
+    let store = match &opt.db {
+        Some(db) => {
+            auth_methods.push(AuthMethods::UserPass as u8);
+            Some(sqlite::SqliteStore::open(db)?)
+        },
+        None => None,
+    };
+
+    // Enable username/password auth against PostgreSQL
+    #[cfg(feature = "postgres")]
+    let postgres_store = match &opt.postgres {
+        Some(params) => {
+            auth_methods.push(AuthMethods::UserPass as u8);
+            Some(postgres::PostgresStore::connect(&secrets::resolve(params)?)?)
+        },
+        None => None,
+    };
+
+    // Enable username/password auth with bearer tokens as passwords
+    #[cfg(feature = "token")]
+    let token_store = match &opt.token_introspection {
+        Some(url) => {
+            auth_methods.push(AuthMethods::UserPass as u8);
+            let credential = opt.token_introspection_secret.as_deref().map(secrets::resolve).transpose()?;
+            Some(token::IntrospectionStore::new(url, credential)?)
+        },
+        None => None,
+    };
+
+    // Enable username/password auth with JWTs as passwords
+    #[cfg(feature = "token")]
+    let jwks_store = match &opt.token_jwks {
+        Some(url) => {
+            auth_methods.push(AuthMethods::UserPass as u8);
+            Some(token::JwksStore::new(url, opt.token_issuer.clone(), opt.token_audience.clone())?)
+        },
+        None => None,
+    };
+
+    if opt.require_auth && !auth_methods.contains(&(AuthMethods::UserPass as u8)) {
+        return Err("--require-auth needs users to authenticate against".into());
+    }
+    if opt.require_auth && auth_methods.contains(&(AuthMethods::NoAuth as u8)) {
+        return Err("--require-auth can't be combined with anonymous access".into());
+    }
+
     if auth_methods.is_empty() {
         warn!("No Authentication methods enabled. Clients will not be able to connect!");
     }
 
 
+    if opt.fault_reset < 0.0 || opt.fault_reset > 1.0 {
+        return Err("--fault-reset must be between 0 and 1".into());
+    }
+    let faults = Faults {
+        latency: Duration::from_millis(opt.fault_latency),
+        jitter: Duration::from_millis(opt.fault_jitter),
+        reset_chance: opt.fault_reset,
+        throttle: opt.fault_throttle,
+    };
+
+    if let Some(kib) = opt.relay_buffer {
+        if !(RELAY_BUFFER_MIN..=RELAY_BUFFER_MAX).contains(&kib.saturating_mul(1024)) {
+            return Err("--relay-buffer must be between 16 and 512".into());
+        }
+    }
+
+    if opt.dry_run {
+        return print_config(&opt, &rules, &groups, &authed_users);
+    }
+
+    match accept::raise_nofile_limit() {
+        Ok(limit) => info!("Open file limit: {}", limit),
+        Err(error) => debug!("Couldn't raise the open file limit: {}", error),
+    }
+
+    // Started by `merino upgrade`, the listeners come from the process being replaced
+    #[cfg(target_os = "linux")]
+    handover::receive()?;
+
+    let connect_options = ConnectOptions { fast_open: opt.tcp_fast_open, mark: opt.mark, mptcp: opt.mptcp };
+
     // Create proxy server
-    let mut merino = Merino::new(opt.port, &opt.ip, auth_methods, authed_users)?;
+    // A single stdio client doesn't need the configured port, and many may run at once
+    let (port, ip) = if opt.inetd { (0, "127.0.0.1") } else { (opt.port, opt.ip.as_str()) };
+    let mut merino = Merino::new(port, ip, auth_methods, authed_users)?
+        .with_rules(rules)
+        .with_username_tags(opt.username_tags)
+        .with_commands(merino::listeners::parse_commands(&opt.commands)?)
+        .with_versions(merino::listeners::parse_versions(&opt.versions)?)
+        .with_require_auth(opt.require_auth)
+        .with_require_hostnames(opt.require_hostnames)
+        .with_sni(opt.sni)
+        .with_sinkhole(opt.sinkhole)
+        .with_groups(groups)
+        .with_reply_address(opt.reply_address)
+        .with_compliance(opt.compliance)
+        .with_limits(Limits {
+            max_username: opt.max_username_len,
+            max_domain: opt.max_domain_len,
+            max_methods: opt.max_auth_methods,
+        })
+        .with_connect_options(connect_options.clone())
+        .with_faults(faults);
+
+    if opt.mptcp_listen {
+        merino = merino.with_mptcp_listener()?;
+    }
+    if opt.tcp_fast_open_listen {
+        merino = merino.with_fast_open_listener()?;
+    }
+
+    if let Some(addr) = opt.tor {
+        merino = merino.with_tor(addr);
+    }
+
+    if let Some(kib) = opt.bandwidth {
+        merino = merino.with_bandwidth(kib.saturating_mul(1024));
+    }
+
+    if !opt.pool.is_empty() {
+        let destinations = opt.pool.iter()
+            .map(|addr| split_host_port(addr).map(|(host, port)| (host.to_string(), port)))
+            .collect::<Result<Vec<_>, _>>()?;
+        merino = merino.with_pool(Pool::new(destinations, opt.pool_size, Duration::from_secs(opt.pool_idle), connect_options));
+    }
+
+    // Sized for the container's limits unless told otherwise, so a connection flood can't get it OOM-killed
+    if let Some(cgroup) = cgroup::Cgroup::detect() {
+        info!("cgroup limits: {}", cgroup.limits());
+    }
+    let (max_connections, relay_buffer) = resource_limits(&opt);
+    if let Some(max) = max_connections {
+        info!("Accepting up to {} connections at once", max);
+        merino = merino.with_max_connections(max);
+    }
+    if let Some(bytes) = relay_buffer {
+        merino = merino.with_relay_buffer(bytes);
+    }
+    if opt.soft_fd_limit.is_some() || opt.soft_memory_limit.is_some() {
+        merino = merino.with_soft_limits(process::SoftLimits {
+            open_fds: opt.soft_fd_limit,
+            resident_memory: opt.soft_memory_limit.map(|mib| mib.saturating_mul(1024 * 1024)),
+        });
+    }
+
+    if let Some(max) = opt.max_sessions {
+        merino = merino.with_max_sessions(max);
+    }
+    if let Some(secs) = opt.max_lifetime {
+        merino = merino.with_max_lifetime(Duration::from_secs(secs));
+    }
+    if opt.dns_negative_ttl > 0 {
+        merino = merino.with_negative_dns_ttl(Duration::from_secs(opt.dns_negative_ttl));
+    }
+    if let Some(threshold) = opt.breaker_threshold {
+        merino = merino.with_circuit_breaker(threshold, Duration::from_secs(opt.breaker_cooldown));
+    }
+    if let Some(ms) = opt.auth_delay {
+        let defaults = merino::tarpit::Subnets::default();
+        let (v4, v6) = opt.subnet_prefixes.unwrap_or((defaults.v4, defaults.v6));
+        let subnets = merino::tarpit::Subnets { allowance: opt.subnet_allowance.unwrap_or(defaults.allowance), v4, v6 };
+        merino = merino.with_auth_delay(Duration::from_millis(ms), subnets);
+    }
+
+    if let Some(path) = &opt.access_log {
+        merino = merino.with_access_log(AccessLog::open(path)?.with_sampling(opt.access_log_sample));
+    }
+
+    if let Some(addr) = opt.metrics {
+        merino = merino.with_metrics(addr)?;
+    }
+
+    if let Some(path) = &opt.ban_list {
+        merino = merino.with_ban_list(path)?;
+    }
+    if let Some(dir) = &opt.capture_dir {
+        merino = merino.with_capture_dir(dir)?;
+    }
+
+    if let Some(addr) = opt.admin {
+        merino = merino.with_admin(addr)?;
+    }
+
+    if let Some(addr) = opt.ready {
+        merino = merino.with_readiness(addr)?;
+    }
+
+    if let Some(path) = &opt.upgrade_socket {
+        #[cfg(target_os = "linux")]
+        {
+            merino = merino.with_upgrades(path, Duration::from_secs(opt.shutdown_grace))?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            return Err(format!("Upgrades through {} are only supported on Linux", path.display()).into());
+        }
+    }
+
+    if let Some(addr) = &opt.statsd {
+        merino = merino.with_statsd(statsd::Statsd::connect(addr.as_str(), &opt.statsd_prefix, &opt.statsd_tags)?);
+    }
+
+    if let Some(path) = &opt.hosts {
+        let hosts = Hosts::load(path)?;
+        info!("Loaded {} host overrides", hosts.len());
+        merino = merino.with_hosts(hosts);
+    }
+
+    for source in &opt.blocklists {
+        let list = Blocklist::load(source)?;
+        info!("Loaded {} blocklist entries from {}", list.len(), source);
+        list.watch(Duration::from_secs(opt.blocklist_refresh));
+        merino = merino.with_blocklist(list);
+    }
+
+    if let Some(path) = &opt.asn_db {
+        let db = asn::AsnDb::load(path)?;
+        info!("Loaded the ASN database {}", path.display());
+        merino = merino.with_asn_db(db);
+    }
+
+    for forward in opt.forwards {
+        merino = merino.with_forward(forward)?;
+    }
+    if let Some(stack) = opt.transport {
+        merino = merino.with_transport(stack);
+    }
+    if let Some(stack) = opt.forward_transport {
+        merino = merino.with_forward_transport(stack);
+    }
+
+    #[cfg(feature = "rhai")]
+    {
+        if let Some(path) = &opt.script {
+            merino = merino.with_plugin(script::ScriptPlugin::load(path)?);
+        }
+    }
+
+    #[cfg(feature = "ext-authz")]
+    {
+        if let Some(url) = &opt.ext_authz {
+            let timeout = Duration::from_millis(opt.ext_authz_timeout);
+            merino = merino.with_plugin(ext_authz::ExtAuthz::new(url, timeout, opt.ext_authz_fail_open)?);
+        }
+    }
+
+    #[cfg(feature = "wasm")]
+    {
+        for path in &opt.plugins {
+            merino = merino.with_plugin(wasm::WasmPlugin::load(path)?);
+        }
+    }
+
+    #[cfg(feature = "shadowsocks")]
+    {
+        if let Some(addr) = opt.shadowsocks {
+            let password = opt.shadowsocks_password.as_ref().ok_or("--shadowsocks needs --shadowsocks-password")?;
+            merino = merino.with_shadowsocks(shadowsocks::Inbound::new(addr, &secrets::resolve(password)?))?;
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    {
+        if let Some(url) = &opt.redis {
+            merino = merino.with_state(state::RedisState::connect(&secrets::resolve(url)?)?);
+        }
+    }
+
+    #[cfg(feature = "sqlite")]
+    {
+        if let Some(store) = store {
+            merino = merino.with_store(store);
+        }
+    }
+
+    #[cfg(feature = "postgres")]
+    {
+        if let Some(store) = postgres_store {
+            merino = merino.with_store(store);
+        }
+    }
+
+    #[cfg(feature = "token")]
+    {
+        if let Some(store) = token_store {
+            merino = merino.with_store(store);
+        }
+        if let Some(store) = jwks_store {
+            merino = merino.with_store(store);
+        }
+    }
+
+    // Network daemons on OpenBSD drop everything they don't need once started
+    #[cfg(target_os = "openbsd")]
+    {
+        let promises = promises(&opt);
+        sandbox::pledge(&promises, &sandbox_paths(&opt))?;
+        info!("Pledged {}", promises);
+    }
+
+    if opt.seccomp {
+        sandbox::seccomp()?;
+        info!("Installed the seccomp filter");
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(signals) = shutdown_signals {
+            drain_on_signal(signals, merino.lifecycle(), Duration::from_secs(opt.shutdown_grace));
+        }
+    }
 
     // Start Proxies
-    merino.serve()?;
+    if opt.inetd {
+        merino.serve_stdio()?;
+    } else {
+        merino.serve()?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,440 @@
+//! Prometheus metrics about sessions and relayed traffic
+//!
+//! Served in the text exposition format on the address given with
+//! [`crate::Merino::with_metrics`], e.g. for `curl http://127.0.0.1:9100/metrics`.
+//!
+//! Connects are also counted per destination `host:port`, to spot a broken
+//! egress path among the working ones. Only the first [`MAX_DESTINATIONS`]
+//! destinations are counted apart, later ones go under `other`, and only the
+//! [`TOP_DESTINATIONS`] failing the most are exported.
+//!
+//! Requests are counted by SOCKS command and by what became of them, see
+//! [`Outcome`], so a burst of denials stands out from destinations failing.
+//! Failed logins come before any command and are counted under `none`.
+use std::fmt::{self, Write as _};
+use std::io::{self, prelude::*};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::process::ProcessUsage;
+use crate::statsd::Statsd;
+use crate::SockCommand;
+
+/// Upper bounds, in seconds, of the session duration buckets
+const DURATION_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// Upper bounds, in seconds, of the handshake latency buckets
+const HANDSHAKE_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// Upper bounds of the bytes per session buckets
+const BYTES_BUCKETS: &[f64] = &[1024.0, 16384.0, 131072.0, 1048576.0, 10485760.0, 104857600.0, 1073741824.0];
+
+/// Destinations counted apart, keeping the number of series bounded
+pub const MAX_DESTINATIONS: usize = 1000;
+
+/// Destinations exported, the ones failing the most first
+pub const TOP_DESTINATIONS: usize = 20;
+
+/// Destination later destinations are counted under once [`MAX_DESTINATIONS`] are
+const OTHER_DESTINATIONS: &str = "other";
+
+/// Commands requests are counted by, `none` for those failing before sending one
+const COMMANDS: [Option<SockCommand>; 4] = [None, Some(SockCommand::Connect), Some(SockCommand::Bind), Some(SockCommand::UdpAssosiate)];
+
+/// What became of a request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    /// Allowed, and connected or relaying
+    Granted,
+    /// Refused by the rules, blocklists or the listener's grants
+    DeniedAcl,
+    /// Refused for bad or missing credentials
+    DeniedAuth,
+    /// Allowed, but connecting to the destination failed
+    ConnectFailed,
+}
+
+impl Outcome {
+    const ALL: [Outcome; 4] = [Outcome::Granted, Outcome::DeniedAcl, Outcome::DeniedAuth, Outcome::ConnectFailed];
+
+    /// Label value of the outcome
+    pub fn name(self) -> &'static str {
+        match self {
+            Outcome::Granted => "granted",
+            Outcome::DeniedAcl => "denied_acl",
+            Outcome::DeniedAuth => "denied_auth",
+            Outcome::ConnectFailed => "connect_failed",
+        }
+    }
+}
+
+/// Label value of `command`
+fn command_name(command: Option<SockCommand>) -> &'static str {
+    match command {
+        None => "none",
+        Some(SockCommand::Connect) => "connect",
+        Some(SockCommand::Bind) => "bind",
+        Some(SockCommand::UdpAssosiate) => "udp_associate",
+    }
+}
+
+/// Connects to one destination
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DestinationStats {
+    /// `host:port`, or `other`
+    pub destination: String,
+    pub attempts: u64,
+    pub failures: u64,
+    /// Total time successful connects took, resolving included
+    pub latency: Duration,
+}
+
+impl DestinationStats {
+    /// Average time a successful connect took
+    pub fn average_latency(&self) -> Option<Duration> {
+        let successes = self.attempts - self.failures;
+        (successes > 0).then(|| self.latency / successes as u32)
+    }
+
+    /// Share of connects that failed, between 0 and 1
+    pub fn failure_rate(&self) -> f64 {
+        if self.attempts == 0 { 0.0 } else { self.failures as f64 / self.attempts as f64 }
+    }
+}
+
+impl fmt::Display for DestinationStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let latency = match self.average_latency() {
+            Some(latency) => format!("{}ms", latency.as_millis()),
+            None => "-".to_string(),
+        };
+        write!(f, "{}\t{} connects\t{} failed ({:.1}%)\t{} average", self.destination, self.attempts, self.failures, self.failure_rate() * 100.0, latency)
+    }
+}
+
+/// Distribution of observed values over fixed buckets
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Observations at or below each bound, the last counting everything
+    buckets: Vec<AtomicU64>,
+    /// Sum of all observations, as `f64` bits
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        for (bound, bucket) in self.bounds.iter().chain(Some(&f64::INFINITY)).zip(&self.buckets) {
+            if value <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |sum| {
+            Some((f64::from_bits(sum) + value).to_bits())
+        }).unwrap_or(0);
+    }
+
+    /// Number of observations
+    pub fn count(&self) -> u64 {
+        self.buckets[self.bounds.len()].load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> f64 {
+        f64::from_bits(self.sum.load(Ordering::Relaxed))
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) -> fmt::Result {
+        writeln!(out, "# HELP {} {}", name, help)?;
+        writeln!(out, "# TYPE {} histogram", name)?;
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, bucket.load(Ordering::Relaxed))?;
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count())?;
+        writeln!(out, "{}_sum {}", name, self.sum())?;
+        writeln!(out, "{}_count {}", name, self.count())
+    }
+}
+
+/// Counters and histograms shared by every connection
+#[derive(Debug)]
+pub struct Metrics {
+    /// Sessions that started relaying
+    pub sessions: AtomicU64,
+    /// Sessions currently relaying
+    pub active: AtomicU64,
+    /// Connections accepted and not yet relaying or closed
+    pub handshaking: AtomicU64,
+    /// Connections that ended without relaying
+    pub failures: AtomicU64,
+    /// Connections closed straight away, at the connection limit or while shedding load
+    pub refused: AtomicU64,
+    /// Bytes relayed in both directions
+    pub bytes: AtomicU64,
+    /// Bytes of relay buffers held by relaying sessions
+    pub relay_buffers: AtomicU64,
+    pub session_duration: Histogram,
+    pub handshake_latency: Histogram,
+    pub session_bytes: Histogram,
+    /// Connects by destination
+    destinations: Mutex<HashMap<String, DestinationStats>>,
+    /// Requests by command and outcome, commands as in [`COMMANDS`] and outcomes as in [`Outcome::ALL`]
+    requests: [[AtomicU64; 4]; 4],
+    /// Agent every event is also sent to
+    statsd: OnceLock<Statsd>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            sessions: AtomicU64::new(0),
+            active: AtomicU64::new(0),
+            handshaking: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            refused: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            relay_buffers: AtomicU64::new(0),
+            session_duration: Histogram::new(DURATION_BUCKETS),
+            handshake_latency: Histogram::new(HANDSHAKE_BUCKETS),
+            session_bytes: Histogram::new(BYTES_BUCKETS),
+            destinations: Mutex::new(HashMap::new()),
+            requests: Default::default(),
+            statsd: OnceLock::new(),
+        }
+    }
+}
+
+/// A relay buffer counted in [`Metrics::relay_buffers`] while it is held
+pub(crate) struct RelayBuffer<'a> {
+    metrics: &'a Metrics,
+    size: u64,
+}
+
+impl Drop for RelayBuffer<'_> {
+    fn drop(&mut self) {
+        self.metrics.relay_buffers.fetch_sub(self.size, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    /// Also send every event to `statsd`, unless an agent is already set
+    pub fn push_to(&self, statsd: Statsd) {
+        if self.statsd.set(statsd).is_err() {
+            warn!("Already sending metrics to statsd");
+        }
+    }
+
+    /// A session finished its handshake `latency` after connecting and started relaying
+    pub fn session_started(&self, latency: Duration) {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        let active = self.active.fetch_add(1, Ordering::Relaxed) + 1;
+        self.handshake_latency.observe(latency.as_secs_f64());
+        if let Some(statsd) = self.statsd.get() {
+            statsd.count("sessions", 1);
+            statsd.gauge("sessions_active", active);
+            statsd.timing("handshake_latency", latency.as_millis() as u64);
+        }
+    }
+
+    /// A relaying session ended after `duration`, having relayed `bytes`
+    pub fn session_ended(&self, duration: Duration, bytes: u64) {
+        let active = self.active.fetch_sub(1, Ordering::Relaxed).saturating_sub(1);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.session_duration.observe(duration.as_secs_f64());
+        self.session_bytes.observe(bytes as f64);
+        if let Some(statsd) = self.statsd.get() {
+            statsd.gauge("sessions_active", active);
+            statsd.count("relayed_bytes", bytes);
+            statsd.timing("session_duration", duration.as_millis() as u64);
+            statsd.histogram("session_bytes", bytes);
+        }
+    }
+
+    /// Connecting to `host:port` took `latency` and succeeded or not
+    pub fn connect_finished(&self, host: &str, port: u16, latency: Duration, succeeded: bool) {
+        let destination = format!("{}:{}", host, port);
+        let mut destinations = self.destinations.lock().unwrap();
+        let key = if destinations.contains_key(&destination) || destinations.len() < MAX_DESTINATIONS {
+            destination
+        } else {
+            OTHER_DESTINATIONS.to_string()
+        };
+        let stats = destinations.entry(key.clone()).or_insert_with(|| DestinationStats { destination: key, ..Default::default() });
+        stats.attempts += 1;
+        if succeeded {
+            stats.latency += latency;
+        } else {
+            stats.failures += 1;
+        }
+        if let Some(statsd) = self.statsd.get() {
+            statsd.count(if succeeded { "connects" } else { "connect_failures" }, 1);
+        }
+    }
+
+    /// A request for `command`, `None` before one was sent, ended up with `outcome`
+    pub fn request_decided(&self, command: Option<SockCommand>, outcome: Outcome) {
+        self.request_counter(command, outcome).fetch_add(1, Ordering::Relaxed);
+        if let Some(statsd) = self.statsd.get() {
+            statsd.count(&format!("requests.{}.{}", command_name(command), outcome.name()), 1);
+        }
+    }
+
+    /// Requests for `command` that ended up with `outcome`
+    pub fn requests(&self, command: Option<SockCommand>, outcome: Outcome) -> u64 {
+        self.request_counter(command, outcome).load(Ordering::Relaxed)
+    }
+
+    fn request_counter(&self, command: Option<SockCommand>, outcome: Outcome) -> &AtomicU64 {
+        let command = COMMANDS.iter().position(|c| *c == command).unwrap_or(0);
+        let outcome = Outcome::ALL.iter().position(|o| *o == outcome).unwrap_or(0);
+        &self.requests[command][outcome]
+    }
+
+    /// Up to `limit` destinations, the ones with the most failed connects first, then the busiest
+    pub fn destinations(&self, limit: usize) -> Vec<DestinationStats> {
+        let mut destinations: Vec<DestinationStats> = self.destinations.lock().unwrap().values().cloned().collect();
+        destinations.sort_by(|a, b| (b.failures, b.attempts, &a.destination).cmp(&(a.failures, a.attempts, &b.destination)));
+        destinations.truncate(limit);
+        destinations
+    }
+
+    /// A connection ended without relaying anything
+    pub fn connection_failed(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        if let Some(statsd) = self.statsd.get() {
+            statsd.count("failed_connections", 1);
+        }
+    }
+
+    /// A connection was closed straight away, at the connection limit or while shedding load
+    pub fn connection_refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        if let Some(statsd) = self.statsd.get() {
+            statsd.count("refused_connections", 1);
+        }
+    }
+
+    /// Count a relay buffer of `size` bytes as held until the guard is dropped
+    pub(crate) fn relay_buffer(&self, size: usize) -> RelayBuffer<'_> {
+        self.relay_buffers.fetch_add(size as u64, Ordering::Relaxed);
+        RelayBuffer { metrics: self, size: size as u64 }
+    }
+
+    /// Connections open, handshaking or relaying
+    pub fn open_connections(&self) -> u64 {
+        self.handshaking.load(Ordering::Relaxed) + self.active.load(Ordering::Relaxed)
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.render_into(&mut out).unwrap_or(());
+        out
+    }
+
+    fn render_into(&self, out: &mut String) -> fmt::Result {
+        let counters = [
+            ("merino_sessions_total", "counter", "Sessions that started relaying", &self.sessions),
+            ("merino_sessions_active", "gauge", "Sessions currently relaying", &self.active),
+            ("merino_handshaking_connections", "gauge", "Connections accepted and not yet relaying", &self.handshaking),
+            ("merino_failed_connections_total", "counter", "Connections that ended without relaying", &self.failures),
+            ("merino_refused_connections_total", "counter", "Connections closed at the connection limit or while shedding load", &self.refused),
+            ("merino_relayed_bytes_total", "counter", "Bytes relayed in both directions", &self.bytes),
+            ("merino_relay_buffer_bytes", "gauge", "Bytes of relay buffers held by relaying sessions", &self.relay_buffers),
+        ];
+        for (name, kind, help, value) in counters.iter() {
+            writeln!(out, "# HELP {} {}", name, help)?;
+            writeln!(out, "# TYPE {} {}", name, kind)?;
+            writeln!(out, "{} {}", name, value.load(Ordering::Relaxed))?;
+        }
+        let usage = ProcessUsage::sample();
+        let process = [
+            ("process_open_fds", "Open file descriptors", usage.open_fds),
+            ("process_resident_memory_bytes", "Resident memory in bytes", usage.resident_memory),
+        ];
+        for (name, help, value) in process.iter() {
+            if let Some(value) = value {
+                writeln!(out, "# HELP {} {}", name, help)?;
+                writeln!(out, "# TYPE {} gauge", name)?;
+                writeln!(out, "{} {}", name, value)?;
+            }
+        }
+        self.session_duration.render(out, "merino_session_duration_seconds", "How long relaying sessions lasted")?;
+        self.handshake_latency.render(out, "merino_handshake_latency_seconds", "Time from connecting to starting to relay")?;
+        self.session_bytes.render(out, "merino_session_bytes", "Bytes relayed per session in both directions")?;
+        self.render_requests(out)?;
+        self.render_destinations(out)
+    }
+
+    fn render_requests(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "# HELP merino_requests_total Requests by SOCKS command and outcome")?;
+        writeln!(out, "# TYPE merino_requests_total counter")?;
+        for command in COMMANDS {
+            for outcome in Outcome::ALL {
+                let count = self.requests(command, outcome);
+                if count > 0 {
+                    writeln!(out, "merino_requests_total{{command=\"{}\",outcome=\"{}\"}} {}", command_name(command), outcome.name(), count)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn render_destinations(&self, out: &mut String) -> fmt::Result {
+        let destinations: Vec<(String, DestinationStats)> = self.destinations(TOP_DESTINATIONS).into_iter()
+            .map(|stats| (format!("{{destination=\"{}\"}}", stats.destination.replace('\\', "\\\\").replace('"', "\\\"")), stats))
+            .collect();
+        writeln!(out, "# HELP merino_destination_connects_total Connects by destination")?;
+        writeln!(out, "# TYPE merino_destination_connects_total counter")?;
+        for (labels, stats) in &destinations {
+            writeln!(out, "merino_destination_connects_total{} {}", labels, stats.attempts)?;
+        }
+        writeln!(out, "# HELP merino_destination_connect_failures_total Failed connects by destination")?;
+        writeln!(out, "# TYPE merino_destination_connect_failures_total counter")?;
+        for (labels, stats) in &destinations {
+            writeln!(out, "merino_destination_connect_failures_total{} {}", labels, stats.failures)?;
+        }
+        writeln!(out, "# HELP merino_destination_connect_seconds Time successful connects took by destination, resolving included")?;
+        writeln!(out, "# TYPE merino_destination_connect_seconds summary")?;
+        for (labels, stats) in &destinations {
+            writeln!(out, "merino_destination_connect_seconds_sum{} {}", labels, stats.latency.as_secs_f64())?;
+            writeln!(out, "merino_destination_connect_seconds_count{} {}", labels, stats.attempts - stats.failures)?;
+        }
+        Ok(())
+    }
+}
+
+/// Answer every HTTP request on `listener` with the current metrics
+pub(crate) fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let stream = crate::accept::accept(&listener);
+        let metrics = metrics.clone();
+        thread::spawn(move || {
+            if let Err(error) = respond(stream, &metrics) {
+                debug!("Failed to serve metrics: {}", error);
+            }
+        });
+    }
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    // The request itself doesn't matter, only that it arrived
+    let mut request = [0u8; 1024];
+    let _ = stream.read(&mut request)?;
+
+    let body = metrics.render();
+    write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)?;
+    stream.shutdown(Shutdown::Write)
+}
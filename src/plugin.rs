@@ -0,0 +1,19 @@
+//! Hooks letting custom policy logic observe and veto sessions
+use std::error::Error;
+
+use crate::rules::{Action, Request};
+
+/// Custom logic consulted for every session
+///
+/// A request is only let through if the access rules and every plugin allow
+/// it. `on_close` is called exactly once for each session `on_request` saw.
+pub trait Plugin: Send + Sync {
+    /// Decide whether a request may go ahead
+    fn on_request(&self, session: u64, request: &Request) -> Result<Action, Box<dyn Error>>;
+
+    /// Outcome of connecting to the destination of an allowed request
+    fn on_connect_result(&self, _session: u64, _connected: bool) {}
+
+    /// The session ended after relaying `bytes` in both directions
+    fn on_close(&self, _session: u64, _bytes: u64) {}
+}
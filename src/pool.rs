@@ -0,0 +1,130 @@
+//! Pre-warmed outbound connections to frequently requested destinations
+//!
+//! Workloads that CONNECT to the same `host:port` over and over, such as
+//! proxied HTTPS to a single API, pay a TCP handshake to the destination on
+//! every request. The pool keeps a few connections to each configured
+//! destination open ahead of time and hands one out instead, refilling in
+//! the background. Relayed connections carry opaque streams and are never
+//! returned to the pool.
+//!
+//! Connections idle for longer than the idle timeout are closed, so
+//! destinations dropping idle clients don't leave dead sockets behind.
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::connect::{self, ConnectOptions};
+
+/// Longest wait between checks for connections to refill or evict
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A warm connection and when it was opened
+struct Idle {
+    stream: TcpStream,
+    since: Instant,
+}
+
+/// Open connections waiting for a request to their destination
+pub struct Pool {
+    /// Connections kept open per destination
+    size: usize,
+    /// How long a connection may wait before it is closed
+    idle: Duration,
+    options: ConnectOptions,
+    connections: Mutex<HashMap<(String, u16), VecDeque<Idle>>>,
+    /// Wakes the maintenance thread when a connection is taken
+    taken: Condvar,
+}
+
+impl Pool {
+    /// Keep `size` connections open to each of `destinations`, closing any idle for longer than `idle`
+    ///
+    /// Connections are made with `options`, except for TCP Fast Open which
+    /// would hold back the handshake until the first request. The pool is
+    /// filled on a background thread, which stops once the pool is dropped.
+    pub fn new(destinations: Vec<(String, u16)>, size: usize, idle: Duration, options: ConnectOptions) -> Arc<Self> {
+        let connections = destinations.into_iter()
+            .map(|(host, port)| ((host.to_lowercase(), port), VecDeque::new()))
+            .collect();
+        let options = ConnectOptions { fast_open: false, ..options };
+        let pool = Arc::new(Pool { size, idle, options, connections: Mutex::new(connections), taken: Condvar::new() });
+        let weak = Arc::downgrade(&pool);
+        thread::spawn(move || maintain(weak));
+        pool
+    }
+
+    /// A warm connection to `host:port`, if the destination is pooled and one is ready
+    pub fn take(&self, host: &str, port: u16) -> Option<TcpStream> {
+        let mut connections = self.connections.lock().unwrap();
+        let idle = connections.get_mut(&(host.to_lowercase(), port))?;
+        while let Some(Idle { stream, since }) = idle.pop_front() {
+            if since.elapsed() < self.idle && is_open(&stream) {
+                self.taken.notify_one();
+                return Some(stream);
+            }
+        }
+        self.taken.notify_one();
+        None
+    }
+
+    /// Number of warm connections to `host:port`
+    pub fn ready(&self, host: &str, port: u16) -> usize {
+        self.connections.lock().unwrap().get(&(host.to_lowercase(), port)).map_or(0, VecDeque::len)
+    }
+
+    /// Close stale connections and open new ones until every destination has `size`
+    fn refill(&self) {
+        let missing: Vec<((String, u16), usize)> = {
+            let mut connections = self.connections.lock().unwrap();
+            connections.iter_mut().map(|(destination, idle)| {
+                idle.retain(|conn| conn.since.elapsed() < self.idle && is_open(&conn.stream));
+                (destination.clone(), self.size.saturating_sub(idle.len()))
+            }).collect()
+        };
+
+        // Connect without holding the lock, so requests aren't held up
+        for ((host, port), count) in missing {
+            for _ in 0..count {
+                match connect::connect((host.as_str(), port), &self.options) {
+                    Ok(stream) => {
+                        if let Some(idle) = self.connections.lock().unwrap().get_mut(&(host.clone(), port)) {
+                            idle.push_back(Idle { stream, since: Instant::now() });
+                        }
+                    },
+                    Err(error) => {
+                        debug!("Failed to pre-warm a connection to {}:{}: {}", host, port, error);
+                        break;
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Keep the pool filled until it is dropped
+fn maintain(pool: Weak<Pool>) {
+    while let Some(pool) = pool.upgrade() {
+        pool.refill();
+        let connections = pool.connections.lock().unwrap();
+        let _ = pool.taken.wait_timeout(connections, MAINTENANCE_INTERVAL).unwrap();
+    }
+}
+
+/// Has the destination not closed `stream` yet
+///
+/// Data already waiting, e.g. a server greeting, still counts as open and is
+/// relayed to the client like anything sent later.
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = match stream.peek(&mut [0u8; 1]) {
+        Ok(0) => false,
+        Ok(_) => true,
+        Err(error) => error.kind() == io::ErrorKind::WouldBlock,
+    };
+    stream.set_nonblocking(false).is_ok() && open
+}
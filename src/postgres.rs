@@ -0,0 +1,80 @@
+//! PostgreSQL authentication backend for centrally managed users
+//!
+//! Users are read from a `merino_users` table:
+//!
+//! ```sql
+//! CREATE TABLE merino_users (
+//!     username TEXT PRIMARY KEY,
+//!     -- Argon2 PHC string, see `merino hash-password`
+//!     password_hash TEXT NOT NULL,
+//!     enabled BOOLEAN NOT NULL DEFAULT TRUE,
+//!     -- Optional per-user access rules, in the same CSV format as `--rules`
+//!     rules TEXT
+//! );
+//! ```
+use pg::{Client, NoTls};
+use std::error::Error;
+use std::sync::Mutex;
+
+use crate::store::{verify_password, UserStore};
+use crate::Rules;
+
+/// Users and their policy loaded from PostgreSQL on every authentication
+pub struct PostgresStore {
+    /// libpq style connection string, kept for reconnecting
+    params: String,
+    client: Mutex<Client>,
+}
+
+impl PostgresStore {
+    /// Connect using a connection string such as `host=db user=merino dbname=proxy`
+    pub fn connect(params: &str) -> Result<Self, Box<dyn Error>> {
+        let client = Client::connect(params, NoTls)?;
+        Ok(PostgresStore {
+            params: params.to_string(),
+            client: Mutex::new(client),
+        })
+    }
+
+    /// Run `f` with a live connection, reconnecting if the previous one was lost
+    fn with_client<T>(&self, f: impl FnOnce(&mut Client) -> Result<T, pg::Error>) -> Result<T, Box<dyn Error>> {
+        let mut client = self.client.lock().unwrap();
+        if client.is_closed() {
+            warn!("Lost connection to PostgreSQL, reconnecting");
+            *client = Client::connect(&self.params, NoTls)?;
+        }
+        Ok(f(&mut client)?)
+    }
+}
+
+impl UserStore for PostgresStore {
+    fn authenticate(&self, username: &str, password: &str) -> Result<bool, Box<dyn Error>> {
+        let row = self.with_client(|client| {
+            client.query_opt("SELECT password_hash, enabled FROM merino_users WHERE username = $1", &[&username])
+        })?;
+
+        let (hash, enabled): (String, bool) = match row {
+            Some(row) => (row.get(0), row.get(1)),
+            None => return Ok(false),
+        };
+
+        if !enabled {
+            info!("Account disabled: {}", username);
+            return Ok(false);
+        }
+
+        verify_password(password, &hash)
+    }
+
+    fn user_rules(&self, username: &str) -> Result<Option<Rules>, Box<dyn Error>> {
+        let row = self.with_client(|client| {
+            client.query_opt("SELECT rules FROM merino_users WHERE username = $1", &[&username])
+        })?;
+
+        match row.and_then(|row| row.get::<_, Option<String>>(0)) {
+            Some(rules) => Ok(Some(Rules::from_reader(rules.as_bytes())
+                .map_err(|e| format!("Invalid rules for {}: {}", username, e))?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Priority classes sharing a limited relay bandwidth by weight
+//!
+//! Access rules put sessions in the `high`, `normal` or `bulk` class. With a
+//! bandwidth limit set, each class that has sessions relaying gets a share
+//! of it in proportion to its weight, 4:2:1, so a few bulk downloads can't
+//! starve interactive sessions. Bandwidth left unused by an idle class goes
+//! to the others.
+//!
+//! Within a class, sessions with data to send get equal turns at its part:
+//! each is held to the class rate divided by the number of sessions that
+//! relayed recently, so one bulk transfer can't take what a busy uplink has
+//! left for the sessions next to it. A session that goes quiet stops counting
+//! and its turn goes to the rest.
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How a session's traffic is weighed against others when bandwidth is short
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Priority {
+    /// Interactive traffic, such as SSH
+    High,
+    #[default]
+    Normal,
+    /// Transfers that can wait, such as backups
+    Bulk,
+}
+
+impl Priority {
+    const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Bulk];
+
+    /// Share of the bandwidth relative to the other classes
+    pub fn weight(self) -> u64 {
+        match self {
+            Priority::High => 4,
+            Priority::Normal => 2,
+            Priority::Bulk => 1,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "high" => Ok(Priority::High),
+            "normal" => Ok(Priority::Normal),
+            "bulk" => Ok(Priority::Bulk),
+            _ => Err(format!("`{}` must be high, normal or bulk", s)),
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Priority::High => write!(f, "high"),
+            Priority::Normal => write!(f, "normal"),
+            Priority::Bulk => write!(f, "bulk"),
+        }
+    }
+}
+
+/// How long a session counts as having data to send after it last did
+const BACKLOG_IDLE: Duration = Duration::from_millis(250);
+
+/// Bytes a class may still send, negative once it has sent ahead of its rate
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new() -> Self {
+        Bucket { tokens: 0.0, updated: Instant::now() }
+    }
+
+    /// Take `bytes` at `rate`, returning how long to wait until they fit
+    fn take(&mut self, rate: f64, bytes: usize) -> Duration {
+        let now = Instant::now();
+        // Allow bursts of up to a second's worth after a quiet spell
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate).min(rate);
+        self.updated = now;
+        self.tokens -= bytes as f64;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / rate),
+            false => Duration::from_secs(0),
+        }
+    }
+}
+
+/// Paces relayed traffic so the classes share `bandwidth` by weight
+pub struct Scheduler {
+    /// Bytes per second relayed in both directions, across all sessions
+    bandwidth: u64,
+    buckets: [Mutex<Bucket>; 3],
+    /// Sessions relaying in each class
+    active: [AtomicU64; 3],
+    /// When each session in a class last relayed, to share the class out between them
+    backlogged: [Mutex<HashMap<u64, Instant>>; 3],
+    next_id: AtomicU64,
+}
+
+impl Scheduler {
+    pub fn new(bandwidth: u64) -> Self {
+        let bucket = || Mutex::new(Bucket::new());
+        let backlogged = || Mutex::new(HashMap::new());
+        Scheduler {
+            bandwidth: bandwidth.max(1),
+            buckets: [bucket(), bucket(), bucket()],
+            active: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+            backlogged: [backlogged(), backlogged(), backlogged()],
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Bytes per second `class` may relay with the classes currently relaying
+    pub fn rate(&self, class: Priority) -> f64 {
+        let active: u64 = Priority::ALL.iter()
+            .filter(|other| **other == class || self.active[other.index()].load(Ordering::Relaxed) > 0)
+            .map(|other| other.weight())
+            .sum();
+        self.bandwidth as f64 * class.weight() as f64 / active as f64
+    }
+
+    /// Count a session in `class` as relaying until the guard is dropped
+    pub fn enter(&self, class: Priority) -> Active<'_> {
+        self.active[class.index()].fetch_add(1, Ordering::Relaxed);
+        Active {
+            scheduler: self,
+            class,
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            bucket: Mutex::new(Bucket::new()),
+        }
+    }
+
+    /// Bytes per second one session in `class` may relay while others in it have data to send
+    pub fn share(&self, class: Priority) -> f64 {
+        let now = Instant::now();
+        let backlogged = self.backlogged[class.index()].lock().unwrap();
+        let sessions = backlogged.values().filter(|last| now.duration_since(**last) < BACKLOG_IDLE).count();
+        self.rate(class) / sessions.max(1) as f64
+    }
+
+    /// Account `bytes` relayed in `class`, waiting until they fit its rate
+    pub fn pace(&self, class: Priority, bytes: usize) {
+        sleep(self.take(class, bytes));
+    }
+
+    fn take(&self, class: Priority, bytes: usize) -> Duration {
+        let rate = self.rate(class);
+        self.buckets[class.index()].lock().unwrap().take(rate, bytes)
+    }
+}
+
+fn sleep(wait: Duration) {
+    if wait > Duration::from_secs(0) {
+        thread::sleep(wait);
+    }
+}
+
+/// A session counted as relaying in its class, see [`Scheduler::enter`]
+pub struct Active<'a> {
+    scheduler: &'a Scheduler,
+    class: Priority,
+    id: u64,
+    /// The session's own turn at its class's rate
+    bucket: Mutex<Bucket>,
+}
+
+impl<'a> Active<'a> {
+    /// Account `bytes` relayed by this session, waiting until they fit both
+    /// its class's rate and its share of it
+    pub fn pace(&self, bytes: usize) {
+        let now = Instant::now();
+        {
+            let mut backlogged = self.scheduler.backlogged[self.class.index()].lock().unwrap();
+            backlogged.insert(self.id, now);
+            backlogged.retain(|_, last| now.duration_since(*last) < BACKLOG_IDLE);
+        }
+        let share = self.scheduler.share(self.class);
+        let own = self.bucket.lock().unwrap().take(share, bytes);
+        sleep(own.max(self.scheduler.take(self.class, bytes)));
+    }
+}
+
+impl<'a> Drop for Active<'a> {
+    fn drop(&mut self) {
+        self.scheduler.active[self.class.index()].fetch_sub(1, Ordering::Relaxed);
+        self.scheduler.backlogged[self.class.index()].lock().unwrap().remove(&self.id);
+    }
+}
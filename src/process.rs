@@ -0,0 +1,96 @@
+//! Open file descriptors and resident memory of the merino process itself
+//!
+//! Reported in the metrics and the admin API's `/resources`. With
+//! [`SoftLimits`], new connections are closed straight away once either
+//! crosses its limit, so the proxy sheds load before `accept()` starts
+//! failing for lack of descriptors or the kernel kills it for memory.
+//! Sessions already relaying carry on.
+//!
+//! Only Linux exposes these through `/proc`, elsewhere they are unknown and
+//! nothing is shed.
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a sample is reused for, as counting descriptors walks them all
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The process's use of descriptors and memory, `None` where it can't be read
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProcessUsage {
+    pub open_fds: Option<u64>,
+    /// Bytes
+    pub resident_memory: Option<u64>,
+}
+
+impl ProcessUsage {
+    /// Read the current usage
+    pub fn sample() -> Self {
+        ProcessUsage { open_fds: open_fds(), resident_memory: resident_memory() }
+    }
+}
+
+/// Open descriptors, less the one listing them
+fn open_fds() -> Option<u64> {
+    Some(fs::read_dir("/proc/self/fd").ok()?.count().saturating_sub(1) as u64)
+}
+
+fn resident_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status.lines().find_map(|line| line.strip_prefix("VmRSS:"))?;
+    Some(kib.trim().trim_end_matches("kB").trim().parse::<u64>().ok()? * 1024)
+}
+
+/// Usage past which new connections are shed
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SoftLimits {
+    pub open_fds: Option<u64>,
+    /// Bytes
+    pub resident_memory: Option<u64>,
+}
+
+impl SoftLimits {
+    /// Why `usage` calls for shedding, if it does
+    pub fn exceeded(&self, usage: &ProcessUsage) -> Option<String> {
+        match (self.open_fds, usage.open_fds) {
+            (Some(limit), Some(fds)) if fds >= limit => return Some(format!("{} open descriptors, the soft limit is {}", fds, limit)),
+            _ => {},
+        }
+        match (self.resident_memory, usage.resident_memory) {
+            (Some(limit), Some(bytes)) if bytes >= limit => Some(format!("{} MiB resident, the soft limit is {} MiB", bytes >> 20, limit >> 20)),
+            _ => None,
+        }
+    }
+}
+
+/// Checks the soft limits against a recent sample
+#[derive(Debug)]
+pub(crate) struct Shedder {
+    limits: SoftLimits,
+    sampled: Mutex<Option<(Instant, Option<String>)>>,
+}
+
+impl Shedder {
+    pub(crate) fn new(limits: SoftLimits) -> Self {
+        Shedder { limits, sampled: Mutex::new(None) }
+    }
+
+    /// Why new connections should be shed right now, if they should
+    pub(crate) fn overloaded(&self) -> Option<String> {
+        let mut sampled = self.sampled.lock().unwrap();
+        match &*sampled {
+            Some((at, reason)) if at.elapsed() < SAMPLE_INTERVAL => reason.clone(),
+            previous => {
+                let reason = self.limits.exceeded(&ProcessUsage::sample());
+                let was_shedding = matches!(previous, Some((_, Some(_))));
+                match &reason {
+                    Some(reason) if !was_shedding => warn!("Shedding new connections: {}", reason),
+                    None if was_shedding => info!("No longer shedding new connections"),
+                    _ => {},
+                }
+                *sampled = Some((Instant::now(), reason.clone()));
+                reason
+            },
+        }
+    }
+}
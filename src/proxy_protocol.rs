@@ -0,0 +1,47 @@
+//! PROXY protocol headers sent to destinations, so they see the client's address
+//!
+//! Services behind HAProxy, or anything else accepting the PROXY protocol,
+//! take the client address from a header sent ahead of the connection's own
+//! bytes. Rules with a `proxy_protocol` of `v2` send the binary version 2
+//! header to the destinations they allow, naming the client merino relays
+//! for instead of merino itself. Destinations that don't expect the header
+//! will see garbage, so only enable it for those that do.
+use std::net::{IpAddr, SocketAddr};
+
+/// Start of every version 2 header
+pub const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version 2, PROXY command
+const VERSION_COMMAND: u8 = 0x21;
+
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+
+/// Version 2 header for a TCP connection from `client` to `destination`
+///
+/// Mixed address families are both sent as IPv6, with IPv4 addresses mapped.
+pub fn v2(client: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = SIGNATURE.to_vec();
+    header.push(VERSION_COMMAND);
+    match (client.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(target)) => {
+            header.push(TCP_OVER_IPV4);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&source.octets());
+            header.extend_from_slice(&target.octets());
+        },
+        (source, target) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.push(TCP_OVER_IPV6);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&v6(source).octets());
+            header.extend_from_slice(&v6(target).octets());
+        },
+    }
+    header.extend_from_slice(&client.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
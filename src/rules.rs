@@ -0,0 +1,965 @@
+//! Access control rules applied to incoming SOCKS requests
+//!
+//! Rules are loaded from a CSV file with the columns
+//! `action,user,source,destination,port`. Empty columns match anything and
+//! rules are evaluated top to bottom, the first match deciding the request.
+//!
+//! ```csv
+//! action,user,source,destination,port
+//! deny,,,*.internal.corp,
+//! allow,bob,10.0.0.0/8,example.com,443
+//! deny,,,*,25
+//! ```
+//!
+//! The optional `days`, `hours` and `timezone` columns restrict a rule to a
+//! schedule, e.g. `mon-fri`, `09:00-17:00` and `Europe/Berlin`. Hour ranges
+//! may wrap past midnight, and times are UTC unless a timezone is given.
+//!
+//! A `user` of `@name` matches every member of the group `name`, see
+//! [`crate::groups`].
+//!
+//! A `destination` of `AS15169` matches addresses announced by that
+//! autonomous system, once an ASN database is loaded, see [`crate::asn`].
+//! Domains are only resolved to find theirs when such a rule is reached,
+//! with the rule's `resolver`, and never for rules routing `via` Tor.
+//!
+//! Allow rules may also `rewrite` the destination to `host`, `host:port` or
+//! just `:port`, e.g. to force TLS or redirect a deprecated service.
+//!
+//! They can mark relayed traffic for downstream QoS too: `dscp` sets the
+//! DSCP value on the connection to the destination and `client_dscp` on the
+//! connection to the client, as a number from 0 to 63 or a name such as
+//! `ef`, `af41` or `cs1`. A `priority` of `high`, `normal` or `bulk` puts
+//! the session in a class sharing the bandwidth limit by weight, see
+//! [`crate::priority`].
+//!
+//! A `max_connections` column caps how many sessions an allow rule lets
+//! through at once, counted together for everything it matches, e.g. to keep
+//! a fragile backend from being flooded. Requests past the cap are denied.
+//!
+//! A `via` of `tor` connects the requests an allow rule lets through over the
+//! Tor SOCKS port given with `--tor`, each user (or, without one, each client
+//! address) on circuits of its own.
+//!
+//! A `proxy_protocol` of `v2` sends the destinations an allow rule lets
+//! through a PROXY protocol header naming the client, see
+//! [`crate::proxy_protocol`].
+//!
+//! A `resolver` resolves the names an allow rule lets through with a DNS
+//! server of its own, e.g. `dns:10.0.0.53` for internal domains or
+//! `doh:https://dns.example/dns-query`, or answers them all with a fixed
+//! address, e.g. `static:10.1.2.3`, see [`crate::dns`].
+//!
+//! A `tag` restricts a rule to requests whose username carried that tag,
+//! e.g. `alice+fast` for `fast`, when username tags are enabled, see
+//! [`split_tag`]. Standard SOCKS clients can then pick an egress, priority
+//! or resolver per stream, and rules without a tag match tagged requests too:
+//!
+//! ```csv
+//! action,user,destination,priority,via,tag
+//! allow,,,bulk,,backup
+//! allow,,,,tor,tor
+//! ```
+//!
+//! Large rule sets can be split across files: a `#include acl.d/*.csv` line
+//! loads the matching files, each with its own header, in name order at
+//! that point. Paths are relative to the including file and `*` or `?`
+//! may only appear in the file name. Other lines starting with `#` are
+//! comments.
+//!
+//! Rules with a `profile` only apply when merino runs with that profile,
+//! see [`Rules::profile`], so e.g. staging and production differences can
+//! live side by side in one file.
+use chrono::{DateTime, Datelike, NaiveTime, Timelike, Utc};
+use chrono_tz::Tz;
+use ipnet::IpNet;
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::dns::Resolver;
+use crate::priority::Priority;
+
+/// Outcome of evaluating a request against the rules
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Let the request through
+    Allow,
+    /// Refuse the request with a ruleset failure
+    Deny,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Allow => write!(f, "allow"),
+            Action::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// Requested destination of a SOCKS request
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Destination {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+impl Destination {
+    /// Parse a destination, treating anything that isn't an IP as a domain
+    pub fn parse(host: &str) -> Self {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        match host.parse::<IpAddr>() {
+            Ok(ip) => Destination::ip(ip),
+            Err(_) => Destination::Domain(host.to_lowercase()),
+        }
+    }
+
+    /// Destination `ip`, with IPv4-mapped IPv6 addresses as the IPv4 ones they are
+    ///
+    /// IPv4 rules and blocklists then apply to them, however they were written.
+    pub fn ip(ip: IpAddr) -> Self {
+        Destination::Ip(ip.to_canonical())
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Destination::Ip(ip) => write!(f, "{}", ip),
+            Destination::Domain(domain) => write!(f, "{}", domain),
+        }
+    }
+}
+
+/// The facts about a request that rules can match on
+#[derive(Clone, Debug)]
+pub struct Request<'a> {
+    pub source: IpAddr,
+    pub user: Option<&'a str>,
+    /// Groups the user belongs to
+    pub groups: &'a [String],
+    pub destination: &'a Destination,
+    pub port: u16,
+    /// Finds the autonomous system of the destination, when an ASN database is loaded
+    pub asn: Option<&'a AsnLookup<'a>>,
+    /// Tag the client passed in its username
+    pub tag: Option<&'a str>,
+}
+
+/// Autonomous system of a request's destination, looked up the first time a rule needs it
+///
+/// The lookup is given the resolver of the rule asking, to resolve domains
+/// with. Answers are kept for the rest of the request.
+pub struct AsnLookup<'a> {
+    lookup: Box<Lookup<'a>>,
+    found: RefCell<Vec<(Option<Resolver>, Option<u32>)>>,
+}
+
+type Lookup<'a> = dyn Fn(Option<&Resolver>) -> Option<u32> + 'a;
+
+impl<'a> AsnLookup<'a> {
+    pub fn new<F: Fn(Option<&Resolver>) -> Option<u32> + 'a>(lookup: F) -> Self {
+        AsnLookup { lookup: Box::new(lookup), found: RefCell::new(Vec::new()) }
+    }
+
+    /// A lookup answering `asn` without looking anything up
+    pub fn known(asn: Option<u32>) -> Self {
+        AsnLookup::new(move |_| asn)
+    }
+
+    /// The autonomous system, resolving domains with `resolver` or the system's
+    pub fn get(&self, resolver: Option<&Resolver>) -> Option<u32> {
+        if let Some((_, asn)) = self.found.borrow().iter().find(|(r, _)| r.as_ref() == resolver) {
+            return *asn;
+        }
+        let asn = (self.lookup)(resolver);
+        self.found.borrow_mut().push((resolver.cloned(), asn));
+        asn
+    }
+}
+
+impl fmt::Debug for AsnLookup<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsnLookup").field("found", &self.found.borrow()).finish()
+    }
+}
+
+/// Split a tag off a username, e.g. `alice+fast` into `alice` and `fast`
+///
+/// The tag follows the last `+` and may only contain letters, digits, `-`
+/// and `_`. Other usernames are returned whole, without a tag.
+pub fn split_tag(username: &str) -> (&str, Option<&str>) {
+    match username.rsplit_once('+') {
+        Some((user, tag)) if !user.is_empty() && valid_tag(tag) => (user, Some(tag)),
+        _ => (username, None),
+    }
+}
+
+fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty() && tag.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Pattern matched against the destination column
+#[derive(Clone, Debug, PartialEq)]
+enum HostPattern {
+    Any,
+    Net(IpNet),
+    /// `*.example.com`, matching subdomains only
+    Suffix(String),
+    Exact(String),
+    /// `AS15169`, matching addresses announced by that autonomous system
+    Asn(u32),
+}
+
+impl HostPattern {
+    fn parse(s: &str) -> Self {
+        if s.is_empty() || s == "*" {
+            HostPattern::Any
+        } else if let Ok(net) = parse_net(s) {
+            HostPattern::Net(net)
+        } else if let Some(asn) = crate::asn::parse_asn(s) {
+            HostPattern::Asn(asn)
+        } else if s.starts_with("*.") {
+            HostPattern::Suffix(s[1..].to_lowercase())
+        } else {
+            HostPattern::Exact(s.to_lowercase())
+        }
+    }
+
+    fn matches<F: FnOnce() -> Option<u32>>(&self, dest: &Destination, asn: F) -> bool {
+        match (self, dest) {
+            (HostPattern::Any, _) => true,
+            (HostPattern::Asn(number), _) => asn() == Some(*number),
+            (HostPattern::Net(net), Destination::Ip(ip)) => net.contains(ip),
+            (HostPattern::Suffix(suffix), Destination::Domain(domain)) => domain.ends_with(suffix.as_str()),
+            (HostPattern::Exact(host), Destination::Domain(domain)) => host == domain,
+            _ => false,
+        }
+    }
+}
+
+/// Parse either a CIDR (`10.0.0.0/8`) or a bare IP as a host network
+fn parse_net(s: &str) -> Result<IpNet, Box<dyn Error>> {
+    match s.parse::<IpNet>() {
+        Ok(net) => Ok(net),
+        Err(_) => Ok(IpNet::from(s.parse::<IpAddr>()?)),
+    }
+}
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+fn parse_weekday(s: &str) -> Result<usize, Box<dyn Error>> {
+    let day = s.trim().to_lowercase();
+    WEEKDAYS.iter()
+        .position(|name| day.starts_with(name))
+        .ok_or_else(|| format!("Invalid day `{}`", s).into())
+}
+
+/// When a rule is in effect
+#[derive(Clone, Debug, PartialEq)]
+struct Schedule {
+    /// Active days, indexed from Monday
+    days: Option<[bool; 7]>,
+    /// Active from the first time up to (excluding) the second
+    hours: Option<(NaiveTime, NaiveTime)>,
+    timezone: Tz,
+}
+
+impl Schedule {
+    fn parse(days: &str, hours: &str, timezone: &str) -> Result<Option<Self>, Box<dyn Error>> {
+        let days = match days {
+            "" | "*" => None,
+            list => {
+                let mut active = [false; 7];
+                for item in list.split([',', ' ']).filter(|item| !item.is_empty()) {
+                    let mut bounds = item.splitn(2, '-');
+                    let first = parse_weekday(bounds.next().unwrap_or_default())?;
+                    let last = match bounds.next() {
+                        Some(last) => parse_weekday(last)?,
+                        None => first,
+                    };
+                    // Ranges such as `fri-mon` wrap around the weekend
+                    let mut day = first;
+                    loop {
+                        active[day] = true;
+                        if day == last {
+                            break;
+                        }
+                        day = (day + 1) % 7;
+                    }
+                }
+                Some(active)
+            }
+        };
+
+        let hours = match hours {
+            "" | "*" => None,
+            range => {
+                let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                    .map_err(|e| format!("Invalid hours `{}`: {}", range, e));
+                let mut bounds = range.splitn(2, '-');
+                let start = parse(bounds.next().unwrap_or_default())?;
+                let end = match bounds.next() {
+                    Some(end) if end.trim() == "24:00" => NaiveTime::MIN,
+                    Some(end) => parse(end)?,
+                    None => return Err(format!("Invalid hours `{}`, expected HH:MM-HH:MM", range).into()),
+                };
+                Some((start, end))
+            }
+        };
+
+        let timezone = match timezone {
+            "" => Tz::UTC,
+            tz => tz.parse().map_err(|e| format!("Invalid timezone `{}`: {}", tz, e))?,
+        };
+
+        if days.is_none() && hours.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Schedule { days, hours, timezone }))
+    }
+
+    /// Is the schedule in effect at `now`
+    fn contains(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.timezone);
+        let time = NaiveTime::from_hms_opt(local.hour(), local.minute(), local.second()).unwrap_or_default();
+        let mut day = local.weekday();
+
+        if let Some((start, end)) = self.hours {
+            let active = if start < end {
+                start <= time && time < end
+            } else {
+                // Wraps past midnight, the early hours belong to the previous day
+                if time < end {
+                    day = day.pred();
+                }
+                time >= start || time < end
+            };
+            if !active {
+                return false;
+            }
+        }
+
+        match self.days {
+            Some(days) => days[day.num_days_from_monday() as usize],
+            None => true,
+        }
+    }
+}
+
+/// New destination for requests matching a rule
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rewrite {
+    /// Keep the requested host when `None`
+    pub host: Option<String>,
+    /// Keep the requested port when `None`
+    pub port: Option<u16>,
+}
+
+impl Rewrite {
+    fn parse(s: &str) -> Result<Self, Box<dyn Error>> {
+        let invalid = |e: &dyn fmt::Display| format!("Invalid rewrite `{}`: {}", s, e);
+        let (host, port) = match s.rfind(':') {
+            // A bare IPv6 address has colons but no port
+            Some(_) if s.parse::<std::net::Ipv6Addr>().is_ok() => (s, None),
+            Some(i) => (&s[..i], Some(s[i + 1..].parse::<u16>().map_err(|e| invalid(&e))?)),
+            None => (s, None),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Ok(Rewrite {
+            host: Some(host).filter(|h| !h.is_empty()).map(str::to_lowercase),
+            port,
+        })
+    }
+
+    /// The destination a request to `destination:port` is sent to
+    pub fn apply(&self, destination: &Destination, port: u16) -> (Destination, u16) {
+        let destination = match &self.host {
+            Some(host) => Destination::parse(host),
+            None => destination.clone(),
+        };
+        (destination, self.port.unwrap_or(port))
+    }
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.host {
+            Some(host) if host.contains(':') => write!(f, "[{}]", host)?,
+            Some(host) => write!(f, "{}", host)?,
+            None => {},
+        }
+        match self.port {
+            Some(port) => write!(f, ":{}", port),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Upstream a rule connects through instead of going to the destination directly
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Via {
+    /// The Tor SOCKS port, with streams isolated per user or client
+    Tor,
+}
+
+impl FromStr for Via {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tor" => Ok(Via::Tor),
+            _ => Err(format!("Unknown upstream `{}`, expected tor", s)),
+        }
+    }
+}
+
+impl fmt::Display for Via {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Via::Tor => write!(f, "tor"),
+        }
+    }
+}
+
+/// Most sessions a rule lets through at once, shared by the clones of its rule set
+#[derive(Clone, Debug)]
+pub struct Cap {
+    max: u64,
+    open: Arc<AtomicU64>,
+}
+
+/// One of a [`Cap`]'s sessions, given back when dropped
+#[derive(Debug)]
+pub struct CapSlot(Arc<AtomicU64>);
+
+impl Cap {
+    fn new(max: u64) -> Self {
+        Cap { max, open: Arc::default() }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// Sessions open under the cap
+    pub fn open(&self) -> u64 {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// Take one of the sessions, `None` if all are in use
+    pub fn claim(&self) -> Option<CapSlot> {
+        self.open.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| (open < self.max).then_some(open + 1)).ok()?;
+        Some(CapSlot(self.open.clone()))
+    }
+}
+
+/// Caps are equal by their limit, whatever is open under them
+impl PartialEq for Cap {
+    fn eq(&self, other: &Self) -> bool {
+        self.max == other.max
+    }
+}
+
+impl Drop for CapSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Parse a DSCP value given as a number or as `ef`, `afXY` or `csN`
+fn parse_dscp(s: &str) -> Result<u8, Box<dyn Error>> {
+    let invalid = || format!("Invalid DSCP `{}`", s);
+    let name = s.to_lowercase();
+    let dscp = match name.as_str() {
+        "ef" => 46,
+        _ if name.starts_with("cs") => match name[2..].parse::<u8>() {
+            Ok(class) if class <= 7 => class * 8,
+            _ => return Err(invalid().into()),
+        },
+        _ if name.starts_with("af") => match &name.as_bytes()[2..] {
+            [class @ b'1'..=b'4', drop @ b'1'..=b'3'] => (class - b'0') * 8 + (drop - b'0') * 2,
+            _ => return Err(invalid().into()),
+        },
+        _ => name.parse::<u8>().map_err(|_| invalid())?,
+    };
+    if dscp > 63 {
+        return Err(format!("DSCP `{}` must be between 0 and 63", s).into());
+    }
+    Ok(dscp)
+}
+
+/// How deeply included files may include others, so include cycles end
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Row of the rules file as it appears on disk
+#[derive(Debug, Deserialize)]
+struct RuleRecord {
+    action: Action,
+    #[serde(default)]
+    user: String,
+    #[serde(default)]
+    source: String,
+    #[serde(default)]
+    destination: String,
+    #[serde(default)]
+    port: String,
+    #[serde(default)]
+    days: String,
+    #[serde(default)]
+    hours: String,
+    #[serde(default)]
+    timezone: String,
+    #[serde(default)]
+    rewrite: String,
+    #[serde(default)]
+    dscp: String,
+    #[serde(default)]
+    client_dscp: String,
+    #[serde(default)]
+    priority: String,
+    #[serde(default)]
+    profile: String,
+    #[serde(default)]
+    via: String,
+    #[serde(default)]
+    max_connections: String,
+    #[serde(default)]
+    proxy_protocol: String,
+    #[serde(default)]
+    resolver: String,
+    #[serde(default)]
+    tag: String,
+}
+
+/// A single access control rule
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub action: Action,
+    user: Option<String>,
+    source: Option<IpNet>,
+    destination: HostPattern,
+    /// Inclusive port range
+    ports: Option<(u16, u16)>,
+    schedule: Option<Schedule>,
+    rewrite: Option<Rewrite>,
+    /// DSCP set on the connections to the destination and to the client
+    dscp: Option<u8>,
+    client_dscp: Option<u8>,
+    priority: Option<Priority>,
+    via: Option<Via>,
+    cap: Option<Cap>,
+    /// Send the destination a PROXY protocol v2 header
+    proxy_protocol: bool,
+    resolver: Option<Resolver>,
+    /// Only applies to requests tagged with this
+    tag: Option<String>,
+    /// Only applies when running with this profile
+    profile: Option<String>,
+    /// Raw columns, kept for display
+    raw: [String; 4],
+    raw_schedule: [String; 3],
+}
+
+impl Rule {
+    fn from_record(record: RuleRecord) -> Result<Self, Box<dyn Error>> {
+        let user = Some(record.user.trim()).filter(|u| !u.is_empty()).map(str::to_string);
+
+        let source = match record.source.trim() {
+            "" | "*" => None,
+            s => Some(parse_net(s).map_err(|e| format!("Invalid source `{}`: {}", s, e))?),
+        };
+
+        let destination = HostPattern::parse(record.destination.trim());
+
+        let ports = match record.port.trim() {
+            "" | "*" => None,
+            p => {
+                let mut bounds = p.splitn(2, '-');
+                let low: u16 = bounds.next().unwrap_or_default().parse()
+                    .map_err(|e| format!("Invalid port `{}`: {}", p, e))?;
+                let high: u16 = match bounds.next() {
+                    Some(high) => high.parse().map_err(|e| format!("Invalid port `{}`: {}", p, e))?,
+                    None => low,
+                };
+                Some((low, high))
+            }
+        };
+
+        let schedule = Schedule::parse(record.days.trim(), record.hours.trim(), record.timezone.trim())?;
+
+        let rewrite = match record.rewrite.trim() {
+            "" => None,
+            _ if record.action == Action::Deny => return Err("Only allow rules can rewrite the destination".into()),
+            r => Some(Rewrite::parse(r)?),
+        };
+
+        let mut marks = [None, None];
+        for (mark, column) in marks.iter_mut().zip([&record.dscp, &record.client_dscp]) {
+            *mark = match column.trim() {
+                "" => None,
+                _ if record.action == Action::Deny => return Err("Only allow rules can set DSCP".into()),
+                d => Some(parse_dscp(d)?),
+            };
+        }
+        let [dscp, client_dscp] = marks;
+
+        let priority = match record.priority.trim() {
+            "" => None,
+            _ if record.action == Action::Deny => return Err("Only allow rules can set a priority".into()),
+            p => Some(p.parse::<Priority>()?),
+        };
+
+        let via = match record.via.trim() {
+            "" => None,
+            _ if record.action == Action::Deny => return Err("Only allow rules can route via an upstream".into()),
+            v => Some(v.parse::<Via>()?),
+        };
+
+        let cap = match record.max_connections.trim() {
+            "" => None,
+            _ if record.action == Action::Deny => return Err("Only allow rules can cap connections".into()),
+            m => Some(Cap::new(m.parse().map_err(|e| format!("Invalid max_connections `{}`: {}", m, e))?)),
+        };
+
+        let proxy_protocol = match record.proxy_protocol.trim() {
+            "" => false,
+            _ if record.action == Action::Deny => return Err("Only allow rules can send a PROXY protocol header".into()),
+            "v2" => true,
+            p => return Err(format!("Invalid proxy_protocol `{}`, only v2 is supported", p).into()),
+        };
+
+        let resolver = match record.resolver.trim() {
+            "" => None,
+            _ if record.action == Action::Deny => return Err("Only allow rules can pick a resolver".into()),
+            r => Some(r.parse::<Resolver>()?),
+        };
+
+        let tag = match record.tag.trim() {
+            "" | "*" => None,
+            t if !valid_tag(t) => return Err(format!("Invalid tag `{}`, expected letters, digits, - and _", t).into()),
+            t => Some(t.to_string()),
+        };
+
+        let profile = Some(record.profile.trim()).filter(|p| !p.is_empty()).map(str::to_string);
+
+        Ok(Rule {
+            action: record.action,
+            user,
+            source,
+            destination,
+            ports,
+            schedule,
+            rewrite,
+            dscp,
+            client_dscp,
+            priority,
+            via,
+            cap,
+            proxy_protocol,
+            resolver,
+            tag,
+            profile,
+            raw: [record.user, record.source, record.destination, record.port],
+            raw_schedule: [record.days, record.hours, record.timezone],
+        })
+    }
+
+    /// Where requests this rule allows are sent instead, if anywhere
+    pub fn rewrite(&self) -> Option<&Rewrite> {
+        self.rewrite.as_ref()
+    }
+
+    /// DSCP to mark traffic to the destination with, if any
+    pub fn dscp(&self) -> Option<u8> {
+        self.dscp
+    }
+
+    /// DSCP to mark traffic to the client with, if any
+    pub fn client_dscp(&self) -> Option<u8> {
+        self.client_dscp
+    }
+
+    /// Class the sessions this rule allows are relayed in, if not normal
+    pub fn priority(&self) -> Option<Priority> {
+        self.priority
+    }
+
+    /// Upstream the requests this rule allows are connected through, if not directly
+    pub fn via(&self) -> Option<Via> {
+        self.via
+    }
+
+    /// Cap on the sessions this rule lets through at once, if any
+    pub fn cap(&self) -> Option<&Cap> {
+        self.cap.as_ref()
+    }
+
+    /// Do destinations this rule allows get a PROXY protocol header
+    pub fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    /// Resolver for the names this rule allows, if not the system's
+    pub fn resolver(&self) -> Option<&Resolver> {
+        self.resolver.as_ref()
+    }
+
+    /// Does this rule apply to `req` made at `now`
+    pub fn matches(&self, req: &Request, now: DateTime<Utc>) -> bool {
+        self.matches_except_destination(req, now) && self.destination.matches(req.destination, || match (req.asn, req.destination) {
+            // Tor resolves the names it carries, resolving them here would leak them
+            (Some(_), Destination::Domain(_)) if self.via == Some(Via::Tor) => None,
+            (Some(lookup), _) => lookup.get(self.resolver.as_ref()),
+            (None, _) => None,
+        })
+    }
+
+    /// Does the destination column only match domain names
+    pub fn is_domain_only(&self) -> bool {
+        matches!(self.destination, HostPattern::Suffix(_) | HostPattern::Exact(_))
+    }
+
+    fn matches_except_destination(&self, req: &Request, now: DateTime<Utc>) -> bool {
+        if let Some(schedule) = &self.schedule {
+            if !schedule.contains(now) {
+                return false;
+            }
+        }
+
+        match self.user.as_deref().map(|user| user.strip_prefix('@')) {
+            Some(Some(group)) if !req.groups.iter().any(|g| g == group) => return false,
+            Some(None) if req.user != self.user.as_deref() => return false,
+            _ => {},
+        }
+
+        if let Some(net) = &self.source {
+            if !net.contains(&req.source) {
+                return false;
+            }
+        }
+
+        if let Some((low, high)) = self.ports {
+            if req.port < low || req.port > high {
+                return false;
+            }
+        }
+
+        if self.tag.is_some() && req.tag != self.tag.as_deref() {
+            return false;
+        }
+
+        true
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let column = |s: &str| if s.trim().is_empty() { "*".to_string() } else { s.trim().to_string() };
+        write!(f, "{} user={} source={} destination={} port={}",
+               self.action,
+               column(&self.raw[0]),
+               column(&self.raw[1]),
+               column(&self.raw[2]),
+               column(&self.raw[3]))?;
+        if self.schedule.is_some() {
+            write!(f, " days={} hours={} timezone={}",
+                   column(&self.raw_schedule[0]),
+                   column(&self.raw_schedule[1]),
+                   if self.raw_schedule[2].trim().is_empty() { "UTC" } else { self.raw_schedule[2].trim() })?;
+        }
+        if let Some(rewrite) = &self.rewrite {
+            write!(f, " rewrite={}", rewrite)?;
+        }
+        if let Some(dscp) = self.dscp {
+            write!(f, " dscp={}", dscp)?;
+        }
+        if let Some(dscp) = self.client_dscp {
+            write!(f, " client_dscp={}", dscp)?;
+        }
+        if let Some(priority) = self.priority {
+            write!(f, " priority={}", priority)?;
+        }
+        if let Some(via) = self.via {
+            write!(f, " via={}", via)?;
+        }
+        if let Some(cap) = &self.cap {
+            write!(f, " max_connections={}", cap.max)?;
+        }
+        if self.proxy_protocol {
+            write!(f, " proxy_protocol=v2")?;
+        }
+        if let Some(resolver) = &self.resolver {
+            write!(f, " resolver={}", resolver)?;
+        }
+        if let Some(tag) = &self.tag {
+            write!(f, " tag={}", tag)?;
+        }
+        if let Some(profile) = &self.profile {
+            write!(f, " profile={}", profile)?;
+        }
+        Ok(())
+    }
+}
+
+/// Result of evaluating a request against the rules
+#[derive(Debug, PartialEq)]
+pub struct Verdict<'a> {
+    pub action: Action,
+    /// Index and rule that decided the request, `None` if the default applied
+    pub rule: Option<(usize, &'a Rule)>,
+}
+
+/// Ordered set of access control rules
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// Parse rules from CSV, including files relative to the current directory
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut rules = Vec::new();
+        parse(reader, Path::new("."), 0, &mut rules)?;
+        Ok(Rules { rules })
+    }
+
+    /// Load rules from a CSV file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let mut rules = Vec::new();
+        load(path.as_ref(), 0, &mut rules)?;
+        Ok(Rules { rules })
+    }
+
+    /// The rules in the order they are evaluated
+    pub fn iter(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.iter()
+    }
+
+    /// Keep only the rules for the profile `name` and those without a profile
+    pub fn profile(self, name: &str) -> Self {
+        if !self.rules.iter().any(|rule| rule.profile.as_deref() == Some(name)) {
+            warn!("No rules are specific to the profile {}", name);
+        }
+        let rules = self.rules.into_iter()
+            .filter(|rule| rule.profile.as_deref().is_none_or(|profile| profile == name))
+            .collect();
+        Rules { rules }
+    }
+
+    /// Number of loaded rules
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Are there no rules loaded
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Find the first rule matching `req`, allowing the request if none do
+    pub fn evaluate(&self, req: &Request) -> Verdict<'_> {
+        self.evaluate_at(req, Utc::now())
+    }
+
+    /// The first domain-only rule that would apply to `req` if it named its destination by domain
+    ///
+    /// A client resolving a name itself and requesting the IP bypasses such
+    /// rules, so callers may want to refuse literal IPs while one applies.
+    pub fn domain_rule(&self, req: &Request) -> Option<(usize, &Rule)> {
+        let now = Utc::now();
+        self.rules.iter().enumerate().find(|(_, rule)| rule.is_domain_only() && rule.matches_except_destination(req, now))
+    }
+
+    /// Evaluate `req` as if it were made at `now`
+    pub fn evaluate_at(&self, req: &Request, now: DateTime<Utc>) -> Verdict<'_> {
+        match self.rules.iter().enumerate().find(|(_, rule)| rule.matches(req, now)) {
+            Some((index, rule)) => Verdict { action: rule.action, rule: Some((index, rule)) },
+            None => Verdict { action: Action::Allow, rule: None },
+        }
+    }
+}
+
+fn load(path: &Path, depth: usize, rules: &mut Vec<Rule>) -> Result<(), Box<dyn Error>> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    parse(file, dir, depth, rules).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Append the rules in `reader` to `rules`, resolving includes relative to `dir`
+fn parse<R: Read>(mut reader: R, dir: &Path, depth: usize, rules: &mut Vec<Rule>) -> Result<(), Box<dyn Error>> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+
+    // Rows since the last include, parsed under the file's header before loading the next one
+    let mut header = None;
+    let mut rows = String::new();
+    for line in text.lines() {
+        if let Some(pattern) = line.strip_prefix("#include ") {
+            parse_rows(header.unwrap_or_default(), &rows, rules)?;
+            rows.clear();
+            if depth >= MAX_INCLUDE_DEPTH {
+                return Err(format!("Includes nested more than {} deep at {}", MAX_INCLUDE_DEPTH, pattern.trim()).into());
+            }
+            for path in expand(&dir.join(pattern.trim()))? {
+                load(&path, depth + 1, rules)?;
+            }
+        } else if header.is_none() && !line.starts_with('#') && !line.trim().is_empty() {
+            header = Some(line);
+        } else {
+            rows.push_str(line);
+            rows.push('\n');
+        }
+    }
+    parse_rows(header.unwrap_or_default(), &rows, rules)
+}
+
+fn parse_rows(header: &str, rows: &str, rules: &mut Vec<Rule>) -> Result<(), Box<dyn Error>> {
+    let csv = format!("{}\n{}", header, rows);
+    let mut rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).comment(Some(b'#')).from_reader(csv.as_bytes());
+    for result in rdr.deserialize() {
+        let record: RuleRecord = result?;
+        let rule = Rule::from_record(record)?;
+        trace!("Loaded rule: {}", rule);
+        rules.push(rule);
+    }
+    Ok(())
+}
+
+/// Files matching `pattern`, in name order, or `pattern` itself if it has no wildcards
+fn expand(pattern: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let name = pattern.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return Ok(vec![pattern.to_path_buf()]);
+    }
+    let dir = pattern.parent().unwrap_or(Path::new("."));
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && wildcard(name.as_bytes(), entry.file_name().as_encoded_bytes()) {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Does `name` match `pattern`, where `*` matches any run of bytes and `?` any one byte
+fn wildcard(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some((b'*', rest)), _) => wildcard(rest, name) || (!name.is_empty() && wildcard(pattern, &name[1..])),
+        (Some((b'?', rest)), Some((_, name))) => wildcard(rest, name),
+        (Some((p, rest)), Some((n, name))) => p == n && wildcard(rest, name),
+        (Some(_), None) => false,
+    }
+}
@@ -0,0 +1,270 @@
+//! Confining the process once it is set up
+//!
+//! [`seccomp`] installs a seccomp-bpf filter allowing only the system calls
+//! serving clients needs. Anything else, such as `execve` or `ptrace`, fails
+//! with `EPERM`, so a bug in the SOCKS parser can't be turned into running
+//! other programs. Call it after binding listeners and loading every file
+//! read at startup; files written while serving, like the access log and
+//! ban list, stay usable.
+//!
+//! [`Landlock`] confines the filesystem instead: only the paths it is given,
+//! such as the users file and log directory, can be opened afterwards.
+//!
+//! On OpenBSD, [`pledge`] does both with the platform's own pledge(2) and
+//! unveil(2).
+use std::io;
+use std::path::PathBuf;
+
+/// `AUDIT_ARCH_*` of the architecture the filter is built for
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+/// System calls allowed once the filter is installed
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+const ALLOWED: &[libc::c_long] = &[
+    // Relaying and socket setup
+    libc::SYS_read, libc::SYS_write, libc::SYS_readv, libc::SYS_writev, libc::SYS_close,
+    libc::SYS_socket, libc::SYS_socketpair, libc::SYS_connect, libc::SYS_accept, libc::SYS_accept4,
+    libc::SYS_bind, libc::SYS_listen, libc::SYS_shutdown, libc::SYS_setsockopt, libc::SYS_getsockopt,
+    libc::SYS_getsockname, libc::SYS_getpeername, libc::SYS_recvfrom, libc::SYS_sendto,
+    libc::SYS_recvmsg, libc::SYS_sendmsg, libc::SYS_recvmmsg, libc::SYS_sendmmsg,
+    libc::SYS_fcntl, libc::SYS_ioctl, libc::SYS_ppoll, libc::SYS_pselect6,
+    libc::SYS_epoll_create1, libc::SYS_epoll_ctl, libc::SYS_epoll_pwait, libc::SYS_eventfd2, libc::SYS_pipe2,
+    // Threads, memory and signals
+    libc::SYS_clone, libc::SYS_clone3, libc::SYS_futex, libc::SYS_set_robust_list, libc::SYS_rseq,
+    libc::SYS_set_tid_address, libc::SYS_sched_yield, libc::SYS_sched_getaffinity,
+    libc::SYS_mmap, libc::SYS_munmap, libc::SYS_mprotect, libc::SYS_madvise, libc::SYS_mremap, libc::SYS_brk,
+    libc::SYS_rt_sigaction, libc::SYS_rt_sigprocmask, libc::SYS_rt_sigtimedwait, libc::SYS_rt_sigreturn, libc::SYS_sigaltstack,
+    libc::SYS_tgkill, libc::SYS_exit, libc::SYS_exit_group, libc::SYS_restart_syscall,
+    libc::SYS_getpid, libc::SYS_gettid, libc::SYS_prctl, libc::SYS_prlimit64,
+    // Time and randomness
+    libc::SYS_nanosleep, libc::SYS_clock_nanosleep, libc::SYS_clock_gettime, libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    // Logs, the ban list, name resolution reading /etc and usage read from /proc
+    libc::SYS_openat, libc::SYS_newfstatat, libc::SYS_fstat, libc::SYS_statx, libc::SYS_lseek, libc::SYS_getdents64,
+    libc::SYS_fsync, libc::SYS_fdatasync, libc::SYS_renameat, libc::SYS_renameat2, libc::SYS_unlinkat,
+    libc::SYS_faccessat, libc::SYS_uname, libc::SYS_getuid, libc::SYS_geteuid, libc::SYS_getgid, libc::SYS_getegid,
+    // The file access of SQLite's unix VFS, for the user database
+    #[cfg(feature = "sqlite")] libc::SYS_pread64,
+    #[cfg(feature = "sqlite")] libc::SYS_pwrite64,
+    #[cfg(feature = "sqlite")] libc::SYS_ftruncate,
+    #[cfg(feature = "sqlite")] libc::SYS_fallocate,
+    #[cfg(feature = "sqlite")] libc::SYS_fchown,
+    #[cfg(feature = "sqlite")] libc::SYS_fchmod,
+    #[cfg(feature = "sqlite")] libc::SYS_getcwd,
+    #[cfg(feature = "sqlite")] libc::SYS_readlinkat,
+    #[cfg(feature = "sqlite")] libc::SYS_mkdirat,
+    #[cfg(all(feature = "sqlite", target_arch = "x86_64"))] libc::SYS_readlink,
+    #[cfg(all(feature = "sqlite", target_arch = "x86_64"))] libc::SYS_mkdir,
+    #[cfg(all(feature = "sqlite", target_arch = "x86_64"))] libc::SYS_rmdir,
+    #[cfg(target_arch = "x86_64")] libc::SYS_open,
+    #[cfg(target_arch = "x86_64")] libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")] libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")] libc::SYS_access,
+    #[cfg(target_arch = "x86_64")] libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")] libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")] libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")] libc::SYS_select,
+    #[cfg(target_arch = "x86_64")] libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")] libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")] libc::SYS_arch_prctl,
+];
+
+/// Restrict every thread of the process to the system calls serving clients needs
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn seccomp() -> io::Result<()> {
+    use libc::{sock_filter, BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+
+    let statement = |code: u32, k: u32| sock_filter { code: code as u16, jt: 0, jf: 0, k };
+    let jump = |k: u32, jt: u8, jf: u8| sock_filter { code: (BPF_JMP | BPF_JEQ | BPF_K) as u16, jt, jf, k };
+    // Offsets into struct seccomp_data
+    let (nr, arch) = (0, 4);
+
+    // System calls made for another architecture could bypass the list, so they end the process
+    let mut filter = vec![
+        statement(BPF_LD | BPF_W | BPF_ABS, arch),
+        jump(AUDIT_ARCH, 1, 0),
+        statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD | BPF_W | BPF_ABS, nr),
+    ];
+    for syscall in ALLOWED {
+        filter.push(jump(*syscall as u32, 0, 1));
+        filter.push(statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    }
+    filter.push(statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32));
+
+    merino_sys::set_no_new_privs()?;
+    merino_sys::seccomp(&filter)
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub fn seccomp() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "seccomp is only supported on Linux x86_64 and aarch64"))
+}
+
+/// What a [`Landlock`] or unveiled path may be used for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    /// Reading files and listing directories
+    Read,
+    /// Reading, plus writing, creating and removing files
+    Write,
+}
+
+/// Files read by name resolution, time zones and the NSS modules glibc loads for it, the cgroup's limits and usage, and the process's own
+const SYSTEM_READ: &[&str] = &[
+    "/etc/hosts", "/etc/resolv.conf", "/etc/nsswitch.conf", "/etc/host.conf", "/etc/gai.conf",
+    "/etc/services", "/etc/localtime", "/etc/ssl", "/usr/share/zoneinfo", "/proc/self/cgroup", "/sys/fs/cgroup",
+    "/proc/self/fd", "/proc/self/status",
+];
+const SYSTEM_LIBRARIES: &[&str] = &["/lib", "/lib64", "/usr/lib", "/usr/lib64"];
+
+/// Filesystem confinement with Landlock
+///
+/// After [`Landlock::restrict`], files can only be opened beneath the paths
+/// added here, plus the system files name resolution needs. Files opened
+/// before, like the access log, stay usable. Only the calling thread and
+/// threads it spawns afterwards are confined, so restrict before serving.
+#[derive(Clone, Debug, Default)]
+pub struct Landlock {
+    paths: Vec<(PathBuf, Access)>,
+}
+
+impl Landlock {
+    pub fn new() -> Self {
+        Landlock::default()
+    }
+
+    /// Allow reading `path`, or everything beneath it if it's a directory
+    pub fn read<P: Into<PathBuf>>(self, path: P) -> Self {
+        self.allow(path, Access::Read)
+    }
+
+    /// Allow reading and writing `path`, and creating or removing files beneath it if it's a directory
+    pub fn write<P: Into<PathBuf>>(self, path: P) -> Self {
+        self.allow(path, Access::Write)
+    }
+
+    /// Allow `access` to `path`
+    pub fn allow<P: Into<PathBuf>>(mut self, path: P, access: Access) -> Self {
+        self.paths.push((path.into(), access));
+        self
+    }
+
+    /// Confine the calling thread and the threads it spawns from now on
+    ///
+    /// Returns `false`, leaving the process unconfined, when the kernel
+    /// doesn't support Landlock. Paths that don't exist are an error.
+    #[cfg(target_os = "linux")]
+    pub fn restrict(&self) -> io::Result<bool> {
+        landlock::restrict(&self.paths)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn restrict(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::os::fd::AsFd;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::{Path, PathBuf};
+
+    use super::{Access, SYSTEM_LIBRARIES, SYSTEM_READ};
+
+    const EXECUTE: u64 = 1 << 0;
+    const WRITE_FILE: u64 = 1 << 1;
+    const READ_FILE: u64 = 1 << 2;
+    const READ_DIR: u64 = 1 << 3;
+    const REMOVE_FILE: u64 = 1 << 5;
+    const MAKE_REG: u64 = 1 << 8;
+    const TRUNCATE: u64 = 1 << 14;
+    /// Every right of the first Landlock ABI
+    const ABI_1: u64 = (1 << 13) - 1;
+    /// Rights that apply to files rather than directories
+    const FILE_RIGHTS: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+
+    fn open(path: &Path) -> io::Result<File> {
+        OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_CLOEXEC).open(path)
+    }
+
+    pub(super) fn restrict(paths: &[(PathBuf, Access)]) -> io::Result<bool> {
+        let abi = match merino_sys::landlock::abi() {
+            Some(abi) => abi,
+            None => return Ok(false),
+        };
+        let handled = if abi >= 3 { ABI_1 | TRUNCATE } else { ABI_1 };
+
+        let ruleset = merino_sys::landlock::create_ruleset(handled)?;
+
+        let add = |file: &File, access: u64| -> io::Result<()> {
+            let access = if file.metadata()?.is_dir() { access } else { access & FILE_RIGHTS };
+            merino_sys::landlock::add_path_beneath(ruleset.as_fd(), file.as_fd(), access & handled)
+        };
+
+        for (path, access) in paths {
+            let file = open(path).map_err(|error| io::Error::new(error.kind(), format!("{}: {}", path.display(), error)))?;
+            match access {
+                Access::Read => add(&file, READ_FILE | READ_DIR)?,
+                Access::Write => add(&file, READ_FILE | READ_DIR | WRITE_FILE | REMOVE_FILE | MAKE_REG | TRUNCATE)?,
+            }
+        }
+        for path in SYSTEM_READ {
+            if let Ok(file) = open(Path::new(path)) {
+                add(&file, READ_FILE | READ_DIR)?;
+            }
+        }
+        for path in SYSTEM_LIBRARIES {
+            if let Ok(file) = open(Path::new(path)) {
+                add(&file, READ_FILE | READ_DIR | EXECUTE)?;
+            }
+        }
+
+        merino_sys::set_no_new_privs()?;
+        merino_sys::landlock::restrict_self(ruleset.as_fd())?;
+        Ok(true)
+    }
+}
+
+/// Limit the process to the `promises` of pledge(2), unveiling only `paths` and the system files name resolution reads
+///
+/// `rpath`, `wpath` and `cpath` are needed on top of e.g. `stdio inet dns`
+/// for unveiled paths to be opened at all.
+#[cfg(target_os = "openbsd")]
+pub fn pledge(promises: &str, paths: &[(PathBuf, Access)]) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let unveil = |path: &std::path::Path, permissions: &str| -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let permissions = CString::new(permissions)?;
+        match merino_sys::unveil(&path, &permissions) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    };
+
+    for (path, access) in paths {
+        unveil(path, match access {
+            Access::Read => "r",
+            Access::Write => "rwc",
+        })?;
+    }
+    for path in SYSTEM_READ {
+        unveil(std::path::Path::new(path), "r")?;
+    }
+    let promises = CString::new(promises)?;
+    merino_sys::lock_unveil()?;
+    merino_sys::pledge(&promises)
+}
+
+#[cfg(not(target_os = "openbsd"))]
+pub fn pledge(_promises: &str, _paths: &[(PathBuf, Access)]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "pledge is only supported on OpenBSD"))
+}
@@ -0,0 +1,98 @@
+//! Rhai scripts as a programmable rules engine
+//!
+//! A script defines `on_request(ctx)`, receiving a map with `session`,
+//! `user` (empty when unauthenticated), `src`, `dst` and `port`, and returns
+//! `"allow"`, `"deny"` or a boolean (`true` allowing the request):
+//!
+//! ```rhai
+//! fn on_request(ctx) {
+//!     if ctx.port == 25 { return "deny"; }
+//!     if ctx.dst.ends_with(".internal.corp") && ctx.user != "admin" { return "deny"; }
+//!     "allow"
+//! }
+//! ```
+//!
+//! `on_connect_result(session, connected)` and `on_close(session, bytes)`
+//! may also be defined to observe sessions.
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use std::error::Error;
+use std::path::Path;
+
+use crate::plugin::Plugin;
+use crate::rules::{Action, Request};
+
+/// A compiled policy script
+pub struct ScriptPlugin {
+    engine: Engine,
+    ast: AST,
+    has_connect_result: bool,
+    has_close: bool,
+}
+
+impl ScriptPlugin {
+    /// Compile the script at `path`
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::from_source(&std::fs::read_to_string(path)?)
+    }
+
+    /// Compile a script from source
+    pub fn from_source(source: &str) -> Result<Self, Box<dyn Error>> {
+        let mut engine = Engine::new();
+        engine.on_print(|text| info!("[script] {}", text));
+        engine.on_debug(|text, _, _| debug!("[script] {}", text));
+
+        let ast = engine.compile(source)?;
+        let defines = |name: &str| ast.iter_functions().any(|f| f.name == name);
+        if !defines("on_request") {
+            return Err("Script does not define on_request(ctx)".into());
+        }
+
+        Ok(ScriptPlugin {
+            has_connect_result: defines("on_connect_result"),
+            has_close: defines("on_close"),
+            engine,
+            ast,
+        })
+    }
+}
+
+impl Plugin for ScriptPlugin {
+    fn on_request(&self, session: u64, request: &Request) -> Result<Action, Box<dyn Error>> {
+        let mut ctx = Map::new();
+        ctx.insert("session".into(), Dynamic::from(session as i64));
+        ctx.insert("user".into(), Dynamic::from(request.user.unwrap_or("").to_string()));
+        ctx.insert("src".into(), Dynamic::from(request.source.to_string()));
+        ctx.insert("dst".into(), Dynamic::from(request.destination.to_string()));
+        ctx.insert("port".into(), Dynamic::from(i64::from(request.port)));
+
+        let decision: Dynamic = self.engine.call_fn(&mut Scope::new(), &self.ast, "on_request", (ctx,))?;
+
+        if let Some(allow) = decision.clone().try_cast::<bool>() {
+            return Ok(if allow { Action::Allow } else { Action::Deny });
+        }
+        match decision.into_string().as_deref() {
+            Ok("allow") => Ok(Action::Allow),
+            Ok("deny") => Ok(Action::Deny),
+            Ok(other) => Err(format!("Script returned unknown decision `{}`", other).into()),
+            Err(kind) => Err(format!("Script returned a {} instead of a decision", kind).into()),
+        }
+    }
+
+    fn on_connect_result(&self, session: u64, connected: bool) {
+        if self.has_connect_result {
+            let result: Result<Dynamic, _> = self.engine.call_fn(&mut Scope::new(), &self.ast, "on_connect_result", (session as i64, connected));
+            if let Err(error) = result {
+                warn!("Script failed in on_connect_result: {}", error);
+            }
+        }
+    }
+
+    fn on_close(&self, session: u64, bytes: u64) {
+        if self.has_close {
+            let result: Result<Dynamic, _> = self.engine.call_fn(&mut Scope::new(), &self.ast, "on_close", (session as i64, bytes as i64));
+            if let Err(error) = result {
+                warn!("Script failed in on_close: {}", error);
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! Secrets referenced from the command line instead of written into it
+//!
+//! Options carrying credentials, such as `--postgres` or `--redis`, accept a
+//! reference in place of the value:
+//!
+//! ```text
+//! env:MERINO_DB_DSN                  environment variable
+//! credential:db-dsn                  systemd credential (LoadCredential=, SetCredentialEncrypted=)
+//! file:/run/secrets/db-dsn           file, e.g. a mounted Docker or Kubernetes secret
+//! vault:secret/data/merino#dsn       field of a HashiCorp Vault KV secret
+//! ```
+//!
+//! Anything else is taken literally. Trailing newlines are trimmed from
+//! files. Vault is reached at `VAULT_ADDR` with `VAULT_TOKEN`, over HTTPS, or
+//! plain HTTP for a local Vault Agent listener.
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+
+/// The value `reference` points to, or `reference` itself if it isn't a reference
+pub fn resolve(reference: &str) -> Result<String, Box<dyn Error>> {
+    let (scheme, name) = match reference.split_once(':') {
+        Some(split) => split,
+        None => return Ok(reference.to_string()),
+    };
+    match scheme {
+        "env" => env::var(name).map_err(|_| format!("environment variable {} isn't set", name).into()),
+        "credential" => {
+            // Set by systemd for units with credentials
+            let dir = env::var_os("CREDENTIALS_DIRECTORY").ok_or("CREDENTIALS_DIRECTORY isn't set, no systemd credentials are available")?;
+            read(PathBuf::from(dir).join(name))
+        },
+        "file" => read(PathBuf::from(name)),
+        "vault" => vault::read(name),
+        _ => Ok(reference.to_string()),
+    }
+}
+
+/// Is `value` a reference to a secret rather than the secret itself
+pub fn is_reference(value: &str) -> bool {
+    matches!(value.split_once(':'), Some(("env" | "credential" | "file" | "vault", _)))
+}
+
+fn read(path: PathBuf) -> Result<String, Box<dyn Error>> {
+    let contents = fs::read_to_string(&path).map_err(|error| format!("{}: {}", path.display(), error))?;
+    Ok(contents.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(feature = "vault")]
+mod vault {
+    use std::env;
+    use std::error::Error;
+    use std::time::Duration;
+
+    /// Field `path#field` of a KV secret, version 1 or 2
+    pub(super) fn read(reference: &str) -> Result<String, Box<dyn Error>> {
+        let (path, field) = reference.split_once('#').ok_or("Vault references need a field, e.g. vault:secret/data/merino#password")?;
+        let addr = env::var("VAULT_ADDR").map_err(|_| "VAULT_ADDR isn't set")?;
+        let token = env::var("VAULT_TOKEN").map_err(|_| "VAULT_TOKEN isn't set")?;
+        if !addr.starts_with("https://") && !addr.starts_with("http://") {
+            return Err("VAULT_ADDR must be an https:// or http:// address".into());
+        }
+
+        let url = format!("{}/v1/{}", addr.trim_end_matches('/'), path.trim_start_matches('/'));
+        let body = crate::http::agent(Duration::from_secs(10)).get(&url)
+            .set("X-Vault-Token", &token)
+            .set("Accept", "application/json")
+            .call()
+            .map_err(|error| format!("Vault failed to read {}: {}", path, crate::http::error(error)))?
+            .into_string()?;
+        let secret: serde_json::Value = serde_json::from_str(&body)?;
+        // KV version 2 nests the secret's fields one level deeper
+        let data = match secret["data"].get("data") {
+            Some(data) if data.is_object() => data,
+            _ => &secret["data"],
+        };
+        match &data[field] {
+            serde_json::Value::String(value) => Ok(value.clone()),
+            serde_json::Value::Null => Err(format!("Vault secret {} has no field {}", path, field).into()),
+            value => Ok(value.to_string()),
+        }
+    }
+}
+
+#[cfg(not(feature = "vault"))]
+mod vault {
+    use std::error::Error;
+
+    pub(super) fn read(_reference: &str) -> Result<String, Box<dyn Error>> {
+        Err("merino was built without Vault support, enable the `vault` feature".into())
+    }
+}
@@ -0,0 +1,470 @@
+//! Shadowsocks inbound, for clients in networks that block plain SOCKS
+//!
+//! Serves the AEAD protocol (SIP004) with the `chacha20-ietf-poly1305`
+//! cipher on a listener of its own. Each direction starts with a random
+//! salt, from which a subkey is derived with the password's key; everything
+//! after it is sealed in length-prefixed chunks. The client's first bytes
+//! name the destination as a SOCKS5 address, after which the connection goes
+//! through the same access rules, plugins and accounting as a forward.
+//!
+//! There are no users: knowing the password gets a client in, and the rules
+//! match it by source address. Salts are remembered to turn replayed
+//! connections away, and a client failing authentication is read from until
+//! it gives up rather than closed on, as probes tell the two apart.
+use chacha20poly1305::{AeadInOut, ChaCha20Poly1305, KeyInit};
+use chrono::{DateTime, Local};
+use hkdf::Hkdf;
+use md5::{Digest, Md5};
+use rand::RngCore;
+use sha1::Sha1;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, prelude::*};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+
+use crate::{access_log, forward, Connection, MerinoError};
+use crate::{Settings, NEXT_SESSION};
+
+/// The only cipher served
+pub const CIPHER: &str = "chacha20-ietf-poly1305";
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+/// Most payload sealed in one chunk
+const MAX_PAYLOAD: usize = 0x3FFF;
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
+
+/// Salts remembered before the older half is forgotten
+const REMEMBERED_SALTS: usize = 1 << 16;
+
+/// How long a client failing authentication is read from before it is closed on
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const ADDR_IPV4: u8 = 1;
+const ADDR_DOMAIN: u8 = 3;
+const ADDR_IPV6: u8 = 4;
+
+/// A Shadowsocks listener and the key its clients share
+#[derive(Clone)]
+pub struct Inbound {
+    /// Address to accept connections on
+    pub listen: SocketAddr,
+    key: [u8; KEY_LEN],
+    salts: Arc<Mutex<Salts>>,
+}
+
+impl Inbound {
+    pub fn new(listen: SocketAddr, password: &str) -> Self {
+        Inbound { listen, key: key(password), salts: Arc::default() }
+    }
+}
+
+impl fmt::Display for Inbound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.listen, CIPHER)
+    }
+}
+
+impl fmt::Debug for Inbound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Inbound").field("listen", &self.listen).finish_non_exhaustive()
+    }
+}
+
+/// Key derived from `password` as Shadowsocks clients do, with OpenSSL's `EVP_BytesToKey`
+pub fn key(password: &str) -> [u8; KEY_LEN] {
+    let mut key = [0u8; KEY_LEN];
+    let mut digest = Vec::new();
+    for chunk in key.chunks_mut(16) {
+        digest = Md5::new().chain_update(&digest).chain_update(password).finalize().to_vec();
+        chunk.copy_from_slice(&digest);
+    }
+    key
+}
+
+/// Salts seen recently, in two generations so the oldest can be dropped together
+#[derive(Debug, Default)]
+struct Salts {
+    current: HashSet<[u8; SALT_LEN]>,
+    previous: HashSet<[u8; SALT_LEN]>,
+}
+
+impl Salts {
+    /// Remember `salt`, false if it was already seen
+    fn insert(&mut self, salt: [u8; SALT_LEN]) -> bool {
+        if self.previous.contains(&salt) || !self.current.insert(salt) {
+            return false;
+        }
+        if self.current.len() >= REMEMBERED_SALTS / 2 {
+            self.previous = std::mem::take(&mut self.current);
+        }
+        true
+    }
+}
+
+/// A connection with a Shadowsocks peer, reading and writing plaintext
+///
+/// Clones share the cipher state, so one thread can read while another writes.
+pub struct Stream<S> {
+    inner: S,
+    key: [u8; KEY_LEN],
+    reader: Arc<Mutex<Reader>>,
+    writer: Arc<Mutex<Option<Cipher>>>,
+    /// Where salts are checked for replays, on the server side
+    salts: Option<Arc<Mutex<Salts>>>,
+}
+
+#[derive(Default)]
+struct Reader {
+    cipher: Option<Cipher>,
+    plaintext: Vec<u8>,
+    read: usize,
+}
+
+impl<S> Stream<S> {
+    /// Talk to a Shadowsocks server over `inner`
+    pub fn client(inner: S, password: &str) -> Self {
+        Stream::with_key(inner, key(password), None)
+    }
+
+    fn with_key(inner: S, key: [u8; KEY_LEN], salts: Option<Arc<Mutex<Salts>>>) -> Self {
+        Stream { inner, key, reader: Arc::default(), writer: Arc::default(), salts }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Read> Stream<S> {
+    /// Read and open the next chunk, false at the end of the stream
+    fn next_chunk(&mut self, reader: &mut Reader) -> io::Result<bool> {
+        if reader.cipher.is_none() {
+            let mut salt = [0u8; SALT_LEN];
+            if !read_exact_or_eof(&mut self.inner, &mut salt)? {
+                return Ok(false);
+            }
+            if let Some(salts) = &self.salts {
+                if !salts.lock().unwrap().insert(salt) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Replayed salt"));
+                }
+            }
+            reader.cipher = Some(Cipher::new(&self.key, &salt));
+        }
+        let cipher = reader.cipher.as_mut().unwrap();
+
+        let mut length = [0u8; 2 + TAG_LEN];
+        if !read_exact_or_eof(&mut self.inner, &mut length)? {
+            return Ok(false);
+        }
+        let length = cipher.open(&mut length)?;
+        let length = usize::from(u16::from_be_bytes([length[0], length[1]])) & MAX_PAYLOAD;
+
+        reader.plaintext.resize(length + TAG_LEN, 0);
+        self.inner.read_exact(&mut reader.plaintext)?;
+        let opened = cipher.open(&mut reader.plaintext)?.len();
+        reader.plaintext.truncate(opened);
+        reader.read = 0;
+        Ok(true)
+    }
+}
+
+impl<S: Read> Read for Stream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let reader = self.reader.clone();
+        let mut reader = reader.lock().unwrap();
+        // Chunks may be empty, so keep going until there is something to return
+        while reader.read == reader.plaintext.len() {
+            if buf.is_empty() || !self.next_chunk(&mut reader)? {
+                return Ok(0);
+            }
+        }
+        let available = &reader.plaintext[reader.read..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        reader.read += len;
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for Stream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut writer = self.writer.lock().unwrap();
+        let mut sealed = Vec::with_capacity(buf.len() + 2 * TAG_LEN + SALT_LEN);
+        if writer.is_none() {
+            let mut salt = [0u8; SALT_LEN];
+            rand::thread_rng().fill_bytes(&mut salt);
+            // Remembered so this stream can't be reflected back as a client's
+            if let Some(salts) = &self.salts {
+                salts.lock().unwrap().insert(salt);
+            }
+            sealed.extend_from_slice(&salt);
+            *writer = Some(Cipher::new(&self.key, &salt));
+        }
+        let cipher = writer.as_mut().unwrap();
+        for chunk in buf.chunks(MAX_PAYLOAD) {
+            cipher.seal(&(chunk.len() as u16).to_be_bytes(), &mut sealed);
+            cipher.seal(chunk, &mut sealed);
+        }
+        self.inner.write_all(&sealed)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Connection> Connection for Stream<S> {
+    fn peer_ip(&self) -> io::Result<IpAddr> {
+        self.inner.peer_ip()
+    }
+
+    fn peer_port(&self) -> Option<u16> {
+        self.inner.peer_port()
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Stream {
+            inner: self.inner.try_clone()?,
+            key: self.key,
+            reader: self.reader.clone(),
+            writer: self.writer.clone(),
+            salts: self.salts.clone(),
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn reset(&self) {
+        self.inner.reset()
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        self.inner.set_dscp(dscp)
+    }
+
+    fn buffer_size(&self) -> Option<usize> {
+        self.inner.buffer_size()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
+
+/// Fill `buf`, false if the stream ended before the first byte
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Write the destination `host:port` as the first bytes of a client stream
+pub fn connect<S: Write>(stream: &mut S, host: &str, port: u16) -> io::Result<()> {
+    let mut address = Vec::new();
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            address.push(ADDR_IPV4);
+            address.extend_from_slice(&ip.octets());
+        },
+        Ok(IpAddr::V6(ip)) => {
+            address.push(ADDR_IPV6);
+            address.extend_from_slice(&ip.octets());
+        },
+        Err(_) => {
+            let len = u8::try_from(host.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Domain name too long"))?;
+            address.push(ADDR_DOMAIN);
+            address.push(len);
+            address.extend_from_slice(host.as_bytes());
+        },
+    }
+    address.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&address)
+}
+
+/// Read the destination a client stream starts with
+fn read_address<R: Read>(stream: &mut R, max_domain: usize) -> Result<(String, u16), MerinoError> {
+    let mut kind = [0u8; 1];
+    stream.read_exact(&mut kind)?;
+    let host = match kind[0] {
+        ADDR_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip)?;
+            Ipv4Addr::from(ip).to_string()
+        },
+        ADDR_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip)?;
+            Ipv6Addr::from(ip).to_string()
+        },
+        ADDR_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            let len = usize::from(len[0]);
+            if len > max_domain {
+                return Err(MerinoError::protocol(format!("Domain name of {} bytes exceeds the limit of {}", len, max_domain)));
+            }
+            let mut domain = vec![0u8; len];
+            stream.read_exact(&mut domain)?;
+            String::from_utf8(domain).map_err(|_| MerinoError::protocol("Domain name isn't valid UTF-8"))?
+        },
+        other => return Err(MerinoError::protocol(format!("Invalid address type {}", other))),
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port)?;
+    Ok((host, u16::from_be_bytes(port)))
+}
+
+/// Accept connections on `listener` and serve each of them
+pub(crate) fn serve(listener: TcpListener, inbound: Inbound, settings: Arc<Settings>) {
+    let inbound = Arc::new(inbound);
+    loop {
+        let stream = crate::accept::accept(&listener);
+        if settings.lifecycle.is_draining() {
+            continue;
+        }
+        let handed_over = settings.lifecycle.is_handed_over();
+        spawn(stream, inbound.clone(), settings.clone());
+        // The new process accepts from here on
+        if handed_over {
+            return;
+        }
+    }
+}
+
+/// Serve `stream` on a new thread
+fn spawn(stream: TcpStream, inbound: Arc<Inbound>, settings: Arc<Settings>) {
+    let handshaking = match settings.admit() {
+        Some(handshaking) => handshaking,
+        None => return,
+    };
+    let id = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+    thread::spawn(move || {
+        let connected = Local::now();
+        let client = stream.peer_ip().unwrap_or(IpAddr::from([0, 0, 0, 0]));
+        let mut stream = Stream::with_key(stream, inbound.key, Some(inbound.salts.clone()));
+        let mut request = None;
+        let result = match read_address(&mut stream, settings.limits.max_domain) {
+            Ok((host, port)) => {
+                drop(handshaking);
+                request = Some(format!("{}:{}", host, port));
+                handle(id, &stream, host, port, settings.clone(), connected)
+            },
+            Err(MerinoError::Io { source }) if source.kind() == io::ErrorKind::InvalidData => {
+                info!("Shadowsocks client {} failed authentication: {} (session {})", client, source, id);
+                drain(stream.get_ref());
+                Err(MerinoError::Auth { user: client.to_string() })
+            },
+            Err(error) => Err(error),
+        };
+        if let Err(error) = result {
+            if !matches!(error, MerinoError::Denied { .. } | MerinoError::Auth { .. }) {
+                error!("Shadowsocks {} failed: {} (session {})", inbound, error, id);
+            }
+            stream.get_ref().shutdown(Shutdown::Both).unwrap_or(());
+            settings.metrics.connection_failed();
+            if let Some(log) = &settings.access_log {
+                log.log(&access_log::Entry {
+                    client,
+                    user: None,
+                    time: connected,
+                    request: request.as_deref(),
+                    status: crate::access_status(&error),
+                    bytes: 0,
+                    sni: None,
+                    fingerprint: None,
+                });
+            }
+        }
+    });
+}
+
+/// Read from a client that failed authentication until it gives up or times out
+fn drain(mut stream: &TcpStream) {
+    if stream.set_read_timeout(Some(PROBE_TIMEOUT)).is_ok() {
+        io::copy(&mut stream, &mut io::sink()).map(drop).unwrap_or(());
+    }
+}
+
+fn handle(id: u64, stream: &Stream<TcpStream>, host: String, port: u16, settings: Arc<Settings>, connected: DateTime<Local>) -> Result<(), MerinoError> {
+    info!("New Shadowsocks Request: Source: {}, Addr: {}, Port: {} (session {})", stream.peer_ip()?, host, port, id);
+    forward::tunnel(id, stream, &host, port, None, settings, connected)
+}
+
+/// ChaCha20-Poly1305 under a subkey, with the nonce counting the chunks sealed or opened
+struct Cipher {
+    key: [u8; KEY_LEN],
+    nonce: u64,
+}
+
+impl Cipher {
+    /// Cipher for the subkey of `key` for `salt`
+    fn new(key: &[u8; KEY_LEN], salt: &[u8; SALT_LEN]) -> Self {
+        let mut subkey = [0u8; KEY_LEN];
+        Hkdf::<Sha1>::new(Some(salt), key).expand(SUBKEY_INFO, &mut subkey)
+            .expect("a 32 byte subkey is within HKDF-SHA1's output limit");
+        Cipher { key: subkey, nonce: 0 }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        nonce
+    }
+
+    /// Append `plaintext` sealed, tag included, to `out`
+    fn seal(&mut self, plaintext: &[u8], out: &mut Vec<u8>) {
+        let nonce = self.next_nonce();
+        let start = out.len();
+        out.extend_from_slice(plaintext);
+        let tag = seal(&self.key, &nonce, &[], &mut out[start..]);
+        out.extend_from_slice(&tag);
+    }
+
+    /// Open `sealed` in place, returning the plaintext
+    fn open<'a>(&mut self, sealed: &'a mut [u8]) -> io::Result<&'a [u8]> {
+        let nonce = self.next_nonce();
+        let (ciphertext, tag) = sealed.split_at_mut(sealed.len() - TAG_LEN);
+        if !open(&self.key, &nonce, &[], ciphertext, tag) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Chunk failed authentication"));
+        }
+        Ok(ciphertext)
+    }
+}
+
+/// Encrypt `data` in place with the ChaCha20-Poly1305 AEAD, returning the tag
+pub fn seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &mut [u8]) -> [u8; 16] {
+    let tag = ChaCha20Poly1305::new(&(*key).into())
+        .encrypt_inout_detached(&(*nonce).into(), aad, data.into())
+        .expect("chunks are far below the cipher's length limit");
+    tag.into()
+}
+
+/// Check `tag` and decrypt `data` in place, false and untouched if it doesn't authenticate
+pub fn open(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], data: &mut [u8], tag: &[u8]) -> bool {
+    let tag = match <[u8; TAG_LEN]>::try_from(tag) {
+        Ok(tag) => tag.into(),
+        Err(_) => return false,
+    };
+    ChaCha20Poly1305::new(&(*key).into())
+        .decrypt_inout_detached(&(*nonce).into(), aad, data.into(), &tag)
+        .is_ok()
+}
@@ -0,0 +1,60 @@
+//! Answering denied requests with a connection to nowhere
+//!
+//! Scanners abusing an open proxy give up on a destination as soon as the
+//! proxy refuses it. With the sinkhole on, requests the access rules deny are
+//! told they succeeded instead, then held on a connection that never answers:
+//! what the client sends is read slowly and dropped, so it learns nothing
+//! and its sends back up, while the first bytes are logged to show what it
+//! was after. Nothing ever leaves for the destination.
+use std::io;
+use std::net::Shutdown;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::Connection;
+
+/// Longest a client is held before being hung up on
+pub const HOLD: Duration = Duration::from_secs(300);
+
+/// Bytes read from the client at a time
+const READ_CHUNK: usize = 64;
+
+/// Wait between reads, keeping the client's send window full
+const READ_PACE: Duration = Duration::from_secs(1);
+
+/// Bytes of what the client sent logged
+const PREVIEW_LEN: usize = 256;
+
+/// Hold `stream` after a reply claiming success, until the client hangs up or [`HOLD`] runs out
+///
+/// Returns how many bytes the client sent meanwhile.
+pub fn hold<S: Connection>(id: u64, stream: &S) -> io::Result<u64> {
+    let mut reader = stream.try_clone()?;
+    let watchdog = stream.try_clone()?;
+    let (done, timeout) = mpsc::channel::<()>();
+    thread::spawn(move || {
+        if let Err(mpsc::RecvTimeoutError::Timeout) = timeout.recv_timeout(HOLD) {
+            watchdog.shutdown(Shutdown::Both).unwrap_or(());
+        }
+    });
+
+    let mut preview = Vec::new();
+    let mut received = 0u64;
+    let mut chunk = [0u8; READ_CHUNK];
+    loop {
+        let read = match reader.read(&mut chunk) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        if preview.len() < PREVIEW_LEN {
+            preview.extend_from_slice(&chunk[..read.min(PREVIEW_LEN - preview.len())]);
+        }
+        received += read as u64;
+        thread::sleep(READ_PACE);
+    }
+    drop(done);
+    stream.shutdown(Shutdown::Both).unwrap_or(());
+    info!("Sinkholed client sent {} bytes, starting \"{}\" (session {})", received, preview.escape_ascii(), id);
+    Ok(received)
+}
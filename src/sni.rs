@@ -0,0 +1,155 @@
+//! Server Name Indication read from the TLS ClientHello of relayed connections
+//!
+//! The first bytes a client sends after CONNECT are peeked at, without
+//! terminating TLS, to learn the hostname it is really talking to. That name
+//! goes to the access log and is checked against the rules like a request
+//! for it would be, so a client can't slip past a domain rule by sending
+//! CONNECT with a bare IP. Anything that isn't TLS is relayed untouched.
+use std::io;
+
+use crate::rules::{self, Destination, Rules};
+use crate::{Connection, Session};
+
+/// Most bytes buffered while waiting for a complete ClientHello, a full TLS record
+const MAX_CLIENT_HELLO: usize = 5 + 16384;
+
+/// What the first bytes from a client turned out to be
+#[derive(Clone, Debug, PartialEq)]
+pub enum ClientHello {
+    /// More bytes are needed to tell
+    Incomplete,
+    /// Not a TLS handshake
+    NotTls,
+    /// A ClientHello, with the server name if it sent one
+    Tls(Option<String>),
+}
+
+/// Parse the start of a client's byte stream as a TLS ClientHello
+pub fn parse(buf: &[u8]) -> ClientHello {
+    // Record header: content type 22 (handshake), version 3.x, length
+    match buf {
+        [] => return ClientHello::Incomplete,
+        [22] | [22, 3] | [22, 3, _] | [22, 3, _, _] => return ClientHello::Incomplete,
+        [22, 3, _, _, _, ..] => {},
+        _ => return ClientHello::NotTls,
+    }
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    let record = &buf[5..];
+    if record.len() < record_len {
+        return ClientHello::Incomplete;
+    }
+    let record = &record[..record_len];
+    // Handshake header: type 1 (ClientHello), 24-bit length
+    match record.first() {
+        Some(1) => {},
+        _ => return ClientHello::NotTls,
+    }
+    ClientHello::Tls(server_name(record.get(4..).unwrap_or_default()))
+}
+
+/// Byte reader over a ClientHello, `None` once it runs out
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|b| b[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+    }
+
+    /// A vector prefixed with its length
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()?;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(len)
+    }
+}
+
+/// The host_name from the server_name extension of a ClientHello body
+fn server_name(hello: &[u8]) -> Option<String> {
+    let mut hello = Cursor(hello);
+    hello.take(2 + 32)?; // legacy_version, random
+    hello.vec8()?; // legacy_session_id
+    hello.vec16()?; // cipher_suites
+    hello.vec8()?; // legacy_compression_methods
+    let mut extensions = Cursor(hello.vec16()?);
+    while let (Some(kind), Some(data)) = (extensions.u16(), extensions.vec16()) {
+        if kind != 0 {
+            continue;
+        }
+        let mut names = Cursor(Cursor(data).vec16()?);
+        while let (Some(kind), Some(name)) = (names.u8(), names.vec16()) {
+            if kind == 0 {
+                return std::str::from_utf8(name).ok().map(|name| name.trim_end_matches('.').to_lowercase());
+            }
+        }
+    }
+    None
+}
+
+/// What to check the server name against
+pub(crate) struct Policy {
+    pub(crate) user_rules: Option<Rules>,
+    pub(crate) port: u16,
+}
+
+/// Read the client's first bytes, after the `early` ones it already sent, record the server name and check it against the rules
+///
+/// Returns the bytes read, to be relayed before anything else, and whether
+/// the session may go on.
+pub(crate) fn inspect<S: Connection>(session: &Session, policy: &Policy, client: &mut S, early: Vec<u8>) -> io::Result<(Vec<u8>, bool)> {
+    let mut buffer = early;
+    let mut chunk = [0u8; 4096];
+    let name = loop {
+        match parse(&buffer) {
+            ClientHello::Incomplete if buffer.len() < MAX_CLIENT_HELLO => {},
+            ClientHello::Tls(name) => break name,
+            _ => return Ok((buffer, true)),
+        }
+        let n = client.read(&mut chunk)?;
+        if n == 0 {
+            return Ok((buffer, true));
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    };
+    let name = match name {
+        Some(name) => name,
+        None => {
+            debug!("TLS ClientHello without a server name (session {})", session.id);
+            return Ok((buffer, true));
+        },
+    };
+    info!("TLS server name: {} (session {})", name, session.id);
+
+    let destination = Destination::Domain(name.clone());
+    let asn = session.settings.asn_of(&destination, policy.port);
+    let request = rules::Request {
+        source: session.source,
+        user: session.user.as_deref(),
+        groups: session.settings.groups.of(session.user.as_deref()),
+        destination: &destination,
+        port: policy.port,
+        asn: asn.as_ref(),
+        tag: session.tag.as_deref(),
+    };
+    *session.sni.lock().unwrap() = Some(name);
+    let allowed = session.settings.authorize(session.id, policy.user_rules.as_ref(), &request)
+        .map_err(|error| io::Error::other(error.to_string()))?
+        .is_some();
+    Ok((buffer, allowed))
+}
@@ -0,0 +1,379 @@
+//! Experimental SOCKS6, following draft-olteanu-intarea-socks-6-11
+//!
+//! Built with the `socks6` feature, for testing the draft against merino.
+//! A SOCKS6 client sends its whole request in one go: the destination, its
+//! auth methods and credentials as options, and optionally initial data
+//! for the destination (0-RTT). merino answers with an authentication
+//! reply, connects, sends the initial data on, and answers with an
+//! operation reply before relaying as for SOCKS5.
+//!
+//! Only CONNECT and NOOP are served, and only no authentication and
+//! username/password (RFC 1929 encoded in an authentication data option).
+//! Other options, such as session and idempotence tokens, are ignored.
+//! Rules, bans, limits and metrics apply as for SOCKS5.
+use std::error::Error;
+use std::io::{self, prelude::*};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::builtin;
+use crate::rules::{self, Destination};
+use crate::sinkhole;
+use crate::{AuthMethods, Connection, MerinoError, ResponseCode, SockCommand, SOCKClient, User, UNSPECIFIED};
+use crate::limits::Limits;
+use crate::metrics::Outcome;
+
+pub const SOCKS6_VERSION: u8 = 0x06;
+
+/// Most initial data a client may send along with its request
+pub const MAX_INITIAL_DATA: usize = 16384;
+
+/// Most bytes of options accepted in a request
+const MAX_OPTIONS: usize = 4096;
+
+const COMMAND_NOOP: u8 = 0;
+const COMMAND_CONNECT: u8 = 1;
+
+const ADDR_IPV4: u8 = 1;
+const ADDR_DOMAIN: u8 = 3;
+const ADDR_IPV6: u8 = 4;
+
+const OPTION_AUTH_METHODS: u16 = 2;
+const OPTION_AUTH_DATA: u16 = 3;
+
+/// A request, as sent by the client before anything else
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    pub command: u8,
+    pub destination: Destination,
+    pub port: u16,
+    /// Auth methods the client advertised, no authentication is always implied
+    pub methods: Vec<u8>,
+    /// Username and password from an authentication data option
+    pub credentials: Option<(String, String)>,
+    /// Sent to the destination as soon as it is connected
+    pub initial_data: Vec<u8>,
+}
+
+impl Request {
+    /// A CONNECT request for `host:port`
+    pub fn connect(host: &str, port: u16) -> Self {
+        Request {
+            command: COMMAND_CONNECT,
+            destination: Destination::parse(host),
+            port,
+            methods: Vec::new(),
+            credentials: None,
+            initial_data: Vec::new(),
+        }
+    }
+
+    /// Read a request, version byte included, with its initial data
+    pub fn read<R: Read>(reader: &mut R, limits: &Limits) -> Result<Self, MerinoError> {
+        let mut header = [0u8; 8];
+        reader.read_exact(&mut header)?;
+        if header[0] != SOCKS6_VERSION {
+            return Err(MerinoError::protocol(format!("Expected SOCKS6, got version {}", header[0])));
+        }
+        let command = header[1];
+        let options_len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let port = u16::from_be_bytes([header[4], header[5]]);
+        let destination = match header[7] {
+            ADDR_IPV4 => {
+                let mut ip = [0u8; 4];
+                reader.read_exact(&mut ip)?;
+                Destination::Ip(IpAddr::from(ip))
+            },
+            ADDR_IPV6 => {
+                let mut ip = [0u8; 16];
+                reader.read_exact(&mut ip)?;
+                Destination::ip(IpAddr::from(ip))
+            },
+            ADDR_DOMAIN => {
+                let mut len = [0u8; 1];
+                reader.read_exact(&mut len)?;
+                if len[0] as usize > limits.max_domain {
+                    return Err(MerinoError::protocol("Domain name too long"));
+                }
+                // The length byte and name are padded to a multiple of 4 bytes
+                let mut domain = vec![0u8; padded(1 + len[0] as usize) - 1];
+                reader.read_exact(&mut domain)?;
+                domain.truncate(len[0] as usize);
+                Destination::parse(&String::from_utf8_lossy(&domain))
+            },
+            other => return Err(MerinoError::protocol(format!("Address type {} not supported", other))),
+        };
+
+        if options_len > MAX_OPTIONS {
+            return Err(MerinoError::protocol(format!("{} bytes of options, more than the limit of {}", options_len, MAX_OPTIONS)));
+        }
+        let mut options = vec![0u8; options_len];
+        reader.read_exact(&mut options)?;
+
+        let mut request = Request { command, destination, port, methods: Vec::new(), credentials: None, initial_data: Vec::new() };
+        let mut initial_data_len = 0;
+        let mut rest = &options[..];
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return Err(MerinoError::protocol("Truncated option"));
+            }
+            let kind = u16::from_be_bytes([rest[0], rest[1]]);
+            let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            if len < 4 || len > rest.len() {
+                return Err(MerinoError::protocol(format!("Option of {} bytes in {} bytes of options", len, rest.len())));
+            }
+            let data = &rest[4..len];
+            rest = &rest[len..];
+            match kind {
+                OPTION_AUTH_METHODS if data.len() >= 2 => {
+                    initial_data_len = u16::from_be_bytes([data[0], data[1]]) as usize;
+                    // Padding reads as method 0, which is implied anyway
+                    request.methods.extend(data[2..].iter().filter(|method| **method != 0));
+                },
+                OPTION_AUTH_DATA if data.first() == Some(&(AuthMethods::UserPass as u8)) => {
+                    request.credentials = Some(credentials(&data[1..], limits)?);
+                },
+                _ => trace!("Ignoring SOCKS6 option {}", kind),
+            }
+        }
+
+        if initial_data_len > MAX_INITIAL_DATA {
+            return Err(MerinoError::protocol(format!("{} bytes of initial data, more than the limit of {}", initial_data_len, MAX_INITIAL_DATA)));
+        }
+        request.initial_data = vec![0u8; initial_data_len];
+        reader.read_exact(&mut request.initial_data)?;
+        Ok(request)
+    }
+
+    /// Encode the request as sent on the wire, initial data included
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut options = Vec::new();
+        if !self.methods.is_empty() || !self.initial_data.is_empty() {
+            let mut data = (self.initial_data.len() as u16).to_be_bytes().to_vec();
+            data.extend_from_slice(&self.methods);
+            push_option(&mut options, OPTION_AUTH_METHODS, &data);
+        }
+        if let Some((username, password)) = &self.credentials {
+            let mut data = vec![AuthMethods::UserPass as u8, 1, username.len() as u8];
+            data.extend_from_slice(username.as_bytes());
+            data.push(password.len() as u8);
+            data.extend_from_slice(password.as_bytes());
+            push_option(&mut options, OPTION_AUTH_DATA, &data);
+        }
+
+        let mut packet = vec![SOCKS6_VERSION, self.command];
+        packet.extend_from_slice(&(options.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&self.port.to_be_bytes());
+        packet.push(0);
+        match &self.destination {
+            Destination::Ip(IpAddr::V4(ip)) => {
+                packet.push(ADDR_IPV4);
+                packet.extend_from_slice(&ip.octets());
+            },
+            Destination::Ip(IpAddr::V6(ip)) => {
+                packet.push(ADDR_IPV6);
+                packet.extend_from_slice(&ip.octets());
+            },
+            Destination::Domain(domain) => {
+                packet.push(ADDR_DOMAIN);
+                let mut address = vec![domain.len() as u8];
+                address.extend_from_slice(domain.as_bytes());
+                address.resize(padded(address.len()), 0);
+                packet.extend_from_slice(&address);
+            },
+        }
+        packet.extend_from_slice(&options);
+        packet.extend_from_slice(&self.initial_data);
+        packet
+    }
+}
+
+/// Username and password in RFC 1929 encoding
+fn credentials(data: &[u8], limits: &Limits) -> Result<(String, String), MerinoError> {
+    let malformed = || MerinoError::protocol("Malformed username/password authentication data");
+    let ulen = *data.get(1).ok_or_else(malformed)? as usize;
+    if ulen > limits.max_username {
+        return Err(MerinoError::protocol("Username too long"));
+    }
+    let username = data.get(2..2 + ulen).ok_or_else(malformed)?;
+    let plen = *data.get(2 + ulen).ok_or_else(malformed)? as usize;
+    let password = data.get(3 + ulen..3 + ulen + plen).ok_or_else(malformed)?;
+    let utf8 = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|_| MerinoError::protocol("Credentials aren't UTF-8"));
+    Ok((utf8(username)?, utf8(password)?))
+}
+
+fn push_option(options: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    let len = padded(4 + data.len());
+    options.extend_from_slice(&kind.to_be_bytes());
+    options.extend_from_slice(&(len as u16).to_be_bytes());
+    options.extend_from_slice(data);
+    options.resize(options.len() + len - 4 - data.len(), 0);
+}
+
+/// `len` rounded up to a multiple of 4
+fn padded(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+/// Authentication reply: success or failure, without options
+pub fn auth_reply(success: bool) -> [u8; 4] {
+    [SOCKS6_VERSION, if success { 0 } else { 1 }, 0, 0]
+}
+
+/// Operation reply with `code` and the address the proxy bound
+pub fn operation_reply(code: ResponseCode, bind: SocketAddr) -> Vec<u8> {
+    let mut packet = vec![SOCKS6_VERSION, code as u8, 0, 0];
+    packet.extend_from_slice(&bind.port().to_be_bytes());
+    packet.push(0);
+    match bind.ip() {
+        IpAddr::V4(ip) => {
+            packet.push(ADDR_IPV4);
+            packet.extend_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            packet.push(ADDR_IPV6);
+            packet.extend_from_slice(&ip.octets());
+        },
+    }
+    packet
+}
+
+/// Send `request` over `stream` and read the replies, returning the address the proxy bound
+pub fn connect<S: Read + Write>(stream: &mut S, request: &Request) -> Result<SocketAddr, Box<dyn Error>> {
+    stream.write_all(&request.to_bytes())?;
+
+    let mut auth = [0u8; 4];
+    stream.read_exact(&mut auth)?;
+    if auth[0] != SOCKS6_VERSION {
+        return Err(format!("Proxy replied with SOCKS{}", auth[0]).into());
+    }
+    skip(stream, u16::from_be_bytes([auth[2], auth[3]]))?;
+    if auth[1] != 0 {
+        return Err("Proxy refused the authentication".into());
+    }
+
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header)?;
+    let ip = match header[7] {
+        ADDR_IPV4 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip)?;
+            IpAddr::from(ip)
+        },
+        ADDR_IPV6 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip)?;
+            IpAddr::from(ip)
+        },
+        other => return Err(format!("Unexpected bind address type {}", other).into()),
+    };
+    skip(stream, u16::from_be_bytes([header[2], header[3]]))?;
+    if header[1] != ResponseCode::Success as u8 {
+        return Err(format!("Proxy replied with error code {}", header[1]).into());
+    }
+    Ok(SocketAddr::new(ip, u16::from_be_bytes([header[4], header[5]])))
+}
+
+/// Read past `len` bytes of options
+fn skip<R: Read>(reader: &mut R, len: u16) -> io::Result<()> {
+    io::copy(&mut reader.take(u64::from(len)), &mut io::sink()).map(drop)
+}
+
+impl<S: Connection> SOCKClient<S> {
+    /// Serve a SOCKS6 client, whose version and `command` bytes were already read
+    pub(crate) fn socks6(&mut self, command: u8) -> Result<(), MerinoError> {
+        let peer = self.stream.peer_ip()?;
+        self.fingerprint.command = Some(command);
+        let header = [SOCKS6_VERSION, command];
+        let request = Request::read(&mut (&header[..]).chain(&mut self.stream), &self.settings.limits)?;
+        let displayed_addr = request.destination.to_string();
+        self.request = Some(format!("{}:{}", displayed_addr, request.port));
+        info!("New SOCKS6 Request: Source: {}, Command: {} Addr: {}, Port: {}, Initial data: {} bytes (session {})",
+              peer, command, displayed_addr, request.port, request.initial_data.len(), self.id);
+
+        // Authenticate
+        let authenticated = match request.credentials.clone() {
+            Some((username, password)) if self.settings.auth_methods.contains(&(AuthMethods::UserPass as u8)) => {
+                self.login(peer, User { username, password, totp: None, expires: None })
+            },
+            _ if self.settings.auth_methods.contains(&(AuthMethods::NoAuth as u8)) => Ok(()),
+            _ => {
+                info!("Refusing unauthenticated client {} (session {})", peer, self.id);
+                self.settings.metrics.request_decided(None, Outcome::DeniedAuth);
+                Err(MerinoError::Denied { reason: "authentication required".to_string() })
+            },
+        };
+        if let Err(error) = authenticated {
+            self.stream.write_all(&auth_reply(false))?;
+            self.shutdown()?;
+            return Err(error);
+        }
+        self.stream.write_all(&auth_reply(true))?;
+
+        match command {
+            COMMAND_CONNECT if self.settings.commands.contains(&(SockCommand::Connect as u8)) => {},
+            COMMAND_NOOP => {
+                self.stream.write_all(&operation_reply(ResponseCode::Success, UNSPECIFIED))?;
+                return Ok(());
+            },
+            _ => {
+                warn!("Invalid SOCKS6 Command {} (session {})", command, self.id);
+                self.stream.write_all(&operation_reply(ResponseCode::CommandNotSupported, UNSPECIFIED))?;
+                self.shutdown()?;
+                return Err(MerinoError::protocol("Command not supported"));
+            },
+        }
+
+        // Check the request against the access rules
+        let session = self.session(format!("{}:{}", displayed_addr, request.port))?;
+        let authorized = {
+            let asn = self.settings.asn_of(&request.destination, request.port);
+            let rules_request = rules::Request {
+                source: peer,
+                user: self.user.as_deref(),
+                groups: self.settings.groups.of(self.user.as_deref()),
+                destination: &request.destination,
+                port: request.port,
+                asn: asn.as_ref(),
+                tag: self.tag.as_deref()
+            };
+            self.settings.authorize(session.id, self.user_rules.as_ref(), &rules_request)?
+        };
+        let sinkhole = self.settings.sinkhole && authorized.is_none();
+        let route = match authorized.filter(|route| route.claim(&session)) {
+            Some(route) => route,
+            None if sinkhole => {
+                self.settings.metrics.request_decided(Some(SockCommand::Connect), Outcome::DeniedAcl);
+                info!("Sinkholing denied request for {}:{} (session {})", displayed_addr, request.port, self.id);
+                self.stream.write_all(&operation_reply(ResponseCode::Success, UNSPECIFIED))?;
+                sinkhole::hold(self.id, &self.stream)?;
+                return Err(MerinoError::Denied { reason: format!("{}:{}", displayed_addr, request.port) });
+            },
+            None => {
+                self.settings.metrics.request_decided(Some(SockCommand::Connect), Outcome::DeniedAcl);
+                self.stream.write_all(&operation_reply(ResponseCode::RuleFailure, UNSPECIFIED))?;
+                self.shutdown()?;
+                return Err(MerinoError::Denied { reason: format!("{}:{}", displayed_addr, request.port) });
+            }
+        };
+
+        if let Some(service) = builtin::Service::of(&request.destination) {
+            debug!("Serving {} (session {})", service, self.id);
+            let target = service.spawn(self.id)?;
+            self.settings.metrics.request_decided(Some(SockCommand::Connect), Outcome::Granted);
+            self.stream.write_all(&operation_reply(ResponseCode::Success, UNSPECIFIED))?;
+            self.download = Some(crate::relay(session, route.priority, &self.stream, &target, None, request.initial_data)?);
+            return Ok(());
+        }
+
+        let mut target = self.connect_counted(SockCommand::Connect, &route)?;
+        route.mark(self.id, &self.stream, &target);
+        route.introduce(self.id, &self.stream, &mut target)?;
+        let bind = self.settings.reply_address.report(target.local_addr().ok());
+        self.stream.write_all(&operation_reply(ResponseCode::Success, bind))?;
+
+        // The initial data is relayed first, checked for a TLS server name like the rest
+        let sni = self.settings.sni.then(|| crate::sni::Policy { user_rules: self.user_rules.clone(), port: request.port });
+        self.download = Some(crate::relay(session, route.priority, &self.stream, &target, sni, request.initial_data)?);
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+//! Latency and throughput of a merino, measured through it against its built-in destinations
+//!
+//! Nothing has to run behind the proxy: sessions go to [`crate::builtin`]'s
+//! `merino.echo` and `merino.discard`, so what is measured is the path from
+//! here to the proxy and the proxy itself, e.g. after a deployment:
+//!
+//! ```text
+//! Handshake: 1.84ms
+//! Latency:   min 0.41ms, median 0.52ms, max 1.10ms over 10 round trips
+//! Upload:    112.30 MiB/s (16777216 bytes in 142.47ms)
+//! Echo:      98.75 MiB/s (16777216 bytes each way in 162.02ms)
+//! ```
+use std::error::Error;
+use std::fmt;
+use std::io::prelude::*;
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::builtin::Service;
+use crate::client;
+
+/// Bytes sent per round trip when measuring latency
+const PING_LEN: usize = 64;
+
+/// Bytes written at a time when measuring throughput
+const CHUNK: usize = 65536;
+
+/// What `merino speedtest` measures
+#[derive(Clone, Debug)]
+pub struct SpeedtestConfig {
+    /// Proxy to measure, as `host:port`
+    pub via: String,
+    pub credentials: Option<(String, String)>,
+    /// Bytes sent for each throughput measurement
+    pub bytes: usize,
+    /// Round trips timed for the latency
+    pub pings: usize,
+    /// How long connecting, and any read or write, may take
+    pub timeout: Duration,
+}
+
+/// What `merino speedtest` measured
+#[derive(Debug)]
+pub struct SpeedtestReport {
+    /// Connecting to the proxy and having the request accepted
+    pub handshake: Duration,
+    /// Round trips through `merino.echo`, sorted ascending
+    pub pings: Vec<Duration>,
+    /// Time to send the payload to `merino.discard` until the proxy closed the session
+    pub upload: Duration,
+    /// Time to send the payload to `merino.echo` and get all of it back
+    pub echo: Duration,
+    pub bytes: usize,
+}
+
+impl SpeedtestReport {
+    /// Median round trip
+    pub fn latency(&self) -> Option<Duration> {
+        self.pings.get(self.pings.len() / 2).copied()
+    }
+}
+
+/// Bytes per second as MiB/s
+fn mib_per_sec(bytes: usize, elapsed: Duration) -> f64 {
+    bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON) / (1024.0 * 1024.0)
+}
+
+impl fmt::Display for SpeedtestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Handshake: {:.2?}", self.handshake)?;
+        match (self.pings.first(), self.latency(), self.pings.last()) {
+            (Some(min), Some(median), Some(max)) => writeln!(f, "Latency:   min {:.2?}, median {:.2?}, max {:.2?} over {} round trips", min, median, max, self.pings.len())?,
+            _ => writeln!(f, "Latency:   -")?,
+        }
+        writeln!(f, "Upload:    {:.2} MiB/s ({} bytes in {:.2?})", mib_per_sec(self.bytes, self.upload), self.bytes, self.upload)?;
+        write!(f, "Echo:      {:.2} MiB/s ({} bytes each way in {:.2?})", mib_per_sec(self.bytes, self.echo), self.bytes, self.echo)
+    }
+}
+
+/// Open a session through the proxy to `service`
+fn open(config: &SpeedtestConfig, proxy: SocketAddr, service: Service) -> Result<TcpStream, Box<dyn Error>> {
+    let mut stream = TcpStream::connect_timeout(&proxy, config.timeout)
+        .map_err(|e| format!("can't reach the proxy at {}: {}", proxy, e))?;
+    stream.set_read_timeout(Some(config.timeout))?;
+    stream.set_write_timeout(Some(config.timeout))?;
+    stream.set_nodelay(true)?;
+    let credentials = config.credentials.as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+    client::connect(&mut stream, &service.to_string(), 7, credentials)
+        .map_err(|e| format!("the proxy at {} refused {}: {}", proxy, service, e))?;
+    Ok(stream)
+}
+
+/// Send `bytes` on `stream` from a thread of its own, then stop sending
+fn send(stream: &TcpStream, bytes: usize) -> Result<thread::JoinHandle<std::io::Result<()>>, Box<dyn Error>> {
+    let mut writer = stream.try_clone()?;
+    Ok(thread::spawn(move || {
+        let chunk = vec![0x5a; CHUNK];
+        let mut left = bytes;
+        while left > 0 {
+            let len = left.min(CHUNK);
+            writer.write_all(&chunk[..len])?;
+            left -= len;
+        }
+        writer.shutdown(Shutdown::Write)
+    }))
+}
+
+/// Read `stream` until the proxy closes it, returning how many bytes came back
+fn drain(stream: &mut TcpStream) -> std::io::Result<usize> {
+    let mut buf = vec![0u8; CHUNK];
+    let mut total = 0;
+    loop {
+        match stream.read(&mut buf)? {
+            0 => return Ok(total),
+            read => total += read,
+        }
+    }
+}
+
+/// Measure the proxy at `config.via`
+pub fn run(config: &SpeedtestConfig) -> Result<SpeedtestReport, Box<dyn Error>> {
+    let proxy = config.via.to_socket_addrs()
+        .map_err(|e| format!("can't resolve {}: {}", config.via, e))?
+        .next()
+        .ok_or_else(|| format!("{} has no addresses", config.via))?;
+
+    let start = Instant::now();
+    let mut stream = open(config, proxy, Service::Echo)?;
+    let handshake = start.elapsed();
+
+    let ping = [0x5a; PING_LEN];
+    let mut pong = [0u8; PING_LEN];
+    let mut pings = Vec::with_capacity(config.pings);
+    for _ in 0..config.pings {
+        let start = Instant::now();
+        stream.write_all(&ping)?;
+        stream.read_exact(&mut pong)?;
+        pings.push(start.elapsed());
+    }
+    pings.sort();
+    stream.shutdown(Shutdown::Both)?;
+
+    let mut stream = open(config, proxy, Service::Discard)?;
+    let start = Instant::now();
+    let sending = send(&stream, config.bytes)?;
+    drain(&mut stream)?;
+    sending.join().map_err(|_| "Upload thread panicked")??;
+    let upload = start.elapsed();
+
+    let mut stream = open(config, proxy, Service::Echo)?;
+    let start = Instant::now();
+    let sending = send(&stream, config.bytes)?;
+    let echoed = drain(&mut stream)?;
+    sending.join().map_err(|_| "Upload thread panicked")??;
+    let echo = start.elapsed();
+    if echoed != config.bytes {
+        return Err(format!("{} echoed {} of {} bytes", Service::Echo, echoed, config.bytes).into());
+    }
+
+    Ok(SpeedtestReport { handshake, pings, upload, echo, bytes: config.bytes })
+}
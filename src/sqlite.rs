@@ -0,0 +1,188 @@
+//! SQLite backed user store with hashed passwords, quotas and usage counters
+//!
+//! Every mutation is a single statement, so concurrent sessions and
+//! management commands never see partial updates and counters survive
+//! restarts.
+use chrono::{DateTime, TimeZone, Utc};
+use rand::distributions::{Alphanumeric, DistString};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::error::Error;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::store::{hash_password, verify_password, UserStore};
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY NOT NULL,
+    password_hash TEXT NOT NULL,
+    quota_bytes INTEGER,
+    used_bytes INTEGER NOT NULL DEFAULT 0,
+    -- Seconds since the epoch when the password stops being accepted
+    expires_at INTEGER
+)";
+
+/// Length of passwords made up by `rotate`
+const ROTATED_PASSWORD_LEN: usize = 24;
+
+fn timestamp(time: Option<DateTime<Utc>>) -> Option<i64> {
+    time.map(|time| time.timestamp())
+}
+
+fn from_timestamp(seconds: Option<i64>) -> Option<DateTime<Utc>> {
+    seconds.and_then(|seconds| Utc.timestamp_opt(seconds, 0).single())
+}
+
+/// A user as stored in the database
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserRecord {
+    pub username: String,
+    /// Maximum bytes the user may relay, unlimited when `None`
+    pub quota: Option<u64>,
+    /// Bytes relayed so far
+    pub used: u64,
+    /// When the password stops being accepted
+    pub expires: Option<DateTime<Utc>>,
+}
+
+/// Users, hashed passwords and quotas persisted in SQLite
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        conn.execute(SCHEMA, [])?;
+        // Databases created before passwords could expire
+        let has_expiry = conn.prepare("SELECT 1 FROM pragma_table_info('users') WHERE name = 'expires_at'")?.exists([])?;
+        if !has_expiry {
+            conn.execute("ALTER TABLE users ADD COLUMN expires_at INTEGER", [])?;
+        }
+        Ok(SqliteStore { conn: Mutex::new(conn) })
+    }
+
+    /// Add a new user, failing if the username is taken
+    pub fn add_user(&self, username: &str, password: &str, quota: Option<u64>, expires: Option<DateTime<Utc>>) -> Result<(), Box<dyn Error>> {
+        let hash = hash_password(password)?;
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO users (username, password_hash, quota_bytes, expires_at) VALUES (?1, ?2, ?3, ?4)",
+            params![username, hash, quota.map(|q| q as i64), timestamp(expires)],
+        )?;
+        Ok(())
+    }
+
+    /// Change a user's password, returning whether the user exists
+    pub fn set_password(&self, username: &str, password: &str) -> Result<bool, Box<dyn Error>> {
+        let hash = hash_password(password)?;
+        let changed = self.conn.lock().unwrap().execute(
+            "UPDATE users SET password_hash = ?1 WHERE username = ?2",
+            params![hash, username],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Set (or with `None`, lift) when a user's password expires, returning whether the user exists
+    pub fn set_expiry(&self, username: &str, expires: Option<DateTime<Utc>>) -> Result<bool, Box<dyn Error>> {
+        let changed = self.conn.lock().unwrap().execute(
+            "UPDATE users SET expires_at = ?1 WHERE username = ?2",
+            params![timestamp(expires), username],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Replace a user's password with a random one expiring at `expires`, returning it if the user exists
+    pub fn rotate(&self, username: &str, expires: Option<DateTime<Utc>>) -> Result<Option<String>, Box<dyn Error>> {
+        let password = Alphanumeric.sample_string(&mut rand::thread_rng(), ROTATED_PASSWORD_LEN);
+        let hash = hash_password(&password)?;
+        let changed = self.conn.lock().unwrap().execute(
+            "UPDATE users SET password_hash = ?1, expires_at = ?2 WHERE username = ?3",
+            params![hash, timestamp(expires), username],
+        )?;
+        Ok((changed > 0).then_some(password))
+    }
+
+    /// Remove a user, returning whether the user existed
+    pub fn remove_user(&self, username: &str) -> Result<bool, Box<dyn Error>> {
+        let changed = self.conn.lock().unwrap().execute("DELETE FROM users WHERE username = ?1", params![username])?;
+        Ok(changed > 0)
+    }
+
+    /// Set (or with `None`, lift) a user's quota, returning whether the user exists
+    pub fn set_quota(&self, username: &str, quota: Option<u64>) -> Result<bool, Box<dyn Error>> {
+        let changed = self.conn.lock().unwrap().execute(
+            "UPDATE users SET quota_bytes = ?1 WHERE username = ?2",
+            params![quota.map(|q| q as i64), username],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// Zero a user's accumulated usage, returning whether the user exists
+    pub fn reset_usage(&self, username: &str) -> Result<bool, Box<dyn Error>> {
+        let changed = self.conn.lock().unwrap().execute(
+            "UPDATE users SET used_bytes = 0 WHERE username = ?1",
+            params![username],
+        )?;
+        Ok(changed > 0)
+    }
+
+    /// All users, ordered by name
+    pub fn users(&self) -> Result<Vec<UserRecord>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT username, quota_bytes, used_bytes, expires_at FROM users ORDER BY username")?;
+        let users = stmt.query_map([], |row| {
+            Ok(UserRecord {
+                username: row.get(0)?,
+                quota: row.get::<_, Option<i64>>(1)?.map(|q| q as u64),
+                used: row.get::<_, i64>(2)? as u64,
+                expires: from_timestamp(row.get(3)?),
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(users)
+    }
+}
+
+impl UserStore for SqliteStore {
+    fn authenticate(&self, username: &str, password: &str) -> Result<bool, Box<dyn Error>> {
+        let row = self.conn.lock().unwrap().query_row(
+            "SELECT password_hash, quota_bytes, used_bytes FROM users WHERE username = ?1",
+            params![username],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, Option<i64>>(1)?, row.get::<_, i64>(2)?)),
+        ).optional()?;
+
+        let (hash, quota, used) = match row {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+
+        if !verify_password(password, &hash)? {
+            return Ok(false);
+        }
+
+        if let Some(quota) = quota {
+            if used >= quota {
+                info!("Quota exhausted for {}: {} of {} bytes used", username, used, quota);
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn expires(&self, username: &str) -> Result<Option<DateTime<Utc>>, Box<dyn Error>> {
+        let expires = self.conn.lock().unwrap().query_row(
+            "SELECT expires_at FROM users WHERE username = ?1",
+            params![username],
+            |row| row.get::<_, Option<i64>>(0),
+        ).optional()?;
+        Ok(from_timestamp(expires.flatten()))
+    }
+
+    fn record_usage(&self, username: &str, bytes: u64) -> Result<(), Box<dyn Error>> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE users SET used_bytes = used_bytes + ?1 WHERE username = ?2",
+            params![bytes as i64, username],
+        )?;
+        Ok(())
+    }
+}
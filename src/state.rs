@@ -0,0 +1,383 @@
+//! Session counters, bans and failed handshakes, optionally shared between merino instances
+//!
+//! [`LocalState`] keeps them in this process. A state shared between
+//! instances, such as [`RedisState`], makes session limits hold across all
+//! of them, every instance refuse a client banned on any, and a source
+//! failing its handshakes on several instances held back as if it had
+//! stuck to one, see [`crate::admin`] and [`crate::tarpit`].
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::admin::{Ban, BanTarget};
+
+/// Sources and subnets whose failed handshakes are counted at most, so spread-out attempts can't grow the table without bound
+const MAX_FAILING: usize = 2 * 65536;
+
+/// Per-user session counts used to enforce concurrency limits, bans, and failed handshake counts
+pub trait SharedState: Send + Sync {
+    /// Register a new session for `user`, returning how many it now has open
+    fn open_session(&self, user: &str) -> Result<u64, Box<dyn Error>>;
+
+    /// Release a session previously registered with `open_session`
+    fn close_session(&self, user: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Record `ban`, replacing any earlier ban on its target
+    fn ban(&self, ban: &Ban) -> Result<(), Box<dyn Error>>;
+
+    /// Lift the ban on `target`, returning whether there was one
+    fn lift(&self, target: &BanTarget) -> Result<bool, Box<dyn Error>>;
+
+    /// Bans still in effect
+    fn bans(&self) -> Result<Vec<Ban>, Box<dyn Error>>;
+
+    /// The ban on one of `targets` still in effect, if any
+    fn banned(&self, targets: &[BanTarget]) -> Result<Option<Ban>, Box<dyn Error>>;
+
+    /// Count a failed handshake of `key`, a source or a subnet, returning how many it had in a row
+    ///
+    /// Failures are forgotten after a quiet spell of `forget_after`. `None`
+    /// means the failure couldn't be counted, e.g. as too many keys are.
+    fn failed_handshake(&self, key: &str, forget_after: Duration) -> Result<Option<u32>, Box<dyn Error>>;
+
+    /// Forget `key`'s failed handshakes
+    fn forgive(&self, key: &str) -> Result<(), Box<dyn Error>>;
+}
+
+/// The same state, e.g. for several proxies in one process
+impl<T: SharedState + ?Sized> SharedState for Arc<T> {
+    fn open_session(&self, user: &str) -> Result<u64, Box<dyn Error>> {
+        (**self).open_session(user)
+    }
+
+    fn close_session(&self, user: &str) -> Result<(), Box<dyn Error>> {
+        (**self).close_session(user)
+    }
+
+    fn ban(&self, ban: &Ban) -> Result<(), Box<dyn Error>> {
+        (**self).ban(ban)
+    }
+
+    fn lift(&self, target: &BanTarget) -> Result<bool, Box<dyn Error>> {
+        (**self).lift(target)
+    }
+
+    fn bans(&self) -> Result<Vec<Ban>, Box<dyn Error>> {
+        (**self).bans()
+    }
+
+    fn banned(&self, targets: &[BanTarget]) -> Result<Option<Ban>, Box<dyn Error>> {
+        (**self).banned(targets)
+    }
+
+    fn failed_handshake(&self, key: &str, forget_after: Duration) -> Result<Option<u32>, Box<dyn Error>> {
+        (**self).failed_handshake(key, forget_after)
+    }
+
+    fn forgive(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        (**self).forgive(key)
+    }
+}
+
+/// Counters and bans kept in this process only
+#[derive(Debug, Default)]
+pub struct LocalState {
+    sessions: Mutex<HashMap<String, u64>>,
+    bans: Mutex<Vec<Ban>>,
+    /// Failed handshakes in a row by key, and when the last one was
+    failures: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl SharedState for LocalState {
+    fn open_session(&self, user: &str) -> Result<u64, Box<dyn Error>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let count = sessions.entry(user.to_string()).or_insert(0);
+        *count += 1;
+        Ok(*count)
+    }
+
+    fn close_session(&self, user: &str) -> Result<(), Box<dyn Error>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(count) = sessions.get_mut(user) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.remove(user);
+            }
+        }
+        Ok(())
+    }
+
+    fn ban(&self, ban: &Ban) -> Result<(), Box<dyn Error>> {
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|other| other.target != ban.target);
+        bans.push(ban.clone());
+        Ok(())
+    }
+
+    fn lift(&self, target: &BanTarget) -> Result<bool, Box<dyn Error>> {
+        let mut bans = self.bans.lock().unwrap();
+        let before = bans.len();
+        bans.retain(|ban| ban.target != *target);
+        Ok(bans.len() < before)
+    }
+
+    fn bans(&self) -> Result<Vec<Ban>, Box<dyn Error>> {
+        let mut bans = self.bans.lock().unwrap();
+        let now = chrono::Local::now();
+        bans.retain(|ban| ban.until > now);
+        Ok(bans.clone())
+    }
+
+    fn banned(&self, targets: &[BanTarget]) -> Result<Option<Ban>, Box<dyn Error>> {
+        let now = chrono::Local::now();
+        Ok(self.bans.lock().unwrap().iter().find(|ban| ban.until > now && targets.contains(&ban.target)).cloned())
+    }
+
+    fn failed_handshake(&self, key: &str, forget_after: Duration) -> Result<Option<u32>, Box<dyn Error>> {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_FAILING {
+            failures.retain(|_, (_, last)| now.duration_since(*last) < forget_after);
+            if failures.len() >= MAX_FAILING && !failures.contains_key(key) {
+                return Ok(None);
+            }
+        }
+        let entry = failures.entry(key.to_string()).or_insert((0, now));
+        if now.duration_since(entry.1) >= forget_after {
+            entry.0 = 0;
+        }
+        entry.0 = entry.0.saturating_add(1);
+        entry.1 = now;
+        Ok(Some(entry.0))
+    }
+
+    fn forgive(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        self.failures.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+/// An open session, released when dropped
+pub(crate) struct SessionSlot {
+    state: Arc<dyn SharedState>,
+    user: String,
+}
+
+impl SessionSlot {
+    /// Claim a session for `user`, or `None` if that would exceed `max`
+    #[cfg(feature = "socks5")]
+    pub(crate) fn claim(state: &Arc<dyn SharedState>, user: &str, max: u64) -> Result<Option<Self>, Box<dyn Error>> {
+        let count = state.open_session(user)?;
+        let slot = SessionSlot { state: state.clone(), user: user.to_string() };
+        if count > max {
+            // Dropping the slot gives the session back
+            return Ok(None);
+        }
+        Ok(Some(slot))
+    }
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        if let Err(error) = self.state.close_session(&self.user) {
+            warn!("Failed to release session for {}: {}", self.user, error);
+        }
+    }
+}
+
+/// Counters and bans stored in Redis so limits hold across every instance sharing it
+///
+/// Each instance counts its sessions under its own field of a per-user
+/// hash, and holds a lease on its ID that it renews every few seconds. An
+/// instance that crashes stops renewing it, and once the lease runs out its
+/// sessions no longer count against anyone's limit.
+#[cfg(feature = "redis")]
+pub struct RedisState {
+    client: redis::Client,
+    conn: Mutex<Option<redis::Connection>>,
+    /// This instance's field in the session hashes
+    instance: String,
+    /// Stops the lease from being renewed once dropped
+    _renewal: std::sync::mpsc::Sender<()>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisState {
+    /// Prefix of the per-user session hashes
+    const PREFIX: &'static str = "merino:sessions:";
+
+    /// Prefix of the instance leases
+    const INSTANCES: &'static str = "merino:instances:";
+
+    /// Prefix of the bans, followed by `source:<ip>` or `user:<name>`
+    const BANS: &'static str = "merino:bans:";
+
+    /// Prefix of the failed handshake counters
+    const FAILURES: &'static str = "merino:failures:";
+
+    /// How long an instance's sessions count after its last renewal
+    const LEASE: Duration = Duration::from_secs(30);
+
+    /// Connect to Redis at a URL such as `redis://127.0.0.1/`
+    pub fn connect(url: &str) -> Result<Self, Box<dyn Error>> {
+        let client = redis::Client::open(url)?;
+        let conn = client.get_connection()?;
+        let instance = format!("{:016x}", rand::random::<u64>());
+        let (renewal, stop) = std::sync::mpsc::channel::<()>();
+        let state = RedisState { client, conn: Mutex::new(Some(conn)), instance, _renewal: renewal };
+        let lease = state.lease();
+        state.with_conn(|c| Self::renew(c, &lease))?;
+
+        // Renew the lease until the state is dropped, reconnecting as needed
+        let client = state.client.clone();
+        std::thread::spawn(move || {
+            let mut conn: Option<redis::Connection> = None;
+            while let Err(std::sync::mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(Self::LEASE / 3) {
+                let renewed = match conn.take() {
+                    Some(c) => Ok(c),
+                    None => client.get_connection(),
+                }.and_then(|mut c| Self::renew(&mut c, &lease).map(|_| c));
+                match renewed {
+                    Ok(c) => conn = Some(c),
+                    Err(error) => warn!("Failed to renew the Redis lease on {}: {}", lease, error),
+                }
+            }
+        });
+        Ok(state)
+    }
+
+    /// Key of this instance's lease
+    fn lease(&self) -> String {
+        format!("{}{}", Self::INSTANCES, self.instance)
+    }
+
+    /// Renew the lease at `lease` for another [`Self::LEASE`]
+    fn renew(c: &mut redis::Connection, lease: &str) -> redis::RedisResult<()> {
+        redis::cmd("SET").arg(lease).arg(1).arg("PX").arg(Self::LEASE.as_millis() as u64).query(c)
+    }
+
+    /// Key of the ban on `target`
+    fn ban_key(target: &BanTarget) -> String {
+        match target {
+            BanTarget::Source(ip) => format!("{}source:{}", Self::BANS, ip),
+            BanTarget::User(name) => format!("{}user:{}", Self::BANS, name),
+        }
+    }
+
+    /// The ban stored at `key` until `until`
+    fn parse_ban(key: &str, until: String) -> Result<Ban, Box<dyn Error>> {
+        let (kind, target) = key.strip_prefix(Self::BANS).and_then(|ban| ban.split_once(':')).ok_or_else(|| format!("Invalid ban key `{}`", key))?;
+        crate::admin::parse_ban(kind, target, &until)
+    }
+
+    /// Run `f` on a connection, reconnecting once if it fails
+    fn with_conn<T>(&self, f: impl Fn(&mut redis::Connection) -> redis::RedisResult<T>) -> Result<T, Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        if let Some(c) = conn.as_mut() {
+            match f(c) {
+                Ok(value) => return Ok(value),
+                Err(error) if error.is_connection_dropped() || error.is_io_error() => {
+                    warn!("Lost connection to Redis, reconnecting: {}", error);
+                },
+                Err(error) => return Err(error.into()),
+            }
+        }
+        *conn = None;
+        let mut c = self.client.get_connection()?;
+        let value = f(&mut c)?;
+        *conn = Some(c);
+        Ok(value)
+    }
+}
+
+#[cfg(feature = "redis")]
+impl SharedState for RedisState {
+    fn open_session(&self, user: &str) -> Result<u64, Box<dyn Error>> {
+        // Count this instance's sessions and those of instances whose lease
+        // is current, dropping the counts of the others
+        let open = redis::Script::new(r"
+            redis.call('SET', KEYS[2], 1, 'PX', ARGV[2])
+            redis.call('HINCRBY', KEYS[1], ARGV[1], 1)
+            local total = 0
+            local counts = redis.call('HGETALL', KEYS[1])
+            for i = 1, #counts, 2 do
+                if redis.call('EXISTS', ARGV[3] .. counts[i]) == 1 then
+                    total = total + tonumber(counts[i + 1])
+                else
+                    redis.call('HDEL', KEYS[1], counts[i])
+                end
+            end
+            return total
+        ");
+        let key = format!("{}{}", Self::PREFIX, user);
+        let count: i64 = self.with_conn(|c| {
+            open.key(&key).key(self.lease())
+                .arg(&self.instance).arg(Self::LEASE.as_millis() as u64).arg(Self::INSTANCES)
+                .invoke(c)
+        })?;
+        Ok(count.max(0) as u64)
+    }
+
+    fn close_session(&self, user: &str) -> Result<(), Box<dyn Error>> {
+        // Drop the field once it reaches zero, the hash goes with its last field
+        let release = redis::Script::new(
+            "local n = redis.call('HINCRBY', KEYS[1], ARGV[1], -1) if n <= 0 then redis.call('HDEL', KEYS[1], ARGV[1]) end return n"
+        );
+        let key = format!("{}{}", Self::PREFIX, user);
+        self.with_conn(|c| release.key(&key).arg(&self.instance).invoke::<i64>(c))?;
+        Ok(())
+    }
+
+    fn ban(&self, ban: &Ban) -> Result<(), Box<dyn Error>> {
+        let ttl = (ban.until - chrono::Local::now()).num_milliseconds();
+        if ttl <= 0 {
+            return Ok(());
+        }
+        let key = Self::ban_key(&ban.target);
+        self.with_conn(|c| redis::cmd("SET").arg(&key).arg(ban.until.to_rfc3339()).arg("PX").arg(ttl).query(c))
+    }
+
+    fn lift(&self, target: &BanTarget) -> Result<bool, Box<dyn Error>> {
+        let key = Self::ban_key(target);
+        let lifted: i64 = self.with_conn(|c| redis::cmd("DEL").arg(&key).query(c))?;
+        Ok(lifted > 0)
+    }
+
+    fn bans(&self) -> Result<Vec<Ban>, Box<dyn Error>> {
+        let pattern = format!("{}*", Self::BANS);
+        let keys: Vec<String> = self.with_conn(|c| Ok(redis::Commands::scan_match(c, &pattern)?.collect()))?;
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let untils: Vec<Option<String>> = self.with_conn(|c| redis::cmd("MGET").arg(&keys).query(c))?;
+        keys.iter().zip(untils)
+            .filter_map(|(key, until)| until.map(|until| Self::parse_ban(key, until)))
+            .collect()
+    }
+
+    fn banned(&self, targets: &[BanTarget]) -> Result<Option<Ban>, Box<dyn Error>> {
+        let keys: Vec<String> = targets.iter().map(Self::ban_key).collect();
+        let untils: Vec<Option<String>> = self.with_conn(|c| redis::cmd("MGET").arg(&keys).query(c))?;
+        match keys.iter().zip(untils).find_map(|(key, until)| Some((key, until?))) {
+            Some((key, until)) => Ok(Some(Self::parse_ban(key, until)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn failed_handshake(&self, key: &str, forget_after: Duration) -> Result<Option<u32>, Box<dyn Error>> {
+        let key = format!("{}{}", Self::FAILURES, key);
+        let (count,): (i64,) = self.with_conn(|c| {
+            redis::pipe().atomic()
+                .incr(&key, 1)
+                .pexpire(&key, forget_after.as_millis() as i64).ignore()
+                .query(c)
+        })?;
+        Ok(Some(count.clamp(0, u32::MAX as i64) as u32))
+    }
+
+    fn forgive(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        let key = format!("{}{}", Self::FAILURES, key);
+        self.with_conn(|c| redis::cmd("DEL").arg(&key).query(c))
+    }
+}
@@ -0,0 +1,59 @@
+//! Metrics pushed to a statsd or DogStatsD agent over UDP
+//!
+//! For environments that don't scrape Prometheus. The same events that
+//! update [`crate::Metrics`] are sent as they happen, named
+//! `<prefix>.<metric>` and carrying DogStatsD tags when any are configured.
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+
+/// A statsd agent to send metrics to
+#[derive(Debug)]
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    /// Rendered `|#tag,tag` suffix, empty without tags
+    tags: String,
+}
+
+impl Statsd {
+    /// Send metrics to the agent at `addr`, naming them `<prefix>.<metric>`
+    pub fn connect<A: ToSocketAddrs>(addr: A, prefix: &str, tags: &[String]) -> io::Result<Self> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address for the statsd agent"))?;
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.connect(addr)?;
+        let tags = match tags.is_empty() {
+            true => String::new(),
+            false => format!("|#{}", tags.join(",")),
+        };
+        Ok(Statsd { socket, prefix: prefix.trim_end_matches('.').to_string(), tags })
+    }
+
+    pub fn count(&self, name: &str, value: u64) {
+        self.send(name, value, "c");
+    }
+
+    pub fn gauge(&self, name: &str, value: u64) {
+        self.send(name, value, "g");
+    }
+
+    /// A duration in milliseconds
+    pub fn timing(&self, name: &str, millis: u64) {
+        self.send(name, millis, "ms");
+    }
+
+    pub fn histogram(&self, name: &str, value: u64) {
+        self.send(name, value, "h");
+    }
+
+    fn send(&self, name: &str, value: u64, kind: &str) {
+        let line = match self.prefix.is_empty() {
+            true => format!("{}:{}|{}{}", name, value, kind, self.tags),
+            false => format!("{}.{}:{}|{}{}", self.prefix, name, value, kind, self.tags),
+        };
+        // Losing a metric is better than slowing down the session it's about
+        if let Err(error) = self.socket.send(line.as_bytes()) {
+            debug!("Failed to send {} to statsd: {}", name, error);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Sources of user credentials
+use chrono::{DateTime, Utc};
+use std::error::Error;
+
+use crate::{Rules, User};
+
+/// Validates credentials and keeps track of what users relay
+pub trait UserStore: Send + Sync {
+    /// Check a username/password pair
+    fn authenticate(&self, username: &str, password: &str) -> Result<bool, Box<dyn Error>>;
+
+    /// Access rules specific to `username`, checked before the global rules
+    fn user_rules(&self, _username: &str) -> Result<Option<Rules>, Box<dyn Error>> {
+        Ok(None)
+    }
+
+    /// TOTP secret of `username`, whose password must then be followed by a current code
+    fn totp_secret(&self, _username: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(None)
+    }
+
+    /// When `username`'s credentials stop being accepted
+    fn expires(&self, _username: &str) -> Result<Option<DateTime<Utc>>, Box<dyn Error>> {
+        Ok(None)
+    }
+
+    /// Record bytes relayed on behalf of `username`
+    fn record_usage(&self, _username: &str, _bytes: u64) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// Users loaded from a CSV file, without usage accounting
+impl UserStore for Vec<User> {
+    fn authenticate(&self, username: &str, password: &str) -> Result<bool, Box<dyn Error>> {
+        Ok(self.iter().any(|user| user.username == username && user.password == password))
+    }
+
+    fn totp_secret(&self, username: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match self.iter().find(|user| user.username == username).and_then(|user| user.totp.as_deref()) {
+            Some(secret) => Ok(Some(crate::totp::decode_secret(secret)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn expires(&self, username: &str) -> Result<Option<DateTime<Utc>>, Box<dyn Error>> {
+        match self.iter().find(|user| user.username == username).and_then(|user| user.expires.as_deref()) {
+            Some(expires) => Ok(Some(crate::expiry::parse(expires)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Hash a password into a PHC string for storage
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub fn hash_password(password: &str) -> Result<String, Box<dyn Error>> {
+    use argon2::password_hash::{rand_core::OsRng, PasswordHasher, SaltString};
+
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(argon2::Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| format!("Failed to hash password: {}", e))?
+        .to_string())
+}
+
+/// Check a password against a stored PHC string
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub fn verify_password(password: &str, hash: &str) -> Result<bool, Box<dyn Error>> {
+    use argon2::password_hash::{PasswordHash, PasswordVerifier};
+
+    let hash = PasswordHash::new(hash).map_err(|e| format!("Invalid password hash: {}", e))?;
+    Ok(argon2::Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
@@ -0,0 +1,141 @@
+//! Slowing down clients that keep failing their handshake
+//!
+//! Each failed handshake from a source waits twice as long before being
+//! answered as the one before, starting from a base delay, so guessing
+//! passwords or probing the proxy from one address slows to a crawl long
+//! before it would get anywhere. Failed logins count, and so do protocol
+//! failures: malformed requests, unsupported versions and refused method
+//! negotiations. Bans stop a source outright once someone notices; the tarpit
+//! costs an attacker time in the meantime, and a legitimate user who mistyped
+//! once hardly notices. A successful login, or a quiet spell, forgets a
+//! source's failures.
+//!
+//! Failures are also counted per subnet, by default a /24 for IPv4 and a /64
+//! for IPv6, so a botnet or a host rotating through addresses in its prefix
+//! can't start afresh with each one. Once a subnet has failed more than its
+//! allowance, [`SUBNET_ALLOWANCE`] by default, its further failures are held
+//! back like those of a single source, whichever delay is longer. Only
+//! failures are ever held back, users logging in correctly from a throttled
+//! subnet aren't slowed, and a subnet is only forgiven after a quiet spell.
+//!
+//! Failures are counted in a [`SharedState`], by default in this process
+//! only. With one shared between instances, e.g. Redis, a source spreading
+//! its attempts over several instances is held back as if it had stuck to
+//! one.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::state::{LocalState, SharedState};
+
+/// Longest a failed handshake is held back
+pub const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How long after its last failure a source is forgiven
+const FORGET_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Failures from a subnet before its further failures are held back as a whole, unless configured
+pub const SUBNET_ALLOWANCE: u32 = 8;
+
+/// Prefix length IPv4 sources are grouped by, unless configured
+pub const SUBNET_V4: u8 = 24;
+
+/// Prefix length IPv6 sources are grouped by, unless configured
+pub const SUBNET_V6: u8 = 64;
+
+/// How sources are grouped into subnets, and how many failures a subnet is allowed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Subnets {
+    /// Failures from a subnet before its further failures are held back as a whole
+    pub allowance: u32,
+    /// Prefix length IPv4 sources are grouped by, at most 32
+    pub v4: u8,
+    /// Prefix length IPv6 sources are grouped by, at most 128
+    pub v6: u8,
+}
+
+impl Default for Subnets {
+    fn default() -> Self {
+        Subnets { allowance: SUBNET_ALLOWANCE, v4: SUBNET_V4, v6: SUBNET_V6 }
+    }
+}
+
+impl Subnets {
+    /// The network address of the subnet `source` is grouped in
+    pub fn network(&self, source: IpAddr) -> IpAddr {
+        match source {
+            IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & u32::MAX.checked_shl(32 - u32::from(self.v4.min(32))).unwrap_or(0))),
+            IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & u128::MAX.checked_shl(128 - u32::from(self.v6.min(128))).unwrap_or(0))),
+        }
+    }
+
+    /// Prefix length of `network`'s subnets
+    fn prefix(&self, network: IpAddr) -> u8 {
+        if network.is_ipv4() { self.v4 } else { self.v6 }
+    }
+}
+
+/// Failed handshakes by source and subnet, and how long to hold back the next answer
+pub struct Tarpit {
+    base: Duration,
+    limits: Subnets,
+    /// Counts the failures, see [`Tarpit::share_failures`]
+    state: Mutex<Arc<dyn SharedState>>,
+}
+
+impl Tarpit {
+    /// Hold back the first failure from a source by `base`, doubling with each one after, and group sources by `subnets`
+    pub fn new(base: Duration, subnets: Subnets) -> Self {
+        Tarpit { base, limits: subnets, state: Mutex::new(Arc::new(LocalState::default())) }
+    }
+
+    /// Count failures in `state`, e.g. to share them with other instances
+    pub fn share_failures(&self, state: Arc<dyn SharedState>) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// Count a failure of `key`, returning how many there were in a row, or `None` if it couldn't be counted
+    fn count(&self, key: &str) -> Option<u32> {
+        let state = self.state.lock().unwrap().clone();
+        state.failed_handshake(key, FORGET_AFTER).unwrap_or_else(|error| {
+            warn!("Failed to count a failed handshake of {}: {}", key, error);
+            None
+        })
+    }
+
+    /// Count a failed handshake from `source`, returning how long to wait before answering it
+    pub fn failed(&self, source: IpAddr) -> Duration {
+        let failures = self.count(&format!("source:{}", source)).unwrap_or(1);
+        let network = self.limits.network(source);
+        let subnet_failures = self.count(&format!("subnet:{}/{}", network, self.limits.prefix(network))).unwrap_or(0);
+        let excess = subnet_failures.saturating_sub(self.limits.allowance);
+        if excess == 1 {
+            warn!("More than {} failed handshakes from {}/{}, holding back further ones", self.limits.allowance, network, self.limits.prefix(network));
+        }
+        self.delay(failures).max(if excess > 0 { self.delay(excess) } else { Duration::from_secs(0) })
+    }
+
+    /// Wait out the delay for a failed handshake from `source`
+    pub fn hold(&self, source: IpAddr) {
+        let delay = self.failed(source);
+        if delay > Duration::from_secs(0) {
+            debug!("Holding back a failed handshake from {} for {:?}", source, delay);
+            thread::sleep(delay);
+        }
+    }
+
+    /// Forget `source`'s failures after it logged in, but not its subnet's
+    pub fn succeeded(&self, source: IpAddr) {
+        let state = self.state.lock().unwrap().clone();
+        if let Err(error) = state.forgive(&format!("source:{}", source)) {
+            warn!("Failed to forget the failed handshakes of {}: {}", source, error);
+        }
+    }
+
+    /// Wait after the `failures`th failure in a row
+    fn delay(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(16);
+        self.base.saturating_mul(1 << doublings).min(MAX_DELAY)
+    }
+}
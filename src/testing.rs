@@ -0,0 +1,174 @@
+//! In-memory harness for driving SOCKS sessions through a proxy in tests
+//!
+//! Clients talk to the proxy over a [`Pipe`] instead of a socket, so no
+//! listener or free port is needed on the client side. Destinations are
+//! still reached over TCP; [`spawn_echo_server`] provides one on an
+//! ephemeral loopback port.
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::{client, serve_client, Connection, Merino};
+
+pub use crate::bench::spawn_echo_server;
+
+/// Bytes flowing in one direction of a pipe
+#[derive(Default)]
+struct Channel {
+    buffer: Mutex<Buffer>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct Buffer {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Channel {
+    fn close(&self) {
+        self.buffer.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// One end of an in-memory duplex stream, created with [`pipe`]
+///
+/// Clones share the same end, like clones of a `TcpStream`.
+#[derive(Clone)]
+pub struct Pipe {
+    incoming: Arc<Channel>,
+    outgoing: Arc<Channel>,
+}
+
+/// Create a connected pair of in-memory streams
+pub fn pipe() -> (Pipe, Pipe) {
+    let (a, b) = (Arc::new(Channel::default()), Arc::new(Channel::default()));
+    (
+        Pipe { incoming: a.clone(), outgoing: b.clone() },
+        Pipe { incoming: b, outgoing: a },
+    )
+}
+
+impl Read for Pipe {
+    /// Block until data arrives, returning 0 once the other end stops writing
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut buffer = self.incoming.buffer.lock().unwrap();
+        while buffer.data.is_empty() && !buffer.closed {
+            buffer = self.incoming.ready.wait(buffer).unwrap();
+        }
+        let n = buf.len().min(buffer.data.len());
+        for (byte, slot) in buffer.data.drain(..n).zip(buf.iter_mut()) {
+            *slot = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buffer = self.outgoing.buffer.lock().unwrap();
+        if buffer.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        buffer.data.extend(buf);
+        self.outgoing.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for Pipe {
+    /// In-memory clients are matched against the rules as loopback
+    fn peer_ip(&self) -> io::Result<IpAddr> {
+        Ok(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Write {
+            self.incoming.close();
+        }
+        if how != Shutdown::Read {
+            self.outgoing.close();
+        }
+        Ok(())
+    }
+
+    fn reset(&self) {
+        self.shutdown(Shutdown::Both).unwrap_or(());
+    }
+}
+
+/// A SOCKS client connected to a proxy over a [`Pipe`]
+///
+/// Reads and writes go to the destination once [`MockClient::connect`] succeeds.
+pub struct MockClient {
+    stream: Pipe,
+    serving: thread::JoinHandle<()>,
+}
+
+impl MockClient {
+    /// Open an in-memory connection to `proxy`, served on its own thread
+    pub fn new(proxy: &Merino) -> Self {
+        let (stream, server) = pipe();
+        let settings = Arc::new(proxy.settings.clone());
+        let serving = thread::spawn(move || {
+            if let Err(error) = serve_client(server, settings) {
+                warn!("In-memory session failed: {}", error);
+            }
+        });
+        MockClient { stream, serving }
+    }
+
+    /// Perform the SOCKS5 CONNECT handshake, see [`client::connect`]
+    pub fn connect(&mut self, host: &str, port: u16, credentials: Option<(&str, &str)>) -> Result<SocketAddr, Box<dyn Error>> {
+        client::connect(&mut self.stream, host, port, credentials)
+    }
+
+    /// Send `data` and read back as many bytes, e.g. through an echo server
+    pub fn roundtrip(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        self.stream.write_all(data)?;
+        let mut echoed = vec![0u8; data.len()];
+        self.stream.read_exact(&mut echoed)?;
+        Ok(echoed)
+    }
+
+    /// Stop sending and wait for the proxy to finish the session
+    pub fn close(self) -> thread::Result<()> {
+        self.stream.shutdown(Shutdown::Write).unwrap_or(());
+        self.serving.join()
+    }
+}
+
+impl Read for MockClient {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for MockClient {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Connect a new [`MockClient`] to `proxy` and complete a handshake for `host:port`
+pub fn handshake(proxy: &Merino, host: &str, port: u16, credentials: Option<(&str, &str)>) -> Result<MockClient, Box<dyn Error>> {
+    let mut client = MockClient::new(proxy);
+    client.connect(host, port, credentials)?;
+    Ok(client)
+}
@@ -0,0 +1,228 @@
+//! Bearer token authentication, against an identity provider's keys or its introspection endpoint
+//!
+//! Clients send a token, e.g. a short-lived JWT or an opaque token from the
+//! identity provider, as the RFC 1929 password. Tokens naming a `username`
+//! or `sub` are only accepted with that as the SOCKS username, so rules and
+//! accounting apply to the identity the token was issued to.
+//!
+//! [`JwksStore`] checks JWTs itself, against the public keys the provider
+//! publishes as a JWK set. Keys are fetched again hourly, and when a token
+//! is signed by a key not seen yet, so rotations are picked up. Tokens
+//! signed with a shared secret (`HS256` and the like) are refused.
+//!
+//! [`IntrospectionStore`] asks the provider about each token instead
+//! ([RFC 7662](https://www.rfc-editor.org/rfc/rfc7662)), which also works
+//! for opaque tokens and sees revocations. It accepts a token while the
+//! endpoint says it's active. Positive answers are cached for up to
+//! [`CACHE_FOR`], never past the token's `exp`, so clients opening many
+//! sessions don't cost a round trip each.
+//!
+//! Endpoints are reached over HTTPS, verified against the root
+//! certificates built into merino. Plain `http://` is only meant for a
+//! provider on loopback or a trusted network.
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::store::UserStore;
+
+/// Longest an active token is trusted without asking again
+pub const CACHE_FOR: Duration = Duration::from_secs(60);
+
+/// How often the JWK set is fetched again
+pub const JWKS_REFRESH: Duration = Duration::from_secs(60 * 60);
+
+/// Least time between fetches of the JWK set for tokens signed by unknown keys
+const JWKS_RETRY: Duration = Duration::from_secs(30);
+
+/// Tokens cached at most, beyond which expired ones are dropped
+const MAX_CACHED: usize = 65536;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn check_url(url: &str) -> Result<(), Box<dyn Error>> {
+    match url.split_once("://") {
+        Some(("https" | "http", rest)) if !rest.is_empty() && !rest.starts_with('/') => Ok(()),
+        _ => Err(format!("`{}` isn't an https:// or http:// URL", url).into()),
+    }
+}
+
+/// Whether the `username` or `sub` claim in `claims`, if either is there, is `username`
+fn issued_to(claims: &serde_json::Value, username: &str) -> bool {
+    let names: Vec<&str> = ["username", "sub"].iter().filter_map(|claim| claims[claim].as_str()).collect();
+    if !names.is_empty() && !names.contains(&username) {
+        debug!("Token for {} presented as {}", names[0], username);
+        return false;
+    }
+    true
+}
+
+/// Users authenticated by JWTs they present as their password, signed by a key in a JWK set
+pub struct JwksStore {
+    url: String,
+    /// Required `iss` claim, if any
+    issuer: Option<String>,
+    /// Required `aud` claim, if any
+    audience: Option<String>,
+    /// The keys, and when they were fetched
+    keys: Mutex<(JwkSet, Option<Instant>)>,
+}
+
+impl JwksStore {
+    /// Check tokens against the keys published at `url`, e.g. `https://idp.example.com/.well-known/jwks.json`
+    ///
+    /// The keys are fetched straight away, failing if they can't be.
+    pub fn new(url: &str, issuer: Option<String>, audience: Option<String>) -> Result<Self, Box<dyn Error>> {
+        check_url(url)?;
+        let store = JwksStore { url: url.to_string(), issuer, audience, keys: Mutex::new((JwkSet { keys: Vec::new() }, None)) };
+        store.fetch(&mut store.keys.lock().unwrap())?;
+        Ok(store)
+    }
+
+    fn fetch(&self, keys: &mut (JwkSet, Option<Instant>)) -> Result<(), Box<dyn Error>> {
+        let body = crate::http::agent(TIMEOUT).get(&self.url)
+            .set("Accept", "application/json")
+            .call()
+            .map_err(crate::http::error)?
+            .into_string()?;
+        *keys = (serde_json::from_str(&body)?, Some(Instant::now()));
+        debug!("Fetched {} keys from {}", keys.0.keys.len(), self.url);
+        Ok(())
+    }
+
+    /// The key with id `kid`, or the only key if the token names none, fetching the set again if it's stale
+    fn key(&self, kid: Option<&str>) -> Result<Option<DecodingKey>, Box<dyn Error>> {
+        let mut keys = self.keys.lock().unwrap();
+        let fetched = keys.1.map(|at| at.elapsed());
+        if fetched.is_none_or(|age| age >= JWKS_REFRESH) {
+            self.fetch(&mut keys)?;
+        }
+        let find = |set: &JwkSet| match kid {
+            Some(kid) => set.find(kid).cloned(),
+            None if set.keys.len() == 1 => set.keys.first().cloned(),
+            None => None,
+        };
+        let jwk = match find(&keys.0) {
+            Some(jwk) => Some(jwk),
+            None if keys.1.is_some_and(|at| at.elapsed() >= JWKS_RETRY) => {
+                // Perhaps the provider rotated its keys since
+                self.fetch(&mut keys)?;
+                find(&keys.0)
+            },
+            None => None,
+        };
+        match jwk {
+            // Anyone holding a shared secret could mint tokens, so only public keys are trusted
+            Some(jwk) if !matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_)) => Ok(Some(DecodingKey::from_jwk(&jwk)?)),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl UserStore for JwksStore {
+    fn authenticate(&self, username: &str, password: &str) -> Result<bool, Box<dyn Error>> {
+        let header = match jsonwebtoken::decode_header(password) {
+            Ok(header) => header,
+            Err(_) => return Ok(false),
+        };
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            debug!("Refusing a token for {} signed with a shared secret", username);
+            return Ok(false);
+        }
+        let key = match self.key(header.kid.as_deref())? {
+            Some(key) => key,
+            None => {
+                debug!("No key {} to check the token for {}", header.kid.as_deref().unwrap_or("-"), username);
+                return Ok(false);
+            },
+        };
+
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        match jsonwebtoken::decode::<serde_json::Value>(password, &key, &validation) {
+            Ok(token) => Ok(issued_to(&token.claims, username)),
+            Err(error) => {
+                debug!("Refusing the token for {}: {}", username, error);
+                Ok(false)
+            },
+        }
+    }
+}
+
+/// Users authenticated by the tokens they present as their password
+pub struct IntrospectionStore {
+    url: String,
+    /// Bearer token merino authenticates to the endpoint with
+    credential: Option<String>,
+    /// Accepted username and token pairs, until when they're trusted
+    cache: Mutex<HashMap<(String, String), Instant>>,
+}
+
+impl IntrospectionStore {
+    /// Introspect tokens at `url`, e.g. `https://idp.example.com/oauth2/introspect`,
+    /// authenticating with `credential` as a bearer token if given
+    pub fn new(url: &str, credential: Option<String>) -> Result<Self, Box<dyn Error>> {
+        check_url(url)?;
+        Ok(IntrospectionStore {
+            url: url.to_string(),
+            credential,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Ask the endpoint about `token`
+    fn introspect(&self, token: &str) -> Result<serde_json::Value, Box<dyn Error>> {
+        let mut request = crate::http::agent(TIMEOUT).post(&self.url).set("Accept", "application/json");
+        if let Some(credential) = &self.credential {
+            request = request.set("Authorization", &format!("Bearer {}", credential));
+        }
+        let body = request.send_form(&[("token", token), ("token_type_hint", "access_token")])
+            .map_err(|error| format!("Introspection endpoint failed: {}", crate::http::error(error)))?
+            .into_string()?;
+        Ok(serde_json::from_str(&body)?)
+    }
+}
+
+impl UserStore for IntrospectionStore {
+    fn authenticate(&self, username: &str, password: &str) -> Result<bool, Box<dyn Error>> {
+        let key = (username.to_string(), password.to_string());
+        let now = Instant::now();
+        if self.cache.lock().unwrap().get(&key).is_some_and(|until| *until > now) {
+            return Ok(true);
+        }
+
+        let answer = self.introspect(password)?;
+        if answer["active"] != serde_json::Value::Bool(true) {
+            return Ok(false);
+        }
+        if !issued_to(&answer, username) {
+            return Ok(false);
+        }
+
+        let mut trusted = CACHE_FOR;
+        if let Some(exp) = answer["exp"].as_u64() {
+            let left = exp.saturating_sub(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+            if left == 0 {
+                return Ok(false);
+            }
+            trusted = trusted.min(Duration::from_secs(left));
+        }
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, until| *until > now);
+        }
+        if cache.len() < MAX_CACHED {
+            cache.insert(key, now + trusted);
+        }
+        Ok(true)
+    }
+}
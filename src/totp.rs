@@ -0,0 +1,143 @@
+//! Time-based one-time passwords as a second factor for password logins
+//!
+//! Users with a `totp` secret (base32, as authenticator apps take it) append
+//! the current 6 digit code to their password, e.g. `hunter2` and `492039`
+//! are sent as `hunter2492039`. Codes are checked as in
+//! [RFC 6238](https://www.rfc-editor.org/rfc/rfc6238), with HMAC-SHA1 and
+//! 30 second steps, accepting the step before and after for clock skew.
+//!
+//! Codes are single use, as RFC 6238 section 5.2 asks. Clients open several
+//! sessions at once with the same credentials, so a verified code keeps
+//! working from the same client address for [`REMEMBER`], one step. After
+//! that, and from any other address, it is refused even while still current.
+use hmac::{Hmac, KeyInit, Mac};
+use rand::RngCore;
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::{Choice, ConstantTimeEq};
+
+/// Digits in a code
+pub const DIGITS: usize = 6;
+
+/// Seconds each code is current for
+pub const STEP: u64 = 30;
+
+/// How long a verified code keeps working from the address that sent it
+pub const REMEMBER: Duration = Duration::from_secs(STEP);
+
+/// How long a verified code is kept as spent, the longest it can stay current
+const SPENT: Duration = Duration::from_secs(3 * STEP);
+
+/// Logins remembered at most, beyond which expired ones are dropped
+const MAX_REMEMBERED: usize = 65536;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Decode a base32 secret, ignoring case, spaces and padding
+pub fn decode_secret(secret: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in secret.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = BASE32.iter().position(|b| *b as char == c.to_ascii_uppercase())
+            .ok_or_else(|| format!("`{}` isn't valid in a base32 TOTP secret", c))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    if bytes.len() < 10 {
+        return Err("TOTP secrets need at least 80 bits".into());
+    }
+    Ok(bytes)
+}
+
+/// Encode `secret` as base32, without padding
+pub fn encode_secret(secret: &[u8]) -> String {
+    let mut encoded = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in secret {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    encoded
+}
+
+/// A new random 160 bit secret, base32 encoded
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    encode_secret(&secret)
+}
+
+/// The code for `secret` at `time`, in seconds since the epoch
+pub fn code(secret: &[u8], time: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&(time / STEP).to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[19] & 0xf) as usize;
+    let value = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+    format!("{:0width$}", value % 10u32.pow(DIGITS as u32), width = DIGITS)
+}
+
+/// Whether `code` is current for `secret` at `time`, give or take a step
+///
+/// Compares in constant time, and against every step, so timing doesn't
+/// reveal how much of a guess was right.
+pub fn verify(secret: &[u8], code: &str, time: u64) -> bool {
+    [time.saturating_sub(STEP), time, time + STEP].iter()
+        .fold(Choice::from(0), |matched, time| matched | self::code(secret, *time).as_bytes().ct_eq(code.as_bytes()))
+        .into()
+}
+
+/// Split the code off the end of a password
+pub fn split(password: &str) -> Option<(&str, &str)> {
+    let at = password.len().checked_sub(DIGITS)?;
+    let (password, code) = (password.get(..at)?, &password[at..]);
+    code.bytes().all(|b| b.is_ascii_digit()).then_some((password, code))
+}
+
+/// Codes verified recently, the address that sent each and when
+#[derive(Default)]
+pub struct Logins {
+    verified: Mutex<HashMap<(String, String), (IpAddr, Instant)>>,
+}
+
+impl Logins {
+    /// Whether `code` is current for `secret` and unused, or was verified for `username` from `peer` within [`REMEMBER`]
+    pub fn check(&self, username: &str, peer: IpAddr, secret: &[u8], code: &str) -> bool {
+        let key = (username.to_string(), code.to_string());
+        let now = Instant::now();
+        let mut verified = self.verified.lock().unwrap();
+        if let Some((from, at)) = verified.get(&key) {
+            if now.duration_since(*at) < SPENT {
+                return *from == peer && now.duration_since(*at) < REMEMBER;
+            }
+        }
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if !verify(secret, code, time) {
+            return false;
+        }
+        if verified.len() >= MAX_REMEMBERED {
+            verified.retain(|_, (_, at)| now.duration_since(*at) < SPENT);
+        }
+        if verified.len() >= MAX_REMEMBERED {
+            // Without room to mark it spent, the code can't be accepted
+            return false;
+        }
+        verified.insert(key, (peer, now));
+        true
+    }
+}
@@ -0,0 +1,456 @@
+//! Obfuscating layers wrapped around connections, against trivial DPI
+//!
+//! A SOCKS handshake is easy to fingerprint: a few fixed bytes, always the
+//! same lengths. A [`Transport`] turns the bytes of each direction into
+//! something else on the wire and back, and [`Stack`] applies several in
+//! order. Both ends must use the same stack, e.g. a merino serving `--forward`
+//! to a remote merino listening with the same `--transport`.
+//!
+//! The layers built in are spelled like `pad:255,xor:KEY`:
+//!
+//! ```text
+//! xor:KEY     a random nonce, then bytes XORed with a ChaCha20 keystream from KEY and the nonce
+//! pad:MAX     frames with up to MAX random bytes of padding each, hiding message lengths
+//! zstd:LEVEL  frames compressed with zstd at LEVEL (1 to 22), or stored when that doesn't pay
+//! ```
+//!
+//! This is scrambling, not encryption: nothing is authenticated and the key
+//! only keeps fixed patterns off the wire.
+//!
+//! `zstd` needs the `zstd` feature and is meant for slow links between
+//! merino nodes. Short writes and TLS records, which don't shrink, are
+//! stored as they are, as is anything compressing doesn't make smaller.
+//! Put it before `xor`, as scrambled bytes don't compress.
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::fmt;
+use std::io::{self, prelude::*};
+use std::net::{IpAddr, Shutdown};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::Connection;
+
+/// Bytes read from the wire at once
+const READ_CHUNK: usize = 16384;
+
+const XOR_NONCE_LEN: usize = 8;
+
+/// Most data in one padded frame
+const PAD_FRAME_MAX: usize = 0xFFFF;
+
+/// Encodes one direction of a connection for the wire, or decodes it
+pub trait Codec: Send {
+    /// Append the wire form of `data` to `out`
+    fn encode(&mut self, data: &[u8], out: &mut Vec<u8>);
+
+    /// Decode what `wire` holds so far into `out`, removing the bytes used from `wire`
+    ///
+    /// Bytes that don't make up a whole unit yet stay in `wire` for next time.
+    fn decode(&mut self, wire: &mut Vec<u8>, out: &mut Vec<u8>) -> io::Result<()>;
+}
+
+/// A way of scrambling connections, handing out a codec per direction
+pub trait Transport: Send + Sync {
+    fn codec(&self) -> Box<dyn Codec>;
+}
+
+/// XOR with a keystream, seeded per connection by a random nonce sent first
+struct Xor {
+    seed: [u8; 32],
+}
+
+struct XorCodec {
+    seed: [u8; 32],
+    keystream: Option<Keystream>,
+}
+
+impl Transport for Xor {
+    fn codec(&self) -> Box<dyn Codec> {
+        Box::new(XorCodec { seed: self.seed, keystream: None })
+    }
+}
+
+impl XorCodec {
+    fn keystream(&self, nonce: [u8; XOR_NONCE_LEN]) -> Keystream {
+        let mut rng = ChaCha20Rng::from_seed(self.seed);
+        rng.set_stream(u64::from_le_bytes(nonce));
+        Keystream { rng, block: [0u8; 64], used: 64 }
+    }
+}
+
+/// Keystream bytes handed out a block at a time, as the RNG drops what is left of a word
+/// when filling lengths that aren't a multiple of 4
+struct Keystream {
+    rng: ChaCha20Rng,
+    block: [u8; 64],
+    used: usize,
+}
+
+impl Keystream {
+    fn xor(&mut self, data: &mut [u8]) {
+        for byte in data {
+            if self.used == self.block.len() {
+                self.rng.fill_bytes(&mut self.block);
+                self.used = 0;
+            }
+            *byte ^= self.block[self.used];
+            self.used += 1;
+        }
+    }
+}
+
+impl Codec for XorCodec {
+    fn encode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        if self.keystream.is_none() {
+            let nonce: [u8; XOR_NONCE_LEN] = rand::thread_rng().gen();
+            out.extend_from_slice(&nonce);
+            self.keystream = Some(self.keystream(nonce));
+        }
+        let start = out.len();
+        out.extend_from_slice(data);
+        self.keystream.as_mut().unwrap().xor(&mut out[start..]);
+    }
+
+    fn decode(&mut self, wire: &mut Vec<u8>, out: &mut Vec<u8>) -> io::Result<()> {
+        if self.keystream.is_none() {
+            if wire.len() < XOR_NONCE_LEN {
+                return Ok(());
+            }
+            let mut nonce = [0u8; XOR_NONCE_LEN];
+            nonce.copy_from_slice(&wire[..XOR_NONCE_LEN]);
+            wire.drain(..XOR_NONCE_LEN);
+            self.keystream = Some(self.keystream(nonce));
+        }
+        let start = out.len();
+        out.append(wire);
+        self.keystream.as_mut().unwrap().xor(&mut out[start..]);
+        Ok(())
+    }
+}
+
+/// Frames of data length, padding length, data and random padding
+struct Pad {
+    max: u8,
+}
+
+impl Transport for Pad {
+    fn codec(&self) -> Box<dyn Codec> {
+        Box::new(PadCodec { max: self.max })
+    }
+}
+
+struct PadCodec {
+    max: u8,
+}
+
+impl Codec for PadCodec {
+    fn encode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        let mut rng = rand::thread_rng();
+        for frame in data.chunks(PAD_FRAME_MAX) {
+            let padding = rng.gen_range(0..=self.max);
+            out.extend_from_slice(&(frame.len() as u16).to_be_bytes());
+            out.push(padding);
+            out.extend_from_slice(frame);
+            let start = out.len();
+            out.resize(start + usize::from(padding), 0);
+            rng.fill_bytes(&mut out[start..]);
+        }
+    }
+
+    fn decode(&mut self, wire: &mut Vec<u8>, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut used = 0;
+        while let [high, low, padding, ..] = wire[used..] {
+            let len = usize::from(u16::from_be_bytes([high, low]));
+            let frame = 3 + len + usize::from(padding);
+            if wire.len() - used < frame {
+                break;
+            }
+            out.extend_from_slice(&wire[used + 3..used + 3 + len]);
+            used += frame;
+        }
+        wire.drain(..used);
+        Ok(())
+    }
+}
+
+/// Frames of kind, data length and data, compressed with zstd where that makes them smaller
+#[cfg(feature = "zstd")]
+struct Zstd {
+    level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Transport for Zstd {
+    fn codec(&self) -> Box<dyn Codec> {
+        Box::new(ZstdCodec { level: self.level, compressor: None, decompressor: None })
+    }
+}
+
+/// Writes shorter than this aren't worth compressing
+#[cfg(feature = "zstd")]
+const ZSTD_MIN: usize = 128;
+#[cfg(feature = "zstd")]
+const ZSTD_STORED: u8 = 0;
+#[cfg(feature = "zstd")]
+const ZSTD_COMPRESSED: u8 = 1;
+
+#[cfg(feature = "zstd")]
+struct ZstdCodec {
+    level: i32,
+    compressor: Option<zstd::bulk::Compressor<'static>>,
+    decompressor: Option<zstd::bulk::Decompressor<'static>>,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdCodec {
+    /// `frame` compressed, if that is worth it
+    fn compress(&mut self, frame: &[u8]) -> Option<Vec<u8>> {
+        // TLS handshake and application records are encrypted or random already
+        if frame.len() < ZSTD_MIN || matches!(frame, [0x16 | 0x17, 0x03, ..]) {
+            return None;
+        }
+        if self.compressor.is_none() {
+            self.compressor = Some(zstd::bulk::Compressor::new(self.level).ok()?);
+        }
+        let compressed = self.compressor.as_mut()?.compress(frame).ok()?;
+        (compressed.len() < frame.len() && compressed.len() <= PAD_FRAME_MAX).then_some(compressed)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Codec for ZstdCodec {
+    fn encode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for frame in data.chunks(PAD_FRAME_MAX) {
+            let (kind, body) = match self.compress(frame) {
+                Some(compressed) => (ZSTD_COMPRESSED, compressed),
+                None => (ZSTD_STORED, frame.to_vec()),
+            };
+            out.push(kind);
+            out.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            out.extend_from_slice(&body);
+        }
+    }
+
+    fn decode(&mut self, wire: &mut Vec<u8>, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut used = 0;
+        while let [kind, high, low, ..] = wire[used..] {
+            let len = usize::from(u16::from_be_bytes([high, low]));
+            if wire.len() - used < 3 + len {
+                break;
+            }
+            let body = &wire[used + 3..used + 3 + len];
+            match kind {
+                ZSTD_STORED => out.extend_from_slice(body),
+                ZSTD_COMPRESSED => {
+                    if self.decompressor.is_none() {
+                        self.decompressor = Some(zstd::bulk::Decompressor::new()?);
+                    }
+                    // A frame never holds more than PAD_FRAME_MAX bytes, whatever it claims
+                    out.extend(self.decompressor.as_mut().unwrap().decompress(body, PAD_FRAME_MAX)?);
+                },
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown zstd frame kind")),
+            }
+            used += 3 + len;
+        }
+        wire.drain(..used);
+        Ok(())
+    }
+}
+
+/// Layers applied in order when writing, and in reverse when reading
+#[derive(Clone)]
+pub struct Stack {
+    layers: Vec<(String, Arc<dyn Transport>)>,
+}
+
+/// Codecs of a stack, with what each layer has been handed but not yet decoded
+struct StackCodec {
+    layers: Vec<(Box<dyn Codec>, Vec<u8>)>,
+}
+
+impl Transport for Stack {
+    fn codec(&self) -> Box<dyn Codec> {
+        Box::new(StackCodec { layers: self.layers.iter().map(|(_, layer)| (layer.codec(), Vec::new())).collect() })
+    }
+}
+
+impl Codec for StackCodec {
+    fn encode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        let mut data = data.to_vec();
+        for (codec, _) in &mut self.layers {
+            let mut encoded = Vec::new();
+            codec.encode(&data, &mut encoded);
+            data = encoded;
+        }
+        out.append(&mut data);
+    }
+
+    fn decode(&mut self, wire: &mut Vec<u8>, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut data = std::mem::take(wire);
+        for (codec, pending) in self.layers.iter_mut().rev() {
+            pending.append(&mut data);
+            codec.decode(pending, &mut data)?;
+        }
+        out.append(&mut data);
+        Ok(())
+    }
+}
+
+impl Stack {
+    /// Wrap `stream`, so what is written to it goes out scrambled and what is read comes back in the clear
+    pub fn wrap<S>(&self, stream: S) -> Wrapped<S> {
+        Wrapped {
+            inner: stream,
+            reader: Arc::new(Mutex::new(Reader { codec: self.codec(), wire: Vec::new(), plaintext: Vec::new(), read: 0 })),
+            writer: Arc::new(Mutex::new(self.codec())),
+        }
+    }
+}
+
+impl FromStr for Stack {
+    type Err = String;
+
+    /// Parse comma-separated layers, e.g. `pad:255,xor:KEY`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut layers = Vec::new();
+        for layer in s.split(',').map(str::trim) {
+            let (name, param) = layer.split_once(':').unwrap_or((layer, ""));
+            let transport: Arc<dyn Transport> = match name {
+                "xor" if param.is_empty() => return Err("`xor` needs a key, as xor:KEY".to_string()),
+                "xor" => {
+                    // Folded into a keystream seed; obfuscation needs no more than that
+                    let mut seed = [0u8; 32];
+                    for (i, byte) in param.bytes().enumerate() {
+                        seed[i % 32] ^= byte;
+                    }
+                    Arc::new(Xor { seed })
+                },
+                "pad" => {
+                    let max = param.parse().map_err(|_| format!("`{}` must be pad:MAX with MAX up to 255", layer))?;
+                    Arc::new(Pad { max })
+                },
+                #[cfg(feature = "zstd")]
+                "zstd" => match param.parse() {
+                    Ok(level) if (1..=22).contains(&level) => Arc::new(Zstd { level }),
+                    _ => return Err(format!("`{}` must be zstd:LEVEL with LEVEL from 1 to 22", layer)),
+                },
+                #[cfg(not(feature = "zstd"))]
+                "zstd" => return Err("`zstd` needs merino built with the zstd feature".to_string()),
+                _ => return Err(format!("Unknown transport layer `{}`, expected xor:KEY, pad:MAX or zstd:LEVEL", layer)),
+            };
+            layers.push((name.to_string(), transport));
+        }
+        Ok(Stack { layers })
+    }
+}
+
+impl fmt::Display for Stack {
+    /// The layers, without their keys
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = self.layers.iter().map(|(name, _)| name.as_str()).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+impl fmt::Debug for Stack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Stack({})", self)
+    }
+}
+
+/// A connection read and written through a [`Stack`]
+///
+/// Clones share the codecs, so one thread can read while another writes.
+pub struct Wrapped<S> {
+    inner: S,
+    reader: Arc<Mutex<Reader>>,
+    writer: Arc<Mutex<Box<dyn Codec>>>,
+}
+
+struct Reader {
+    codec: Box<dyn Codec>,
+    wire: Vec<u8>,
+    plaintext: Vec<u8>,
+    read: usize,
+}
+
+impl<S> Wrapped<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Read> Read for Wrapped<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().unwrap();
+        let reader = &mut *reader;
+        while reader.read == reader.plaintext.len() {
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            reader.plaintext.clear();
+            reader.read = 0;
+            let mut chunk = [0u8; READ_CHUNK];
+            let read = self.inner.read(&mut chunk)?;
+            if read == 0 {
+                return Ok(0);
+            }
+            reader.wire.extend_from_slice(&chunk[..read]);
+            reader.codec.decode(&mut reader.wire, &mut reader.plaintext)?;
+        }
+        let available = &reader.plaintext[reader.read..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        reader.read += len;
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for Wrapped<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut encoded = Vec::with_capacity(buf.len() + 64);
+        self.writer.lock().unwrap().encode(buf, &mut encoded);
+        self.inner.write_all(&encoded)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Connection> Connection for Wrapped<S> {
+    fn peer_ip(&self) -> io::Result<IpAddr> {
+        self.inner.peer_ip()
+    }
+
+    fn peer_port(&self) -> Option<u16> {
+        self.inner.peer_port()
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(Wrapped { inner: self.inner.try_clone()?, reader: self.reader.clone(), writer: self.writer.clone() })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    fn reset(&self) {
+        self.inner.reset()
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        self.inner.set_dscp(dscp)
+    }
+
+    fn buffer_size(&self) -> Option<usize> {
+        self.inner.buffer_size()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+}
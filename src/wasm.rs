@@ -0,0 +1,141 @@
+//! WebAssembly plugins, so policy logic can be deployed without recompiling merino
+//!
+//! A plugin module exports any of the following, all optional:
+//!
+//! ```text
+//! ;; Return 0 to allow the request, anything else to deny it.
+//! ;; Strings are UTF-8, written into memory obtained from `alloc`.
+//! on_request(session: i64, source: i32, source_len: i32, user: i32, user_len: i32,
+//!            destination: i32, destination_len: i32, port: i32) -> i32
+//! on_connect_result(session: i64, connected: i32)
+//! on_close(session: i64, bytes: i64)
+//! ```
+//!
+//! Modules exporting `on_request` must also export their `memory` and an
+//! `alloc(len: i32) -> i32` function; memory handed out by `alloc` is only
+//! read during the call it was allocated for. Plugins may import
+//! `merino.log(ptr: i32, len: i32)` to write to merino's log.
+use std::error::Error;
+use std::path::Path;
+use std::sync::Mutex;
+use wasmi::{Caller, Engine, Extern, Instance, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::plugin::Plugin;
+use crate::rules::{Action, Request};
+
+type OnRequest = TypedFunc<(i64, i32, i32, i32, i32, i32, i32, i32), i32>;
+
+struct Inner {
+    store: Store<()>,
+    memory: Option<Memory>,
+    alloc: Option<TypedFunc<i32, i32>>,
+    on_request: Option<OnRequest>,
+    on_connect_result: Option<TypedFunc<(i64, i32), ()>>,
+    on_close: Option<TypedFunc<(i64, i64), ()>>,
+}
+
+impl Inner {
+    /// Copy `bytes` into guest memory, returning the pointer and length
+    fn write(&mut self, bytes: &[u8]) -> Result<(i32, i32), Box<dyn Error>> {
+        let (alloc, memory) = match (self.alloc, self.memory) {
+            (Some(alloc), Some(memory)) => (alloc, memory),
+            _ => return Err("Plugin must export `alloc` and `memory`".into()),
+        };
+        let ptr = alloc.call(&mut self.store, bytes.len() as i32)?;
+        memory.write(&mut self.store, ptr as usize, bytes).map_err(|e| e.to_string())?;
+        Ok((ptr, bytes.len() as i32))
+    }
+}
+
+/// A loaded WebAssembly plugin
+///
+/// Calls into a module are serialized, so hooks should be quick.
+pub struct WasmPlugin {
+    name: String,
+    inner: Mutex<Inner>,
+}
+
+impl WasmPlugin {
+    /// Load a plugin from a `.wasm` file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let name = path.as_ref().display().to_string();
+        Self::from_bytes(&name, &std::fs::read(path)?)
+    }
+
+    /// Instantiate a plugin from the bytes of a module
+    pub fn from_bytes(name: &str, wasm: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm)?;
+        let mut store = Store::new(&engine, ());
+
+        let mut linker = <Linker<()>>::new(&engine);
+        let plugin = name.to_string();
+        linker.func_wrap("merino", "log", move |caller: Caller<'_, ()>, ptr: i32, len: i32| {
+            if let Some(Extern::Memory(memory)) = caller.get_export("memory") {
+                let mut buf = vec![0u8; len.max(0) as usize];
+                if memory.read(&caller, ptr as usize, &mut buf).is_ok() {
+                    info!("[{}] {}", plugin, String::from_utf8_lossy(&buf));
+                }
+            }
+        })?;
+
+        let instance: Instance = linker.instantiate(&mut store, &module)?.start(&mut store)?;
+
+        let inner = Inner {
+            memory: instance.get_memory(&store, "memory"),
+            alloc: instance.get_typed_func(&store, "alloc").ok(),
+            on_request: instance.get_typed_func(&store, "on_request").ok(),
+            on_connect_result: instance.get_typed_func(&store, "on_connect_result").ok(),
+            on_close: instance.get_typed_func(&store, "on_close").ok(),
+            store,
+        };
+        if inner.on_request.is_some() && (inner.alloc.is_none() || inner.memory.is_none()) {
+            return Err(format!("Plugin {} exports `on_request` without `alloc` and `memory`", name).into());
+        }
+
+        info!("Loaded plugin {}", name);
+        Ok(WasmPlugin { name: name.to_string(), inner: Mutex::new(inner) })
+    }
+}
+
+impl Plugin for WasmPlugin {
+    fn on_request(&self, session: u64, request: &Request) -> Result<Action, Box<dyn Error>> {
+        let mut inner = self.inner.lock().unwrap();
+        let on_request = match inner.on_request {
+            Some(f) => f,
+            None => return Ok(Action::Allow),
+        };
+
+        let (source, source_len) = inner.write(request.source.to_string().as_bytes())?;
+        let (user, user_len) = inner.write(request.user.unwrap_or("").as_bytes())?;
+        let (destination, destination_len) = inner.write(request.destination.to_string().as_bytes())?;
+
+        let verdict = on_request.call(&mut inner.store, (
+            session as i64,
+            source, source_len,
+            user, user_len,
+            destination, destination_len,
+            i32::from(request.port),
+        )).map_err(|e| format!("Plugin {} failed: {}", self.name, e))?;
+
+        Ok(if verdict == 0 { Action::Allow } else { Action::Deny })
+    }
+
+    fn on_connect_result(&self, session: u64, connected: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(f) = inner.on_connect_result {
+            if let Err(error) = f.call(&mut inner.store, (session as i64, connected as i32)) {
+                warn!("Plugin {} failed in on_connect_result: {}", self.name, error);
+            }
+        }
+    }
+
+    fn on_close(&self, session: u64, bytes: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(f) = inner.on_close {
+            if let Err(error) = f.call(&mut inner.store, (session as i64, bytes as i64)) {
+                warn!("Plugin {} failed in on_close: {}", self.name, error);
+            }
+        }
+    }
+}
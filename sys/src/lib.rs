@@ -0,0 +1,172 @@
+//! The system calls merino makes that the compiler can't check, behind safe functions
+//!
+//! merino itself forbids `unsafe`. The little it needs beyond std, socket2
+//! and nix lives here instead, so there is one small place to audit:
+//! integer socket options std doesn't wrap, taking ownership of descriptors
+//! received over `SCM_RIGHTS`, and the seccomp, Landlock, pledge and unveil
+//! calls confining the process. Every `unsafe` block says why it is sound,
+//! and none of these functions can be misused into undefined behaviour.
+#![deny(unsafe_op_in_unsafe_fn)]
+
+#[cfg(target_os = "linux")]
+pub use linux::*;
+
+#[cfg(target_os = "openbsd")]
+pub use openbsd::*;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::convert::TryFrom;
+    use std::io;
+    use std::mem::size_of;
+    use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::net::UnixStream;
+
+    use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+
+    /// Result of a system call returning 0 on success and -1 on failure
+    fn check(result: libc::c_long) -> io::Result<()> {
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Set the integer socket option `name` at `level` of `socket`
+    pub fn setsockopt_int(socket: BorrowedFd<'_>, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        // SAFETY: `value` outlives the call, which reads exactly its size
+        check(unsafe {
+            libc::setsockopt(socket.as_raw_fd(), level, name, &value as *const libc::c_int as *const libc::c_void, size_of::<libc::c_int>() as libc::socklen_t)
+        }.into())
+    }
+
+    /// The integer socket option `name` at `level` of `socket`
+    pub fn getsockopt_int(socket: BorrowedFd<'_>, level: libc::c_int, name: libc::c_int) -> io::Result<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: `value` and `len` outlive the call, which writes at most `len` bytes
+        check(unsafe {
+            libc::getsockopt(socket.as_raw_fd(), level, name, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len)
+        }.into())?;
+        Ok(value)
+    }
+
+    /// Receive one byte from `stream`, and up to `N` descriptors sent along with it over `SCM_RIGHTS`
+    pub fn recv_fds<const N: usize>(stream: &UnixStream) -> io::Result<Vec<OwnedFd>> {
+        let mut byte = [0u8; 1];
+        let mut iov = [io::IoSliceMut::new(&mut byte)];
+        let mut space = nix::cmsg_space!([RawFd; N]);
+        let message = recvmsg::<()>(stream.as_fd().as_raw_fd(), &mut iov, Some(&mut space), MsgFlags::MSG_CMSG_CLOEXEC)?;
+        let mut fds = Vec::new();
+        for cmsg in message.cmsgs()? {
+            if let ControlMessageOwned::ScmRights(received) = cmsg {
+                // SAFETY: the kernel just created these descriptors for this process, nothing else owns them
+                fds.extend(received.into_iter().map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+            }
+        }
+        Ok(fds)
+    }
+
+    /// Keep this process and its children from gaining privileges, e.g. through setuid binaries
+    pub fn set_no_new_privs() -> io::Result<()> {
+        // SAFETY: takes no pointers
+        check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) }.into())
+    }
+
+    /// Install the seccomp-bpf `filter` on every thread of the process
+    ///
+    /// Requires [`set_no_new_privs`] unless running with `CAP_SYS_ADMIN`.
+    pub fn seccomp(filter: &[libc::sock_filter]) -> io::Result<()> {
+        let len = u16::try_from(filter.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "seccomp filter too long"))?;
+        // The kernel only reads the filter, the pointer is mutable in the struct alone
+        let program = libc::sock_fprog { len, filter: filter.as_ptr() as *mut libc::sock_filter };
+        // SAFETY: `program` and the filter it points to outlive the call, and the kernel validates and copies them
+        check(unsafe {
+            libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, libc::SECCOMP_FILTER_FLAG_TSYNC, &program as *const libc::sock_fprog)
+        })
+    }
+
+    /// Landlock's ruleset creation and path rules, see landlock(7)
+    pub mod landlock {
+        use super::*;
+
+        const CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+        const RULE_PATH_BENEATH: libc::c_int = 1;
+
+        #[repr(C)]
+        struct RulesetAttr {
+            handled_access_fs: u64,
+        }
+
+        #[repr(C, packed)]
+        struct PathBeneathAttr {
+            allowed_access: u64,
+            parent_fd: i32,
+        }
+
+        /// Landlock ABI version of the running kernel, if it supports Landlock at all
+        pub fn abi() -> Option<i64> {
+            // SAFETY: querying the version takes no attributes
+            let version = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<RulesetAttr>(), 0, CREATE_RULESET_VERSION) };
+            (version > 0).then_some(version)
+        }
+
+        /// A ruleset restricting the filesystem accesses in `handled`
+        pub fn create_ruleset(handled: u64) -> io::Result<OwnedFd> {
+            let attr = RulesetAttr { handled_access_fs: handled };
+            // SAFETY: the attribute outlives the call, which reads exactly its size
+            let fd = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, &attr as *const RulesetAttr, size_of::<RulesetAttr>(), 0) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: the kernel just created this descriptor for this process, nothing else owns it
+            Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+        }
+
+        /// Allow `access` beneath the file or directory open as `parent`
+        pub fn add_path_beneath(ruleset: BorrowedFd<'_>, parent: BorrowedFd<'_>, access: u64) -> io::Result<()> {
+            let attr = PathBeneathAttr { allowed_access: access, parent_fd: parent.as_raw_fd() };
+            // SAFETY: both descriptors and the attribute outlive the call, which only reads the attribute
+            check(unsafe { libc::syscall(libc::SYS_landlock_add_rule, ruleset.as_raw_fd(), RULE_PATH_BENEATH, &attr as *const PathBeneathAttr, 0) })
+        }
+
+        /// Confine the calling thread, and threads it starts later, to `ruleset`
+        ///
+        /// Requires [`set_no_new_privs`] unless running with `CAP_SYS_ADMIN`.
+        pub fn restrict_self(ruleset: BorrowedFd<'_>) -> io::Result<()> {
+            // SAFETY: takes no pointers, the descriptor outlives the call
+            check(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) })
+        }
+    }
+}
+
+#[cfg(target_os = "openbsd")]
+mod openbsd {
+    use std::ffi::CStr;
+    use std::io;
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Make `path` visible with `permissions`, see unveil(2)
+    pub fn unveil(path: &CStr, permissions: &CStr) -> io::Result<()> {
+        // SAFETY: both strings outlive the call
+        check(unsafe { libc::unveil(path.as_ptr(), permissions.as_ptr()) })
+    }
+
+    /// Hide everything not unveiled so far, and allow no further unveil calls
+    pub fn lock_unveil() -> io::Result<()> {
+        // SAFETY: null pointers lock unveil
+        check(unsafe { libc::unveil(std::ptr::null(), std::ptr::null()) })
+    }
+
+    /// Limit the process to `promises`, see pledge(2), leaving exec promises unchanged
+    pub fn pledge(promises: &CStr) -> io::Result<()> {
+        // SAFETY: the string outlives the call, a null pointer leaves exec promises unchanged
+        check(unsafe { libc::pledge(promises.as_ptr(), std::ptr::null()) })
+    }
+}
@@ -0,0 +1,20 @@
+use merino::*;
+use std::net::{TcpListener, TcpStream};
+
+#[test]
+/// Accepting hands over the next connection
+fn accept_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let stream = accept::accept(&listener);
+    assert_eq!(stream.peer_addr().unwrap(), client.local_addr().unwrap());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+/// The open file limit can be raised to the hard limit
+fn accept_nofile_limit() {
+    let limit = accept::raise_nofile_limit().unwrap();
+    assert!(limit > 0);
+    assert_eq!(accept::raise_nofile_limit().unwrap(), limit);
+}
@@ -0,0 +1,131 @@
+#![cfg(feature = "socks5")]
+use merino::access_log::{Entry, Fingerprint};
+use merino::*;
+use chrono::{Local, TimeZone};
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Writer collecting everything into a shared buffer
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+/// Entries are written in the combined log format
+fn access_log_format() {
+    let time = Local.with_ymd_and_hms(2024, 5, 6, 10, 0, 0).unwrap();
+    let entry = Entry {
+        client: "10.0.0.5".parse().unwrap(),
+        user: Some("alice"),
+        time,
+        request: Some("example.com:443"),
+        status: 200,
+        bytes: 5120,
+        sni: None,
+        fingerprint: None,
+    };
+    let expected = format!("10.0.0.5 - alice [06/May/2024:10:00:00 {}] \"CONNECT example.com:443\" 200 5120 \"-\" \"-\"", time.format("%z"));
+    assert_eq!(entry.to_string(), expected);
+
+    let entry = Entry { sni: Some("example.com"), ..entry };
+    assert!(entry.to_string().ends_with(" 200 5120 \"example.com\" \"-\""));
+
+    let entry = Entry { user: None, request: None, status: 400, bytes: 0, sni: None, ..entry };
+    assert!(entry.to_string().ends_with("] \"-\" 400 - \"-\" \"-\""));
+}
+
+#[test]
+/// Handshake fingerprints name the version, methods in the order offered, and as much of the request as was sent
+fn access_log_fingerprint() {
+    let fingerprint = Fingerprint {
+        version: 5,
+        methods: vec![0x02, 0x00, 0x80],
+        userpass: Some(1),
+        command: Some(1),
+        address: Some(3),
+    };
+    assert_eq!(fingerprint.to_string(), "socks5 m=02,00,80 up=1 cmd=1 atyp=3");
+    assert_eq!(Fingerprint { version: 4, ..Fingerprint::default() }.to_string(), "socks4");
+
+    let entry = Entry {
+        client: "10.0.0.5".parse().unwrap(),
+        user: None,
+        time: Local::now(),
+        request: None,
+        status: 400,
+        bytes: 0,
+        sni: None,
+        fingerprint: Some(&fingerprint),
+    };
+    assert!(entry.to_string().ends_with(" 400 - \"-\" \"socks5 m=02,00,80 up=1 cmd=1 atyp=3\""));
+}
+
+#[test]
+/// Sampling skips relayed sessions but never failures
+fn access_log_sampling() {
+    let out = Shared::default();
+    let log = AccessLog::new(out.clone()).with_sampling(3);
+    let entry = Entry {
+        client: "10.0.0.5".parse().unwrap(),
+        user: None,
+        time: Local::now(),
+        request: Some("example.com:443"),
+        status: 200,
+        bytes: 1,
+        sni: None,
+        fingerprint: None,
+    };
+    for bytes in 1..=7 {
+        log.log(&Entry { bytes, ..entry.clone() });
+        log.log(&Entry { status: 403, bytes: 0, ..entry.clone() });
+    }
+
+    let log = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let relayed: Vec<&str> = log.lines().filter(|line| line.contains(" 200 ")).collect();
+    assert_eq!(relayed.len(), 3);
+    assert!(relayed[0].contains(" 200 1 ") && relayed[1].contains(" 200 4 ") && relayed[2].contains(" 200 7 "));
+    assert_eq!(log.lines().filter(|line| line.contains(" 403 ")).count(), 7);
+}
+
+#[test]
+/// Relayed and denied connections are both logged
+fn access_log_sessions() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let out = Shared::default();
+    let rules = Rules::from_reader("action,user,source,destination,port\ndeny,,,,9\n".as_bytes()).unwrap();
+    let proxy = Arc::new(Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_rules(rules)
+        .with_access_log(AccessLog::new(out.clone())));
+
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let serving = { let proxy = proxy.clone(); thread::spawn(move || proxy.serve_connection(server).is_ok()) };
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    assert!(serving.join().unwrap());
+
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let serving = { let proxy = proxy.clone(); thread::spawn(move || proxy.serve_connection(server).is_ok()) };
+    assert!(client::connect(&mut stream, "127.0.0.1", 9, None).is_err());
+    assert!(!serving.join().unwrap());
+
+    let log = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains(&format!("\"CONNECT {}\" 200 10 ", echo)));
+    assert!(lines[0].ends_with("\"socks5 m=00 cmd=1 atyp=1\""));
+    assert!(lines[1].contains("\"CONNECT 127.0.0.1:9\" 403 - "));
+}
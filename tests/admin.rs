@@ -0,0 +1,422 @@
+#![cfg(feature = "socks5")]
+use merino::admin::BanTarget;
+use merino::*;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Send an HTTP request to the admin API, returning the response
+fn request(addr: SocketAddr, method: &str, path: &str) -> String {
+    let mut http = TcpStream::connect(addr).unwrap();
+    write!(http, "{} {} HTTP/1.1\r\n\r\n", method, path).expect(path);
+    let mut response = String::new();
+    http.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+/// Sessions are listed and killed, and banned sources refused, over HTTP
+fn admin_kill_and_ban() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_admin(admin_addr).unwrap();
+    let admin = proxy.admin();
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+
+    let sessions = admin.sessions();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].request, echo.to_string());
+    let response = request(admin_addr, "GET", "/sessions");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains(&format!("{}\t127.0.0.1\t-\t{}\t", sessions[0].id, echo)));
+
+    let response = request(admin_addr, "POST", &format!("/sessions/{}/kill", sessions[0].id));
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert!(matches!(stream.read(&mut buf), Ok(0) | Err(_)));
+    assert!(request(admin_addr, "POST", "/sessions/999999/kill").starts_with("HTTP/1.1 404"));
+
+    // Too long to represent, the API still answering afterwards
+    assert!(request(admin_addr, "POST", &format!("/bans/source/127.0.0.1?minutes={}", u64::MAX / 30)).starts_with("HTTP/1.1 400"));
+    assert!(request(admin_addr, "POST", "/bans/source/127.0.0.1?minutes=5").starts_with("HTTP/1.1 200 OK"));
+    assert!(request(admin_addr, "GET", "/bans").contains("source 127.0.0.1\tuntil "));
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert!(client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).is_err());
+
+    let start = Instant::now();
+    while !admin.sessions().is_empty() && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(admin.sessions().is_empty());
+
+    assert!(request(admin_addr, "DELETE", "/bans/source/127.0.0.1").starts_with("HTTP/1.1 200 OK"));
+    assert!(request(admin_addr, "DELETE", "/bans/source/127.0.0.1").starts_with("HTTP/1.1 404"));
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+}
+
+#[test]
+/// Banned users are refused after authenticating
+fn admin_ban_user() {
+    let users: Vec<User> = csv::Reader::from_reader("username,password\nbob,secret\n".as_bytes())
+        .deserialize().collect::<Result<_, _>>().unwrap();
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], users).unwrap();
+    proxy.admin().ban(BanTarget::User("bob".to_string()), Duration::from_secs(60)).unwrap();
+    assert_eq!(proxy.admin().bans().unwrap().len(), 1);
+
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let serving = thread::spawn(move || proxy.serve_connection(server));
+    assert!(client::connect(&mut stream, "127.0.0.1", 80, Some(("bob", "secret"))).is_err());
+    match serving.join().unwrap() {
+        Err(MerinoError::Denied { reason }) => assert_eq!(reason, "user bob is banned"),
+        other => panic!("Expected a denial, got {:?}", other),
+    }
+}
+
+#[test]
+/// Bans longer than dates can represent are cut to the longest allowed
+fn admin_ban_capped() {
+    let admin = Admin::default();
+    admin.ban(BanTarget::User("mallory".to_string()), Duration::MAX).unwrap();
+    let until = admin.bans().unwrap()[0].until;
+    assert!(until > chrono::Local::now() + chrono::Duration::days(99 * 365));
+    assert!(until < chrono::Local::now() + chrono::Duration::days(101 * 365));
+}
+
+#[test]
+/// Bans saved to the ban list are loaded again, and lifting removes them
+fn admin_ban_list() {
+    let path = std::env::temp_dir().join(format!("merino-bans-{}.csv", std::process::id()));
+    std::fs::write(&path, "kind,target,until\nuser,expired,2000-01-01T00:00:00+00:00\n").unwrap();
+
+    let admin = Admin::default();
+    admin.persist_to(&path).unwrap();
+    assert!(admin.bans().unwrap().is_empty());
+    admin.ban(BanTarget::Source("203.0.113.7".parse().unwrap()), Duration::from_secs(60)).unwrap();
+    admin.ban(BanTarget::User("mallory".to_string()), Duration::from_secs(60)).unwrap();
+
+    let restarted = Admin::default();
+    restarted.persist_to(&path).unwrap();
+    assert_eq!(restarted.bans().unwrap(), admin.bans().unwrap());
+    assert!(restarted.banned("203.0.113.7".parse().unwrap(), None).unwrap().is_some());
+
+    assert!(restarted.lift(&BanTarget::User("mallory".to_string())).unwrap());
+    assert!(!restarted.lift(&BanTarget::User("mallory".to_string())).unwrap());
+    let restarted = Admin::default();
+    restarted.persist_to(&path).unwrap();
+    assert_eq!(restarted.bans().unwrap().len(), 1);
+    assert!(restarted.banned("10.0.0.5".parse().unwrap(), Some("mallory")).unwrap().is_none());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+/// A session's traffic is captured to a pcap file while asked to over HTTP
+fn admin_capture() {
+    let dir = std::env::temp_dir().join(format!("merino-captures-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let echo = bench::spawn_echo_server().unwrap();
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_admin(admin_addr).unwrap()
+        .with_capture_dir(&dir).unwrap();
+    let admin = proxy.admin();
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    let mut buf = [0u8; 6];
+    stream.write_all(b"before").unwrap();
+    stream.read_exact(&mut buf).unwrap();
+
+    let id = admin.sessions()[0].id;
+    // The capture is in place once the API answers, so only what is relayed from then on is recorded
+    let response = request(admin_addr, "POST", &format!("/sessions/{}/capture", id));
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    let path = dir.join(format!("session-{}.pcap", id));
+    assert!(response.trim_end().ends_with(&format!("Capturing session {} into {}", id, path.display())), "{}", response);
+    stream.write_all(b"during").unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    // The reply is recorded just after the client gets it
+    let count = |needle: &[u8]| {
+        let pcap = std::fs::read(&path).unwrap();
+        pcap.windows(needle.len()).filter(|window| *window == needle).count()
+    };
+    let start = Instant::now();
+    while count(b"during") < 2 && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(request(admin_addr, "DELETE", &format!("/sessions/{}/capture", id)).starts_with("HTTP/1.1 200 OK"));
+    assert!(request(admin_addr, "DELETE", &format!("/sessions/{}/capture", id)).starts_with("HTTP/1.1 404"));
+    stream.write_all(b"after!").unwrap();
+    stream.read_exact(&mut buf).unwrap();
+
+    assert_eq!(count(b"during"), 2);
+    assert_eq!(count(b"before") + count(b"after!"), 0);
+    assert!(request(admin_addr, "POST", "/sessions/999999/capture").starts_with("HTTP/1.1 404"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+/// Destinations the proxy fails to connect to are listed first over HTTP
+fn admin_destinations() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_admin(admin_addr).unwrap();
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    client::connect(&mut TcpStream::connect(addr).unwrap(), "127.0.0.1", echo.port(), None).unwrap();
+    assert!(client::connect(&mut TcpStream::connect(addr).unwrap(), "127.0.0.1", closed.port(), None).is_err());
+
+    let response = request(admin_addr, "GET", "/destinations");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    let lines: Vec<&str> = body.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with(&format!("{}\t1 connects\t1 failed (100.0%)\t-", closed)), "{}", body);
+    assert!(lines[1].starts_with(&format!("{}\t1 connects\t0 failed", echo)), "{}", body);
+    assert_eq!(request(admin_addr, "GET", "/destinations?limit=1").split("\r\n\r\n").nth(1).unwrap().lines().count(), 1);
+    assert!(request(admin_addr, "GET", "/destinations?limit=x").starts_with("HTTP/1.1 400"));
+}
+
+#[test]
+/// Listeners opened over HTTP relay like the main one, and stop accepting once closed
+fn admin_listeners() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], Vec::new()).unwrap()
+        .with_admin(admin_addr).unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+    let start = Instant::now();
+    while TcpStream::connect(admin_addr).is_err() && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let response = request(admin_addr, "POST", "/listeners/127.0.0.1:0?auth=none");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    let addr: SocketAddr = response.trim_end().rsplit(' ').next().unwrap().parse().unwrap();
+    assert!(request(admin_addr, "GET", "/listeners").contains(&format!("{}\tsocks5\tauth=none", addr)));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    assert!(request(admin_addr, "POST", &format!("/listeners/{}", addr)).starts_with("HTTP/1.1 409"));
+    assert!(request(admin_addr, "POST", "/listeners/127.0.0.1:0?protocol=gopher").starts_with("HTTP/1.1 400"));
+    assert!(request(admin_addr, "DELETE", &format!("/listeners/{}", addr)).starts_with("HTTP/1.1 200 OK"));
+    assert!(request(admin_addr, "DELETE", &format!("/listeners/{}", addr)).starts_with("HTTP/1.1 404"));
+    let start = Instant::now();
+    while TcpStream::connect(addr).is_ok() && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(TcpStream::connect(addr).is_err());
+
+    // Sessions accepted before closing carry on
+    stream.write_all(b"again").unwrap();
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"again");
+}
+
+#[test]
+/// Requests a web page could make are refused, and anonymous listeners when clients must authenticate
+fn admin_refuses_browsers() {
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], Vec::new()).unwrap()
+        .with_require_auth(true)
+        .with_admin(admin_addr).unwrap();
+    let admin = proxy.admin();
+    thread::spawn(move || proxy.serve().unwrap());
+    let start = Instant::now();
+    while TcpStream::connect(admin_addr).is_err() && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let send = |headers: &str| {
+        let mut http = TcpStream::connect(admin_addr).unwrap();
+        write!(http, "POST /listeners/127.0.0.1:0?protocol=forward&to=127.0.0.1:22 HTTP/1.1\r\n{}\r\n", headers).unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    assert!(send("Origin: http://evil.example\r\n").starts_with("HTTP/1.1 403"));
+    assert!(send("Host: evil.example:8080\r\n").starts_with("HTTP/1.1 403"));
+    assert!(admin.listeners().is_empty());
+    assert!(send(&format!("Host: {}\r\n", admin_addr)).starts_with("HTTP/1.1 200 OK"));
+    assert!(send("Host: localhost\r\n").starts_with("HTTP/1.1 200 OK"));
+
+    let response = request(admin_addr, "POST", "/listeners/127.0.0.1:0?auth=none");
+    assert!(response.starts_with("HTTP/1.1 409"), "{}", response);
+    assert!(request(admin_addr, "POST", "/listeners/127.0.0.1:0?auth=password").starts_with("HTTP/1.1 200 OK"));
+}
+
+#[test]
+/// Listeners opened over HTTP serve only CONNECT over SOCKS5 unless granted more
+fn admin_listener_capabilities() {
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_admin(admin_addr).unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+    let start = Instant::now();
+    while TcpStream::connect(admin_addr).is_err() && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let open = |query: &str| -> SocketAddr {
+        let response = request(admin_addr, "POST", &format!("/listeners/127.0.0.1:0?{}", query));
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        response.trim_end().rsplit(' ').next().unwrap().parse().unwrap()
+    };
+    // SOCKS6 greetings are dropped unless the listener is granted the version
+    let addr = open("auth=none&commands=connect");
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(&[6, 0]).unwrap();
+    assert_eq!(stream.read(&mut [0u8; 16]).unwrap_or(0), 0);
+
+    #[cfg(feature = "socks6")]
+    {
+        use merino::socks6::{self, Request};
+        let addr = open("auth=none&versions=socks5,socks6");
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let noop = Request { command: 0, ..Request::connect("127.0.0.1", 80) };
+        socks6::connect(&mut stream, &noop).unwrap();
+    }
+}
+
+#[test]
+/// Listener specs are parsed from the admin API's address and query
+fn listener_spec_parse() {
+    use merino::listeners::{ListenerSpec, Protocol};
+
+    let spec = ListenerSpec::parse("127.0.0.1:1081", "auth=none,password").unwrap();
+    assert_eq!(spec.protocol, Protocol::Socks5);
+    assert_eq!(spec.auth, Some(vec![AuthMethods::NoAuth as u8, AuthMethods::UserPass as u8]));
+    assert_eq!(spec.commands, vec![SockCommand::Connect as u8]);
+    assert_eq!(spec.versions, vec![5]);
+    assert_eq!(spec.to_string(), "127.0.0.1:1081\tsocks5\tauth=none,password\tcommands=connect\tversions=socks5");
+
+    let spec = ListenerSpec::parse("127.0.0.1:1081", "commands=connect&versions=socks5,socks6").unwrap();
+    assert_eq!(spec.versions, vec![5, 6]);
+    assert_eq!(spec.to_string(), "127.0.0.1:1081\tsocks5\tauth=default\tcommands=connect\tversions=socks5,socks6");
+
+    let spec = ListenerSpec::parse("127.0.0.1:5433", "protocol=forward&to=db.internal:5432").unwrap();
+    assert_eq!(spec.to_string(), "127.0.0.1:5433\tforward\tto=db.internal:5432");
+
+    let spec = ListenerSpec::parse("127.0.0.1:5433", "protocol=forward&to=db.internal:5432&fast_open=true").unwrap();
+    assert!(spec.fast_open);
+    assert_eq!(spec.to_string(), "127.0.0.1:5433\tforward\tto=db.internal:5432\tfast_open");
+    assert!(ListenerSpec::parse("127.0.0.1:1081", "fast_open=yes").is_err());
+
+    assert!(ListenerSpec::parse("127.0.0.1:5433", "protocol=forward").is_err());
+    assert!(ListenerSpec::parse("127.0.0.1:5433", "protocol=forward&to=db:5432&auth=none").is_err());
+    assert!(ListenerSpec::parse("127.0.0.1:1081", "auth=kerberos").is_err());
+    assert!(ListenerSpec::parse("127.0.0.1:1081", "commands=bind").is_err());
+    assert!(ListenerSpec::parse("127.0.0.1:1081", "commands=udp").is_err());
+    assert!(ListenerSpec::parse("127.0.0.1:1081", "versions=socks4").is_err());
+    assert!(ListenerSpec::parse("127.0.0.1:5433", "protocol=forward&to=db:5432&commands=connect").is_err());
+    assert!(ListenerSpec::parse("localhost", "").is_err());
+}
+
+#[test]
+/// New connections are closed while the connection limit is reached, and counted in the resources
+fn admin_max_connections() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_admin(admin_addr).unwrap()
+        .with_max_connections(1);
+    let metrics = proxy.metrics();
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    let start = Instant::now();
+    while metrics.open_connections() != 1 && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut refused = TcpStream::connect(addr).unwrap();
+    assert!(client::connect(&mut refused, &echo.ip().to_string(), echo.port(), None).is_err());
+    let resources = request(admin_addr, "GET", "/resources");
+    assert!(resources.starts_with("HTTP/1.1 200 OK"));
+    assert!(resources.contains("\nconnections 1\n"), "{}", resources);
+    assert!(resources.contains("\nmax_connections 1\n"));
+    assert!(resources.contains("\nrefused_connections 1\n"));
+
+    drop(stream);
+    let start = Instant::now();
+    while metrics.open_connections() != 0 && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+}
+
+#[test]
+/// Maintenance lets sessions finish while new clients are refused, or passed on to a standby proxy
+fn admin_maintenance() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let admin_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_admin(admin_addr).unwrap();
+    let lifecycle = proxy.lifecycle();
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+    let mut standby = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let standby_addr = standby.local_addr().unwrap();
+    let standby_admin = standby.admin();
+    thread::spawn(move || standby.serve().unwrap());
+
+    let mut session = TcpStream::connect(addr).unwrap();
+    client::connect(&mut session, &echo.ip().to_string(), echo.port(), None).unwrap();
+    let mut buf = [0u8; 5];
+
+    assert!(request(admin_addr, "POST", "/maintenance").starts_with("HTTP/1.1 200 OK"));
+    assert!(request(admin_addr, "GET", "/maintenance").ends_with("refusing new clients\n"));
+    assert!(!lifecycle.is_ready());
+    let mut refused = TcpStream::connect(addr).unwrap();
+    assert!(client::connect(&mut refused, &echo.ip().to_string(), echo.port(), None).is_err());
+    // Clients that never send a greeting are closed rather than held while draining
+    let mut idle = TcpStream::connect(addr).unwrap();
+    idle.set_read_timeout(Some(Duration::from_secs(30))).unwrap();
+    let start = Instant::now();
+    let _ = idle.read_to_end(&mut Vec::new());
+    assert!(start.elapsed() < Duration::from_secs(20));
+    session.write_all(b"still").unwrap();
+    session.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"still");
+
+    assert!(request(admin_addr, "POST", "/maintenance?standby=nowhere").starts_with("HTTP/1.1 400"));
+    assert!(request(admin_addr, "POST", &format!("/maintenance?standby={}", standby_addr)).starts_with("HTTP/1.1 200 OK"));
+    let mut passed = TcpStream::connect(addr).unwrap();
+    client::connect(&mut passed, &echo.ip().to_string(), echo.port(), None).unwrap();
+    passed.write_all(b"hello").unwrap();
+    passed.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+    assert_eq!(standby_admin.sessions().len(), 1);
+
+    assert!(request(admin_addr, "DELETE", "/maintenance").starts_with("HTTP/1.1 200 OK"));
+    assert!(request(admin_addr, "DELETE", "/maintenance").starts_with("HTTP/1.1 404"));
+    assert!(lifecycle.is_ready());
+    let mut back = TcpStream::connect(addr).unwrap();
+    assert!(client::connect(&mut back, &echo.ip().to_string(), echo.port(), None).is_ok());
+}
@@ -0,0 +1,199 @@
+use merino::asn::{Asn, AsnDb};
+use merino::rules::{Action, AsnLookup, Destination, Request};
+use merino::*;
+use std::cell::Cell;
+use std::net::IpAddr;
+
+/// Build a MaxMind DB mapping each network to an AS number and organization
+///
+/// Organizations after the first are pointers to the first, as real
+/// databases deduplicate repeated values.
+fn mmdb(ip_version: u16, record_size: u16, networks: &[(&str, u8, u32)]) -> Vec<u8> {
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+
+    let string = |s: &str| match s.len() {
+        len if len < 29 => [&[(2 << 5) | len as u8][..], s.as_bytes()].concat(),
+        len => [&[(2 << 5) | 29, (len - 29) as u8][..], s.as_bytes()].concat(),
+    };
+    let mut data = Vec::new();
+    let mut nodes = vec![[Record::Empty; 2]];
+    let mut organization = None;
+    for (network, prefix, number) in networks {
+        let offset = data.len();
+        data.push((7 << 5) | 2);
+        data.extend(string("autonomous_system_number"));
+        data.push((6 << 5) | 4);
+        data.extend(number.to_be_bytes());
+        data.extend(string("autonomous_system_organization"));
+        match organization {
+            None => {
+                organization = Some(data.len());
+                data.extend(string("Example Networks"));
+            },
+            Some(pointer) => data.extend([(1 << 5) | (pointer >> 8) as u8, pointer as u8]),
+        }
+
+        let (octets, prefix) = match network.parse::<IpAddr>().unwrap() {
+            IpAddr::V4(ip) if ip_version == 6 => (ip.to_ipv6_compatible().octets().to_vec(), 96 + *prefix as usize),
+            IpAddr::V4(ip) => (ip.octets().to_vec(), *prefix as usize),
+            IpAddr::V6(ip) => (ip.octets().to_vec(), *prefix as usize),
+        };
+        let mut node = 0;
+        for i in 0..prefix {
+            let bit = (octets[i / 8] >> (7 - i % 8) & 1) as usize;
+            if i == prefix - 1 {
+                nodes[node][bit] = Record::Data(offset);
+            } else {
+                node = match nodes[node][bit] {
+                    Record::Node(next) => next,
+                    _ => {
+                        nodes.push([Record::Empty; 2]);
+                        nodes[node][bit] = Record::Node(nodes.len() - 1);
+                        nodes.len() - 1
+                    },
+                };
+            }
+        }
+    }
+
+    let count = nodes.len() as u32;
+    let value = |record: Record| match record {
+        Record::Empty => count,
+        Record::Node(node) => node as u32,
+        Record::Data(offset) => count + 16 + offset as u32,
+    };
+    let mut file = Vec::new();
+    for [left, right] in nodes {
+        let (left, right) = (value(left), value(right));
+        match record_size {
+            24 => file.extend([&left.to_be_bytes()[1..], &right.to_be_bytes()[1..]].concat()),
+            _ => file.extend([&left.to_be_bytes()[1..], &[((left >> 24) as u8) << 4 | (right >> 24) as u8], &right.to_be_bytes()[1..]].concat()),
+        }
+    }
+    file.extend([0; 16]);
+    file.extend(data);
+
+    file.extend(b"\xab\xcd\xefMaxMind.com");
+    file.push((7 << 5) | 9);
+    file.extend(string("node_count"));
+    file.push((6 << 5) | 4);
+    file.extend(count.to_be_bytes());
+    for (key, value) in [("record_size", record_size), ("ip_version", ip_version), ("binary_format_major_version", 2), ("binary_format_minor_version", 0)].iter() {
+        file.extend(string(key));
+        file.push((5 << 5) | 2);
+        file.extend(value.to_be_bytes());
+    }
+    // Zero and empty, build_epoch and languages are the extended uint64 and array types
+    file.extend(string("build_epoch"));
+    file.extend([0, 2]);
+    file.extend(string("database_type"));
+    file.extend(string("GeoLite2-ASN"));
+    file.extend(string("languages"));
+    file.extend([0, 4]);
+    file.extend(string("description"));
+    file.push(7 << 5);
+    file
+}
+
+fn ip(s: &str) -> IpAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+/// Addresses are found in both IPv4 and IPv6 databases, with their organization
+fn asn_lookup() {
+    let networks = [("192.0.2.0", 24, 64500), ("198.51.100.0", 25, 64501), ("2001:db8::", 32, 64502)];
+    let asn = |number| Some(Asn { number, organization: Some("Example Networks".to_string()) });
+
+    let v6 = AsnDb::from_bytes(mmdb(6, 28, &networks)).unwrap();
+    assert_eq!(v6.lookup(ip("192.0.2.77")), asn(64500));
+    assert_eq!(v6.lookup(ip("::ffff:198.51.100.1")), asn(64501));
+    assert_eq!(v6.lookup(ip("2001:db8:1::1")), asn(64502));
+    assert_eq!(v6.lookup(ip("198.51.100.200")), None);
+    assert_eq!(v6.lookup(ip("203.0.113.1")), None);
+
+    let v4 = AsnDb::from_bytes(mmdb(4, 24, &networks[..2])).unwrap();
+    assert_eq!(v4.lookup(ip("198.51.100.1")), asn(64501));
+    assert_eq!(v4.lookup(ip("2001:db8::1")), None);
+    assert_eq!(asn(64501).unwrap().to_string(), "AS64501 (Example Networks)");
+
+    assert!(AsnDb::from_bytes(b"not a database".to_vec()).is_err());
+}
+
+#[test]
+/// Rules naming an AS match requests for its addresses
+fn asn_rules() {
+    let rules = Rules::from_reader("action,user,source,destination,port\ndeny,,,AS64500,\n".as_bytes()).unwrap();
+    let destination = Destination::parse("192.0.2.1");
+    let evaluate = |asn: Option<&AsnLookup>| rules.evaluate(&Request {
+        source: ip("10.0.0.1"),
+        user: None,
+        groups: &[],
+        destination: &destination,
+        port: 443,
+        asn,
+        tag: None,
+    }).action;
+
+    assert_eq!(evaluate(Some(&AsnLookup::known(Some(64500)))), Action::Deny);
+    assert_eq!(evaluate(Some(&AsnLookup::known(Some(64501)))), Action::Allow);
+    assert_eq!(evaluate(None), Action::Allow);
+}
+
+#[test]
+/// Destinations are only looked up once a rule naming an AS is reached, and domains never for Tor
+fn asn_rules_lazy() {
+    let rules = Rules::from_reader("action,user,source,destination,port,via\n\
+        deny,,,blocked.example,,\n\
+        allow,,,AS64500,,tor\n\
+        allow,,,AS64500,,\n\
+        deny,,,AS64500,,\n".as_bytes()).unwrap();
+    let lookups = Cell::new(0);
+    let lookup = AsnLookup::new(|_| {
+        lookups.set(lookups.get() + 1);
+        Some(64500)
+    });
+    let evaluate = |host: &str| {
+        let destination = Destination::parse(host);
+        rules.evaluate(&Request {
+            source: ip("10.0.0.1"),
+            user: None,
+            groups: &[],
+            destination: &destination,
+            port: 443,
+            asn: Some(&lookup),
+            tag: None,
+        }).rule.map(|(index, _)| index)
+    };
+
+    assert_eq!(evaluate("blocked.example"), Some(0));
+    assert_eq!(lookups.get(), 0);
+    // Skips the Tor rule without resolving, then looks up once for the rest
+    assert_eq!(evaluate("example.com"), Some(2));
+    assert_eq!(lookups.get(), 1);
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// The proxy looks destinations up in the database before applying the rules
+fn asn_proxy() {
+    use std::net::TcpStream;
+    use std::thread;
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let db = AsnDb::from_bytes(mmdb(6, 24, &[("127.0.0.0", 8, 64500)])).unwrap();
+    let rules = Rules::from_reader("action,user,source,destination,port\ndeny,,,AS64500,\n".as_bytes()).unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_rules(rules)
+        .with_asn_db(db);
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let error = client::connect(&mut TcpStream::connect(addr).unwrap(), "127.0.0.1", echo.port(), None).unwrap_err();
+    assert!(error.to_string().contains("reply code 2"), "{}", error);
+}
@@ -0,0 +1,25 @@
+#![cfg(feature = "socks5")]
+use merino::*;
+use std::thread;
+
+#[test]
+/// Sessions driven by `bench` are relayed through a local proxy
+fn bench_local_proxy() {
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let report = bench::run(&bench::BenchConfig {
+        proxy: addr,
+        target: None,
+        sessions: 20,
+        concurrency: 4,
+        payload: 4096,
+        credentials: None,
+    }).unwrap();
+
+    assert_eq!(report.succeeded, 20);
+    assert_eq!(report.failed, 0);
+    assert_eq!(report.bytes, 20 * 4096 * 2);
+    assert!(report.percentile(50.0) <= report.percentile(99.0));
+}
@@ -0,0 +1,140 @@
+use merino::rules::Destination;
+use merino::*;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const LIST: &str = "# malware
+bad.example
+0.0.0.0 ads.example    # hosts-file style
+203.0.113.0/24
+2001:db8::1
+";
+
+fn blocked(list: &Blocklist, host: &str) -> bool {
+    list.contains(&Destination::parse(host))
+}
+
+/// Serve `body` with `etag` over HTTP, answering 304 to requests that already have it
+///
+/// Returns the feed URL and the If-None-Match header of every request received.
+fn spawn_feed(body: Arc<Mutex<(&'static str, &'static str)>>) -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/feed.txt", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut if_none_match = None;
+            for line in BufReader::new(stream.try_clone().unwrap()).lines() {
+                let line = line.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(etag) = line.strip_prefix("If-None-Match: ") {
+                    if_none_match = Some(etag.to_string());
+                }
+            }
+            let (body, etag) = *body.lock().unwrap();
+            if if_none_match.as_deref() == Some(etag) {
+                write!(stream, "HTTP/1.0 304 Not Modified\r\n\r\n").unwrap();
+            } else {
+                write!(stream, "HTTP/1.0 200 OK\r\nETag: {}\r\n\r\n{}", etag, body).unwrap();
+            }
+            seen.lock().unwrap().push(if_none_match);
+        }
+    });
+    (url, requests)
+}
+
+#[test]
+/// Domains block their subdomains, networks the addresses in them
+fn blocklist_contains() {
+    let list = Blocklist::from_reader(LIST.as_bytes()).unwrap();
+    assert_eq!(list.len(), 4);
+
+    assert!(blocked(&list, "bad.example"));
+    assert!(blocked(&list, "cdn.Bad.Example."));
+    assert!(blocked(&list, "ads.example"));
+    assert!(!blocked(&list, "notbad.example"));
+    assert!(!blocked(&list, "example"));
+    assert!(blocked(&list, "203.0.113.9"));
+    assert!(blocked(&list, "2001:db8::1"));
+    assert!(!blocked(&list, "2001:db8::2"));
+}
+
+#[test]
+/// Feeds are fetched again only when their ETag changes
+fn blocklist_feed_refresh() {
+    let body = Arc::new(Mutex::new(("bad.example\n", "\"v1\"")));
+    let (url, requests) = spawn_feed(body.clone());
+
+    let list = Blocklist::load(&url).unwrap();
+    assert!(blocked(&list, "bad.example"));
+    assert!(!list.refresh().unwrap());
+
+    *body.lock().unwrap() = ("worse.example\n", "\"v2\"");
+    assert!(list.refresh().unwrap());
+    assert!(blocked(&list, "worse.example"));
+    assert!(!blocked(&list, "bad.example"));
+
+    assert_eq!(*requests.lock().unwrap(), [None, Some("\"v1\"".to_string()), Some("\"v1\"".to_string())]);
+}
+
+#[test]
+/// A feed that fails to refresh keeps its previous entries
+fn blocklist_feed_failure() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/feed.txt", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for response in &["HTTP/1.0 200 OK\r\n\r\nbad.example\n", "HTTP/1.0 503 Service Unavailable\r\n\r\n"] {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = BufReader::new(stream.try_clone().unwrap());
+            request.lines().map(Result::unwrap).take_while(|line| !line.is_empty()).for_each(drop);
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    let list = Blocklist::load(&url).unwrap();
+    assert!(list.refresh().is_err());
+    assert!(blocked(&list, "bad.example"));
+
+}
+
+#[test]
+/// https:// feeds are fetched over TLS, so nobody on the path can rewrite them
+fn blocklist_feed_https() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("https://{}/feed.txt", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut first = [0u8; 1];
+        std::io::Read::read_exact(&mut stream, &mut first).unwrap();
+        // A plain HTTP answer isn't taken for the feed
+        stream.write_all(b"HTTP/1.0 200 OK\r\n\r\nbad.example\n").unwrap();
+        first[0]
+    });
+
+    assert!(Blocklist::load(&url).is_err());
+    // A TLS handshake record
+    assert_eq!(server.join().unwrap(), 0x16);
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// Listed destinations are denied before the rules are consulted
+fn blocklist_proxy() {
+    use std::net::TcpStream;
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let list = Blocklist::from_reader("127.0.0.1\n".as_bytes()).unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_blocklist(Arc::new(list));
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let error = client::connect(&mut TcpStream::connect(addr).unwrap(), "127.0.0.1", echo.port(), None).unwrap_err();
+    assert!(error.to_string().contains("reply code 2"), "{}", error);
+}
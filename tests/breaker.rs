@@ -0,0 +1,64 @@
+use merino::breaker::Breaker;
+use std::thread;
+use std::time::Duration;
+
+#[test]
+/// Destinations fail fast after enough failures in a row, until a probe after the cooldown gets through
+fn breaker_opens() {
+    let breaker = Breaker::new(3, Duration::from_millis(100));
+    for _ in 0..2 {
+        assert!(breaker.allow("db.internal", 5432));
+        breaker.record("db.internal", 5432, false);
+    }
+    breaker.record("db.internal", 5432, true);
+    for _ in 0..3 {
+        assert!(breaker.allow("db.internal", 5432));
+        breaker.record("db.internal", 5432, false);
+    }
+    assert!(!breaker.allow("db.internal", 5432));
+    assert!(breaker.allow("db.internal", 5433));
+
+    thread::sleep(Duration::from_millis(150));
+    assert!(breaker.allow("db.internal", 5432));
+    assert!(!breaker.allow("db.internal", 5432));
+    breaker.record("db.internal", 5432, false);
+    assert!(!breaker.allow("db.internal", 5432));
+
+    thread::sleep(Duration::from_millis(150));
+    assert!(breaker.allow("db.internal", 5432));
+    breaker.record("db.internal", 5432, true);
+    assert!(breaker.allow("db.internal", 5432));
+    assert!(breaker.allow("db.internal", 5432));
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// Clients are told a failing destination is unreachable without the proxy connecting to it
+fn breaker_socks() {
+    use merino::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    // Nothing listens on a port just released
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_circuit_breaker(2, Duration::from_secs(60));
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve());
+
+    // Reply code the proxy answers a CONNECT to the port with
+    let request = || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&[5, 1, 0]).unwrap();
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).unwrap();
+        stream.write_all(&[5, 1, 0, 1, 127, 0, 0, 1]).unwrap();
+        stream.write_all(&port.to_be_bytes()).unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        reply[1]
+    };
+    assert_eq!(request(), ResponseCode::ConnectionRefused as u8);
+    assert_eq!(request(), ResponseCode::ConnectionRefused as u8);
+    assert_eq!(request(), ResponseCode::HostUnreachable as u8);
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "socks5")]
+use merino::builtin::Service;
+use merino::*;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+
+#[test]
+/// Only the reserved names are built-in destinations
+fn builtin_names() {
+    assert_eq!(Service::of(&rules::Destination::parse("merino.echo")), Some(Service::Echo));
+    assert_eq!(Service::of(&rules::Destination::parse("Merino.Discard")), Some(Service::Discard));
+    assert_eq!(Service::of(&rules::Destination::parse("echo.merino")), None);
+    assert_eq!(Service::of(&rules::Destination::parse("127.0.0.1")), None);
+}
+
+#[test]
+/// Echo and discard are served through the proxy without any server behind it, unless the rules deny them
+fn builtin_relay() {
+    let rules = Rules::from_reader("action,user,source,destination,port\ndeny,,,merino.discard,13\nallow,,,,\n".as_bytes()).unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_rules(rules);
+    let addr = proxy.local_addr().unwrap();
+    let admin = proxy.admin();
+    thread::spawn(move || proxy.serve());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, "merino.echo", 7, None).unwrap();
+    let large = vec![7u8; 200_000];
+    let writer = { let mut stream = stream.try_clone().unwrap(); let large = large.clone(); thread::spawn(move || stream.write_all(&large)) };
+    let mut echoed = vec![0u8; large.len()];
+    stream.read_exact(&mut echoed).unwrap();
+    writer.join().unwrap().unwrap();
+    assert_eq!(echoed, large);
+    assert!(admin.sessions().iter().any(|session| session.request == "merino.echo:7"));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, "merino.discard", 9, None).unwrap();
+    stream.write_all(&large).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut discarded = Vec::new();
+    stream.read_to_end(&mut discarded).unwrap();
+    assert!(discarded.is_empty());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert!(client::connect(&mut stream, "merino.discard", 13, None).is_err());
+}
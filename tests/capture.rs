@@ -0,0 +1,79 @@
+use merino::capture::{Capture, Direction};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// Writer whose bytes stay readable after the capture owning it is dropped
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Packets of a pcap file, as (IP header, TCP segment)
+fn packets(pcap: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut packets = Vec::new();
+    let mut rest = &pcap[24..];
+    while !rest.is_empty() {
+        let len = u32::from_le_bytes([rest[8], rest[9], rest[10], rest[11]]) as usize;
+        let packet = &rest[16..16 + len];
+        let ip_len = if packet[0] >> 4 == 4 { 20 } else { 40 };
+        packets.push(packet.split_at(ip_len));
+        rest = &rest[16 + len..];
+    }
+    packets
+}
+
+fn seq(tcp: &[u8]) -> (u32, u32) {
+    (u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]), u32::from_be_bytes([tcp[8], tcp[9], tcp[10], tcp[11]]))
+}
+
+#[test]
+/// Relayed bytes are written as TCP segments after a handshake, numbered as on the wire
+fn capture_ipv4() {
+    let pcap = Shared::default();
+    let mut capture = Capture::new(pcap.clone(), "10.0.0.1:50000".parse().unwrap(), "[::ffff:192.0.2.1]:443".parse().unwrap()).unwrap();
+    capture.record(Direction::ToServer, b"hello").unwrap();
+    capture.record(Direction::ToClient, b"world!").unwrap();
+    drop(capture);
+
+    let pcap = pcap.0.lock().unwrap();
+    assert_eq!(&pcap[..4], &0xa1b2_c3d4u32.to_le_bytes());
+    assert_eq!(&pcap[20..24], &101u32.to_le_bytes());
+    let packets = packets(&pcap);
+    // Handshake, two segments and the close
+    assert_eq!(packets.len(), 3 + 2 + 3);
+    let (ip, tcp) = packets[3];
+    assert_eq!(&ip[12..20], &[10, 0, 0, 1, 192, 0, 2, 1]);
+    assert_eq!(&tcp[..4], &[0xc3, 0x50, 0x01, 0xbb]);
+    assert_eq!(&tcp[20..], b"hello");
+    assert_eq!(seq(tcp), (1, 1));
+    let (ip, tcp) = packets[4];
+    assert_eq!(&ip[12..20], &[192, 0, 2, 1, 10, 0, 0, 1]);
+    assert_eq!(&tcp[20..], b"world!");
+    assert_eq!(seq(tcp), (1, 6));
+    assert_eq!(seq(packets[5].1), (6, 7));
+}
+
+#[test]
+/// Either end on IPv6 makes both IPv6, and large writes are split
+fn capture_ipv6() {
+    let pcap = Shared::default();
+    let mut capture = Capture::new(pcap.clone(), "10.0.0.1:50000".parse().unwrap(), "[2001:db8::1]:443".parse().unwrap()).unwrap();
+    capture.record(Direction::ToClient, &vec![7; 40_000]).unwrap();
+    drop(capture);
+
+    let pcap = pcap.0.lock().unwrap();
+    let packets = packets(&pcap);
+    assert_eq!(packets.len(), 3 + 2 + 3);
+    assert!(packets.iter().all(|(ip, _)| ip[0] >> 4 == 6));
+    assert_eq!(packets[3].1.len() - 20 + packets[4].1.len() - 20, 40_000);
+    assert_eq!(seq(packets[4].1).0, 1 + (packets[3].1.len() - 20) as u32);
+}
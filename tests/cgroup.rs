@@ -0,0 +1,80 @@
+use merino::cgroup::{Cgroup, Defaults, ResourceLimits};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// An empty directory to lay out a cgroup filesystem in
+fn root(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("merino-cgroup-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+/// cgroup v2 limits and usage are read from the process's own cgroup
+fn cgroup_v2() {
+    let root = root("v2");
+    let dir = root.join("kubepods/pod1");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(root.join("cgroup.controllers"), "cpu memory\n").unwrap();
+    fs::write(dir.join("memory.max"), "268435456\n").unwrap();
+    fs::write(dir.join("memory.current"), "10485760\n").unwrap();
+    fs::write(dir.join("cpu.max"), "50000 100000\n").unwrap();
+    fs::write(dir.join("cpu.stat"), "usage_usec 1500000\nuser_usec 1000000\n").unwrap();
+
+    let cgroup = Cgroup::at(&root, "0::/kubepods/pod1\n").unwrap();
+    assert_eq!(cgroup.limits(), ResourceLimits { memory: Some(256 * 1024 * 1024), cpus: Some(0.5) });
+    let usage = cgroup.usage();
+    assert_eq!(usage.memory, Some(10 * 1024 * 1024));
+    assert_eq!(usage.cpu, Some(Duration::from_millis(1500)));
+
+    fs::write(dir.join("memory.max"), "max\n").unwrap();
+    fs::write(dir.join("cpu.max"), "max 100000\n").unwrap();
+    assert_eq!(cgroup.limits(), ResourceLimits::default());
+    assert_eq!(cgroup.limits().defaults(), None);
+
+    // Inside a cgroup namespace the path names a directory only the host sees
+    assert!(Cgroup::at(&root, "0::/elsewhere\n").is_some());
+}
+
+#[test]
+/// cgroup v1 limits are read per controller, with its huge number meaning unlimited
+fn cgroup_v1() {
+    let root = root("v1");
+    for controller in ["memory", "cpu", "cpuacct"].iter() {
+        fs::create_dir_all(root.join(controller).join("docker/abc")).unwrap();
+    }
+    fs::write(root.join("memory/docker/abc/memory.limit_in_bytes"), "1073741824\n").unwrap();
+    fs::write(root.join("memory/docker/abc/memory.usage_in_bytes"), "2048\n").unwrap();
+    fs::write(root.join("cpu/docker/abc/cpu.cfs_quota_us"), "200000\n").unwrap();
+    fs::write(root.join("cpu/docker/abc/cpu.cfs_period_us"), "100000\n").unwrap();
+    fs::write(root.join("cpuacct/docker/abc/cpuacct.usage"), "2000000000\n").unwrap();
+
+    let membership = "4:memory:/docker/abc\n3:cpu,cpuacct:/docker/abc\n0::/\n";
+    let cgroup = Cgroup::at(&root, membership).unwrap();
+    assert_eq!(cgroup.limits(), ResourceLimits { memory: Some(1024 * 1024 * 1024), cpus: Some(2.0) });
+    assert_eq!(cgroup.usage().memory, Some(2048));
+    assert_eq!(cgroup.usage().cpu, Some(Duration::from_secs(2)));
+
+    fs::write(root.join("memory/docker/abc/memory.limit_in_bytes"), "9223372036854771712\n").unwrap();
+    fs::write(root.join("cpu/docker/abc/cpu.cfs_quota_us"), "-1\n").unwrap();
+    assert_eq!(cgroup.limits(), ResourceLimits::default());
+
+    assert_eq!(Cgroup::at(root.join("missing"), membership), None);
+}
+
+#[test]
+/// Smaller memory limits get smaller relay buffers and fewer connections, capped by the CPUs
+fn cgroup_defaults() {
+    let mib = 1024 * 1024;
+    let small = ResourceLimits { memory: Some(128 * mib), cpus: None }.defaults().unwrap();
+    assert_eq!(small, Defaults { max_connections: 64 * mib / (288 * 1024), relay_buffer: 16 * 1024 });
+
+    let large = ResourceLimits { memory: Some(4096 * mib), cpus: None }.defaults().unwrap();
+    assert_eq!(large.relay_buffer, 128 * 1024);
+    assert_eq!(large.max_connections, 2048 * mib / (512 * 1024));
+
+    let throttled = ResourceLimits { memory: Some(4096 * mib), cpus: Some(0.25) }.defaults().unwrap();
+    assert_eq!(throttled.max_connections, 1024);
+}
@@ -0,0 +1,51 @@
+#![cfg(feature = "socks5")]
+use merino::*;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+/// Send a CONNECT to `echo` with the reserved byte set, returning the reply code
+fn connect_with_reserved(compliance: Compliance, echo: std::net::SocketAddr) -> u8 {
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_compliance(compliance);
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    thread::spawn(move || proxy.serve_connection(server).is_ok());
+
+    stream.write_all(&[5, 1, 0]).unwrap();
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).unwrap();
+
+    let mut request = vec![5, 1, 0xff, 1];
+    match echo.ip() {
+        std::net::IpAddr::V4(ip) => request.extend_from_slice(&ip.octets()),
+        std::net::IpAddr::V6(_) => unreachable!(),
+    }
+    request.extend_from_slice(&echo.port().to_be_bytes());
+    stream.write_all(&request).unwrap();
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+#[test]
+/// Lenient proxies let a non-zero reserved byte through
+fn compliance_lenient() {
+    let echo = bench::spawn_echo_server().unwrap();
+    assert_eq!(connect_with_reserved(Compliance::Lenient, echo), 0);
+}
+
+#[test]
+/// Strict proxies refuse a non-zero reserved byte
+fn compliance_strict() {
+    let echo = bench::spawn_echo_server().unwrap();
+    assert_eq!(connect_with_reserved(Compliance::Strict, echo), 1);
+}
+
+#[test]
+fn compliance_parse() {
+    assert_eq!("strict".parse::<Compliance>().unwrap(), Compliance::Strict);
+    assert_eq!("lenient".parse::<Compliance>().unwrap(), Compliance::Lenient);
+    assert!("loose".parse::<Compliance>().is_err());
+    assert_eq!(Compliance::default(), Compliance::Lenient);
+}
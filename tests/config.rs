@@ -0,0 +1,24 @@
+use merino::config;
+use std::ffi::OsString;
+
+fn args(args: &[&str]) -> Vec<OsString> {
+    args.iter().map(OsString::from).collect()
+}
+
+#[test]
+/// Lines of a config file become long options, flags included
+fn config_parse() {
+    let text = "# merino.conf\nport = 1081\n\nno-auth\nsni = false\n--access-log = \"/var/log/merino access.log\"\nblocklist = a.txt\nblocklist = b.txt\n";
+    assert_eq!(config::parse(text).unwrap(), [
+        "--port", "1081", "--no-auth", "--access-log", "/var/log/merino access.log", "--blocklist", "a.txt", "--blocklist", "b.txt",
+    ]);
+    assert!(config::parse("no auth\n").unwrap_err().to_string().starts_with("Line 1:"));
+}
+
+#[test]
+/// Options from the command line win over the same ones from a file, and subcommands stay last
+fn config_merge() {
+    let defaults = config::parse("port = 1081\nno-auth\nblocklist = a.txt\nblocklist = b.txt\nrules = rules.csv\n").unwrap();
+    let merged = config::merge(args(&["merino", "--port=2000", "--blocklist", "c.txt", "test-policy"]), &defaults);
+    assert_eq!(merged, args(&["merino", "--no-auth", "--rules", "rules.csv", "--port=2000", "--blocklist", "c.txt", "test-policy"]));
+}
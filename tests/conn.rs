@@ -0,0 +1,44 @@
+#![cfg(feature = "socks5")]
+use merino::*;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+#[test]
+/// Clients accepted on any transport get the same handshake and relay
+fn conn_unix_socket() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let serving = thread::spawn(move || proxy.serve_connection(server).unwrap());
+
+    assert!(client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).is_ok());
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // Closing the client ends the session
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    serving.join().unwrap();
+}
+
+#[test]
+/// IPv4 clients of a dual-stack listener are checked against IPv4 rules, not as mapped IPv6 addresses
+fn conn_dual_stack_client() {
+    use std::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("[::]:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let (accepted, _) = listener.accept().unwrap();
+    assert_eq!(accepted.peer_ip().unwrap(), "127.0.0.1".parse::<std::net::IpAddr>().unwrap());
+
+    let rules = Rules::from_reader("action,user,source,destination,port\ndeny,,127.0.0.0/8,,\n".as_bytes()).unwrap();
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_rules(rules);
+    let serving = thread::spawn(move || proxy.serve_connection(accepted));
+    assert!(client::connect(&mut client, "example.com", 443, None).is_err());
+    assert!(matches!(serving.join().unwrap(), Err(MerinoError::Denied { .. })));
+}
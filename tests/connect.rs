@@ -0,0 +1,121 @@
+use merino::*;
+use std::io::{Read, Write};
+
+#[test]
+/// Connections with TCP Fast Open requested relay data like any other
+fn connect_fast_open() {
+    let echo = bench::spawn_echo_server().unwrap();
+
+    let options = ConnectOptions { fast_open: true, ..ConnectOptions::default() };
+    let mut stream = connect::connect(echo, &options).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    assert!(connect::connect(&[][..] as &[std::net::SocketAddr], &options).is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+/// DSCP values are written to the upper bits of the ToS byte
+fn connect_dscp() {
+    use nix::sys::socket::{getsockopt, sockopt};
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let stream = connect::connect(echo, &ConnectOptions::default()).unwrap();
+    connect::set_dscp(&stream, 46).unwrap();
+    assert_eq!(getsockopt(&stream, sockopt::IpTos).unwrap(), 46 << 2);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+/// Marked connections carry SO_MARK, or fail without CAP_NET_ADMIN
+fn connect_mark() {
+    use nix::sys::socket::{getsockopt, sockopt};
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let options = ConnectOptions { mark: Some(0x42), ..ConnectOptions::default() };
+    match connect::connect(echo, &options) {
+        Ok(stream) => assert_eq!(getsockopt(&stream, sockopt::Mark).unwrap(), 0x42),
+        Err(error) => assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied),
+    }
+}
+
+#[test]
+/// MPTCP connections relay data, falling back to TCP where the kernel lacks it
+fn connect_mptcp() {
+    let echo = bench::spawn_echo_server().unwrap();
+
+    let options = ConnectOptions { mptcp: true, ..ConnectOptions::default() };
+    let mut stream = connect::connect(echo, &options).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// The SOCKS listener is rebound on its address for MPTCP clients
+fn connect_mptcp_listener() {
+    use std::net::TcpStream;
+    use std::thread;
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addr = proxy.local_addr().unwrap();
+    let mut proxy = proxy.with_mptcp_listener().unwrap();
+    assert_eq!(proxy.local_addr().unwrap(), addr);
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let options = ConnectOptions { mptcp: true, ..ConnectOptions::default() };
+    let mut stream: TcpStream = connect::connect(addr, &options).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[cfg(all(target_os = "linux", feature = "socks5"))]
+#[test]
+/// The SOCKS listener relays as before with TCP Fast Open accepted
+fn connect_fast_open_listener() {
+    use std::net::TcpStream;
+    use std::thread;
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addr = proxy.local_addr().unwrap();
+    let mut proxy = proxy.with_fast_open_listener().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let options = ConnectOptions { fast_open: true, ..ConnectOptions::default() };
+    let mut stream: TcpStream = connect::connect(addr, &options).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+/// Listeners queue TCP Fast Open connections once enabled
+fn connect_listen_fast_open() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    assert_eq!(connect::fast_open_queue(&listener).unwrap(), 0);
+    connect::listen_fast_open(&listener).unwrap();
+    assert_eq!(connect::fast_open_queue(&listener).unwrap(), connect::FAST_OPEN_QUEUE);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+/// Listeners speak MPTCP where the kernel supports it
+fn connect_is_mptcp() {
+    let listener = connect::listen_mptcp("127.0.0.1:0".parse().unwrap()).unwrap();
+    let supported = std::fs::read_to_string("/proc/sys/net/mptcp/enabled").is_ok_and(|enabled| enabled.trim() == "1");
+    assert_eq!(connect::is_mptcp(&listener).unwrap(), supported);
+    assert!(!connect::is_mptcp(&std::net::TcpListener::bind("127.0.0.1:0").unwrap()).unwrap());
+}
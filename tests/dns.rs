@@ -0,0 +1,181 @@
+use merino::dns::{NegativeCache, Resolver};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Answer to `query`: `db.corp` is 127.0.0.1 and ::1, and nothing else exists
+fn answer(query: &[u8]) -> Vec<u8> {
+    let question = &query[12..];
+    let qtype = u16::from_be_bytes([question[question.len() - 4], question[question.len() - 3]]);
+    let known = question.to_ascii_lowercase().starts_with(b"\x02db\x04corp\x00");
+    let mut answer = query[..2].to_vec();
+    answer.extend_from_slice(&[0x81, if known { 0x80 } else { 0x83 }, 0, 1, 0, known as u8, 0, 0, 0, 0]);
+    answer.extend_from_slice(question);
+    if known {
+        let data: Vec<u8> = match qtype {
+            1 => vec![127, 0, 0, 1],
+            _ => std::net::Ipv6Addr::LOCALHOST.octets().to_vec(),
+        };
+        answer.extend_from_slice(&[0xc0, 12]);
+        answer.extend_from_slice(&qtype.to_be_bytes());
+        answer.extend_from_slice(&[0, 1, 0, 0, 0, 60, 0, data.len() as u8]);
+        answer.extend_from_slice(&data);
+    }
+    answer
+}
+
+/// DNS server giving the [`answer`]s
+fn dns_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || loop {
+        let mut buf = [0u8; 512];
+        let (len, client) = socket.recv_from(&mut buf).unwrap();
+        socket.send_to(&answer(&buf[..len]), client).unwrap();
+    });
+    addr
+}
+
+/// DNS over HTTP server giving the [`answer`]s to POSTed queries, keeping the request heads it got
+fn doh_server() -> (SocketAddr, Arc<Mutex<Vec<Vec<String>>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut head = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push(line);
+            }
+            let len: usize = head.iter()
+                .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length: ").map(|len| len.trim().parse().unwrap()))
+                .unwrap();
+            let mut query = vec![0u8; len];
+            reader.read_exact(&mut query).unwrap();
+            let answer = answer(&query);
+            let stream = reader.get_mut();
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", answer.len()).unwrap();
+            stream.write_all(&answer).unwrap();
+            seen.lock().unwrap().push(head);
+        }
+    });
+    (addr, requests)
+}
+
+#[test]
+/// Names that fail to resolve keep failing the same way until the TTL is up
+fn dns_negative_cache() {
+    let cache = NegativeCache::new(Duration::from_millis(200));
+    let first = cache.resolve("no-such-host.invalid", 443).unwrap_err();
+    assert!(cache.failing("No-Such-Host.invalid."));
+    let cached = cache.resolve("no-such-host.invalid", 80).unwrap_err();
+    assert_eq!((cached.kind(), cached.to_string()), (first.kind(), first.to_string()));
+
+    thread::sleep(Duration::from_millis(250));
+    assert!(!cache.failing("no-such-host.invalid"));
+}
+
+#[test]
+/// Literal addresses and names that resolve are never cached
+fn dns_resolves() {
+    let cache = NegativeCache::new(Duration::from_secs(60));
+    assert_eq!(cache.resolve("::1", 443).unwrap(), vec!["[::1]:443".parse().unwrap()]);
+    assert!(cache.resolve("localhost", 443).unwrap().iter().all(|addr| addr.ip().is_loopback()));
+    assert!(!cache.failing("localhost"));
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// Requests for a dead name are refused without asking the resolver again
+fn dns_socks() {
+    use merino::*;
+    use std::net::TcpStream;
+
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_negative_dns_ttl(Duration::from_secs(60));
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve());
+
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        assert!(client::connect(&mut stream, "no-such-host.invalid", 443, None).is_err());
+    }
+}
+
+#[test]
+/// Resolvers are DNS servers, asked for both address families, or static answers
+fn dns_resolver() {
+    let server = dns_server();
+    let resolver: Resolver = format!("dns:{}", server).parse().unwrap();
+    assert_eq!(resolver.to_string(), format!("dns:{}", server));
+    let addrs = resolver.lookup("DB.corp.", 5432).unwrap();
+    assert_eq!(addrs, vec!["127.0.0.1:5432".parse().unwrap(), "[::1]:5432".parse().unwrap()]);
+    assert_eq!(resolver.lookup("www.corp", 443).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+    assert_eq!("dns:10.0.0.53".parse::<Resolver>().unwrap(), Resolver::Server("10.0.0.53:53".parse().unwrap()));
+    assert_eq!("dns:[fd00::53]".parse::<Resolver>().unwrap(), Resolver::Server("[fd00::53]:53".parse().unwrap()));
+    let fixed: Resolver = "static:10.1.2.3".parse().unwrap();
+    assert_eq!(fixed.lookup("anything.example", 80).unwrap(), vec!["10.1.2.3:80".parse().unwrap()]);
+    assert_eq!("doh:https://dns.example/dns-query".parse::<Resolver>().unwrap(), Resolver::Https("https://dns.example/dns-query".to_string()));
+    assert!("doh:dns.example".parse::<Resolver>().is_err());
+    assert!("10.0.0.53".parse::<Resolver>().is_err());
+}
+
+#[test]
+/// DNS over HTTPS servers are POSTed DNS messages, over TLS for https:// URLs
+fn dns_https_resolver() {
+    let (server, requests) = doh_server();
+    let resolver: Resolver = format!("doh:http://{}/dns-query", server).parse().unwrap();
+    assert_eq!(resolver.to_string(), format!("doh:http://{}/dns-query", server));
+    let addrs = resolver.lookup("db.corp", 5432).unwrap();
+    assert_eq!(addrs, vec!["127.0.0.1:5432".parse().unwrap(), "[::1]:5432".parse().unwrap()]);
+    assert_eq!(resolver.lookup("www.corp", 443).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests[0][0], "POST /dns-query HTTP/1.1\r\n");
+    assert!(requests[0].iter().any(|line| line.eq_ignore_ascii_case("content-type: application/dns-message\r\n")));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let resolver: Resolver = format!("doh:https://{}/dns-query", listener.local_addr().unwrap()).parse().unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut first = [0u8; 1];
+        stream.read_exact(&mut first).unwrap();
+        first[0]
+    });
+    assert!(resolver.lookup("db.corp", 5432).is_err());
+    // A TLS handshake record, so the name didn't go out in the clear
+    assert_eq!(server.join().unwrap(), 0x16);
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// Names matched by a rule with a resolver are resolved by it, others by the system
+fn dns_rule_resolver() {
+    use merino::*;
+    use std::net::TcpStream;
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let rules = format!("action,user,source,destination,port,resolver\nallow,,,*.corp,,dns:{}\nallow,,,*.example,,static:127.0.0.1\nallow,,,,,\n", dns_server());
+    let rules = Rules::from_reader(rules.as_bytes()).unwrap();
+    assert!(rules.iter().next().unwrap().to_string().ends_with(&format!("resolver={}", rules.iter().next().unwrap().resolver().unwrap())));
+    assert!(Rules::from_reader("action,user,source,destination,port,resolver\ndeny,,,,,static:10.1.2.3\n".as_bytes()).is_err());
+
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_rules(rules);
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve());
+
+    for (host, reachable) in [("db.corp", true), ("api.example", true), ("www.corp", false)] {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        assert_eq!(client::connect(&mut stream, host, echo.port(), None).is_ok(), reachable, "{}", host);
+    }
+}
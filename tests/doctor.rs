@@ -0,0 +1,40 @@
+#![cfg(feature = "socks5")]
+use merino::doctor::{self, DoctorConfig, Status};
+use merino::*;
+use std::net::TcpListener;
+use std::time::Duration;
+
+fn config(outbound: u16) -> DoctorConfig {
+    DoctorConfig {
+        proxy: None,
+        credentials: None,
+        resolve: "localhost".to_string(),
+        outbound: ("127.0.0.1".to_string(), outbound),
+        timeout: Duration::from_secs(5),
+    }
+}
+
+#[test]
+/// Every check passes against a temporary proxy on a working host
+fn doctor_healthy() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let report = doctor::run(&config(echo.port()));
+
+    let names: Vec<&str> = report.checks.iter().map(|check| check.name).collect();
+    assert_eq!(names, ["proxy", "dns", "outbound", "fd limit"]);
+    assert!(report.healthy(), "{}", report);
+    assert!(report.to_string().starts_with("[ ok ] proxy: relayed 4096 bytes"));
+}
+
+#[test]
+/// Unreachable proxies and destinations fail their checks
+fn doctor_failures() {
+    // Bound and immediately closed, so nothing listens there
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let report = doctor::run(&DoctorConfig { proxy: Some(closed), ..config(closed.port()) });
+
+    assert!(!report.healthy());
+    assert_eq!(report.checks[0].status, Status::Fail);
+    assert!(report.checks[0].detail.contains("can't reach the proxy"));
+    assert_eq!(report.checks[2].status, Status::Fail);
+}
@@ -0,0 +1,92 @@
+use std::fs;
+use std::net::TcpListener;
+use std::process::Command;
+
+#[test]
+/// `--dry-run` prints the effective settings and rules without serving or revealing secrets
+fn dry_run_prints_config() {
+    let dir = std::env::temp_dir().join(format!("merino-dry-run-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("users.csv"), "username,password\nbob,hunter2\n").unwrap();
+    fs::write(dir.join("rules.csv"), "action,destination,port\ndeny,*.internal.corp,\n").unwrap();
+    // Dry runs don't listen, so a port already in use is fine
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merino"))
+        .arg("--port").arg(taken.local_addr().unwrap().port().to_string())
+        .arg("--users").arg(dir.join("users.csv"))
+        .arg("--rules").arg(dir.join("rules.csv"))
+        .args(["--pool-size", "8", "--dry-run"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(&format!("listen = 127.0.0.1:{}\n", taken.local_addr().unwrap().port())));
+    assert!(stdout.contains("(1 users)"));
+    assert!(stdout.contains("pool_size = 8\n"));
+    assert!(stdout.contains("rule #1: deny user=* source=* destination=*.internal.corp port=*\n"));
+    assert!(!stdout.contains("hunter2"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+/// Invalid settings fail the dry run as they would fail startup
+fn dry_run_invalid() {
+    let output = Command::new(env!("CARGO_BIN_EXE_merino"))
+        .args(["--no-auth", "--relay-buffer", "1024", "--dry-run"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+/// Options come from the config file and $MERINO_ARGS, unless given on the command line
+fn dry_run_config_file() {
+    let path = std::env::temp_dir().join(format!("merino-config-{}.conf", std::process::id()));
+    fs::write(&path, "port = 1081\nno-auth\npool-size = 8\nstatsd-prefix = from-file\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merino"))
+        .env("MERINO_CONFIG", &path)
+        .env("MERINO_ARGS", "--pool-size 6 --sni")
+        .args(["--pool-size", "2", "--dry-run"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("listen = 127.0.0.1:1081\n"), "{}", stdout);
+    assert!(stdout.contains("pool_size = 2\n"));
+    assert!(stdout.contains("sni = true\n"));
+    assert!(stdout.contains("statsd_prefix = from-file\n"));
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+/// `--require-auth` refuses to start with anonymous access, from the command line or the config file
+fn dry_run_require_auth_no_auth() {
+    let dir = std::env::temp_dir().join(format!("merino-require-auth-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("users.csv"), "username,password\nbob,hunter2\n").unwrap();
+    fs::write(dir.join("merino.conf"), "no-auth\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merino"))
+        .args(["--no-auth", "--require-auth", "--dry-run"])
+        .arg("--users").arg(dir.join("users.csv"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merino"))
+        .env("MERINO_CONFIG", dir.join("merino.conf"))
+        .args(["--require-auth", "--dry-run"])
+        .arg("--users").arg(dir.join("users.csv"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--no-auth"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
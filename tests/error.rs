@@ -0,0 +1,68 @@
+#![cfg(feature = "socks5")]
+use merino::*;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+/// Serve one unauthenticated client with `rules`, returning how its session ended
+fn serve(rules: Rules, host: &str, port: u16) -> Result<(), MerinoError> {
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_rules(rules);
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let serving = thread::spawn(move || proxy.serve_connection(server));
+
+    assert!(client::connect(&mut stream, host, port, None).is_err());
+    serving.join().unwrap()
+}
+
+#[test]
+/// Denied requests are reported as policy denials
+fn error_denied() {
+    let rules = Rules::from_reader("action,user,source,destination,port\ndeny,,,127.0.0.1,\n".as_bytes()).unwrap();
+    match serve(rules, "127.0.0.1", 80) {
+        Err(MerinoError::Denied { reason }) => assert_eq!(reason, "127.0.0.1:80"),
+        other => panic!("Expected a denial, got {:?}", other),
+    }
+}
+
+#[test]
+/// Clients offering only NO AUTH to a proxy requiring credentials get no acceptable method
+fn error_auth_required() {
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], Vec::new()).unwrap();
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let serving = thread::spawn(move || proxy.serve_connection(server));
+
+    stream.write_all(&[5, 1, 0]).unwrap();
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).unwrap();
+    assert_eq!(choice, [5, 0xff]);
+    match serving.join().unwrap() {
+        Err(MerinoError::Denied { reason }) => assert_eq!(reason, "authentication required"),
+        other => panic!("Expected a denial, got {:?}", other),
+    }
+}
+
+#[test]
+/// Unreachable destinations are reported as connect errors
+fn error_connect() {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    match serve(Rules::default(), "127.0.0.1", port) {
+        Err(MerinoError::Connect { port: failed, .. }) => assert_eq!(failed, port),
+        other => panic!("Expected a connect error, got {:?}", other),
+    }
+}
+
+#[test]
+/// Malformed requests are reported as protocol errors
+fn error_protocol() {
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let serving = thread::spawn(move || proxy.serve_connection(server));
+
+    stream.write_all(&[5, 1, 0]).unwrap();
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).unwrap();
+    stream.write_all(&[5, 9, 0, 1]).unwrap();
+    assert!(matches!(serving.join().unwrap(), Err(MerinoError::Protocol { .. })));
+}
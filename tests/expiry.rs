@@ -0,0 +1,44 @@
+use chrono::{Duration, TimeZone, Utc};
+use merino::expiry::{self, Warnings};
+
+#[test]
+/// Expiries are dates, meaning midnight UTC, or RFC 3339 times
+fn expiry_parse() {
+    assert_eq!(expiry::parse("2026-12-31").unwrap(), Utc.with_ymd_and_hms(2026, 12, 31, 0, 0, 0).unwrap());
+    assert_eq!(expiry::parse("2026-12-31T18:00:00+02:00").unwrap(), Utc.with_ymd_and_hms(2026, 12, 31, 16, 0, 0).unwrap());
+    assert!(expiry::parse("next tuesday").is_err());
+}
+
+#[test]
+/// Credentials are refused from their expiry on, and only warned about ahead of it
+fn expiry_expired() {
+    let warnings = Warnings::default();
+    let now = Utc::now();
+    assert!(warnings.expired("bob", now - Duration::seconds(1), now));
+    assert!(warnings.expired("bob", now, now));
+    assert!(!warnings.expired("bob", now + Duration::days(1), now));
+    assert!(!warnings.expired("bob", now + Duration::days(30), now));
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// Users of the users file are refused once their credentials expire
+fn expiry_login() {
+    use merino::*;
+    use std::net::TcpStream;
+
+    let users: Vec<User> = csv::Reader::from_reader("username,password,expires\nbob,secret,2000-01-01\nalice,secret,2999-01-01\ncarol,secret,\n".as_bytes())
+        .deserialize().collect::<Result<_, _>>().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], users).unwrap();
+    let addr = proxy.local_addr().unwrap();
+    std::thread::spawn(move || proxy.serve());
+    let echo = bench::spawn_echo_server().unwrap();
+
+    let login = |user| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        client::connect(&mut stream, &echo.ip().to_string(), echo.port(), Some((user, "secret"))).is_ok()
+    };
+    assert!(!login("bob"));
+    assert!(login("alice"));
+    assert!(login("carol"));
+}
@@ -0,0 +1,33 @@
+#![cfg(feature = "ext-authz")]
+use merino::ext_authz::ExtAuthz;
+use merino::rules::{Action, Destination, Request};
+use merino::*;
+use std::net::TcpListener;
+use std::time::Duration;
+
+fn decide(authz: &ExtAuthz) -> Action {
+    let destination = Destination::parse("example.com");
+    authz.on_request(1, &Request {
+        source: "10.0.0.5".parse().unwrap(),
+        user: Some("bob"),
+        groups: &[],
+        destination: &destination,
+        port: 443,
+        asn: None,
+        tag: None,
+    }).unwrap()
+}
+
+#[test]
+/// An unreachable policy server denies requests unless failing open
+fn ext_authz_unreachable() {
+    // Reserve a port with nothing listening on it
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let url = format!("http://127.0.0.1:{}", port);
+
+    let closed = ExtAuthz::new(&url, Duration::from_millis(200), false).unwrap();
+    assert_eq!(decide(&closed), Action::Deny);
+
+    let open = ExtAuthz::new(&url, Duration::from_millis(200), true).unwrap();
+    assert_eq!(decide(&open), Action::Allow);
+}
@@ -0,0 +1,56 @@
+use merino::faults::{relay, Relayed};
+use merino::*;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+#[test]
+/// Without faults everything is copied through
+fn faults_disabled() {
+    let faults = Faults::default();
+    assert!(!faults.is_enabled());
+
+    let mut out = Vec::new();
+    let relayed = relay(&mut Cursor::new(vec![7u8; 20_000]), &mut out, &faults, 8192).unwrap();
+    assert_eq!(relayed, Relayed::Eof(20_000));
+    assert_eq!(out, vec![7u8; 20_000]);
+}
+
+#[test]
+/// Throttled relays take as long as the configured rate requires
+fn faults_throttle() {
+    let faults = Faults { throttle: Some(10_000), ..Faults::default() };
+
+    let start = Instant::now();
+    let mut out = Vec::new();
+    relay(&mut Cursor::new(vec![0u8; 3_000]), &mut out, &faults, 8192).unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(250));
+    assert_eq!(out.len(), 3_000);
+}
+
+#[test]
+/// A certain reset stops the relay before anything is forwarded
+fn faults_reset() {
+    let faults = Faults { reset_chance: 1.0, ..Faults::default() };
+
+    let mut out = Vec::new();
+    assert_eq!(relay(&mut Cursor::new(vec![0u8; 100]), &mut out, &faults, 8192).unwrap(), Relayed::Reset);
+    assert!(out.is_empty());
+}
+
+#[test]
+/// Chunks never exceed the buffer size
+fn faults_buffer_size() {
+    struct Chunks(Vec<usize>);
+    impl std::io::Write for Chunks {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.push(buf.len());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    let mut out = Chunks(Vec::new());
+    let relayed = relay(&mut Cursor::new(vec![0u8; 100_000]), &mut out, &Faults::default(), 32 * 1024).unwrap();
+    assert_eq!(relayed, Relayed::Eof(100_000));
+    assert_eq!(out.0, vec![32 * 1024, 32 * 1024, 32 * 1024, 100_000 - 3 * 32 * 1024]);
+}
@@ -0,0 +1,88 @@
+use merino::*;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+/// A forward from a currently unused local port to `target`
+fn forward_to(target: std::net::SocketAddr) -> Forward {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    format!("127.0.0.1:{}={}", port, target).parse().unwrap()
+}
+
+#[test]
+/// Connections to a forwarded port are relayed to its destination
+fn forward_relays() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let forward = forward_to(echo);
+    let listen = forward.listen;
+
+    let mut proxy = Merino::new(0, "127.0.0.1", Vec::new(), Vec::new()).unwrap()
+        .with_forward(forward).unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let mut stream = TcpStream::connect(listen).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+/// Forwarded connections are subject to the access rules
+fn forward_denied() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let forward = forward_to(echo);
+    let listen = forward.listen;
+    let rules = Rules::from_reader("action,user,source,destination,port\ndeny,,,127.0.0.1,\n".as_bytes()).unwrap();
+
+    let mut proxy = Merino::new(0, "127.0.0.1", Vec::new(), Vec::new()).unwrap()
+        .with_rules(rules)
+        .with_forward(forward).unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let mut stream = TcpStream::connect(listen).unwrap();
+    let mut buf = Vec::new();
+    assert_eq!(stream.read_to_end(&mut buf).unwrap_or(0), 0);
+}
+
+#[test]
+/// A rule's connection cap holds across the sessions it allows, and frees up as they end
+fn forward_rule_cap() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let forward = forward_to(echo);
+    let listen = forward.listen;
+    let rules = Rules::from_reader("action,user,source,destination,port,max_connections\nallow,,,127.0.0.1,,1\n".as_bytes()).unwrap();
+
+    let mut proxy = Merino::new(0, "127.0.0.1", Vec::new(), Vec::new()).unwrap()
+        .with_rules(rules)
+        .with_forward(forward).unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let echoes = |mut stream: &TcpStream| {
+        let mut buf = [0u8; 5];
+        stream.write_all(b"hello").is_ok() && stream.read_exact(&mut buf).is_ok()
+    };
+    let first = TcpStream::connect(listen).unwrap();
+    assert!(echoes(&first));
+    let second = TcpStream::connect(listen).unwrap();
+    assert!(!echoes(&second));
+
+    drop(first);
+    let start = std::time::Instant::now();
+    while !echoes(&TcpStream::connect(listen).unwrap()) {
+        assert!(start.elapsed() < std::time::Duration::from_secs(5), "The cap wasn't freed");
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+#[test]
+/// Forwards are written as LISTEN=HOST:PORT
+fn forward_parse() {
+    let forward: Forward = "127.0.0.1:5433=db.internal:5432".parse().unwrap();
+    assert_eq!(forward.listen, "127.0.0.1:5433".parse().unwrap());
+    assert_eq!((forward.host.as_str(), forward.port), ("db.internal", 5432));
+    assert_eq!(forward.to_string(), "127.0.0.1:5433 -> db.internal:5432");
+
+    assert!("127.0.0.1:5433".parse::<Forward>().is_err());
+    assert!("127.0.0.1:5433=db.internal".parse::<Forward>().is_err());
+}
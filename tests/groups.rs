@@ -0,0 +1,54 @@
+use merino::rules::{Action, Destination, Request};
+use merino::*;
+
+const GROUPS: &str = "group,user
+developers,alice
+developers,bob
+ci-bots,runner-1
+developers,alice
+";
+
+const RULES: &str = "action,user,source,destination,port
+allow,@ci-bots,,artifacts.example.com,443
+deny,@ci-bots,,,
+deny,@developers,,*.prod.internal,
+";
+
+#[test]
+/// Users are listed in every group they belong to, once each
+fn groups_memberships() {
+    let groups = Groups::from_reader(GROUPS.as_bytes()).unwrap();
+    assert_eq!(groups.len(), 3);
+    assert_eq!(groups.of(Some("alice")), ["developers".to_string()]);
+    assert_eq!(groups.of(Some("runner-1")), ["ci-bots".to_string()]);
+    assert!(groups.of(Some("mallory")).is_empty());
+    assert!(groups.of(None).is_empty());
+
+    assert!(Groups::from_reader("group,user\ndevelopers,\n".as_bytes()).is_err());
+}
+
+#[test]
+/// `@group` rules apply to every member and nobody else
+fn groups_rules() {
+    let groups = Groups::from_reader(GROUPS.as_bytes()).unwrap();
+    let rules = Rules::from_reader(RULES.as_bytes()).unwrap();
+    let evaluate = |user: Option<&str>, host: &str, port| {
+        let destination = Destination::parse(host);
+        rules.evaluate(&Request {
+            source: "10.0.0.5".parse().unwrap(),
+            user,
+            groups: groups.of(user),
+            destination: &destination,
+            port,
+            asn: None,
+            tag: None,
+        }).action
+    };
+
+    assert_eq!(evaluate(Some("runner-1"), "artifacts.example.com", 443), Action::Allow);
+    assert_eq!(evaluate(Some("runner-1"), "example.com", 443), Action::Deny);
+    assert_eq!(evaluate(Some("bob"), "db.prod.internal", 5432), Action::Deny);
+    assert_eq!(evaluate(Some("bob"), "example.com", 443), Action::Allow);
+    assert_eq!(evaluate(Some("mallory"), "db.prod.internal", 5432), Action::Allow);
+    assert_eq!(evaluate(None, "db.prod.internal", 5432), Action::Allow);
+}
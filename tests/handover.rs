@@ -0,0 +1,77 @@
+#![cfg(all(feature = "socks5", target_os = "linux"))]
+use merino::*;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Relay a few bytes through the proxy at `port`
+fn echo_through(port: u16, echo: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+    client::connect(&mut stream, "127.0.0.1", echo, None)?;
+    stream.write_all(b"ping")?;
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(())
+}
+
+#[test]
+/// `merino upgrade` hands the listener to a new process without refusing clients, and the old one drains
+fn handover_upgrade() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let socket = std::env::temp_dir().join(format!("merino-upgrade-{}.sock", std::process::id()));
+    let mut old = Command::new(env!("CARGO_BIN_EXE_merino"))
+        .args(["--no-auth", "--port", &port.to_string(), "--shutdown-grace", "10", "--upgrade-socket"])
+        .arg(&socket)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let start = Instant::now();
+    while echo_through(port, echo.port()).is_err() && start.elapsed() < Duration::from_secs(10) {
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    // A session open across the upgrade keeps the old process around until it ends
+    let mut session = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    client::connect(&mut session, "127.0.0.1", echo.port(), None).unwrap();
+    let upgrading = Arc::new(AtomicBool::new(true));
+    let clients = {
+        let upgrading = upgrading.clone();
+        thread::spawn(move || {
+            let mut relayed = 0;
+            while upgrading.load(Ordering::Relaxed) {
+                echo_through(port, echo.port()).unwrap();
+                relayed += 1;
+            }
+            relayed
+        })
+    };
+
+    let output = Command::new(env!("CARGO_BIN_EXE_merino"))
+        .arg("upgrade").arg("--socket").arg(&socket)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let pid: u32 = stdout.trim().strip_prefix("Upgraded, process ").and_then(|rest| rest.strip_suffix(" is serving")).unwrap().parse().unwrap();
+    assert_ne!(pid, old.id());
+
+    thread::sleep(Duration::from_millis(200));
+    upgrading.store(false, Ordering::Relaxed);
+    assert!(clients.join().unwrap() > 0);
+    assert!(old.try_wait().unwrap().is_none());
+    session.write_all(b"still here").unwrap();
+    let mut buf = [0u8; 10];
+    session.read_exact(&mut buf).unwrap();
+    drop(session);
+    assert!(old.wait().unwrap().success());
+
+    echo_through(port, echo.port()).unwrap();
+    Command::new("kill").arg(pid.to_string()).status().unwrap();
+    std::fs::remove_file(&socket).unwrap();
+}
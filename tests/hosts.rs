@@ -0,0 +1,52 @@
+use merino::rules::Destination;
+use merino::*;
+
+const HOSTS: &str = "# staging
+10.1.2.3            api.example.com www.example.com
+staging.internal    shop.example.com
+10.1.2.4:8443       Payments.example.com
+[fd00::7]:443       cdn.example.com
+fd00::8             ::1
+";
+
+#[test]
+/// Overridden hosts resolve to their target, keeping the port unless one is given
+fn hosts_resolve() {
+    let hosts = Hosts::from_reader(HOSTS.as_bytes()).unwrap();
+    assert_eq!(hosts.len(), 6);
+
+    let resolve = |host: &str, port| hosts.resolve(&Destination::parse(host), port).map(|(h, p)| (h.to_string(), p));
+    assert_eq!(resolve("www.example.com", 80), Some(("10.1.2.3".to_string(), 80)));
+    assert_eq!(resolve("shop.example.com", 443), Some(("staging.internal".to_string(), 443)));
+    assert_eq!(resolve("payments.example.com", 443), Some(("10.1.2.4".to_string(), 8443)));
+    assert_eq!(resolve("cdn.example.com", 80), Some(("fd00::7".to_string(), 443)));
+    assert_eq!(resolve("0:0:0:0:0:0:0:1", 22), Some(("fd00::8".to_string(), 22)));
+    assert_eq!(resolve("example.com", 80), None);
+
+    assert!(Hosts::from_reader("10.1.2.3\n".as_bytes()).is_err());
+    assert!(Hosts::from_reader("10.1.2.3:http example.com\n".as_bytes()).is_err());
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// The proxy connects to the override instead of the requested host
+fn hosts_proxy() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let hosts = Hosts::from_reader(format!("{} echo.test\n", echo).as_bytes()).unwrap();
+
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_hosts(hosts);
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, "echo.test", 7, None).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
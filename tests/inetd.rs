@@ -0,0 +1,52 @@
+#![cfg(feature = "socks5")]
+use merino::*;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+
+#[test]
+/// `--inetd` serves one SOCKS session over stdin/stdout and exits once it ends
+fn inetd_session() {
+    let echo = bench::spawn_echo_server().unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_merino"))
+        .args(["--inetd", "--no-auth"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+
+    let mut client = ReadWrite(&mut stdout, &mut stdin);
+    let bound = client::connect(&mut client, &echo.ip().to_string(), echo.port(), None);
+    assert!(bound.is_ok());
+
+    stdin.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stdout.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // Closing stdin ends the session
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}
+
+/// Separate halves of a pipe as one stream
+struct ReadWrite<'a, R, W>(&'a mut R, &'a mut W);
+
+impl<'a, R: Read, W> Read for ReadWrite<'a, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<'a, R, W: Write> Write for ReadWrite<'a, R, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.1.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.1.flush()
+    }
+}
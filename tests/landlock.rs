@@ -0,0 +1,56 @@
+use merino::*;
+use std::fs;
+use std::io::ErrorKind;
+use std::thread;
+
+#[test]
+/// Once confined, only the allowed paths can be opened, for reading or writing as granted
+fn landlock_restrict() {
+    let dir = std::env::temp_dir().join(format!("merino-landlock-{}", std::process::id()));
+    let (read, write) = (dir.join("read"), dir.join("write"));
+    fs::create_dir_all(&read).unwrap();
+    fs::create_dir_all(&write).unwrap();
+    fs::write(read.join("users.csv"), "username,password\n").unwrap();
+
+    // Landlock confines the calling thread, so the rest of the tests stay unaffected
+    let landlock = sandbox::Landlock::new().read(&read).write(&write);
+    let confined = thread::spawn(move || {
+        if !landlock.restrict().unwrap() {
+            return false;
+        }
+        assert_eq!(fs::read_to_string(read.join("users.csv")).unwrap(), "username,password\n");
+        assert_eq!(fs::write(read.join("users.csv"), "").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        fs::write(write.join("access.log"), "logged\n").unwrap();
+        fs::rename(write.join("access.log"), write.join("access.log.1")).unwrap();
+        assert_eq!(fs::read("Cargo.toml").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        // Name resolution keeps working, and soft limits see the process's usage
+        std::net::ToSocketAddrs::to_socket_addrs("localhost:80").unwrap();
+        let usage = process::ProcessUsage::sample();
+        assert!(usage.open_fds.unwrap() >= 3);
+        assert!(usage.resident_memory.unwrap() > 0);
+        true
+    }).join().unwrap();
+
+    if confined {
+        assert!(fs::read("Cargo.toml").is_ok());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+/// Paths that don't exist can't be allowed
+fn landlock_missing_path() {
+    let landlock = sandbox::Landlock::new().read("/nonexistent/merino/users.csv");
+    let result = thread::spawn(move || landlock.restrict()).join().unwrap();
+    if let Err(error) = result {
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+}
+
+#[test]
+#[cfg(not(target_os = "openbsd"))]
+/// pledge is refused elsewhere rather than silently doing nothing
+fn pledge_unsupported() {
+    let error = sandbox::pledge("stdio inet dns", &[]).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Unsupported);
+}
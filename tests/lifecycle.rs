@@ -0,0 +1,91 @@
+#![cfg(feature = "socks5")]
+use merino::*;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Status line of the readiness endpoint
+fn probe(addr: SocketAddr) -> String {
+    let mut http = TcpStream::connect(addr).unwrap();
+    http.write_all(b"GET /ready HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    http.read_to_string(&mut response).unwrap();
+    response.lines().next().unwrap_or_default().to_string()
+}
+
+/// Wait up to 5 seconds for `condition`
+fn eventually(condition: impl Fn() -> bool) -> bool {
+    let start = Instant::now();
+    while !condition() && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    condition()
+}
+
+#[test]
+/// Draining fails readiness, turns new clients away and waits for relaying sessions
+fn lifecycle_drain() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_readiness("127.0.0.1:0".parse().unwrap()).unwrap();
+    let ready_addr = proxy.readiness_addr().unwrap();
+    let (lifecycle, metrics) = (proxy.lifecycle(), proxy.metrics());
+    let addr = proxy.local_addr().unwrap();
+    assert!(!lifecycle.is_ready());
+    thread::spawn(move || proxy.serve().unwrap());
+    assert!(eventually(|| probe(ready_addr) == "HTTP/1.1 200 OK"));
+
+    let mut session = TcpStream::connect(addr).unwrap();
+    client::connect(&mut session, "127.0.0.1", echo.port(), None).unwrap();
+    assert!(eventually(|| metrics.active.load(std::sync::atomic::Ordering::Relaxed) == 1));
+    assert!(!lifecycle.drain(Duration::from_millis(200)));
+    assert_eq!(probe(ready_addr), "HTTP/1.1 503 Service Unavailable");
+    assert!(client::connect(&mut TcpStream::connect(addr).unwrap(), "127.0.0.1", echo.port(), None).is_err());
+
+    // The session still relays until it ends
+    session.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    session.read_exact(&mut buf).unwrap();
+    let draining = thread::spawn(move || lifecycle.drain(Duration::from_secs(5)));
+    session.shutdown(std::net::Shutdown::Write).unwrap();
+    session.read_to_end(&mut Vec::new()).unwrap();
+    assert!(draining.join().unwrap());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+/// In sidecar mode SIGTERM drains and exits cleanly, logging JSON to stdout
+fn lifecycle_sidecar_sigterm() {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_merino"))
+        .args(["--sidecar", "--no-auth", "--port", "0", "--ready", "127.0.0.1:0"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The port picked for readiness is only known from the log
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut stdout = String::new();
+    let ready_addr: SocketAddr = loop {
+        let line = lines.next().expect("merino exited before serving readiness").unwrap();
+        stdout += &line;
+        stdout.push('\n');
+        if let Some(at) = line.find("Serving readiness on ") {
+            break line[at + "Serving readiness on ".len()..].trim_end_matches(['"', '}']).parse().unwrap();
+        }
+    };
+    assert!(eventually(|| probe(ready_addr) == "HTTP/1.1 200 OK"));
+
+    let killed = Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    assert!(killed.success());
+    for line in lines {
+        stdout += &line.unwrap();
+        stdout.push('\n');
+    }
+    assert!(child.wait().unwrap().success());
+
+    assert!(stdout.lines().all(|line| line.starts_with("{\"time\":\"") && line.ends_with('}')), "{}", stdout);
+    assert!(stdout.contains("\"message\":\"Received SIGTERM, draining sessions for up to 25s\""), "{}", stdout);
+}
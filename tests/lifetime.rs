@@ -0,0 +1,34 @@
+#![cfg(feature = "socks5")]
+use merino::*;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+/// Sessions are shut down once they reach the maximum lifetime, and not before
+fn lifetime_shuts_down() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_max_lifetime(Duration::from_millis(500));
+    let addr = proxy.local_addr().unwrap();
+    let admin = proxy.admin();
+    thread::spawn(move || proxy.serve());
+
+    let start = Instant::now();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(admin.sessions().len(), 1);
+
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    while !admin.sessions().is_empty() && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(admin.sessions().is_empty());
+}
@@ -0,0 +1,40 @@
+#![cfg(feature = "socks5")]
+use merino::*;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+/// An unauthenticated proxy with `limits`, served over a socket pair
+fn connect(limits: Limits) -> UnixStream {
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_limits(limits);
+    let (stream, server) = UnixStream::pair().unwrap();
+    thread::spawn(move || proxy.serve_connection(server).is_ok());
+    stream
+}
+
+#[test]
+/// Offering more auth methods than allowed gets no acceptable method
+fn limits_methods() {
+    let mut stream = connect(Limits { max_methods: 2, ..Limits::default() });
+    stream.write_all(&[5, 3, 0, 1, 2]).unwrap();
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).unwrap();
+    assert_eq!(choice, [5, 0xff]);
+}
+
+#[test]
+/// Domain names over the limit are refused with a general failure
+fn limits_domain() {
+    let mut stream = connect(Limits { max_domain: 8, ..Limits::default() });
+    assert!(client::connect(&mut stream, "too-long.example.com", 80, None).is_err());
+
+    let mut stream = connect(Limits { max_domain: 8, ..Limits::default() });
+    stream.write_all(&[5, 1, 0]).unwrap();
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).unwrap();
+    stream.write_all(&[5, 1, 0, 3, 9]).unwrap();
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [5, 1]);
+}
@@ -0,0 +1,133 @@
+#![cfg(feature = "socks5")]
+use merino::*;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+/// Histograms count observations into every bucket at or above them
+fn metrics_histogram() {
+    let metrics = Metrics::default();
+    metrics.session_ended(Duration::from_secs(2), 2048);
+    metrics.session_ended(Duration::from_millis(50), 10);
+
+    assert_eq!(metrics.session_duration.count(), 2);
+    assert_eq!(metrics.session_bytes.sum(), 2058.0);
+    let text = metrics.render();
+    assert!(text.contains("merino_session_duration_seconds_bucket{le=\"0.1\"} 1\n"));
+    assert!(text.contains("merino_session_duration_seconds_bucket{le=\"5\"} 2\n"));
+    assert!(text.contains("merino_session_bytes_bucket{le=\"1024\"} 1\n"));
+    assert!(text.contains("merino_relayed_bytes_total 2058\n"));
+}
+
+#[test]
+/// Connects are counted by destination, up to a bound, and the ones failing most listed first
+fn metrics_destinations() {
+    let metrics = Metrics::default();
+    metrics.connect_finished("example.com", 443, Duration::from_millis(30), true);
+    metrics.connect_finished("example.com", 443, Duration::from_millis(10), true);
+    metrics.connect_finished("broken.example", 443, Duration::from_secs(5), false);
+    metrics.connect_finished("192.0.2.1", 80, Duration::from_millis(1), true);
+
+    let destinations = metrics.destinations(2);
+    assert_eq!(destinations.len(), 2);
+    assert_eq!((destinations[0].destination.as_str(), destinations[0].failures), ("broken.example:443", 1));
+    assert_eq!(destinations[0].average_latency(), None);
+    assert_eq!(destinations[1].destination, "example.com:443");
+    assert_eq!(destinations[1].average_latency(), Some(Duration::from_millis(20)));
+    assert_eq!(destinations[1].to_string(), "example.com:443\t2 connects\t0 failed (0.0%)\t20ms average");
+
+    let text = metrics.render();
+    assert!(text.contains("merino_destination_connects_total{destination=\"example.com:443\"} 2\n"));
+    assert!(text.contains("merino_destination_connect_failures_total{destination=\"broken.example:443\"} 1\n"));
+    assert!(text.contains("merino_destination_connect_seconds_count{destination=\"192.0.2.1:80\"} 1\n"));
+
+    for port in 0..metrics::MAX_DESTINATIONS as u16 {
+        metrics.connect_finished("many.example", port, Duration::from_millis(1), true);
+    }
+    let destinations = metrics.destinations(usize::MAX);
+    assert_eq!(destinations.len(), metrics::MAX_DESTINATIONS + 1);
+    assert!(destinations.iter().any(|stats| stats.destination == "other" && stats.attempts == 3));
+}
+
+#[test]
+/// Relayed sessions are measured and served over HTTP
+fn metrics_sessions() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let metrics_addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_metrics(metrics_addr).unwrap();
+    let metrics = proxy.metrics();
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    stream.read_to_end(&mut Vec::new()).unwrap();
+
+    let start = Instant::now();
+    while metrics.session_bytes.count() == 0 && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(metrics.sessions.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.handshake_latency.count(), 1);
+    assert_eq!(metrics.session_bytes.sum(), 10.0);
+
+    let mut http = TcpStream::connect(metrics_addr).unwrap();
+    http.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    http.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("merino_sessions_total 1\n"));
+    assert!(response.contains("merino_session_bytes_count 1\n"));
+
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let proxy = Merino::new(0, "127.0.0.1", Vec::new(), Vec::new()).unwrap();
+    let failed = proxy.metrics();
+    stream.write_all(&[5, 1, 0]).unwrap();
+    assert!(proxy.serve_connection(server).is_err());
+    assert_eq!(failed.failures.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+/// Requests are counted by command and by whether they were granted, denied or failed to connect
+fn metrics_requests() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let rules = Rules::from_reader("action,user,source,destination,port\ndeny,,,192.0.2.0/24,\nallow,,,,\n".as_bytes()).unwrap();
+    let users: Vec<User> = csv::Reader::from_reader("username,password\nalice,secret\n".as_bytes())
+        .deserialize().collect::<Result<_, _>>().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], users).unwrap()
+        .with_rules(rules);
+    let metrics = proxy.metrics();
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+    let credentials = Some(("alice", "secret"));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), credentials).unwrap();
+    assert!(client::connect(&mut TcpStream::connect(addr).unwrap(), "192.0.2.1", 80, credentials).is_err());
+    assert!(client::connect(&mut TcpStream::connect(addr).unwrap(), "127.0.0.1", closed.port(), credentials).is_err());
+    assert!(client::connect(&mut TcpStream::connect(addr).unwrap(), "127.0.0.1", echo.port(), Some(("alice", "wrong"))).is_err());
+
+    let start = Instant::now();
+    while metrics.requests(Some(SockCommand::Connect), metrics::Outcome::ConnectFailed) == 0 && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(metrics.requests(Some(SockCommand::Connect), metrics::Outcome::Granted), 1);
+    assert_eq!(metrics.requests(Some(SockCommand::Connect), metrics::Outcome::DeniedAcl), 1);
+    assert_eq!(metrics.requests(Some(SockCommand::Connect), metrics::Outcome::ConnectFailed), 1);
+    assert_eq!(metrics.requests(None, metrics::Outcome::DeniedAuth), 1);
+
+    let text = metrics.render();
+    assert!(text.contains("merino_requests_total{command=\"connect\",outcome=\"granted\"} 1\n"));
+    assert!(text.contains("merino_requests_total{command=\"none\",outcome=\"denied_auth\"} 1\n"));
+    assert!(!text.contains("command=\"bind\""));
+}
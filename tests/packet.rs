@@ -0,0 +1,33 @@
+use merino::*;
+
+#[test]
+/// Replies encode their bind address with the matching address type
+fn packet_reply_bytes() {
+    let reply = SOCKSReply { version: 5, reply: ResponseCode::Success, bind: "10.0.0.1:1080".parse().unwrap() };
+    assert_eq!(reply.to_bytes(), vec![5, 0, 0, 1, 10, 0, 0, 1, 0x04, 0x38]);
+
+    let reply = SOCKSReply { version: 5, reply: ResponseCode::RuleFailure, bind: "[::1]:0".parse().unwrap() };
+    let bytes = reply.to_bytes();
+    assert_eq!(&bytes[..4], &[5, 2, 0, 4]);
+    assert_eq!(bytes.len(), 22);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+/// Requests and replies round-trip through serde as structured data
+fn packet_serde() {
+    let request = SOCKSReq {
+        version: 5,
+        command: SockCommand::Connect,
+        destination: rules::Destination::Domain("example.com".to_string()),
+        port: 443,
+    };
+    let json = serde_json::to_value(&request).unwrap();
+    assert_eq!(json["destination"]["Domain"], "example.com");
+    assert_eq!(json["command"], "Connect");
+    assert_eq!(serde_json::from_value::<SOCKSReq>(json).unwrap(), request);
+
+    let reply = SOCKSReply { version: 5, reply: ResponseCode::HostUnreachable, bind: "127.0.0.1:0".parse().unwrap() };
+    let json = serde_json::to_string(&reply).unwrap();
+    assert_eq!(serde_json::from_str::<SOCKSReply>(&json).unwrap(), reply);
+}
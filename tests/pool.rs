@@ -0,0 +1,68 @@
+use merino::*;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Wait up to 5 seconds for `pool` to have `count` connections to `host:port` ready
+fn wait_ready(pool: &Pool, host: &str, port: u16, count: usize) -> bool {
+    let start = Instant::now();
+    while pool.ready(host, port) != count && start.elapsed() < Duration::from_secs(5) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    pool.ready(host, port) == count
+}
+
+#[test]
+/// Pooled destinations are connected ahead of time and refilled once taken
+fn pool_prewarm() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let host = echo.ip().to_string();
+    let pool = Pool::new(vec![(host.clone(), echo.port())], 2, Duration::from_secs(30), ConnectOptions::default());
+    assert!(wait_ready(&pool, &host, echo.port(), 2));
+
+    let mut stream = pool.take(&host, echo.port()).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+    assert!(wait_ready(&pool, &host, echo.port(), 2));
+
+    assert!(pool.take(&host, echo.port() + 1).is_none());
+    assert!(pool.take("example.com", echo.port()).is_none());
+}
+
+#[test]
+/// Connections idle for longer than the timeout are never handed out
+fn pool_idle_eviction() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let host = echo.ip().to_string();
+    let pool = Pool::new(vec![(host.clone(), echo.port())], 1, Duration::from_millis(50), ConnectOptions::default());
+    assert!(wait_ready(&pool, &host, echo.port(), 1));
+
+    thread::sleep(Duration::from_millis(100));
+    assert!(pool.take(&host, echo.port()).is_none());
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// CONNECT requests to a pooled destination are relayed over a warm connection
+fn pool_proxy() {
+    use std::os::unix::net::UnixStream;
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let host = echo.ip().to_string();
+    let pool = Pool::new(vec![(host.clone(), echo.port())], 1, Duration::from_secs(30), ConnectOptions::default());
+    assert!(wait_ready(&pool, &host, echo.port(), 1));
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_pool(pool.clone());
+
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let serving = thread::spawn(move || proxy.serve_connection(server).is_ok());
+    client::connect(&mut stream, &host, echo.port(), None).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    assert!(serving.join().unwrap());
+}
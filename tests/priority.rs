@@ -0,0 +1,85 @@
+use merino::priority::Scheduler;
+use merino::*;
+use std::time::{Duration, Instant};
+
+#[test]
+/// Classes relaying at once share the bandwidth by weight, idle ones leaving theirs to the others
+fn priority_shares() {
+    let scheduler = Scheduler::new(7000);
+    assert_eq!(scheduler.rate(Priority::Bulk), 7000.0);
+
+    let _high = scheduler.enter(Priority::High);
+    assert_eq!(scheduler.rate(Priority::High), 7000.0);
+    assert_eq!(scheduler.rate(Priority::Bulk), 1400.0);
+
+    let _normal = scheduler.enter(Priority::Normal);
+    let _bulk = scheduler.enter(Priority::Bulk);
+    assert_eq!(scheduler.rate(Priority::High), 4000.0);
+    assert_eq!(scheduler.rate(Priority::Normal), 2000.0);
+    assert_eq!(scheduler.rate(Priority::Bulk), 1000.0);
+
+    drop(_high);
+    assert_eq!(scheduler.rate(Priority::Bulk), 7000.0 / 3.0);
+}
+
+#[test]
+/// Traffic beyond a class's rate is held back until it fits
+fn priority_pacing() {
+    let scheduler = Scheduler::new(100_000);
+    let _active = scheduler.enter(Priority::Normal);
+
+    let start = Instant::now();
+    for _ in 0..4 {
+        scheduler.pace(Priority::Normal, 10_000);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(350));
+}
+
+#[test]
+/// Sessions in a class with data to send get equal shares of it, so a bulk transfer can't take it all
+fn priority_fair_sessions() {
+    let scheduler = Scheduler::new(100_000);
+    let bulk = scheduler.enter(Priority::Normal);
+    let interactive = scheduler.enter(Priority::Normal);
+    assert_eq!(scheduler.share(Priority::Normal), 100_000.0);
+
+    bulk.pace(1000);
+    interactive.pace(1000);
+    assert_eq!(scheduler.share(Priority::Normal), 50_000.0);
+
+    // The bulk session is held to its half while the other keeps sending
+    let start = Instant::now();
+    for _ in 0..8 {
+        bulk.pace(5_000);
+        interactive.pace(100);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(700));
+
+    // Gone sessions give their share back
+    drop(interactive);
+    assert_eq!(scheduler.share(Priority::Normal), 100_000.0);
+}
+
+#[test]
+/// Rules put sessions in a class, deny rules can't
+fn priority_rules() {
+    let rules = Rules::from_reader("action,user,source,destination,port,priority\nallow,,,*,22,high\nallow,,,*,873,bulk\n".as_bytes()).unwrap();
+    let priority = |port| {
+        let destination = rules::Destination::parse("example.com");
+        rules.evaluate(&rules::Request {
+            source: "10.0.0.5".parse().unwrap(),
+            user: None,
+            groups: &[],
+            destination: &destination,
+            port,
+            asn: None,
+            tag: None,
+        }).rule.and_then(|(_, rule)| rule.priority())
+    };
+    assert_eq!(priority(22), Some(Priority::High));
+    assert_eq!(priority(873), Some(Priority::Bulk));
+    assert_eq!(priority(443), None);
+
+    assert!(Rules::from_reader("action,user,source,destination,port,priority\nallow,,,,,urgent\n".as_bytes()).is_err());
+    assert!(Rules::from_reader("action,user,source,destination,port,priority\ndeny,,,,,bulk\n".as_bytes()).is_err());
+}
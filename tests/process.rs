@@ -0,0 +1,62 @@
+use merino::process::{ProcessUsage, SoftLimits};
+use merino::*;
+
+#[cfg(target_os = "linux")]
+#[test]
+/// Descriptors and resident memory are read from /proc and exported as metrics
+fn process_usage() {
+    let usage = ProcessUsage::sample();
+    assert!(usage.open_fds.unwrap() >= 3);
+    assert!(usage.resident_memory.unwrap() > 1024 * 1024);
+
+    let metrics = metrics::Metrics::default().render();
+    assert!(metrics.contains("\nprocess_open_fds "));
+    assert!(metrics.contains("\nprocess_resident_memory_bytes "));
+    assert!(metrics.contains("\nmerino_relay_buffer_bytes 0\n"));
+}
+
+#[test]
+/// Either soft limit being reached calls for shedding, unknown usage doesn't
+fn process_soft_limits() {
+    let limits = SoftLimits { open_fds: Some(100), resident_memory: Some(64 * 1024 * 1024) };
+    let usage = |open_fds, resident_memory| ProcessUsage { open_fds, resident_memory };
+
+    assert_eq!(limits.exceeded(&usage(Some(99), Some(1024))), None);
+    assert!(limits.exceeded(&usage(Some(100), Some(1024))).unwrap().contains("100 open descriptors"));
+    assert!(limits.exceeded(&usage(Some(1), Some(64 * 1024 * 1024))).unwrap().contains("64 MiB resident"));
+    assert_eq!(limits.exceeded(&usage(None, None)), None);
+    assert_eq!(SoftLimits::default().exceeded(&usage(Some(u64::MAX), Some(u64::MAX))), None);
+}
+
+#[cfg(all(feature = "socks5", target_os = "linux"))]
+#[test]
+/// New connections are closed while past a soft limit, and relayed sessions hold relay buffers
+fn process_shedding() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let metrics = proxy.metrics();
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert!(metrics.relay_buffers.load(Ordering::Relaxed) >= 2 * RELAY_BUFFER_MIN as u64);
+
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_soft_limits(SoftLimits { open_fds: Some(1), resident_memory: None });
+    let metrics = proxy.metrics();
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert!(client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).is_err());
+    assert_eq!(metrics.refused.load(Ordering::Relaxed), 1);
+}
@@ -0,0 +1,68 @@
+use merino::proxy_protocol::{self, SIGNATURE};
+use merino::*;
+
+#[test]
+/// Headers carry both addresses, IPv4 when both ends are and mapped IPv6 otherwise
+fn proxy_protocol_v2() {
+    let header = proxy_protocol::v2("192.0.2.7:1234".parse().unwrap(), "10.0.0.1:443".parse().unwrap());
+    assert_eq!(&header[..12], &SIGNATURE);
+    assert_eq!(&header[12..], &[0x21, 0x11, 0, 12, 192, 0, 2, 7, 10, 0, 0, 1, 0x04, 0xd2, 0x01, 0xbb]);
+
+    let header = proxy_protocol::v2("192.0.2.7:1234".parse().unwrap(), "[2001:db8::1]:443".parse().unwrap());
+    assert_eq!(&header[12..16], &[0x21, 0x21, 0, 36]);
+    assert_eq!(&header[16..32], &"::ffff:192.0.2.7".parse::<std::net::Ipv6Addr>().unwrap().octets());
+    assert_eq!(header.len(), 16 + 36);
+}
+
+#[test]
+/// Only allow rules can ask for the header, and only version 2 of it
+fn proxy_protocol_rules() {
+    let rules = Rules::from_reader("action,user,source,destination,port,proxy_protocol\nallow,,,*.internal,,v2\n".as_bytes()).unwrap();
+    assert!(rules.iter().next().unwrap().proxy_protocol());
+    assert!(rules.iter().next().unwrap().to_string().contains("proxy_protocol=v2"));
+    assert!(Rules::from_reader("action,user,source,destination,port,proxy_protocol\ndeny,,,,,v2\n".as_bytes()).is_err());
+    assert!(Rules::from_reader("action,user,source,destination,port,proxy_protocol\nallow,,,,,v1\n".as_bytes()).is_err());
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// Destinations a rule marks get the client's address ahead of its data, others don't
+fn proxy_protocol_relay() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend_addr = backend.local_addr().unwrap();
+    let received = thread::spawn(move || {
+        (0..2).map(|_| {
+            let (mut stream, _) = backend.accept().unwrap();
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).unwrap();
+            data
+        }).collect::<Vec<_>>()
+    });
+
+    let rules = Rules::from_reader(format!("action,user,source,destination,port,proxy_protocol\nallow,,,127.0.0.1,{},v2\nallow,,,localhost,,\n", backend_addr.port()).as_bytes()).unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_rules(rules);
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve());
+
+    let mut client_ports = Vec::new();
+    for host in ["127.0.0.1", "localhost"] {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        client_ports.push(stream.local_addr().unwrap().port());
+        client::connect(&mut stream, host, backend_addr.port(), None).unwrap();
+        stream.write_all(b"hello").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+    }
+
+    let received = received.join().unwrap();
+    let expected = proxy_protocol::v2(format!("127.0.0.1:{}", client_ports[0]).parse().unwrap(), backend_addr);
+    assert_eq!(&received[0][..expected.len()], &expected[..]);
+    assert_eq!(&received[0][expected.len()..], b"hello");
+    assert_eq!(received[1], b"hello");
+}
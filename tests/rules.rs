@@ -0,0 +1,369 @@
+use merino::rules::{Action, Destination, Request};
+use merino::*;
+use std::net::IpAddr;
+
+const RULES: &str = "action,user,source,destination,port
+deny,,,*.internal.corp,
+allow,bob,10.0.0.0/8,example.com,443
+deny,,,*,25-26
+";
+
+fn evaluate(rules: &Rules, source: &str, user: Option<&str>, host: &str, port: u16) -> (Action, Option<usize>) {
+    let destination = Destination::parse(host);
+    let verdict = rules.evaluate(&Request {
+        source: source.parse::<IpAddr>().unwrap(),
+        user,
+        groups: &[],
+        destination: &destination,
+        port,
+        asn: None,
+        tag: None,
+    });
+    (verdict.action, verdict.rule.map(|(index, _)| index))
+}
+
+#[test]
+/// First matching rule decides the request
+fn rules_first_match() {
+    let rules = Rules::from_reader(RULES.as_bytes()).unwrap();
+    assert_eq!(rules.len(), 3);
+
+    assert_eq!(evaluate(&rules, "10.0.0.5", Some("bob"), "example.com", 443), (Action::Allow, Some(1)));
+    assert_eq!(evaluate(&rules, "10.0.0.5", Some("bob"), "db.internal.corp", 443), (Action::Deny, Some(0)));
+    assert_eq!(evaluate(&rules, "192.168.1.1", None, "203.0.113.7", 26), (Action::Deny, Some(2)));
+}
+
+#[test]
+/// Requests no rule matches are allowed
+fn rules_default_allow() {
+    let rules = Rules::from_reader(RULES.as_bytes()).unwrap();
+
+    assert_eq!(evaluate(&rules, "192.168.1.1", Some("bob"), "example.com", 443), (Action::Allow, None));
+    assert_eq!(evaluate(&rules, "10.0.0.5", Some("alice"), "internal.corp", 80), (Action::Allow, None));
+}
+
+#[test]
+/// IPv4-mapped IPv6 destinations are matched, and shown, as the IPv4 addresses they are
+fn rules_mapped_destination() {
+    let rules = Rules::from_reader("action,user,source,destination,port\ndeny,,,10.0.0.0/8,\n".as_bytes()).unwrap();
+    assert_eq!(Destination::parse("::ffff:10.1.2.3"), Destination::Ip("10.1.2.3".parse().unwrap()));
+    assert_eq!(Destination::parse("[::ffff:10.1.2.3]").to_string(), "10.1.2.3");
+    assert_eq!(evaluate(&rules, "192.168.1.1", None, "::ffff:10.1.2.3", 443), (Action::Deny, Some(0)));
+    assert_eq!(Destination::parse("2001:db8::1"), Destination::Ip("2001:db8::1".parse().unwrap()));
+}
+
+#[test]
+/// Malformed rules are rejected when loading
+fn rules_invalid() {
+    assert!(Rules::from_reader("action,user,source,destination,port\nblock,,,,\n".as_bytes()).is_err());
+    assert!(Rules::from_reader("action,user,source,destination,port\ndeny,,10.0.0.0/33,,\n".as_bytes()).is_err());
+    assert!(Rules::from_reader("action,user,source,destination,port\ndeny,,,,http\n".as_bytes()).is_err());
+}
+
+#[test]
+/// Scheduled rules only apply within their days and hours
+fn rules_schedule() {
+    use chrono::{DateTime, Utc};
+
+    let rules = Rules::from_reader("action,user,source,destination,port,days,hours,timezone
+deny,,,*.games.example,,mon-fri,09:00-17:00,Europe/Berlin
+deny,,,night.example,,,22:00-06:00,
+".as_bytes()).unwrap();
+    let at = |time: &str, host: &str| {
+        let destination = Destination::parse(host);
+        let now = DateTime::parse_from_rfc3339(time).unwrap().with_timezone(&Utc);
+        rules.evaluate_at(&Request {
+            source: "10.0.0.5".parse::<IpAddr>().unwrap(),
+            user: None,
+            groups: &[],
+            destination: &destination,
+            port: 443,
+            asn: None,
+            tag: None,
+        }, now).action
+    };
+
+    // Wednesday, 10:30 in Berlin
+    assert_eq!(at("2024-05-08T08:30:00Z", "www.games.example"), Action::Deny);
+    // Wednesday, 17:30 in Berlin
+    assert_eq!(at("2024-05-08T15:30:00Z", "www.games.example"), Action::Allow);
+    // Saturday, 10:30 in Berlin
+    assert_eq!(at("2024-05-11T08:30:00Z", "www.games.example"), Action::Allow);
+
+    assert_eq!(at("2024-05-08T23:00:00Z", "night.example"), Action::Deny);
+    assert_eq!(at("2024-05-09T05:59:00Z", "night.example"), Action::Deny);
+    assert_eq!(at("2024-05-09T06:00:00Z", "night.example"), Action::Allow);
+
+    assert!(Rules::from_reader("action,user,source,destination,port,days\ndeny,,,,,someday\n".as_bytes()).is_err());
+    assert!(Rules::from_reader("action,user,source,destination,port,days,hours,timezone\ndeny,,,,,,09:00-17:00,Mars/Olympus\n".as_bytes()).is_err());
+}
+
+#[test]
+/// Allow rules can send requests to another host or port
+fn rules_rewrite() {
+    let rules = Rules::from_reader("action,user,source,destination,port,days,hours,timezone,rewrite
+allow,,,old.example.com,,,,,new.example.com
+allow,,,*.example.com,80,,,,:443
+allow,,,legacy.test,,,,,[fd00::1]:8080
+".as_bytes()).unwrap();
+    let rewrite = |host: &str, port: u16| {
+        let destination = Destination::parse(host);
+        let verdict = rules.evaluate(&Request {
+            source: "10.0.0.5".parse::<IpAddr>().unwrap(),
+            user: None,
+            groups: &[],
+            destination: &destination,
+            port,
+            asn: None,
+            tag: None,
+        });
+        verdict.rule
+            .and_then(|(_, rule)| rule.rewrite())
+            .map(|rewrite| rewrite.apply(&destination, port))
+            .map(|(destination, port)| (destination.to_string(), port))
+    };
+
+    assert_eq!(rewrite("old.example.com", 8000), Some(("new.example.com".to_string(), 8000)));
+    assert_eq!(rewrite("www.example.com", 80), Some(("www.example.com".to_string(), 443)));
+    assert_eq!(rewrite("legacy.test", 80), Some(("fd00::1".to_string(), 8080)));
+    assert_eq!(rewrite("www.example.com", 8080), None);
+
+    assert!(Rules::from_reader("action,user,source,destination,port,days,hours,timezone,rewrite\ndeny,,,,,,,,example.com\n".as_bytes()).is_err());
+    assert!(Rules::from_reader("action,user,source,destination,port,days,hours,timezone,rewrite\nallow,,,,,,,,example.com:https\n".as_bytes()).is_err());
+}
+
+#[test]
+/// Allow rules can mark traffic with DSCP values given as numbers or names
+fn rules_dscp() {
+    let rules = Rules::from_reader("action,user,source,destination,port,dscp,client_dscp
+allow,,,*,22,ef,af41
+allow,,,*,873,cs1,
+allow,,,*,80,10,
+".as_bytes()).unwrap();
+    let dscp = |port: u16| {
+        let destination = Destination::parse("example.com");
+        let verdict = rules.evaluate(&Request {
+            source: "10.0.0.5".parse::<IpAddr>().unwrap(),
+            user: None,
+            groups: &[],
+            destination: &destination,
+            port,
+            asn: None,
+            tag: None,
+        });
+        verdict.rule.map(|(_, rule)| (rule.dscp(), rule.client_dscp()))
+    };
+
+    assert_eq!(dscp(22), Some((Some(46), Some(34))));
+    assert_eq!(dscp(873), Some((Some(8), None)));
+    assert_eq!(dscp(80), Some((Some(10), None)));
+
+    for invalid in ["64", "af44", "cs8", "best"] {
+        let rules = format!("action,user,source,destination,port,dscp\nallow,,,,,{}\n", invalid);
+        assert!(Rules::from_reader(rules.as_bytes()).is_err(), "{}", invalid);
+    }
+    assert!(Rules::from_reader("action,user,source,destination,port,dscp\ndeny,,,,,ef\n".as_bytes()).is_err());
+}
+
+#[test]
+/// Included files are loaded in name order where the include appears, relative to the including file
+fn rules_include() {
+    let dir = std::env::temp_dir().join(format!("merino-rules-include-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("acl.d")).unwrap();
+    std::fs::write(dir.join("rules.csv"), "action,user,source,destination,port
+# Specific exceptions first
+#include acl.d/*.csv
+deny,,,*,25
+#include acl.d/late/last.csv
+").unwrap();
+    std::fs::write(dir.join("acl.d/20-internal.csv"), "action,destination\ndeny,*.internal.corp\n").unwrap();
+    std::fs::write(dir.join("acl.d/10-bob.csv"), "action,user,destination\nallow,bob,db.internal.corp\n").unwrap();
+    std::fs::write(dir.join("acl.d/notes.txt"), "not rules\n").unwrap();
+    std::fs::create_dir_all(dir.join("acl.d/late")).unwrap();
+    std::fs::write(dir.join("acl.d/late/last.csv"), "action,destination,port\ndeny,example.com,\n#include ../10-bob.csv\n").unwrap();
+
+    let rules = Rules::load(dir.join("rules.csv")).unwrap();
+    assert_eq!(rules.len(), 5);
+    assert_eq!(evaluate(&rules, "10.0.0.5", Some("bob"), "db.internal.corp", 443), (Action::Allow, Some(0)));
+    assert_eq!(evaluate(&rules, "10.0.0.5", Some("alice"), "db.internal.corp", 443), (Action::Deny, Some(1)));
+    assert_eq!(evaluate(&rules, "10.0.0.5", None, "203.0.113.7", 25), (Action::Deny, Some(2)));
+    assert_eq!(evaluate(&rules, "10.0.0.5", None, "example.com", 443), (Action::Deny, Some(3)));
+
+    // A file including itself is stopped instead of recursing forever
+    std::fs::write(dir.join("loop.csv"), "action,destination\n#include loop.csv\n").unwrap();
+    assert!(Rules::load(dir.join("loop.csv")).is_err());
+    std::fs::write(dir.join("missing.csv"), "action,destination\n#include nowhere.csv\n").unwrap();
+    assert!(Rules::load(dir.join("missing.csv")).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+/// Rules for other profiles are dropped, those without a profile always apply
+fn rules_profile() {
+    let rules = "action,destination,profile
+deny,*.staging.corp,production
+allow,*.staging.corp,staging
+deny,*.internal.corp,
+";
+    let staging = Rules::from_reader(rules.as_bytes()).unwrap().profile("staging");
+    assert_eq!(staging.len(), 2);
+    assert_eq!(evaluate(&staging, "10.0.0.5", None, "api.staging.corp", 443), (Action::Allow, Some(0)));
+
+    let production = Rules::from_reader(rules.as_bytes()).unwrap().profile("production");
+    assert_eq!(evaluate(&production, "10.0.0.5", None, "api.staging.corp", 443), (Action::Deny, Some(0)));
+    assert_eq!(evaluate(&production, "10.0.0.5", None, "db.internal.corp", 443), (Action::Deny, Some(1)));
+
+    // Without a profile every rule applies
+    let all = Rules::from_reader(rules.as_bytes()).unwrap();
+    assert_eq!(all.len(), 3);
+}
+
+#[test]
+/// Domain-only rules are found for literal IPs when everything but the destination matches
+fn rules_domain_rule() {
+    let rules = Rules::from_reader(RULES.as_bytes()).unwrap();
+    let destination = Destination::parse("203.0.113.7");
+    let request = |source: &str, user, port| Request {
+        source: source.parse::<IpAddr>().unwrap(),
+        user,
+        groups: &[],
+        destination: &destination,
+        port,
+        asn: None,
+        tag: None,
+    };
+
+    // The first rule applies to every client and port, the second to bob on 10/8 and 443 only
+    assert_eq!(rules.domain_rule(&request("192.168.1.1", None, 80)).map(|(index, _)| index), Some(0));
+    assert_eq!(rules.iter().map(|rule| rule.is_domain_only()).collect::<Vec<_>>(), [true, true, false]);
+
+    let networks = Rules::from_reader("action,user,source,destination,port\ndeny,,,10.0.0.0/8,\nallow,bob,,example.com,443\n".as_bytes()).unwrap();
+    assert_eq!(networks.domain_rule(&request("192.168.1.1", Some("bob"), 443)).map(|(index, _)| index), Some(1));
+    assert_eq!(networks.domain_rule(&request("192.168.1.1", Some("alice"), 443)), None);
+    assert_eq!(networks.domain_rule(&request("192.168.1.1", Some("bob"), 80)), None);
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// With hostnames required, literal IPs are refused while a domain rule applies
+fn rules_require_hostnames() {
+    use std::net::TcpStream;
+    use std::thread;
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let rules = Rules::from_reader(format!("action,user,source,destination,port\ndeny,,,echo.test,{}\n", echo.port()).as_bytes()).unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_rules(rules)
+        .with_require_hostnames(true);
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve().unwrap());
+
+    let error = client::connect(&mut TcpStream::connect(addr).unwrap(), "127.0.0.1", echo.port(), None).unwrap_err();
+    assert!(error.to_string().contains("reply code 2"), "{}", error);
+    // No domain rule applies to other ports
+    let other = bench::spawn_echo_server().unwrap();
+    assert!(client::connect(&mut TcpStream::connect(addr).unwrap(), "127.0.0.1", other.port(), None).is_ok());
+}
+
+#[test]
+/// Allow rules can route requests via Tor
+fn rules_via() {
+    let rules = Rules::from_reader("action,user,source,destination,port,via\nallow,,,*.onion,,tor\nallow,,,,,\n".as_bytes()).unwrap();
+    let via = |host: &str| {
+        let destination = Destination::parse(host);
+        let verdict = rules.evaluate(&Request {
+            source: "10.0.0.5".parse::<IpAddr>().unwrap(),
+            user: None,
+            groups: &[],
+            destination: &destination,
+            port: 80,
+            asn: None,
+            tag: None,
+        });
+        verdict.rule.and_then(|(_, rule)| rule.via())
+    };
+    assert_eq!(via("duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion"), Some(rules::Via::Tor));
+    assert_eq!(via("example.com"), None);
+
+    assert!(Rules::from_reader("action,user,source,destination,port,via\nallow,,,,,i2p\n".as_bytes()).is_err());
+    assert!(Rules::from_reader("action,user,source,destination,port,via\ndeny,,,,,tor\n".as_bytes()).is_err());
+}
+
+#[test]
+/// Allow rules can cap their connections, shared between clones of the rules
+fn rules_cap() {
+    let rules = Rules::from_reader("action,user,source,destination,port,max_connections\nallow,,,*.internal.corp,,2\n".as_bytes()).unwrap();
+    let cap = rules.iter().next().unwrap().cap().unwrap().clone();
+    assert_eq!(cap.max(), 2);
+
+    let first = cap.claim().unwrap();
+    let _second = rules.clone().iter().next().unwrap().cap().unwrap().claim().unwrap();
+    assert_eq!(cap.open(), 2);
+    assert!(cap.claim().is_none());
+    drop(first);
+    assert!(cap.claim().is_some());
+
+    assert!(Rules::from_reader("action,user,source,destination,port,max_connections\nallow,,,,,many\n".as_bytes()).is_err());
+    assert!(Rules::from_reader("action,user,source,destination,port,max_connections\ndeny,,,,,5\n".as_bytes()).is_err());
+}
+
+#[test]
+/// Rules with a tag only match requests tagged with it, and tags are split off usernames
+fn rules_tag() {
+    let rules = Rules::from_reader("action,user,source,destination,port,priority,tag\nallow,bob,,,,bulk,backup\nallow,,,,,,\n".as_bytes()).unwrap();
+    assert_eq!(rules.iter().next().unwrap().to_string(), "allow user=bob source=* destination=* port=* priority=bulk tag=backup");
+    let destination = Destination::parse("example.com");
+    let priority = |tag| {
+        let verdict = rules.evaluate(&Request {
+            source: "10.0.0.5".parse::<IpAddr>().unwrap(),
+            user: Some("bob"),
+            groups: &[],
+            destination: &destination,
+            port: 443,
+            asn: None,
+            tag,
+        });
+        verdict.rule.and_then(|(_, rule)| rule.priority())
+    };
+    assert_eq!(priority(Some("backup")), Some(Priority::Bulk));
+    assert_eq!(priority(Some("fast")), None);
+    assert_eq!(priority(None), None);
+
+    assert_eq!(rules::split_tag("bob+backup"), ("bob", Some("backup")));
+    assert_eq!(rules::split_tag("bob+work@example.com"), ("bob+work@example.com", None));
+    assert_eq!(rules::split_tag("bob+"), ("bob+", None));
+    assert_eq!(rules::split_tag("+backup"), ("+backup", None));
+    assert!(Rules::from_reader("action,user,source,destination,port,tag\nallow,,,,,no spaces\n".as_bytes()).is_err());
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// Clients pick rules by tagging their username, once username tags are enabled
+fn rules_username_tag() {
+    use std::net::TcpStream;
+    use std::thread;
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let rules = Rules::from_reader("action,user,source,destination,port,tag\ndeny,,,,,blocked\nallow,,,,,\n".as_bytes()).unwrap();
+    let users: Vec<User> = csv::Reader::from_reader("username,password\nbob,secret\n".as_bytes())
+        .deserialize().collect::<Result<_, _>>().unwrap();
+    let proxy = |tags| {
+        let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], users.clone()).unwrap()
+            .with_rules(rules.clone())
+            .with_username_tags(tags);
+        let addr = proxy.local_addr().unwrap();
+        thread::spawn(move || proxy.serve().unwrap());
+        addr
+    };
+    let connect = |addr, username| client::connect(&mut TcpStream::connect(addr).unwrap(), &echo.ip().to_string(), echo.port(), Some((username, "secret")));
+
+    let addr = proxy(true);
+    assert!(connect(addr, "bob").is_ok());
+    assert!(connect(addr, "bob+fast").is_ok());
+    let error = connect(addr, "bob+blocked").unwrap_err();
+    assert!(error.to_string().contains("reply code 2"), "{}", error);
+
+    // Without tags the whole username has to log in
+    assert!(connect(proxy(false), "bob+fast").is_err());
+}
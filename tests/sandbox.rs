@@ -0,0 +1,23 @@
+#![cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"), feature = "testing", feature = "socks5"))]
+use merino::*;
+use std::process::Command;
+
+#[test]
+/// Sessions still relay once the filter is installed, but processes can no longer be spawned
+fn sandbox_seccomp() {
+    // The filter applies to the whole test process, hence the only test in this file
+    let echo = testing::spawn_echo_server().unwrap();
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    sandbox::seccomp().unwrap();
+
+    let mut client = testing::handshake(&proxy, &echo.ip().to_string(), echo.port(), None).unwrap();
+    assert_eq!(client.roundtrip(b"hello").unwrap(), b"hello");
+    client.close().unwrap();
+
+    assert!(Command::new("true").status().is_err());
+
+    // Soft limits keep seeing the process's usage
+    let usage = process::ProcessUsage::sample();
+    assert!(usage.open_fds.unwrap() >= 3);
+    assert!(usage.resident_memory.unwrap() > 0);
+}
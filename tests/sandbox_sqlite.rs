@@ -0,0 +1,27 @@
+#![cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"), feature = "testing", feature = "sqlite"))]
+use merino::sqlite::SqliteStore;
+use merino::*;
+
+#[test]
+/// Users in an SQLite database still log in, and their usage is recorded, once the filter is installed
+fn sandbox_seccomp_sqlite() {
+    // The filter applies to the whole test process, hence a file of its own
+    let path = std::env::temp_dir().join(format!("merino-seccomp-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    SqliteStore::open(&path).unwrap().add_user("alice", "secret", None, None).unwrap();
+
+    let echo = testing::spawn_echo_server().unwrap();
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], Vec::new()).unwrap()
+        .with_store(SqliteStore::open(&path).unwrap());
+    sandbox::seccomp().unwrap();
+
+    let mut client = testing::handshake(&proxy, &echo.ip().to_string(), echo.port(), Some(("alice", "secret"))).unwrap();
+    assert_eq!(client.roundtrip(b"hello").unwrap(), b"hello");
+    client.close().unwrap();
+    assert!(testing::handshake(&proxy, &echo.ip().to_string(), echo.port(), Some(("alice", "wrong"))).is_err());
+
+    let store = SqliteStore::open(&path).unwrap();
+    assert!(store.users().unwrap()[0].used > 0);
+    drop(store);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,42 @@
+#![cfg(feature = "rhai")]
+use merino::rules::{Action, Destination, Request};
+use merino::script::ScriptPlugin;
+use merino::*;
+
+const SCRIPT: &str = r#"
+fn on_request(ctx) {
+    if ctx.port == 25 { return "deny"; }
+    if ctx.dst.ends_with(".internal.corp") { return ctx.user == "admin"; }
+    "allow"
+}
+"#;
+
+fn decide(plugin: &ScriptPlugin, user: Option<&str>, host: &str, port: u16) -> Action {
+    let destination = Destination::parse(host);
+    plugin.on_request(1, &Request {
+        source: "10.0.0.5".parse().unwrap(),
+        user,
+        groups: &[],
+        destination: &destination,
+        port,
+        asn: None,
+        tag: None,
+    }).unwrap()
+}
+
+#[test]
+/// Scripts decide requests from the request context
+fn script_on_request() {
+    let plugin = ScriptPlugin::from_source(SCRIPT).unwrap();
+
+    assert_eq!(decide(&plugin, None, "example.com", 443), Action::Allow);
+    assert_eq!(decide(&plugin, None, "example.com", 25), Action::Deny);
+    assert_eq!(decide(&plugin, Some("bob"), "db.internal.corp", 5432), Action::Deny);
+    assert_eq!(decide(&plugin, Some("admin"), "db.internal.corp", 5432), Action::Allow);
+}
+
+#[test]
+/// Scripts without on_request are rejected
+fn script_requires_on_request() {
+    assert!(ScriptPlugin::from_source("fn on_close(session, bytes) {}").is_err());
+}
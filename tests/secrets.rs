@@ -0,0 +1,79 @@
+use merino::secrets;
+use std::env;
+use std::fs;
+
+#[test]
+/// Plain values, including URLs, are taken literally
+fn secrets_literal() {
+    assert_eq!(secrets::resolve("host=db user=merino").unwrap(), "host=db user=merino");
+    assert_eq!(secrets::resolve("redis://127.0.0.1/").unwrap(), "redis://127.0.0.1/");
+    assert!(!secrets::is_reference("redis://127.0.0.1/"));
+    assert!(secrets::is_reference("vault:secret/data/merino#dsn"));
+}
+
+#[test]
+/// Environment variables, files and systemd credentials are read, trimming trailing newlines
+fn secrets_sources() {
+    env::set_var("MERINO_TEST_SECRET", "from-env");
+    assert_eq!(secrets::resolve("env:MERINO_TEST_SECRET").unwrap(), "from-env");
+    assert!(secrets::resolve("env:MERINO_TEST_UNSET").is_err());
+
+    let dir = env::temp_dir().join(format!("merino-secrets-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("db-dsn"), "host=db password=hunter2\n").unwrap();
+    assert_eq!(secrets::resolve(&format!("file:{}", dir.join("db-dsn").display())).unwrap(), "host=db password=hunter2");
+
+    env::set_var("CREDENTIALS_DIRECTORY", &dir);
+    assert_eq!(secrets::resolve("credential:db-dsn").unwrap(), "host=db password=hunter2");
+    assert!(secrets::resolve("credential:missing").is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[cfg(feature = "vault")]
+/// Fields of KV version 2 secrets are read from Vault with the token
+fn secrets_vault() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    env::set_var("VAULT_ADDR", format!("http://{}", listener.local_addr().unwrap()));
+    env::set_var("VAULT_TOKEN", "s.token");
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut head = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            head.push(line);
+        }
+        let body = r#"{"data":{"data":{"dsn":"host=db password=hunter2"},"metadata":{"version":3}}}"#;
+        write!(reader.get_mut(), "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        head
+    });
+
+    assert_eq!(secrets::resolve("vault:secret/data/merino#dsn").unwrap(), "host=db password=hunter2");
+    let head = server.join().unwrap();
+    assert_eq!(head[0], "GET /v1/secret/data/merino HTTP/1.1\r\n");
+    assert!(head.contains(&"X-Vault-Token: s.token\r\n".to_string()));
+
+    assert!(secrets::resolve("vault:secret/data/merino").is_err());
+
+    // https:// addresses are spoken to over TLS, so the token never crosses in the clear
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    env::set_var("VAULT_ADDR", format!("https://{}", listener.local_addr().unwrap()));
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut first = [0u8; 1];
+        std::io::Read::read_exact(&mut &stream, &mut first).unwrap();
+        first[0]
+    });
+    assert!(secrets::resolve("vault:secret/data/merino#dsn").is_err());
+    // A TLS handshake record
+    assert_eq!(server.join().unwrap(), 0x16);
+}
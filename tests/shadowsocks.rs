@@ -0,0 +1,84 @@
+#![cfg(feature = "shadowsocks")]
+use merino::shadowsocks::{self, Inbound, Stream};
+use merino::*;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+
+fn hex(text: &str) -> Vec<u8> {
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+}
+
+#[test]
+/// Sealing matches the AEAD test vector of RFC 8439 section 2.8.2
+fn shadowsocks_aead_vector() {
+    let key: [u8; 32] = std::array::from_fn(|i| 0x80 + i as u8);
+    let nonce = [0x07, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47];
+    let aad = hex("50515253c0c1c2c3c4c5c6c7");
+    let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.";
+
+    let mut data = plaintext.to_vec();
+    let tag = shadowsocks::seal(&key, &nonce, &aad, &mut data);
+    assert_eq!(tag.to_vec(), hex("1ae10b594f09e26a7e902ecbd0600691"));
+    assert_eq!(&data[..16], &hex("d31a8d34648e60db7b86afbc53ef7ec2")[..]);
+
+    assert!(!shadowsocks::open(&key, &nonce, &[], &mut data.clone(), &tag));
+    assert!(shadowsocks::open(&key, &nonce, &aad, &mut data, &tag));
+    assert_eq!(&data[..], &plaintext[..]);
+}
+
+#[test]
+/// Streams sealed by another implementation open with the same password
+fn shadowsocks_stream_vector() {
+    assert_eq!(shadowsocks::key("secret").to_vec(), hex("5ebe2294ecd0e0f08eab7690d2a6ee6926ae5cc854e36b6bdfca366848dea6bb"));
+
+    let sealed = hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\
+        7d9000cc6fe43ae102faf407e2b473b66c95fd8adee290464d2805ed6be44081803e90b4401028");
+    let mut plaintext = String::new();
+    Stream::client(&sealed[..], "secret").read_to_string(&mut plaintext).unwrap();
+    assert_eq!(plaintext, "hello");
+    assert!(Stream::client(&sealed[..], "wrong").read_to_string(&mut plaintext).is_err());
+}
+
+fn proxy(password: &str) -> std::net::SocketAddr {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_shadowsocks(Inbound::new(addr, password)).unwrap();
+    std::thread::spawn(move || proxy.serve());
+    addr
+}
+
+#[test]
+/// Clients with the password are relayed to the destination they name
+fn shadowsocks_relay() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let addr = proxy("secret");
+
+    let mut stream = Stream::client(TcpStream::connect(addr).unwrap(), "secret");
+    shadowsocks::connect(&mut stream, &echo.ip().to_string(), echo.port()).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // Large writes are split across chunks
+    let large = vec![7u8; 100_000];
+    stream.write_all(&large).unwrap();
+    let mut echoed = vec![0u8; large.len()];
+    stream.read_exact(&mut echoed).unwrap();
+    assert_eq!(echoed, large);
+}
+
+#[test]
+/// Clients with the wrong password are read from until they hang up, and get nothing back
+fn shadowsocks_wrong_password() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let addr = proxy("secret");
+
+    let mut stream = Stream::client(TcpStream::connect(addr).unwrap(), "wrong");
+    shadowsocks::connect(&mut stream, &echo.ip().to_string(), echo.port()).unwrap();
+    stream.write_all(b"hello").unwrap();
+    stream.get_ref().shutdown(Shutdown::Write).unwrap();
+    let mut raw = Vec::new();
+    stream.get_ref().read_to_end(&mut raw).unwrap();
+    assert!(raw.is_empty());
+}
@@ -0,0 +1,27 @@
+#![cfg(feature = "socks5")]
+use merino::*;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::thread;
+
+#[test]
+/// Denied requests are told they succeeded, then get nothing back while nothing reaches the destination
+fn sinkhole_denied() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let rules = Rules::from_reader(format!("action,user,source,destination,port\ndeny,,,,{}\n", echo.port()).as_bytes()).unwrap();
+    let proxy = Arc::new(Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_rules(rules)
+        .with_sinkhole(true));
+
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let serving = { let proxy = proxy.clone(); thread::spawn(move || proxy.serve_connection(server)) };
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).unwrap();
+    assert!(echoed.is_empty());
+    assert!(matches!(serving.join().unwrap(), Err(MerinoError::Denied { .. })));
+}
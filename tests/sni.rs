@@ -0,0 +1,97 @@
+use merino::sni::{self, ClientHello};
+
+/// A TLS 1.3 style ClientHello record, with a server_name extension if `name` is given
+fn client_hello(name: Option<&str>) -> Vec<u8> {
+    let mut extensions = vec![0x00, 0x0b, 0, 2, 1, 0];
+    if let Some(name) = name {
+        let len = name.len() as u16;
+        extensions.extend([0, 0]);
+        extensions.extend((len + 5).to_be_bytes());
+        extensions.extend((len + 3).to_be_bytes());
+        extensions.push(0);
+        extensions.extend(len.to_be_bytes());
+        extensions.extend(name.as_bytes());
+    }
+    let mut body = vec![3, 3];
+    body.extend([7; 32]);
+    body.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+    body.extend((extensions.len() as u16).to_be_bytes());
+    body.extend(extensions);
+
+    let mut handshake = vec![1];
+    handshake.extend(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend(body);
+    let mut record = vec![22, 3, 1];
+    record.extend((handshake.len() as u16).to_be_bytes());
+    record.extend(handshake);
+    record
+}
+
+#[test]
+/// The server name is read from complete ClientHellos only
+fn sni_parse() {
+    let hello = client_hello(Some("Example.COM"));
+    assert_eq!(sni::parse(&hello), ClientHello::Tls(Some("example.com".to_string())));
+    assert_eq!(sni::parse(&client_hello(None)), ClientHello::Tls(None));
+
+    for len in [0, 3, 5, hello.len() - 1].iter() {
+        assert_eq!(sni::parse(&hello[..*len]), ClientHello::Incomplete);
+    }
+    assert_eq!(sni::parse(b"GET / HTTP/1.1\r\n"), ClientHello::NotTls);
+    assert_eq!(sni::parse(b"SSH-2.0-OpenSSH_9.6\r\n"), ClientHello::NotTls);
+    // A handshake record that isn't a ClientHello
+    assert_eq!(sni::parse(&[22, 3, 3, 0, 4, 2, 0, 0, 0]), ClientHello::NotTls);
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// Sessions are reset when the rules deny their server name, which is logged either way
+fn sni_proxy() {
+    use merino::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let out = Shared::default();
+    let rules = Rules::from_reader("action,user,source,destination,port\ndeny,,,*.blocked.example,\n".as_bytes()).unwrap();
+    let proxy = Arc::new(Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_rules(rules)
+        .with_sni(true)
+        .with_access_log(AccessLog::new(out.clone())));
+
+    let session = |name: &str| {
+        let (mut stream, server) = UnixStream::pair().unwrap();
+        let serving = { let proxy = proxy.clone(); thread::spawn(move || proxy.serve_connection(server)) };
+        client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+        let hello = client_hello(Some(name));
+        stream.write_all(&hello).unwrap();
+        let mut echoed = vec![0u8; hello.len()];
+        let relayed = stream.read_exact(&mut echoed).is_ok() && echoed == hello;
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        serving.join().unwrap().unwrap();
+        relayed
+    };
+    assert!(session("www.allowed.example"));
+    assert!(!session("www.blocked.example"));
+
+    let log = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains(" 200 ") && lines[0].contains(" \"www.allowed.example\" \"socks5 m=00 cmd=1 "), "{}", lines[0]);
+    assert!(lines[1].contains(" 403 - \"www.blocked.example\" \"socks5 "), "{}", lines[1]);
+}
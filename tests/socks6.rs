@@ -0,0 +1,65 @@
+#![cfg(feature = "socks6")]
+use merino::rules::Destination;
+use merino::socks6::{self, Request};
+use merino::*;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+#[test]
+/// Requests read back as written, padding, credentials and initial data included
+fn socks6_request_round_trip() {
+    let mut request = Request::connect("example.org", 443);
+    request.methods = vec![AuthMethods::UserPass as u8];
+    request.credentials = Some(("bob".to_string(), "secret".to_string()));
+    request.initial_data = b"hello".to_vec();
+
+    let bytes = request.to_bytes();
+    assert_eq!(bytes[0], socks6::SOCKS6_VERSION);
+    let read = Request::read(&mut &bytes[..], &limits::Limits::default()).unwrap();
+    assert_eq!(read, request);
+    assert_eq!(read.destination, Destination::parse("example.org"));
+}
+
+#[test]
+/// Initial data reaches the destination before anything else is relayed
+fn socks6_connect_initial_data() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_versions(vec![5, 6]);
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    thread::spawn(move || proxy.serve_connection(server));
+
+    let mut request = Request::connect(&echo.ip().to_string(), echo.port());
+    request.initial_data = b"early".to_vec();
+    socks6::connect(&mut stream, &request).unwrap();
+    stream.write_all(b" data").unwrap();
+    let mut buf = [0u8; 10];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"early data");
+}
+
+#[test]
+/// Clients without credentials are refused when only username/password is allowed
+fn socks6_auth_required() {
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], Vec::new()).unwrap()
+        .with_versions(vec![5, 6]);
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let serving = thread::spawn(move || proxy.serve_connection(server));
+
+    assert!(socks6::connect(&mut stream, &Request::connect("127.0.0.1", 80)).is_err());
+    assert!(matches!(serving.join().unwrap(), Err(MerinoError::Denied { .. })));
+}
+
+#[test]
+/// NOOP requests are answered without connecting anywhere
+fn socks6_noop() {
+    let proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_versions(vec![5, 6]);
+    let (mut stream, server) = UnixStream::pair().unwrap();
+    let serving = thread::spawn(move || proxy.serve_connection(server));
+
+    let request = Request { command: 0, ..Request::connect("127.0.0.1", 80) };
+    socks6::connect(&mut stream, &request).unwrap();
+    serving.join().unwrap().unwrap();
+}
@@ -0,0 +1,34 @@
+#![cfg(feature = "socks5")]
+use merino::speedtest::{self, SpeedtestConfig};
+use merino::*;
+use std::time::Duration;
+
+fn config(via: String, credentials: Option<(&str, &str)>) -> SpeedtestConfig {
+    SpeedtestConfig {
+        via,
+        credentials: credentials.map(|(user, password)| (user.to_string(), password.to_string())),
+        bytes: 1 << 20,
+        pings: 5,
+        timeout: Duration::from_secs(5),
+    }
+}
+
+#[test]
+/// A proxy is measured against its own echo and discard destinations, with nothing running behind it
+fn speedtest_through_proxy() {
+    let users: Vec<User> = csv::Reader::from_reader("username,password\nbob,secret\n".as_bytes())
+        .deserialize().collect::<Result<_, _>>().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], users).unwrap();
+    let addr = proxy.local_addr().unwrap();
+    std::thread::spawn(move || proxy.serve());
+
+    let report = speedtest::run(&config(addr.to_string(), Some(("bob", "secret")))).unwrap();
+    assert_eq!(report.pings.len(), 5);
+    assert!(report.latency().is_some());
+    assert_eq!(report.bytes, 1 << 20);
+    let printed = report.to_string();
+    assert!(printed.contains("over 5 round trips") && printed.contains("Upload:") && printed.contains("Echo:"), "{}", printed);
+
+    let refused = speedtest::run(&config(addr.to_string(), Some(("bob", "wrong")))).unwrap_err();
+    assert!(refused.to_string().contains("refused merino.echo"), "{}", refused);
+}
@@ -0,0 +1,44 @@
+#![cfg(feature = "sqlite")]
+use merino::sqlite::SqliteStore;
+use merino::*;
+
+#[test]
+/// Passwords are verified against their hashes and quotas cut users off
+fn sqlite_store_quota() {
+    let store = SqliteStore::open(":memory:").unwrap();
+    store.add_user("bob", "secret", Some(1000), None).unwrap();
+    assert!(store.add_user("bob", "other", None, None).is_err());
+
+    assert!(store.authenticate("bob", "secret").unwrap());
+    assert!(!store.authenticate("bob", "wrong").unwrap());
+    assert!(!store.authenticate("alice", "secret").unwrap());
+
+    store.record_usage("bob", 600).unwrap();
+    store.record_usage("bob", 600).unwrap();
+    assert_eq!(store.users().unwrap()[0].used, 1200);
+    assert!(!store.authenticate("bob", "secret").unwrap());
+
+    assert!(store.set_quota("bob", None).unwrap());
+    assert!(store.authenticate("bob", "secret").unwrap());
+    assert!(store.remove_user("bob").unwrap());
+    assert!(store.users().unwrap().is_empty());
+}
+
+#[test]
+/// Expired passwords are refused, and rotating replaces the password and its expiry
+fn sqlite_store_expiry() {
+    use chrono::{Duration, Utc};
+
+    let store = SqliteStore::open(":memory:").unwrap();
+    store.add_user("bob", "secret", None, Some(Utc::now() - Duration::days(1))).unwrap();
+    assert!(store.expires("bob").unwrap().unwrap() < Utc::now());
+
+    let password = store.rotate("bob", Some(Utc::now() + Duration::days(30))).unwrap().unwrap();
+    assert!(!store.authenticate("bob", "secret").unwrap());
+    assert!(store.authenticate("bob", &password).unwrap());
+    assert!(store.users().unwrap()[0].expires.unwrap() > Utc::now());
+
+    assert!(store.set_expiry("bob", None).unwrap());
+    assert_eq!(store.expires("bob").unwrap(), None);
+    assert!(store.rotate("alice", None).unwrap().is_none());
+}
@@ -0,0 +1,41 @@
+use merino::admin::BanTarget;
+use merino::state::LocalState;
+use merino::tarpit::{Subnets, Tarpit};
+use merino::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[test]
+/// Sessions are counted per user and released again
+fn local_state_counts() {
+    let state = LocalState::default();
+    assert_eq!(state.open_session("bob").unwrap(), 1);
+    assert_eq!(state.open_session("bob").unwrap(), 2);
+    assert_eq!(state.open_session("alice").unwrap(), 1);
+
+    state.close_session("bob").unwrap();
+    assert_eq!(state.open_session("bob").unwrap(), 2);
+}
+
+#[test]
+/// Bans and failed handshakes recorded through one proxy count for every proxy sharing the state
+fn shared_state_bans() {
+    let state = Arc::new(LocalState::default());
+    let first = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap().with_state(state.clone());
+    let second = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap().with_state(state.clone());
+    let mallory = "203.0.113.7".parse().unwrap();
+
+    first.admin().ban(BanTarget::Source(mallory), Duration::from_secs(60)).unwrap();
+    assert_eq!(second.admin().banned(mallory, None).unwrap().unwrap().target, BanTarget::Source(mallory));
+    assert_eq!(second.admin().bans().unwrap().len(), 1);
+    assert!(second.admin().lift(&BanTarget::Source(mallory)).unwrap());
+    assert!(first.admin().banned(mallory, Some("bob")).unwrap().is_none());
+
+    let (one, other) = (Tarpit::new(Duration::from_millis(500), Subnets::default()), Tarpit::new(Duration::from_millis(500), Subnets::default()));
+    one.share_failures(state.clone());
+    other.share_failures(state);
+    assert_eq!(one.failed(mallory), Duration::from_millis(500));
+    assert_eq!(other.failed(mallory), Duration::from_secs(1));
+    other.succeeded(mallory);
+    assert_eq!(one.failed(mallory), Duration::from_millis(500));
+}
@@ -0,0 +1,30 @@
+use merino::statsd::Statsd;
+use merino::*;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+#[test]
+/// Events are sent with the prefix and tags
+fn statsd_events() {
+    let agent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    agent.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let statsd = Statsd::connect(agent.local_addr().unwrap(), "proxy", &["env:test".to_string()]).unwrap();
+
+    let metrics = Metrics::default();
+    metrics.push_to(statsd);
+    metrics.connection_failed();
+    metrics.session_started(Duration::from_millis(12));
+
+    let mut buf = [0u8; 512];
+    let mut received = Vec::new();
+    for _ in 0..4 {
+        let n = agent.recv(&mut buf).unwrap();
+        received.push(String::from_utf8_lossy(&buf[..n]).to_string());
+    }
+    assert_eq!(received, vec![
+        "proxy.failed_connections:1|c|#env:test",
+        "proxy.sessions:1|c|#env:test",
+        "proxy.sessions_active:1|g|#env:test",
+        "proxy.handshake_latency:12|ms|#env:test",
+    ]);
+}
@@ -0,0 +1,116 @@
+use merino::tarpit::{Subnets, Tarpit, MAX_DELAY, SUBNET_ALLOWANCE};
+use std::time::Duration;
+
+#[test]
+/// Each failure from a source waits twice as long as the last, up to a cap, until it logs in
+fn tarpit_doubles() {
+    let tarpit = Tarpit::new(Duration::from_millis(500), Subnets::default());
+    let (mallory, alice) = ("10.0.0.66".parse().unwrap(), "10.0.1.5".parse().unwrap());
+
+    assert_eq!(tarpit.failed(mallory), Duration::from_millis(500));
+    assert_eq!(tarpit.failed(mallory), Duration::from_secs(1));
+    assert_eq!(tarpit.failed(mallory), Duration::from_secs(2));
+    assert_eq!(tarpit.failed(alice), Duration::from_millis(500));
+    // Few enough to stay within the subnet's allowance
+    for _ in 0..3 {
+        tarpit.failed(mallory);
+    }
+    assert_eq!(tarpit.failed(mallory), MAX_DELAY);
+
+    tarpit.succeeded(mallory);
+    assert_eq!(tarpit.failed(mallory), Duration::from_millis(500));
+}
+
+#[test]
+/// Sources rotating through a subnet are held back together once it exceeds its allowance
+fn tarpit_subnets() {
+    let tarpit = Tarpit::new(Duration::from_millis(500), Subnets::default());
+    for host in 1..=SUBNET_ALLOWANCE {
+        assert_eq!(tarpit.failed(format!("10.0.0.{}", host).parse().unwrap()), Duration::from_millis(500));
+    }
+    assert_eq!(tarpit.failed("10.0.0.100".parse().unwrap()), Duration::from_millis(500));
+    assert_eq!(tarpit.failed("10.0.0.101".parse().unwrap()), Duration::from_secs(1));
+    assert_eq!(tarpit.failed("10.0.0.102".parse().unwrap()), Duration::from_secs(2));
+    assert_eq!(tarpit.failed("10.0.1.1".parse().unwrap()), Duration::from_millis(500));
+
+    // Logging in forgets the source, not the subnet
+    tarpit.succeeded("10.0.0.102".parse().unwrap());
+    assert_eq!(tarpit.failed("10.0.0.102".parse().unwrap()), Duration::from_secs(4));
+
+    let subnets = Subnets::default();
+    assert_eq!(subnets.network("10.1.2.3".parse().unwrap()), "10.1.2.0".parse::<std::net::IpAddr>().unwrap());
+    assert_eq!(subnets.network("2001:db8:1:2:3:4:5:6".parse().unwrap()), "2001:db8:1:2::".parse::<std::net::IpAddr>().unwrap());
+}
+
+#[test]
+/// The allowance and prefix lengths are configurable, down to a single failure per whole address family
+fn tarpit_subnet_limits() {
+    let subnets = Subnets { allowance: 1, v4: 16, v6: 0 };
+    assert_eq!(subnets.network("10.1.2.3".parse().unwrap()), "10.1.0.0".parse::<std::net::IpAddr>().unwrap());
+    assert_eq!(subnets.network("2001:db8::1".parse().unwrap()), "::".parse::<std::net::IpAddr>().unwrap());
+
+    let tarpit = Tarpit::new(Duration::from_millis(500), subnets);
+    assert_eq!(tarpit.failed("10.1.0.1".parse().unwrap()), Duration::from_millis(500));
+    assert_eq!(tarpit.failed("10.1.200.1".parse().unwrap()), Duration::from_millis(500));
+    assert_eq!(tarpit.failed("10.1.200.2".parse().unwrap()), Duration::from_secs(1));
+    assert_eq!(tarpit.failed("10.2.0.1".parse().unwrap()), Duration::from_millis(500));
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// Wrong passwords are answered late, right ones straight away
+fn tarpit_login() {
+    use merino::*;
+    use std::net::TcpStream;
+    use std::time::Instant;
+
+    let users: Vec<User> = csv::Reader::from_reader("username,password\nbob,secret\n".as_bytes())
+        .deserialize().collect::<Result<_, _>>().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], users).unwrap()
+        .with_auth_delay(Duration::from_millis(200), Subnets::default());
+    let addr = proxy.local_addr().unwrap();
+    std::thread::spawn(move || proxy.serve());
+    let echo = bench::spawn_echo_server().unwrap();
+
+    let login = |password| {
+        let start = Instant::now();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let connected = client::connect(&mut stream, &echo.ip().to_string(), echo.port(), Some(("bob", password)));
+        (connected.is_ok(), start.elapsed())
+    };
+    let (ok, first) = login("guess");
+    assert!(!ok && first >= Duration::from_millis(200));
+    let (ok, second) = login("guess");
+    assert!(!ok && second >= Duration::from_millis(400));
+    let (ok, elapsed) = login("secret");
+    assert!(ok && elapsed < Duration::from_millis(200));
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// Unsupported versions and failed method negotiations are held back like failed logins
+fn tarpit_handshake() {
+    use merino::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Instant;
+
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], Vec::new()).unwrap()
+        .with_auth_delay(Duration::from_millis(200), Subnets::default());
+    let addr = proxy.local_addr().unwrap();
+    std::thread::spawn(move || proxy.serve());
+
+    // Time until the proxy answers `handshake`, returning what it answered
+    let answer = |handshake: &[u8]| {
+        let start = Instant::now();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(handshake).unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap_or(0);
+        (reply, start.elapsed())
+    };
+    let (reply, elapsed) = answer(&[4, 1, 0, 80, 127, 0, 0, 1, 0]);
+    assert!(reply.is_empty() && elapsed >= Duration::from_millis(200));
+    let (reply, elapsed) = answer(&[5, 1, AuthMethods::NoAuth as u8]);
+    assert!(reply == [5, AuthMethods::NoMethods as u8] && elapsed >= Duration::from_millis(400));
+}
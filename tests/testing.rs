@@ -0,0 +1,86 @@
+#![cfg(all(feature = "testing", feature = "socks5"))]
+use merino::testing::{self, MockClient};
+use merino::*;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+
+/// A proxy that accepts unauthenticated clients
+fn proxy() -> Merino {
+    Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+}
+
+#[test]
+/// In-memory clients are proxied to their destination
+fn testing_handshake() {
+    let echo = testing::spawn_echo_server().unwrap();
+    let proxy = proxy();
+
+    let mut client = testing::handshake(&proxy, &echo.ip().to_string(), echo.port(), None).unwrap();
+    assert_eq!(client.roundtrip(b"hello").unwrap(), b"hello");
+    client.close().unwrap();
+}
+
+#[test]
+/// Successful replies carry the address the proxy connected from
+fn testing_bind_address() {
+    let echo = testing::spawn_echo_server().unwrap();
+    let proxy = proxy();
+
+    let mut client = MockClient::new(&proxy);
+    let bind = client.connect(&echo.ip().to_string(), echo.port(), None).unwrap();
+    assert_eq!(bind.ip(), echo.ip());
+    assert_ne!(bind.port(), 0);
+    assert_ne!(bind, echo);
+    client.close().unwrap();
+}
+
+#[test]
+/// The reported address can hide the one the proxy connected from
+fn testing_reply_address() {
+    let echo = testing::spawn_echo_server().unwrap();
+
+    let unspecified = proxy().with_reply_address(ReplyAddress::Unspecified);
+    let mut client = MockClient::new(&unspecified);
+    assert_eq!(client.connect(&echo.ip().to_string(), echo.port(), None).unwrap(), "0.0.0.0:0".parse().unwrap());
+    client.close().unwrap();
+
+    let fixed = proxy().with_reply_address("203.0.113.7".parse().unwrap());
+    let mut client = MockClient::new(&fixed);
+    let bind = client.connect(&echo.ip().to_string(), echo.port(), None).unwrap();
+    assert_eq!(bind.ip().to_string(), "203.0.113.7");
+    assert_ne!(bind.port(), 0);
+    client.close().unwrap();
+
+    assert_eq!("local".parse(), Ok(ReplyAddress::Local));
+    assert!("somewhere".parse::<ReplyAddress>().is_err());
+}
+
+#[test]
+/// Handshakes refused by the proxy are reported to the client
+fn testing_denied() {
+    let echo = testing::spawn_echo_server().unwrap();
+    let rules = Rules::from_reader("action,user,source,destination,port\ndeny,,,127.0.0.1,\n".as_bytes()).unwrap();
+    let proxy = proxy().with_rules(rules);
+
+    let mut client = MockClient::new(&proxy);
+    assert!(client.connect(&echo.ip().to_string(), echo.port(), None).is_err());
+    client.close().unwrap();
+}
+
+#[test]
+/// Pipes carry bytes both ways and end with EOF once shut down
+fn testing_pipe() {
+    let (mut a, mut b) = testing::pipe();
+    a.write_all(b"ping").unwrap();
+    a.shutdown(Shutdown::Write).unwrap();
+
+    let mut buf = Vec::new();
+    b.read_to_end(&mut buf).unwrap();
+    assert_eq!(buf, b"ping");
+    assert!(a.write_all(b"more").is_err());
+
+    b.write_all(b"pong").unwrap();
+    let mut buf = [0u8; 4];
+    a.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"pong");
+}
@@ -0,0 +1,183 @@
+#![cfg(feature = "token")]
+use merino::store::UserStore;
+use merino::token::{IntrospectionStore, JwksStore};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Request heads and bodies an endpoint got
+type Requests = Arc<Mutex<Vec<(Vec<String>, String)>>>;
+
+/// Introspection endpoint answering `answer(token)` to every request, keeping the requests it got
+fn endpoint(answer: fn(&str) -> String) -> (String, Requests) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/oauth2/introspect", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut head = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push(line);
+            }
+            let len: usize = head.iter()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .map(|len| len.trim().parse().unwrap())
+                .unwrap_or(0);
+            let mut body = vec![0u8; len];
+            reader.read_exact(&mut body).unwrap();
+            let body = String::from_utf8(body).unwrap();
+            let token = body.split('&').find_map(|field| field.strip_prefix("token=")).unwrap_or("").to_string();
+            let answer = answer(&token);
+            write!(reader.get_mut(), "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", answer.len(), answer).unwrap();
+            seen.lock().unwrap().push((head, body));
+        }
+    });
+    (url, requests)
+}
+
+#[test]
+/// Active tokens are accepted for the user they name, once asked about and then from the cache
+fn token_introspection() {
+    let (url, requests) = endpoint(|token| match token {
+        "eyJ.good.sig" => r#"{"active":true,"sub":"ci-runner","exp":4102444800}"#.to_string(),
+        "anonymous" => r#"{"active":true}"#.to_string(),
+        _ => r#"{"active":false}"#.to_string(),
+    });
+    let store = IntrospectionStore::new(&url, Some("s3cret".to_string())).unwrap();
+
+    assert!(store.authenticate("ci-runner", "eyJ.good.sig").unwrap());
+    assert!(store.authenticate("ci-runner", "eyJ.good.sig").unwrap());
+    assert_eq!(requests.lock().unwrap().len(), 1);
+    let (head, body) = requests.lock().unwrap()[0].clone();
+    assert_eq!(head[0], "POST /oauth2/introspect HTTP/1.1\r\n");
+    assert!(head.contains(&"Authorization: Bearer s3cret\r\n".to_string()));
+    assert_eq!(body, "token=eyJ.good.sig&token_type_hint=access_token");
+
+    assert!(!store.authenticate("mallory", "eyJ.good.sig").unwrap());
+    assert!(!store.authenticate("ci-runner", "eyJ.revoked.sig").unwrap());
+    assert!(store.authenticate("anyone", "anonymous").unwrap());
+}
+
+#[test]
+/// Expired tokens are refused even if the endpoint still calls them active
+fn token_expired() {
+    let (url, _) = endpoint(|_| r#"{"active":true,"sub":"ci-runner","exp":1000000000}"#.to_string());
+    let store = IntrospectionStore::new(&url, None).unwrap();
+    assert!(!store.authenticate("ci-runner", "eyJ.old.sig").unwrap());
+}
+
+#[test]
+/// Endpoints must be http(s) URLs, https ones are spoken to over TLS, and failing ones fail authentication
+fn token_endpoint() {
+    assert!(IntrospectionStore::new("idp.example.com/introspect", None).is_err());
+    assert!(IntrospectionStore::new("ftp://idp.example.com/introspect", None).is_err());
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("https://{}/introspect", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut first = [0u8; 1];
+        stream.read_exact(&mut first).unwrap();
+        first[0]
+    });
+    assert!(IntrospectionStore::new(&url, Some("s3cret".to_string())).unwrap().authenticate("bob", "token").is_err());
+    // A TLS handshake record, so neither the token nor the secret went out in the clear
+    assert_eq!(server.join().unwrap(), 0x16);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/introspect", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 1024];
+        let _ = stream.read(&mut buf).unwrap();
+        stream.write_all(b"HTTP/1.0 401 Unauthorized\r\n\r\n").unwrap();
+    });
+    assert!(IntrospectionStore::new(&url, None).unwrap().authenticate("bob", "token").is_err());
+}
+
+/// A test Ed25519 key, as PKCS#8, and its public half as a JWK's `x`
+const ED25519_PKCS8: [u8; 48] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+    0x8d, 0xc4, 0xc1, 0x8a, 0x6e, 0x9d, 0x41, 0x71, 0xd8, 0x5c, 0x07, 0x50, 0xd1, 0xa5, 0x13, 0x8d,
+    0x32, 0x30, 0xe0, 0xfa, 0x95, 0x58, 0x06, 0x36, 0x7c, 0xa8, 0xfd, 0x09, 0x32, 0xc3, 0x81, 0xdc,
+];
+const ED25519_X: &str = "0BcAAS4GtLqXyZxYUS8dC-M_zkmKOs_Lm81bpeLb6KE";
+
+/// JWK set endpoint publishing the test key as `k1`, counting the requests it got
+fn jwks_endpoint() -> (String, Arc<Mutex<usize>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/.well-known/jwks.json", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(0));
+    let seen = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                line.clear();
+            }
+            let body = format!(r#"{{"keys":[{{"kty":"OKP","crv":"Ed25519","kid":"k1","x":"{}"}}]}}"#, ED25519_X);
+            write!(reader.get_mut(), "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+            *seen.lock().unwrap() += 1;
+        }
+    });
+    (url, requests)
+}
+
+/// A JWT with `claims`, signed by the test key as `kid`
+fn jwt(kid: &str, claims: serde_json::Value) -> String {
+    let header = jsonwebtoken::Header { kid: Some(kid.to_string()), ..jsonwebtoken::Header::new(jsonwebtoken::Algorithm::EdDSA) };
+    jsonwebtoken::encode(&header, &claims, &jsonwebtoken::EncodingKey::from_ed_der(&ED25519_PKCS8)).unwrap()
+}
+
+#[test]
+/// JWTs signed by a published key are accepted for the user they name, until they expire
+fn token_jwks() {
+    let (url, requests) = jwks_endpoint();
+    let store = JwksStore::new(&url, Some("https://idp.example.com".to_string()), Some("merino".to_string())).unwrap();
+    let claims = serde_json::json!({"sub": "ci-runner", "iss": "https://idp.example.com", "aud": "merino", "exp": 4102444800u64});
+
+    let token = jwt("k1", claims.clone());
+    assert!(store.authenticate("ci-runner", &token).unwrap());
+    assert!(!store.authenticate("mallory", &token).unwrap());
+
+    let mut old = claims.clone();
+    old["exp"] = 1000000000.into();
+    assert!(!store.authenticate("ci-runner", &jwt("k1", old)).unwrap());
+    let mut elsewhere = claims.clone();
+    elsewhere["aud"] = "other-service".into();
+    assert!(!store.authenticate("ci-runner", &jwt("k1", elsewhere)).unwrap());
+
+    // Forged or unknown keys, shared secrets and other passwords get nowhere
+    let mut forged = token.clone().into_bytes();
+    let byte = forged.len() - 10;
+    forged[byte] = if forged[byte] == b'A' { b'B' } else { b'A' };
+    let forged = String::from_utf8(forged).unwrap();
+    assert!(!store.authenticate("ci-runner", &forged).unwrap());
+    assert!(!store.authenticate("ci-runner", &jwt("k2", claims.clone())).unwrap());
+    let hmac = jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &jsonwebtoken::EncodingKey::from_secret(ED25519_X.as_bytes())).unwrap();
+    assert!(!store.authenticate("ci-runner", &hmac).unwrap());
+    assert!(!store.authenticate("ci-runner", "hunter2").unwrap());
+
+    // Unknown keys don't send merino to the endpoint on every attempt
+    assert_eq!(*requests.lock().unwrap(), 1);
+}
+
+#[test]
+/// Only one of the token stores may authenticate users, rather than the last one given replacing the other
+fn token_stores_exclusive() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_merino"))
+        .args(["--token-introspection", "https://idp/introspect", "--token-jwks", "https://idp/jwks.json", "--dry-run"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--token-introspection, --token-jwks"));
+}
@@ -0,0 +1,58 @@
+#![cfg(feature = "socks5")]
+use merino::*;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+/// A stand-in for Tor's SOCKS port, taking the isolation credentials merino sends
+fn fake_tor(isolations: &[&str]) -> (std::net::SocketAddr, std::sync::Arc<admin::Admin>) {
+    let csv: String = isolations.iter().map(|isolation| format!("{},merino\n", isolation)).collect();
+    let users: Vec<User> = csv::Reader::from_reader(format!("username,password\n{}", csv).as_bytes())
+        .deserialize().collect::<Result<_, _>>().unwrap();
+    let mut tor = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], users).unwrap();
+    let (addr, admin) = (tor.local_addr().unwrap(), tor.admin());
+    thread::spawn(move || tor.serve());
+    (addr, admin)
+}
+
+#[test]
+/// Requests a rule routes via Tor reach the destination through it, with credentials naming the client
+fn tor_isolation() {
+    let echo = bench::spawn_echo_server().unwrap();
+    let (tor, tor_admin) = fake_tor(&["client:127.0.0.1", "user:bob"]);
+    let rules = Rules::from_reader("action,user,source,destination,port,via\nallow,,,,,tor\n".as_bytes()).unwrap();
+    let users: Vec<User> = csv::Reader::from_reader("username,password\nbob,secret\n".as_bytes())
+        .deserialize().collect::<Result<_, _>>().unwrap();
+
+    for (methods, credentials, isolation) in [
+        (vec![AuthMethods::NoAuth as u8], None, "client:127.0.0.1"),
+        (vec![AuthMethods::UserPass as u8], Some(("bob", "secret")), "user:bob"),
+    ] {
+        let mut proxy = Merino::new(0, "127.0.0.1", methods, users.clone()).unwrap()
+            .with_rules(rules.clone())
+            .with_tor(tor);
+        let addr = proxy.local_addr().unwrap();
+        thread::spawn(move || proxy.serve());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        client::connect(&mut stream, &echo.ip().to_string(), echo.port(), credentials).unwrap();
+        stream.write_all(b"hello").unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(tor_admin.sessions().iter().any(|session| session.user.as_deref() == Some(isolation)));
+    }
+}
+
+#[test]
+/// Without a Tor port, requests routed via Tor fail to connect
+fn tor_missing() {
+    let rules = Rules::from_reader("action,user,source,destination,port,via\nallow,,,,,tor\n".as_bytes()).unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_rules(rules);
+    let addr = proxy.local_addr().unwrap();
+    thread::spawn(move || proxy.serve());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert!(client::connect(&mut stream, "127.0.0.1", 80, None).is_err());
+}
@@ -0,0 +1,85 @@
+use merino::totp;
+
+/// RFC 6238's SHA1 secret, "12345678901234567890"
+const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+#[test]
+/// Codes match RFC 6238's test vectors, cut to 6 digits
+fn totp_codes() {
+    let secret = totp::decode_secret(SECRET).unwrap();
+    assert_eq!(secret, b"12345678901234567890");
+    assert_eq!(totp::code(&secret, 59), "287082");
+    assert_eq!(totp::code(&secret, 1111111109), "081804");
+    assert_eq!(totp::code(&secret, 1234567890), "005924");
+
+    assert!(totp::verify(&secret, "287082", 59 + totp::STEP));
+    assert!(!totp::verify(&secret, "287082", 59 + 2 * totp::STEP));
+}
+
+#[test]
+/// Secrets round trip through base32, and short or malformed ones are refused
+fn totp_secrets() {
+    assert_eq!(totp::encode_secret(b"12345678901234567890"), SECRET);
+    assert_eq!(totp::decode_secret("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap(), b"12345678901234567890");
+    let secret = totp::generate_secret();
+    assert_eq!(totp::decode_secret(&secret).unwrap().len(), 20);
+    assert!(totp::decode_secret("GEZDGNBV").is_err());
+    assert!(totp::decode_secret("GEZDGNBVGY3TQOJQ1").is_err());
+}
+
+#[test]
+/// The code is the last 6 digits of the password
+fn totp_split() {
+    assert_eq!(totp::split("hunter2492039"), Some(("hunter2", "492039")));
+    assert_eq!(totp::split("492039"), Some(("", "492039")));
+    assert_eq!(totp::split("hunter2"), None);
+    assert_eq!(totp::split("12345"), None);
+}
+
+#[test]
+/// A verified code keeps working from the address that sent it, and no other
+fn totp_single_use() {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let secret = totp::decode_secret(SECRET).unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let code = totp::code(&secret, now);
+    let (client, attacker) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+    let logins = totp::Logins::default();
+
+    assert!(logins.check("bob", client, &secret, &code));
+    assert!(logins.check("bob", client, &secret, &code));
+    assert!(!logins.check("bob", attacker, &secret, &code));
+    assert!(logins.check("alice", attacker, &secret, &code));
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// Users with a secret log in with their password followed by a current code
+fn totp_login() {
+    use merino::*;
+    use std::net::TcpStream;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let users: Vec<User> = csv::Reader::from_reader(format!("username,password,totp\nbob,secret,{}\nalice,open,\n", SECRET).as_bytes())
+        .deserialize().collect::<Result<_, _>>().unwrap();
+    let mut proxy = Merino::new(0, "127.0.0.1", vec![AuthMethods::UserPass as u8], users).unwrap();
+    let addr = proxy.local_addr().unwrap();
+    std::thread::spawn(move || proxy.serve());
+    let echo = bench::spawn_echo_server().unwrap();
+
+    let login = |user, password: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        client::connect(&mut stream, &echo.ip().to_string(), echo.port(), Some((user, password))).is_ok()
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let code = totp::code(&totp::decode_secret(SECRET).unwrap(), now);
+    let stale = totp::code(&totp::decode_secret(SECRET).unwrap(), now - 10 * totp::STEP);
+
+    assert!(!login("bob", "secret"));
+    assert!(!login("bob", &format!("secret{}", stale)));
+    assert!(!login("bob", &format!("guess{}", code)));
+    assert!(login("bob", &format!("secret{}", code)));
+    assert!(login("bob", &format!("secret{}", code)));
+    assert!(login("alice", "open"));
+}
@@ -0,0 +1,95 @@
+use merino::transport::Stack;
+use std::io::{Read, Write};
+
+#[test]
+/// Layers decode what they encoded, and the same bytes look different on every connection
+fn transport_round_trip() {
+    let stack: Stack = "pad:255,xor:s3cret".parse().unwrap();
+    let greeting = [5u8, 1, 0];
+
+    let mut first = stack.wrap(Vec::new());
+    first.write_all(&greeting).unwrap();
+    first.write_all(&vec![7u8; 100_000]).unwrap();
+    let mut second = stack.wrap(Vec::new());
+    second.write_all(&greeting).unwrap();
+    assert_ne!(first.get_ref()[..11], second.get_ref()[..11]);
+
+    let mut decoded = Vec::new();
+    stack.wrap(&first.get_ref()[..]).read_to_end(&mut decoded).unwrap();
+    assert_eq!(&decoded[..3], &greeting);
+    assert_eq!(decoded.len(), 100_003);
+
+    let other: Stack = "pad:255,xor:other".parse().unwrap();
+    let mut garbled = Vec::new();
+    other.wrap(&first.get_ref()[..]).read_to_end(&mut garbled).unwrap_or(0);
+    assert_ne!(garbled, decoded);
+}
+
+#[test]
+/// Stacks are parsed from layer lists and displayed without their keys
+fn transport_parse() {
+    let stack: Stack = "pad:16, xor:s3cret".parse().unwrap();
+    assert_eq!(stack.to_string(), "pad,xor");
+    assert!("xor".parse::<Stack>().is_err());
+    assert!("pad:256".parse::<Stack>().is_err());
+    assert!("rot13".parse::<Stack>().is_err());
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+/// Compressible writes shrink on the wire, others are stored, and both come back whole
+fn transport_zstd() {
+    let stack: Stack = "zstd:3,xor:s3cret".parse().unwrap();
+    assert_eq!(stack.to_string(), "zstd,xor");
+    assert!("zstd:0".parse::<Stack>().is_err());
+    assert!("zstd:23".parse::<Stack>().is_err());
+
+    let text = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(4000);
+    let mut noise = vec![0u8; 50_000];
+    rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut noise);
+    let mut wrapped = stack.wrap(Vec::new());
+    wrapped.write_all(&text).unwrap();
+    assert!(wrapped.get_ref().len() < text.len() / 10);
+    wrapped.write_all(b"short").unwrap();
+    wrapped.write_all(&noise).unwrap();
+
+    let mut decoded = Vec::new();
+    stack.wrap(&wrapped.get_ref()[..]).read_to_end(&mut decoded).unwrap();
+    assert_eq!(decoded, [&text[..], b"short", &noise[..]].concat());
+}
+
+#[cfg(feature = "socks5")]
+#[test]
+/// A forward scrambling its connections reaches a proxy listening with the same transport
+fn transport_forward_to_proxy() {
+    use merino::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    let echo = bench::spawn_echo_server().unwrap();
+    let stack: Stack = "pad:64,xor:s3cret".parse().unwrap();
+    let mut remote = Merino::new(0, "127.0.0.1", vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap()
+        .with_transport(stack.clone());
+    let remote_addr = remote.local_addr().unwrap();
+    thread::spawn(move || remote.serve());
+
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let forward: Forward = format!("127.0.0.1:{}={}", port, remote_addr).parse().unwrap();
+    let listen = forward.listen;
+    let mut local = Merino::new(0, "127.0.0.1", Vec::new(), Vec::new()).unwrap()
+        .with_forward(forward).unwrap()
+        .with_forward_transport(stack);
+    thread::spawn(move || local.serve());
+
+    let mut stream = TcpStream::connect(listen).unwrap();
+    client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // Plain SOCKS clients get nowhere with the scrambled listener
+    let mut stream = TcpStream::connect(remote_addr).unwrap();
+    stream.set_read_timeout(Some(std::time::Duration::from_millis(500))).unwrap();
+    assert!(client::connect(&mut stream, &echo.ip().to_string(), echo.port(), None).is_err());
+}
@@ -0,0 +1,54 @@
+#![cfg(feature = "wasm")]
+use merino::rules::{Action, Destination, Request};
+use merino::wasm::WasmPlugin;
+use merino::*;
+
+/// Denies port 25 and any destination longer than 20 bytes
+const PLUGIN: &str = r#"
+(module
+  (import "merino" "log" (func $log (param i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "checking")
+  (func (export "alloc") (param $len i32) (result i32)
+    (i32.const 1024))
+  (func (export "on_request")
+    (param $session i64) (param $src i32) (param $src_len i32) (param $user i32) (param $user_len i32)
+    (param $dst i32) (param $dst_len i32) (param $port i32) (result i32)
+    (call $log (i32.const 0) (i32.const 8))
+    (i32.or (i32.eq (local.get $port) (i32.const 25))
+            (i32.gt_u (local.get $dst_len) (i32.const 20))))
+  (func (export "on_close") (param $session i64) (param $bytes i64)))
+"#;
+
+fn decide(plugin: &WasmPlugin, host: &str, port: u16) -> Action {
+    let destination = Destination::parse(host);
+    plugin.on_request(1, &Request {
+        source: "10.0.0.5".parse().unwrap(),
+        user: Some("bob"),
+        groups: &[],
+        destination: &destination,
+        port,
+        asn: None,
+        tag: None,
+    }).unwrap()
+}
+
+#[test]
+/// Plugins decide requests through `on_request`
+fn wasm_plugin_on_request() {
+    let plugin = WasmPlugin::from_bytes("test", &wat::parse_str(PLUGIN).unwrap()).unwrap();
+
+    assert_eq!(decide(&plugin, "example.com", 443), Action::Allow);
+    assert_eq!(decide(&plugin, "example.com", 25), Action::Deny);
+    assert_eq!(decide(&plugin, "a-rather-long-hostname.example.com", 443), Action::Deny);
+
+    plugin.on_connect_result(1, true);
+    plugin.on_close(1, 100);
+}
+
+#[test]
+/// Modules that can't receive request details are rejected
+fn wasm_plugin_requires_alloc() {
+    let wasm = wat::parse_str(r#"(module (func (export "on_request") (param i64 i32 i32 i32 i32 i32 i32 i32) (result i32) (i32.const 0)))"#).unwrap();
+    assert!(WasmPlugin::from_bytes("test", &wasm).is_err());
+}